@@ -0,0 +1,13 @@
+fn main() {
+    // the gRPC service is generated from its definition, with a vendored
+    // protoc so building needs nothing installed
+    #[cfg(feature = "grpc")]
+    {
+        let mut config = tonic_prost_build::Config::new();
+        config.protoc_executable(protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this platform"));
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_with_config(config, &["proto/upload.proto"], &["proto"])
+            .expect("failed to compile proto/upload.proto");
+    }
+}
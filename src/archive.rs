@@ -0,0 +1,177 @@
+//! Uploading each top-level subdirectory of an uploaded directory as one
+//! archive (`--archive-per-dir zip`), for trees of many small files that
+//! upload slowly one request at a time. Archives are never written to local
+//! disk: they are made on the blocking pool as they upload, over again for
+//! each attempt, and keep the structure inside the subdirectory.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use chrono::{Datelike, Timelike};
+use reqwest::Body;
+use tokio::sync::mpsc;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+use crate::body::{copy_file, stream_body, Chunk, ChunkReader, ChunkWriter, Streamed};
+use crate::inputs::Input;
+
+/// how subdirectories are archived, from `--archive-per-dir`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+}
+
+impl std::str::FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err(format!("unknown archive format {:?}, expected zip", s)),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    /// appended to the names of archived directories
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => ".zip",
+        }
+    }
+}
+
+/// The `inputs` found walking `dir`, named with `prefix`, with the files of
+/// each subdirectory of it replaced by one input for the subdirectory, named
+/// as its archive. Files directly in `dir` stay as they are.
+pub(crate) fn group(inputs: Vec<Input>, dir: &Path, prefix: &str, format: ArchiveFormat) -> Vec<Input> {
+    let mut archived = HashSet::new();
+    let mut grouped = Vec::new();
+    for input in inputs {
+        let Some((subdir, _)) = input.name.strip_prefix(prefix).and_then(|rest| rest.split_once('/')) else {
+            grouped.push(input);
+            continue;
+        };
+        if archived.insert(subdir.to_string()) {
+            grouped.push(Input {
+                path: dir.join(subdir).to_string_lossy().to_string(),
+                name: format!("{}{}{}", prefix, subdir, format.extension()),
+                priority: input.priority,
+            });
+        }
+    }
+    grouped
+}
+
+/// Body streaming an archive of `files`, named inside it by their names,
+/// read going easy on the machine if `nice`, what was sent added to
+/// `streamed`.
+pub(crate) fn body(format: ArchiveFormat, files: Vec<Input>, chunk_size: usize, nice: bool,
+    streamed: Arc<Mutex<Streamed>>) -> Body {
+    // a couple of chunks archived ahead of the upload
+    let (tx, rx) = mpsc::channel(2);
+    tokio::task::spawn_blocking(move || {
+        let out = ChunkWriter::new(tx.clone(), chunk_size);
+        let last = match format {
+            ArchiveFormat::Zip => write_zip(&files, out, chunk_size, nice),
+        };
+        let _ = tx.blocking_send(match last {
+            Ok(()) => Chunk::Done,
+            Err(e) => Chunk::Error(e),
+        });
+    });
+    stream_body(ChunkReader::new(rx), chunk_size, streamed)
+}
+
+fn write_zip(files: &[Input], out: ChunkWriter, chunk_size: usize, nice: bool) -> io::Result<()> {
+    let mut zip = ZipWriter::new_stream(out);
+    for input in files {
+        let file = File::open(&input.path)?;
+        let metadata = file.metadata()?;
+        let mut options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            // sizes go after the data when streaming, too late to switch to zip64
+            .large_file(metadata.len() >= u32::MAX as u64);
+        if let Some(time) = metadata.modified().ok().and_then(zip_time) {
+            options = options.last_modified_time(time);
+        }
+        zip.start_file(input.name.as_str(), options).map_err(io::Error::other)?;
+        copy_file(&file, &mut zip, chunk_size, nice)?;
+    }
+    zip.finish().map_err(io::Error::other)?.into_inner().flush()
+}
+
+/// local time as zip has it, None outside of what it can hold (1980-2107)
+fn zip_time(time: SystemTime) -> Option<DateTime> {
+    let time = chrono::DateTime::<chrono::Local>::from(time);
+    DateTime::from_date_and_time(time.year().try_into().ok()?, time.month() as u8, time.day() as u8,
+        time.hour() as u8, time.minute() as u8, time.second() as u8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn input(path: &str, name: &str) -> Input {
+        Input { path: path.to_string(), name: name.to_string(), priority: 2 }
+    }
+
+    #[test]
+    fn test_group() {
+        let inputs = vec![
+            input("d/a.txt", "d/a.txt"),
+            input("d/raw/b.fits", "d/raw/b.fits"),
+            input("d/raw/night1/c.fits", "d/raw/night1/c.fits"),
+            input("d/cal/e.fits", "d/cal/e.fits"),
+        ];
+        let grouped = group(inputs, Path::new("d"), "d/", ArchiveFormat::Zip);
+        let names: Vec<_> = grouped.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["d/a.txt", "d/raw.zip", "d/cal.zip"]);
+        assert_eq!(grouped[1].path, Path::new("d").join("raw").to_string_lossy());
+        assert_eq!(grouped[1].priority, 2);
+        // contents of `d/` go directly under the destination
+        let grouped = group(vec![input("d/raw/b.fits", "raw/b.fits")], Path::new("d"), "", ArchiveFormat::Zip);
+        assert_eq!(grouped[0].name, "raw.zip");
+    }
+
+    #[tokio::test]
+    async fn test_body() {
+        use http_body_util::BodyExt;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tempdir.path().join("night1")).unwrap();
+        let a = tempdir.path().join("a.txt");
+        let b = tempdir.path().join("night1/b.txt");
+        std::fs::write(&a, "first").unwrap();
+        std::fs::write(&b, "second".repeat(1000)).unwrap();
+        let files = vec![
+            input(a.to_str().unwrap(), "a.txt"),
+            input(b.to_str().unwrap(), "night1/b.txt"),
+        ];
+        let streamed = Arc::new(Mutex::new(Streamed { hasher: Some(sha2::Digest::new()), ..Default::default() }));
+        let data = body(ArchiveFormat::Zip, files, 1024, false, streamed.clone()).collect().await.unwrap().to_bytes();
+        // hashed as sent, for verifying the copy
+        let mut streamed = std::mem::take(&mut *streamed.lock().unwrap());
+        assert_eq!(streamed.bytes, data.len() as u64);
+        let hashed = crate::checksum::to_hex(&<sha2::Sha256 as sha2::Digest>::digest(&data));
+        assert_eq!(streamed.checksum(), Some(hashed));
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(data)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut contents = String::new();
+        archive.by_name("night1/b.txt").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "second".repeat(1000));
+
+        // a missing file fails the upload rather than leaving it out
+        let files = vec![input(tempdir.path().join("gone").to_str().unwrap(), "gone")];
+        let streamed = Arc::new(Mutex::new(Streamed { hasher: Some(sha2::Digest::new()), ..Default::default() }));
+        assert!(body(ArchiveFormat::Zip, files, 1024, false, streamed.clone()).collect().await.is_err());
+        assert_eq!(streamed.lock().unwrap().checksum(), None);
+    }
+}
@@ -0,0 +1,406 @@
+//! Request bodies streaming files. Each attempt of an upload streams the same
+//! open file with positional reads, so retries need neither a new file handle
+//! nor seeking back, and everything sent passes through one stream where it
+//! can be observed. FIFOs can't be read that way, they are streamed front to
+//! back once. Small files uploaded to several destinations are read once,
+//! their contents sent from memory to each.
+
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, ready};
+use std::time::{Instant, SystemTime};
+
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use reqwest::Body;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::io::ReaderStream;
+
+#[cfg(unix)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Reads a shared file from the start, independent of any other reader of it.
+/// Reads run on the blocking pool like tokio::fs does, going easy on the
+/// machine if `nice`.
+pub(crate) struct FileReader {
+    file: Arc<File>,
+    offset: u64,
+    nice: bool,
+    read: Option<JoinHandle<io::Result<Vec<u8>>>>,
+}
+
+impl FileReader {
+    pub(crate) fn new(file: Arc<File>, nice: bool) -> Self {
+        FileReader { file, offset: 0, nice, read: None }
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let read = this.read.get_or_insert_with(|| {
+            let (file, offset, len, nice) = (this.file.clone(), this.offset, buf.remaining(), this.nice);
+            tokio::task::spawn_blocking(move || {
+                let _reading = nice.then(|| crate::nice::reading(len));
+                let mut data = vec![0; len];
+                let n = read_at(&file, &mut data, offset)?;
+                data.truncate(n);
+                Ok(data)
+            })
+        });
+        let result = ready!(Pin::new(read).poll(cx));
+        this.read = None;
+        let data = result.map_err(io::Error::other)??;
+        // the buffer is normally the same size as when the read started
+        let n = data.len().min(buf.remaining());
+        buf.put_slice(&data[..n]);
+        this.offset += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// what a producer thread hands to a [`ChunkReader`]
+pub(crate) enum Chunk {
+    Data(Vec<u8>),
+    Error(io::Error),
+    Done,
+}
+
+/// Reads the chunks a producer thread sends over a channel. Fails rather than
+/// ending early if the producer goes away without sending `Done`, so a file is
+/// never uploaded truncated.
+pub(crate) struct ChunkReader {
+    chunks: mpsc::Receiver<Chunk>,
+    data: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl ChunkReader {
+    pub(crate) fn new(chunks: mpsc::Receiver<Chunk>) -> Self {
+        ChunkReader { chunks, data: Vec::new(), pos: 0, done: false }
+    }
+}
+
+impl AsyncRead for ChunkReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.pos == this.data.len() && !this.done {
+            match ready!(this.chunks.poll_recv(cx)) {
+                Some(Chunk::Data(data)) => (this.data, this.pos) = (data, 0),
+                Some(Chunk::Done) => this.done = true,
+                Some(Chunk::Error(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Err(io::Error::other("reader stopped before the end of the file"))),
+            }
+        }
+        let n = (this.data.len() - this.pos).min(buf.remaining());
+        buf.put_slice(&this.data[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Writes into chunks sent to a [`ChunkReader`], blocking while the upload
+/// catches up.
+pub(crate) struct ChunkWriter {
+    chunks: mpsc::Sender<Chunk>,
+    buf: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl ChunkWriter {
+    pub(crate) fn new(chunks: mpsc::Sender<Chunk>, chunk_size: usize) -> Self {
+        ChunkWriter { chunks, buf: Vec::with_capacity(chunk_size), chunk_size }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= self.chunk_size {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::replace(&mut self.buf, Vec::with_capacity(self.chunk_size));
+        // the upload was given up
+        self.chunks.blocking_send(Chunk::Data(data)).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+/// Copy all of `file` with positional reads.
+pub(crate) fn copy_file(file: &File, out: &mut impl Write, chunk_size: usize, nice: bool) -> io::Result<()> {
+    let mut buf = vec![0; chunk_size];
+    let mut offset = 0;
+    loop {
+        let reading = nice.then(|| crate::nice::reading(chunk_size));
+        let n = read_at(file, &mut buf, offset)?;
+        drop(reading);
+        if n == 0 {
+            return Ok(());
+        }
+        out.write_all(&buf[..n])?;
+        offset += n as u64;
+    }
+}
+
+/// Contents of a file uploaded to several destinations, read by the first of
+/// its uploads and sent from memory by the others while the file is the same.
+#[derive(Clone, Default)]
+pub(crate) struct SharedContents(Arc<tokio::sync::Mutex<Option<(Stamp, Bytes)>>>);
+
+/// size and modification time of a file
+pub(crate) type Stamp = (u64, Option<SystemTime>);
+
+impl SharedContents {
+    /// Contents of the file at `path` as of `stamp` (its size and
+    /// modification time), read unless they were already. None if they can't
+    /// be read or it changed in the meantime.
+    pub(crate) async fn get(&self, path: &str, stamp: Stamp) -> Option<Bytes> {
+        let mut contents = self.0.lock().await;
+        if let Some((read, bytes)) = &*contents
+            && *read == stamp {
+            return Some(bytes.clone());
+        }
+        let bytes = Bytes::from(tokio::fs::read(path).await.ok()?);
+        if bytes.len() as u64 != stamp.0 {
+            return None;
+        }
+        *contents = Some((stamp, bytes.clone()));
+        Some(bytes)
+    }
+}
+
+/// Body streaming the file at `path` from the start, read with io_uring if
+/// enabled (unless `nice`, io_uring reads have their own priority) or from
+/// the already open `file`.
+pub(crate) fn file_body(file: &Arc<File>, path: &str, chunk_size: usize, nice: bool) -> Body {
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    if !nice && let Some(body) = crate::uring::body(path, chunk_size) {
+        return body;
+    }
+    let _ = path;
+    Body::wrap_stream(ReaderStream::with_capacity(FileReader::new(file.clone(), nice), chunk_size))
+}
+
+/// Whether `metadata` is of a FIFO (named pipe), whose data can only be read
+/// once, front to back, and whose length isn't known up front.
+pub(crate) fn is_fifo(metadata: &std::fs::Metadata) -> bool {
+    #[cfg(unix)]
+    return std::os::unix::fs::FileTypeExt::is_fifo(&metadata.file_type());
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        false
+    }
+}
+
+/// what went through a [`stream_body`] so far
+#[derive(Default)]
+pub(crate) struct Streamed {
+    pub(crate) bytes: u64,
+    pub(crate) hasher: Option<Sha256>,
+    /// all of it was read
+    pub(crate) ended: bool,
+}
+
+impl Streamed {
+    /// The checksum of all that was read, if it was hashed and read to the
+    /// end.
+    pub(crate) fn checksum(&mut self) -> Option<String> {
+        let hasher = self.hasher.take().filter(|_| self.ended)?;
+        Some(crate::checksum::to_hex(&hasher.finalize()))
+    }
+}
+
+/// Passes on what's read, adding it to `streamed`.
+struct Observed<R> {
+    inner: R,
+    streamed: Arc<Mutex<Streamed>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Observed<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let (filled, room) = (buf.filled().len(), buf.remaining() > 0);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let data = &buf.filled()[filled..];
+        let mut streamed = self.streamed.lock().unwrap();
+        // nothing read into room for more is the end
+        streamed.ended |= data.is_empty() && room;
+        streamed.bytes += data.len() as u64;
+        if let Some(hasher) = &mut streamed.hasher {
+            hasher.update(data);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Body streaming `reader` front to back as it's read, for FIFOs and archives
+/// made on the way. It's sent chunked as its length isn't known, what was
+/// sent is added to `streamed`.
+pub(crate) fn stream_body(reader: impl AsyncRead + Send + Unpin + 'static, chunk_size: usize,
+    streamed: Arc<Mutex<Streamed>>) -> Body {
+    Body::wrap_stream(ReaderStream::with_capacity(Observed { inner: reader, streamed }, chunk_size))
+}
+
+/// Bytes of request bodies sent in a run, retries included, for measuring
+/// throughput as it happens rather than as files complete. Clones count
+/// together.
+#[derive(Clone, Default)]
+pub(crate) struct Sent(Arc<AtomicU64>);
+
+impl Sent {
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// `body`, noting in `started` when the transfer of its data starts and
+/// counting what it sends in `sent`.
+pub(crate) fn timed(body: Body, started: Arc<OnceLock<Instant>>, sent: Sent) -> Body {
+    Body::wrap(Timed { inner: body, started, sent })
+}
+
+/// Wraps a body to note when it's first polled, which is when the connection
+/// is up and the headers were sent, i.e. the transfer of the data starts.
+struct Timed {
+    inner: Body,
+    started: Arc<OnceLock<Instant>>,
+    sent: Sent,
+}
+
+impl http_body::Body for Timed {
+    type Data = Bytes;
+    type Error = reqwest::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        self.started.get_or_init(Instant::now);
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if let Some(data) = frame.as_ref().and_then(|f| f.as_ref().ok()).and_then(|f| f.data_ref()) {
+            self.sent.0.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_file_reader() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("data");
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let file = Arc::new(File::open(&path).unwrap());
+        // readers don't share a position, as retries of an upload need
+        for _ in 0..2 {
+            let mut read = Vec::new();
+            FileReader::new(file.clone(), false).read_to_end(&mut read).await.unwrap();
+            assert_eq!(read, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_contents() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("data");
+        let stamp = |path: &std::path::Path| {
+            let metadata = std::fs::metadata(path).unwrap();
+            (metadata.len(), metadata.modified().ok())
+        };
+        std::fs::write(&path, "first").unwrap();
+        let contents = SharedContents::default();
+        let first = stamp(&path);
+        let name = path.to_str().unwrap();
+        assert_eq!(contents.get(name, first).await.unwrap(), "first");
+        // as read by the first upload, while the file is the same
+        std::fs::write(&path, "other").unwrap();
+        assert_eq!(contents.get(name, first).await.unwrap(), "first");
+        std::fs::write(&path, "second!").unwrap();
+        assert_eq!(contents.get(name, stamp(&path)).await.unwrap(), "second!");
+        // changed since the upload looked at it
+        assert!(contents.get(name, (3, None)).await.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stream_body() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("fifo");
+        assert!(std::process::Command::new("mkfifo").arg(&path).status().unwrap().success());
+        assert!(is_fifo(&std::fs::metadata(&path).unwrap()));
+        assert!(!is_fifo(&std::fs::metadata(tempdir.path()).unwrap()));
+        let writer = std::thread::spawn({
+            let path = path.clone();
+            move || std::fs::write(path, "hello world")
+        });
+        let streamed = Arc::new(Mutex::new(Streamed { hasher: Some(Sha256::new()), ..Default::default() }));
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        let body = stream_body(file, 4, streamed.clone());
+        // no length, so it goes chunked
+        assert_eq!(body.as_bytes(), None);
+        let data = http_body_util::BodyExt::collect(body).await.unwrap().to_bytes();
+        writer.join().unwrap().unwrap();
+        assert_eq!(data, "hello world");
+        let mut streamed = std::mem::take(&mut *streamed.lock().unwrap());
+        assert_eq!((streamed.bytes, streamed.ended), (11, true));
+        assert_eq!(streamed.checksum().unwrap(), "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+    }
+
+    #[tokio::test]
+    async fn test_timed() {
+        let (run, other) = (Sent::default(), Sent::default());
+        let started = Arc::new(OnceLock::new());
+        let body = timed(Body::from("hello"), started.clone(), run.clone());
+        http_body_util::BodyExt::collect(body).await.unwrap();
+        assert!(started.get().is_some());
+        // counted for its run only
+        assert_eq!((run.get(), other.get()), (5, 0));
+    }
+
+    #[tokio::test]
+    async fn test_chunk_reader() {
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(Chunk::Data(b"hello ".to_vec())).await.unwrap();
+        tx.send(Chunk::Data(b"world".to_vec())).await.unwrap();
+        tx.send(Chunk::Done).await.unwrap();
+        let mut read = Vec::new();
+        ChunkReader::new(rx).read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"hello world");
+        // a producer going away without finishing is an error, not the end
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(Chunk::Data(b"hello".to_vec())).await.unwrap();
+        drop(tx);
+        assert!(ChunkReader::new(rx).read_to_end(&mut Vec::new()).await.is_err());
+    }
+}
@@ -0,0 +1,58 @@
+//! Uploading CSV files as tables into CasJobs (e.g. the user's MyDB) rather
+//! than as files onto the fileservice. The CasJobs REST API takes the CSV as
+//! the body of a POST to `contexts/<context>/Tables/<table>`, authenticated by
+//! the same token, so uploads go through the same scheduling and retries.
+
+use std::collections::BTreeMap;
+
+/// Table a file uploaded as `name` is loaded into: its name without the
+/// extension, with what SQL Server doesn't take in unquoted names replaced,
+/// so `night 1/stars-v2.csv` becomes `night_1_stars_v2`.
+pub(crate) fn table_name(name: &str) -> String {
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() && !stem.ends_with('/') => stem,
+        _ => name,
+    };
+    let table: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    // names can't start with a digit
+    if table.starts_with(|c: char| c.is_ascii_digit()) { format!("_{}", table) } else { table }
+}
+
+/// Files, by their `names`, that would load into the same table as another,
+/// by table.
+pub(crate) fn collisions<'a>(names: impl IntoIterator<Item = &'a str>) -> BTreeMap<String, Vec<&'a str>> {
+    let mut tables: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for name in names {
+        tables.entry(table_name(name)).or_default().push(name);
+    }
+    tables.retain(|_, names| names.len() > 1);
+    tables
+}
+
+/// url of the tables of `context` at the CasJobs `endpoint`
+pub(crate) fn tables_url(endpoint: &str, context: &str) -> String {
+    format!("{}/contexts/{}/Tables", endpoint.trim_end_matches('/'), context.trim_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_name() {
+        assert_eq!(table_name("stars.csv"), "stars");
+        assert_eq!(table_name("night 1/stars-v2.csv"), "night_1_stars_v2");
+        assert_eq!(table_name("2mass.csv"), "_2mass");
+        assert_eq!(table_name(".hidden"), "_hidden");
+        assert_eq!(table_name("galaxies"), "galaxies");
+        assert_eq!(tables_url("http://h/RestApi/", "MyDB"), "http://h/RestApi/contexts/MyDB/Tables");
+    }
+
+    #[test]
+    fn test_collisions() {
+        let collisions = collisions(["a.b.csv", "a_b.csv", "a/b.csv", "c.csv", "d.csv"]);
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions["a_b"], ["a.b.csv", "a_b.csv", "a/b.csv"]);
+        assert!(super::collisions(["stars.csv", "galaxies.csv"]).is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+//! `--chaos`: failures injected into uploads at random, to see retries,
+//! failover and the other settings for failures at work before trusting them
+//! with real data. Only built with the `chaos` feature. Given as
+//! comma-separated `kind=probability` pairs, each attempt of an upload
+//! failing that way with that probability:
+//!
+//! - `read-error`: reading the file fails partway through the body
+//! - `delay`: the request is held up to `max-delay` (1s by default) first
+//! - `5xx`: the fileservice answers 500, 502, 503 or 504, without the
+//!   request being sent
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use bytes::Bytes;
+use fastrand::Rng;
+use http_body::{Frame, SizeHint};
+use reqwest::{Body, Response, StatusCode};
+
+/// what a fake error response says
+const INJECTED: &str = "injected by --chaos";
+
+/// probabilities of the failures injected into each attempt
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chaos {
+    read_error: f64,
+    delay: f64,
+    max_delay: Duration,
+    server_error: f64,
+}
+
+impl std::str::FromStr for Chaos {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Chaos { read_error: 0.0, delay: 0.0, max_delay: Duration::from_secs(1), server_error: 0.0 };
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (kind, value) = pair.split_once('=')
+                .ok_or_else(|| format!("invalid chaos {:?}, expected e.g. read-error=0.01,5xx=0.05", pair))?;
+            let probability = || match value.trim().parse::<f64>() {
+                Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+                _ => Err(format!("invalid probability {:?} of {}, expected 0 to 1", value, kind)),
+            };
+            match kind.trim() {
+                "read-error" => chaos.read_error = probability()?,
+                "delay" => chaos.delay = probability()?,
+                "max-delay" => chaos.max_delay = crate::units::parse_duration(value)?,
+                "5xx" => chaos.server_error = probability()?,
+                kind => return Err(format!("unknown chaos {:?}, expected read-error, delay, max-delay or 5xx", kind)),
+            }
+        }
+        Ok(chaos)
+    }
+}
+
+impl std::fmt::Display for Chaos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "read errors {}%, delays {}% (up to {}), 5xx responses {}%", self.read_error * 100.0,
+            self.delay * 100.0, humantime::format_duration(self.max_delay), self.server_error * 100.0)
+    }
+}
+
+impl Chaos {
+    /// `body` of `size` bytes, failing to read partway through now and then.
+    pub(crate) fn body(&self, body: Body, size: u64, rng: &mut Rng) -> Body {
+        if rng.f64() >= self.read_error {
+            return body;
+        }
+        Body::wrap(Faulty { inner: body, left: rng.u64(0..size.max(1)) })
+    }
+
+    /// Send a request with `send`, now and then after a delay or not at all,
+    /// answering with a server error in its place.
+    pub(crate) async fn send(&self, send: impl Future<Output = reqwest::Result<Response>>, rng: &mut Rng)
+        -> reqwest::Result<Response> {
+        if rng.f64() < self.delay {
+            tokio::time::sleep(self.max_delay.mul_f64(rng.f64())).await;
+        }
+        if rng.f64() < self.server_error {
+            let status = [500, 502, 503, 504][rng.usize(..4)];
+            let response = hyper::Response::builder().status(StatusCode::from_u16(status).unwrap()).body(INJECTED);
+            return Ok(response.unwrap().into());
+        }
+        send.await
+    }
+}
+
+/// Passes on `left` bytes of a body, then fails as if reading the file did.
+struct Faulty {
+    inner: Body,
+    left: u64,
+}
+
+impl http_body::Body for Faulty {
+    type Data = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        if self.left == 0 {
+            return Poll::Ready(Some(Err(io::Error::other(INJECTED).into())));
+        }
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        if let Some(data) = frame.as_ref().and_then(|f| f.as_ref().ok()).and_then(|f| f.data_ref()) {
+            self.left = self.left.saturating_sub(data.len() as u64);
+        }
+        Poll::Ready(frame.map(|f| f.map_err(Into::into)))
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let chaos: Chaos = "read-error=0.01, 5xx=0.5,max-delay=2s".parse().unwrap();
+        assert_eq!(chaos, Chaos { read_error: 0.01, delay: 0.0, max_delay: Duration::from_secs(2), server_error: 0.5 });
+        assert_eq!(chaos.to_string(), "read errors 1%, delays 0% (up to 2s), 5xx responses 50%");
+        assert!("5xx=2".parse::<Chaos>().is_err());
+        assert!("timeouts=0.1".parse::<Chaos>().is_err());
+        assert!("5xx".parse::<Chaos>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_injected() {
+        let chaos: Chaos = "read-error=1,5xx=1".parse().unwrap();
+        let rng = &mut Rng::new();
+        let body = chaos.body(Body::from("hello world"), 11, rng);
+        assert!(http_body_util::BodyExt::collect(body).await.is_err());
+        // never sent, nothing listens there
+        let response = chaos.send(reqwest::Client::new().put("http://127.0.0.1:9/").send(), rng).await.unwrap();
+        assert!(response.status().is_server_error());
+        assert_eq!(response.text().await.unwrap(), INJECTED);
+    }
+}
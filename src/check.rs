@@ -0,0 +1,226 @@
+//! Endpoint diagnostics, answering "is it my token, my network, or the
+//! service?" before starting a large run.
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use reqwest::{StatusCode, Url};
+use tokio::net::{lookup_host, TcpStream};
+
+use crate::{build_client, Settings};
+
+/// number of requests used to estimate latency
+const LATENCY_SAMPLES: u32 = 5;
+/// default port of socks proxies, which `Url` doesn't know about
+const SOCKS_PORT: u16 = 1080;
+
+pub struct CheckResult {
+    pub name: &'static str,
+    pub ok: bool,
+    pub elapsed: Option<Duration>,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn pass(name: &'static str, elapsed: Duration, detail: String) -> Self {
+        CheckResult { name, ok: true, elapsed: Some(elapsed), detail }
+    }
+
+    fn fail(name: &'static str, elapsed: Option<Duration>, detail: String) -> Self {
+        CheckResult { name, ok: false, elapsed, detail }
+    }
+}
+
+/// Check every configured endpoint, returning results keyed by endpoint url.
+pub async fn check_endpoints(settings: &Settings) -> Vec<(String, Vec<CheckResult>)> {
+    let mut all = Vec::new();
+    for destination in 0..settings.destinations() {
+        for url in settings.destination(destination).urls() {
+            // copies are on endpoints checked already
+            if all.iter().any(|(checked, _)| checked == url) {
+                continue;
+            }
+            all.push((url.clone(), check_endpoint(&settings.at_destination(destination), url).await));
+        }
+    }
+    all
+}
+
+/// Run each diagnostic step against an endpoint in turn, stopping at the first
+/// failure since later steps depend on earlier ones.
+async fn check_endpoint(settings: &Settings, endpoint: &str) -> Vec<CheckResult> {
+    let mut results = Vec::new();
+
+    let url = match Url::parse(endpoint) {
+        Ok(url) => url,
+        Err(e) => {
+            results.push(CheckResult::fail("url", None, format!("invalid endpoint: {}", e)));
+            return results;
+        }
+    };
+    let client = match build_client(settings) {
+        Ok(client) => client,
+        Err(e) => {
+            results.push(CheckResult::fail("client", None, e.to_string()));
+            return results;
+        }
+    };
+
+    // behind a proxy the endpoint may not resolve locally, only the proxy must
+    let target = match &settings.proxy {
+        Some(proxy) => match Url::parse(proxy) {
+            Ok(proxy) => proxy,
+            Err(e) => {
+                results.push(CheckResult::fail("url", None, format!("invalid proxy: {}", e)));
+                return results;
+            }
+        },
+        None => url.clone(),
+    };
+    let port = match target.scheme() {
+        "socks5" | "socks5h" => target.port().or(Some(SOCKS_PORT)),
+        _ => target.port_or_known_default(),
+    };
+    let (host, port) = match (target.host_str(), port) {
+        (Some(host), Some(port)) => (host.to_string(), port),
+        _ => {
+            results.push(CheckResult::fail("url", None, format!("{} has no host", target)));
+            return results;
+        }
+    };
+
+    let timer = Instant::now();
+    let overrides: Vec<SocketAddr> = settings.resolve.iter()
+        .filter(|(h, _)| *h == host)
+        .map(|(_, a)| if a.port() == 0 { SocketAddr::new(a.ip(), port) } else { *a })
+        .collect();
+    let addrs: Vec<_> = if !overrides.is_empty() {
+        overrides
+    } else {
+        match lookup_host((host.as_str(), port)).await {
+            Ok(addrs) => addrs.filter(|a| settings.ip_family.is_none_or(|f| f.matches(a))).collect(),
+            Err(e) => {
+                results.push(CheckResult::fail("dns", Some(timer.elapsed()), e.to_string()));
+                return results;
+            }
+        }
+    };
+    let Some(addr) = addrs.first().copied() else {
+        results.push(CheckResult::fail("dns", Some(timer.elapsed()), format!("no addresses for {}", host)));
+        return results;
+    };
+    let names: Vec<String> = addrs.iter().map(|a| a.ip().to_string()).collect();
+    results.push(CheckResult::pass("dns", timer.elapsed(), names.join(", ")));
+
+    let timer = Instant::now();
+    match TcpStream::connect(addr).await {
+        Ok(_) => results.push(CheckResult::pass("connect", timer.elapsed(), addr.to_string())),
+        Err(e) => {
+            results.push(CheckResult::fail("connect", Some(timer.elapsed()), format!("{}: {}", addr, e)));
+            return results;
+        }
+    }
+
+    // the session cookie has to be there for the token check
+    let timer = Instant::now();
+    match settings.log_in(&client, endpoint).await {
+        Some(Ok(())) => results.push(CheckResult::pass("login", timer.elapsed(), "session started".to_string())),
+        Some(Err(e)) => {
+            results.push(CheckResult::fail("login", Some(timer.elapsed()), e));
+            return results;
+        }
+        None => (),
+    }
+
+    // first full request covers the tls handshake (for https) and token check
+    let volumes = settings.api_url(endpoint, "volumes");
+    let stage = if url.scheme() == "https" { "tls" } else { "http" };
+    let timer = Instant::now();
+    let response = match client.get(&volumes).send().await {
+        Ok(response) => {
+            results.push(CheckResult::pass(stage, timer.elapsed(), format!("{:?}", response.version())));
+            response
+        }
+        Err(e) => {
+            results.push(CheckResult::fail(stage, Some(timer.elapsed()), error_chain(&e)));
+            return results;
+        }
+    };
+    let elapsed = timer.elapsed();
+    match response.status() {
+        StatusCode::OK => results.push(CheckResult::pass("auth", elapsed, "token accepted".to_string())),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            results.push(CheckResult::fail("auth", Some(elapsed), "token rejected".to_string()));
+            return results;
+        }
+        status => {
+            results.push(CheckResult::fail("auth", Some(elapsed), format!("unexpected status {}", status)));
+            return results;
+        }
+    }
+
+    // connection is warm now, so these reflect request round trips only
+    let mut samples = Vec::with_capacity(LATENCY_SAMPLES as usize);
+    for _ in 0..LATENCY_SAMPLES {
+        let timer = Instant::now();
+        if let Err(e) = client.get(&volumes).send().await {
+            results.push(CheckResult::fail("latency", Some(timer.elapsed()), error_chain(&e)));
+            return results;
+        }
+        samples.push(timer.elapsed());
+    }
+    let min = samples.iter().min().unwrap();
+    let max = samples.iter().max().unwrap();
+    let avg = samples.iter().sum::<Duration>() / LATENCY_SAMPLES;
+    results.push(CheckResult::pass("latency", avg, format!(
+        "min {:.1} ms, max {:.1} ms over {} requests",
+        millis(*min), millis(*max), LATENCY_SAMPLES)));
+
+    results
+}
+
+/// reqwest's own message is generic ("error sending request"), the useful
+/// part (e.g. which certificate check failed) is in the sources
+fn error_chain(e: &dyn Error) -> String {
+    let mut msg = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        msg.push_str(&format!(": {}", e));
+        source = e.source();
+    }
+    msg
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+pub fn format_check_table(results: &[CheckResult]) -> String {
+    let mut table = format!("{:<10}{:<8}{:>12}  {}\n", "CHECK", "STATUS", "TIME", "DETAIL");
+    for result in results {
+        let status = if result.ok { "ok" } else { "FAILED" };
+        let time = match result.elapsed {
+            Some(d) => format!("{:.1} ms", millis(d)),
+            None => "-".to_string(),
+        };
+        table.push_str(&format!("{:<10}{:<8}{:>12}  {}\n", result.name, status, time, result.detail));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_bad_endpoint() {
+        let settings = Settings::with_endpoint("not a url".to_string(), "t".to_string());
+        let results = check_endpoint(&settings, "not a url").await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].name, "url");
+        assert!(!results[0].ok);
+        let table = format_check_table(&results);
+        assert!(table.lines().nth(1).unwrap().starts_with("url       FAILED"));
+    }
+}
@@ -0,0 +1,256 @@
+//! SHA-256 checksums of uploaded files. Hashing is CPU bound, so files are
+//! hashed on the blocking pool ahead of their uploads, while earlier files are
+//! still transferring, instead of after each upload. Checksums are kept in a
+//! cache across runs, so files that didn't change since aren't hashed again.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::UNIX_EPOCH;
+
+use sha2::{Digest, Sha256};
+use tokio::sync::{OnceCell, Semaphore};
+use tokio::task::JoinSet;
+
+/// checksum of a file, computed once and shared by its uploads, None if the
+/// file couldn't be read
+pub(crate) type Checksum = Arc<OnceCell<Option<String>>>;
+
+/// Hash the file at `path`, going easy on the disk if `nice`.
+fn sha256(path: &str, nice: bool) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let reading = nice.then(|| crate::nice::reading(buf.len()));
+        let n = file.read(&mut buf)?;
+        drop(reading);
+        match n {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// size and modification time (ns since the epoch) of a file, which change
+/// with its contents
+type Stamp = (u64, u128);
+
+fn stamp(path: &str) -> io::Result<Stamp> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok((metadata.len(), modified.as_nanos()))
+}
+
+/// entries a [`HashCache`] keeps at most, those the run used first
+const MAX_CACHED: usize = 1 << 20;
+
+/// a file as hashed
+struct Cached {
+    stamp: Stamp,
+    checksum: String,
+    /// looked up or hashed in this run
+    used: bool,
+}
+
+/// Checksums of files hashed earlier by their absolute path, valid while
+/// their size and modification time stay the same. Read from its file when
+/// first needed, written back with [`HashCache::save`].
+pub struct HashCache {
+    path: PathBuf,
+    entries: OnceLock<Mutex<HashMap<String, Cached>>>,
+    changed: AtomicBool,
+}
+
+impl HashCache {
+    /// The cache kept in the file at `path`, NUL terminated path, size,
+    /// modification time and checksum of each file.
+    pub fn new(path: PathBuf) -> Self {
+        HashCache { path, entries: OnceLock::new(), changed: AtomicBool::new(false) }
+    }
+
+    fn entries(&self) -> &Mutex<HashMap<String, Cached>> {
+        self.entries.get_or_init(|| {
+            let data = std::fs::read_to_string(&self.path).unwrap_or_default();
+            let fields: Vec<_> = data.split_terminator('\0').collect();
+            let entries = fields.chunks_exact(4)
+                .filter_map(|entry| {
+                    let stamp = (entry[1].parse().ok()?, entry[2].parse().ok()?);
+                    Some((entry[0].to_string(), Cached { stamp, checksum: entry[3].to_string(), used: false }))
+                })
+                .collect();
+            Mutex::new(entries)
+        })
+    }
+
+    /// The checksum of `path`, from the cache if it didn't change since it
+    /// was hashed.
+    fn sha256(&self, path: &str, nice: bool) -> io::Result<String> {
+        let key = std::path::absolute(path)?.to_string_lossy().to_string();
+        let before = stamp(path)?;
+        if let Some(cached) = self.entries().lock().unwrap().get_mut(&key).filter(|c| c.stamp == before) {
+            cached.used = true;
+            return Ok(cached.checksum.clone());
+        }
+        let checksum = sha256(path, nice)?;
+        // changed while being hashed, the checksum is of neither version
+        if stamp(path)? == before {
+            let cached = Cached { stamp: before, checksum: checksum.clone(), used: true };
+            self.entries().lock().unwrap().insert(key, cached);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+        Ok(checksum)
+    }
+
+    /// Write the cache back to its file if files were hashed.
+    pub fn save(&self) -> io::Result<()> {
+        self.save_at_most(MAX_CACHED)
+    }
+
+    /// Write the cache back with files that are gone or changed left out,
+    /// and no more than `max` entries, those the run used first.
+    fn save_at_most(&self, max: usize) -> io::Result<()> {
+        if !self.changed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut entries = self.entries().lock().unwrap();
+        entries.retain(|path, cached| stamp(path).is_ok_and(|s| s == cached.stamp));
+        let mut kept: Vec<_> = entries.iter().collect();
+        if kept.len() > max {
+            kept.sort_by_key(|(_, cached)| !cached.used);
+            kept.truncate(max);
+        }
+        let data: String = kept.into_iter()
+            .map(|(path, Cached { stamp: (size, modified), checksum, .. })| {
+                format!("{}\0{}\0{}\0{}\0", path, size, modified, checksum)
+            })
+            .collect();
+        drop(entries);
+        let dir = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)?;
+        // replaced whole, a run stopped while writing leaves the old one, and
+        // runs at the same time don't write into each other's
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let partial = dir.join(format!(".{}.{}-{:016x}.partial", name, std::process::id(), fastrand::u64(..)));
+        std::fs::write(&partial, data)?;
+        std::fs::rename(&partial, &self.path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&partial);
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// The checksum of `path`, hashing it now if that hasn't started already,
+/// unless it's in `cache`. Reads go easy on the disk if `nice`.
+pub(crate) async fn get(checksum: &Checksum, path: &str, cache: Option<&Arc<HashCache>>, nice: bool)
+    -> Option<String> {
+    checksum.get_or_init(|| {
+        let (path, cache) = (path.to_string(), cache.cloned());
+        let hash = move || match cache {
+            Some(cache) => cache.sha256(&path, nice).ok(),
+            None => sha256(&path, nice).ok(),
+        };
+        async move { tokio::task::spawn_blocking(hash).await.ok().flatten() }
+    }).await.clone()
+}
+
+/// Hash `files` in order, as many at once as there are cpus, going easy on
+/// the disk if `nice`. Runs until all are hashed or the returned set is
+/// dropped.
+pub(crate) fn precompute(files: Vec<(String, Checksum)>, cache: Option<Arc<HashCache>>, nice: bool)
+    -> JoinSet<()> {
+    let workers = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let mut pipeline = JoinSet::new();
+    pipeline.spawn(async move {
+        let semaphore = Arc::new(Semaphore::new(workers));
+        let mut tasks = JoinSet::new();
+        for (path, checksum) in files {
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let cache = cache.clone();
+            tasks.spawn(async move {
+                get(&checksum, &path, cache.as_ref(), nice).await;
+                drop(permit);
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+    });
+    pipeline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_checksum() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("data");
+        std::fs::write(&path, "hello\n").unwrap();
+        let path = path.to_str().unwrap();
+        let checksum = Checksum::default();
+        let _pipeline = precompute(vec![(path.to_string(), checksum.clone())], None, false);
+        assert_eq!(get(&checksum, path, None, false).await.unwrap(),
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03");
+        assert_eq!(get(&Checksum::default(), "/nonexistent", None, false).await, None);
+        // going easy on the disk reads the same
+        assert_eq!(get(&Checksum::default(), path, None, true).await.unwrap(),
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03");
+    }
+
+    #[test]
+    fn test_hash_cache() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let data = tempdir.path().join("data");
+        std::fs::write(&data, "hello\n").unwrap();
+        let data = data.to_str().unwrap();
+        let cache = HashCache::new(tempdir.path().join("state/hashes"));
+        let hello = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        assert_eq!(cache.sha256(data, false).unwrap(), hello);
+        cache.save().unwrap();
+        // an unchanged file isn't read again, so a cached wrong checksum shows
+        let saved = std::fs::read_to_string(cache.path()).unwrap();
+        std::fs::write(cache.path(), saved.replace(hello, "cached")).unwrap();
+        let cache = HashCache::new(cache.path().to_path_buf());
+        assert_eq!(cache.sha256(data, false).unwrap(), "cached");
+        // while a changed one is
+        std::fs::write(data, "hello world\n").unwrap();
+        assert_ne!(cache.sha256(data, false).unwrap(), "cached");
+        assert!(cache.sha256("/nonexistent", false).is_err());
+
+        // over the limit, those the run didn't use are left out
+        let file = |name: &str| {
+            let path = tempdir.path().join(name);
+            std::fs::write(&path, name).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let (other, third, fourth) = (file("other"), file("third"), file("fourth"));
+        let cache = HashCache::new(tempdir.path().join("state/hashes"));
+        cache.sha256(&other, false).unwrap();
+        cache.save().unwrap();
+        let cache = HashCache::new(cache.path().to_path_buf());
+        cache.sha256(data, false).unwrap();
+        cache.sha256(&third, false).unwrap();
+        cache.save_at_most(2).unwrap();
+        let saved = std::fs::read_to_string(cache.path()).unwrap();
+        assert!(saved.contains(data) && saved.contains(&third) && !saved.contains(&other));
+        // as are files gone
+        std::fs::remove_file(&third).unwrap();
+        let cache = HashCache::new(cache.path().to_path_buf());
+        cache.sha256(&fourth, false).unwrap();
+        cache.save().unwrap();
+        let saved = std::fs::read_to_string(cache.path()).unwrap();
+        assert!(saved.contains(data) && saved.contains(&fourth) && !saved.contains(&third));
+        // no temporary files left behind
+        assert_eq!(std::fs::read_dir(tempdir.path().join("state")).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,78 @@
+//! Colors for the status bar and reports, plain ANSI escapes. Only used on
+//! terminals unless asked for, and never if `NO_COLOR` is set
+//! (<https://no-color.org>).
+
+use std::fmt::Display;
+use std::io::IsTerminal;
+
+/// when to color output
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    /// on terminals, unless NO_COLOR is set
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("unknown color choice {:?}, expected auto, always or never", s)),
+        }
+    }
+}
+
+/// whether to color what goes to stdout (the status bar) and stderr (reports)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Colors {
+    pub(crate) stdout: bool,
+    pub(crate) stderr: bool,
+}
+
+impl ColorChoice {
+    pub(crate) fn resolve(&self) -> Colors {
+        match self {
+            ColorChoice::Always => Colors { stdout: true, stderr: true },
+            ColorChoice::Never => Colors::default(),
+            ColorChoice::Auto => {
+                let allowed = std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && std::env::var_os("TERM").is_none_or(|t| t != "dumb");
+                Colors {
+                    stdout: allowed && std::io::stdout().is_terminal(),
+                    stderr: allowed && std::io::stderr().is_terminal(),
+                }
+            }
+        }
+    }
+}
+
+pub(crate) const GREEN: &str = "32";
+pub(crate) const YELLOW: &str = "33";
+pub(crate) const RED: &str = "31";
+
+/// `text` in the color `code` if `enabled`
+pub(crate) fn paint(enabled: bool, code: &str, text: impl Display) -> String {
+    if enabled {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paint() {
+        assert_eq!(paint(true, RED, 3), "\x1b[31m3\x1b[0m");
+        assert_eq!(paint(false, RED, 3), "3");
+        assert_eq!(ColorChoice::Never.resolve(), Colors::default());
+        assert_eq!(ColorChoice::Always.resolve(), Colors { stdout: true, stderr: true });
+    }
+}
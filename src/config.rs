@@ -0,0 +1,243 @@
+//! The configuration file, with settings shared by a team such as names for
+//! their deployments. It's made of `[section]`s of `key = value` lines, `#`
+//! starting comments:
+//!
+//! ```text
+//! [endpoints]
+//! jhu-test = https://apps-test.sciserver.org/fileservice/api/file
+//!
+//! [env.ci]
+//! endpoint = jhu-test
+//! token-env = CI_SCISERVER_TOKEN
+//! path = Storage/ci/persistent
+//! ```
+//!
+//! An `[env.<name>]` section is a deployment profile selected with `--env` or
+//! `SCISERVER_ENV`, keeping the endpoint, where the token comes from
+//! (`token-env` or `token-file`), how it's sent (`auth`, e.g. `bearer` for a
+//! deployment behind a gateway) and the volume paths are under together.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::AuthScheme;
+
+/// endpoints known by name without being configured
+const BUILTIN_ENDPOINTS: &[(&str, &str)] = &[("jhu-prod", "https://apps.sciserver.org/fileservice/api/file")];
+
+/// Where the configuration is read from by default, following the XDG base
+/// directory spec.
+pub fn default_config_path() -> PathBuf {
+    let base = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("sciserver-upload").join("config")
+}
+
+/// where the token of a profile is read from
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenSource {
+    /// this environment variable
+    Env(String),
+    /// the first line of this file, `~/` standing for the home directory
+    File(PathBuf),
+}
+
+impl TokenSource {
+    pub fn read(&self) -> Result<String, String> {
+        match self {
+            TokenSource::Env(name) => std::env::var(name).map_err(|_| format!("{} is not set", name)),
+            TokenSource::File(path) => {
+                let path = match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+                    (Ok(rest), Some(home)) => Path::new(&home).join(rest),
+                    _ => path.clone(),
+                };
+                let text = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                Ok(text.lines().next().unwrap_or_default().trim().to_string())
+            }
+        }
+    }
+}
+
+/// A deployment profile, from an `[env.<name>]` section. The endpoint, token
+/// and auth given on the command line win over it, what it leaves out comes
+/// from there as usual.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    /// url or name from `[endpoints]`
+    pub endpoint: Option<String>,
+    pub token: Option<TokenSource>,
+    pub auth: Option<AuthScheme>,
+    /// volume (e.g. `Storage/ci/persistent`) paths given are under
+    pub path: Option<String>,
+}
+
+impl Profile {
+    /// The remote path `path` given stands for: relative to the profile's
+    /// volume, unless it starts with `/` (or there's no volume).
+    pub fn path(&self, path: &str) -> String {
+        match &self.path {
+            Some(volume) if !path.starts_with('/') => {
+                let volume = volume.trim_matches('/');
+                match path.trim_matches('/') {
+                    "" | "." => volume.to_string(),
+                    path => format!("{}/{}", volume, path),
+                }
+            }
+            _ => path.trim_start_matches('/').to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    /// sections in the order of the file, with their entries
+    sections: Vec<(String, Vec<(String, String)>)>,
+}
+
+impl Config {
+    /// Read the configuration at `path`, empty if there is no file.
+    pub fn load(path: &Path) -> io::Result<Config> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut sections: Vec<(String, Vec<(String, String)>)> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                sections.push((name.trim().to_string(), Vec::new()));
+                continue;
+            }
+            let entry = line.split_once('=').map(|(key, value)| (key.trim(), value.trim()));
+            match (entry, sections.last_mut()) {
+                (Some((key, value)), Some((_, entries))) if !key.is_empty() => {
+                    entries.push((key.to_string(), value.to_string()));
+                }
+                (Some(_), None) => return Err(format!("line {}: outside of a [section]", number + 1)),
+                _ => return Err(format!("line {}: expected key = value", number + 1)),
+            }
+        }
+        Ok(Config { sections })
+    }
+
+    /// Entries of `section` in order, of all of its appearances. Where a key
+    /// is repeated the first counts.
+    pub fn section(&self, section: &str) -> impl Iterator<Item = (&str, &str)> {
+        self.sections.iter()
+            .filter(move |(name, _)| name == section)
+            .flat_map(|(_, entries)| entries.iter().map(|(key, value)| (key.as_str(), value.as_str())))
+    }
+
+    /// The endpoint url `endpoint` stands for: urls are themselves, anything
+    /// else an alias from `[endpoints]` or a builtin one like `jhu-prod`.
+    pub fn endpoint(&self, endpoint: &str) -> Result<String, String> {
+        if endpoint.contains("://") {
+            return Ok(endpoint.to_string());
+        }
+        let aliases = || self.section("endpoints").chain(BUILTIN_ENDPOINTS.iter().copied());
+        match aliases().find(|(name, _)| *name == endpoint) {
+            Some((_, url)) => Ok(url.to_string()),
+            None => {
+                let mut known = Vec::new();
+                for (name, _) in aliases() {
+                    if !known.contains(&name) {
+                        known.push(name);
+                    }
+                }
+                Err(format!("unknown endpoint {:?}, expected a url or one of {}", endpoint, known.join(", ")))
+            }
+        }
+    }
+
+    /// The profile `[env.<name>]`.
+    pub fn profile(&self, name: &str) -> Result<Profile, String> {
+        let section = format!("env.{}", name);
+        if !self.sections.iter().any(|(s, _)| *s == section) {
+            let known: Vec<_> = self.sections.iter().filter_map(|(s, _)| s.strip_prefix("env.")).collect();
+            return Err(match known.is_empty() {
+                true => format!("no environment {:?} in the config file, there are none", name),
+                false => format!("no environment {:?} in the config file, there are: {}", name, known.join(", ")),
+            });
+        }
+        let mut profile = Profile::default();
+        for (key, value) in self.section(&section).collect::<Vec<_>>().into_iter().rev() {
+            match key {
+                "endpoint" => profile.endpoint = Some(value.to_string()),
+                "token-env" => profile.token = Some(TokenSource::Env(value.to_string())),
+                "token-file" => profile.token = Some(TokenSource::File(PathBuf::from(value))),
+                "auth" => profile.auth = Some(value.parse().map_err(|e| format!("[{}]: {}", section, e))?),
+                "path" => profile.path = Some(value.to_string()),
+                _ => return Err(format!(
+                    "[{}]: unknown key {:?}, expected endpoint, token-env, token-file, auth or path", section, key)),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("# team deployments\n[endpoints]\njhu-test = https://test/api/file\n\n\
+            [other]\nkey=value\n[endpoints]\njhu-test = https://later/api/file\n\
+            local = http://localhost:8080/api/file\n").unwrap();
+        assert_eq!(config.section("endpoints").collect::<Vec<_>>(), vec![
+            ("jhu-test", "https://test/api/file"),
+            ("jhu-test", "https://later/api/file"),
+            ("local", "http://localhost:8080/api/file"),
+        ]);
+        assert_eq!(config.section("other").collect::<Vec<_>>(), vec![("key", "value")]);
+        assert_eq!(Config::parse("a = b"), Err("line 1: outside of a [section]".to_string()));
+        assert_eq!(Config::parse("[endpoints]\njhu-test"), Err("line 2: expected key = value".to_string()));
+        assert_eq!(Config::load(Path::new("/nonexistent/config")).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let config = Config::parse("[endpoints]\njhu-test = https://test/api/file\n").unwrap();
+        assert_eq!(config.endpoint("jhu-test").unwrap(), "https://test/api/file");
+        assert_eq!(config.endpoint("jhu-prod").unwrap(), "https://apps.sciserver.org/fileservice/api/file");
+        assert_eq!(config.endpoint("http://h:1/api/file").unwrap(), "http://h:1/api/file");
+        assert_eq!(config.endpoint("jhu-tset"),
+            Err("unknown endpoint \"jhu-tset\", expected a url or one of jhu-test, jhu-prod".to_string()));
+    }
+
+    #[test]
+    fn test_profile() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let token_file = tempdir.path().join("token");
+        std::fs::write(&token_file, "file-token\n").unwrap();
+        let config = Config::parse(&format!("[env.ci]\nendpoint = jhu-test\ntoken-env = UPLOAD_TEST_PROFILE_TOKEN\n\
+            path = Storage/ci/persistent/\n[env.cron]\ntoken-file = {}\nauth = bearer\n[env.bad]\nvolume = x\n\
+            [env.worse]\nauth = basic\n",
+            token_file.display())).unwrap();
+        let ci = config.profile("ci").unwrap();
+        assert_eq!(ci.endpoint.as_deref(), Some("jhu-test"));
+        assert_eq!(ci.token, Some(TokenSource::Env("UPLOAD_TEST_PROFILE_TOKEN".to_string())));
+        assert!(ci.token.as_ref().unwrap().read().is_err());
+        assert_eq!(ci.path("run42/"), "Storage/ci/persistent/run42");
+        assert_eq!(ci.path("."), "Storage/ci/persistent");
+        assert_eq!(ci.path("/Storage/other/persistent"), "Storage/other/persistent");
+        let cron = config.profile("cron").unwrap();
+        assert_eq!(cron.token.as_ref().unwrap().read().unwrap(), "file-token");
+        assert_eq!(cron.path("Storage/u/persistent"), "Storage/u/persistent");
+        assert_eq!((ci.auth, cron.auth), (None, Some(AuthScheme::Bearer)));
+        assert!(config.profile("bad").unwrap_err().contains("unknown key \"volume\""));
+        assert!(config.profile("worse").unwrap_err().contains("unknown auth scheme \"basic\""));
+        assert_eq!(config.profile("prod"),
+            Err("no environment \"prod\" in the config file, there are: ci, cron, bad, worse".to_string()));
+    }
+}
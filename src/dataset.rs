@@ -0,0 +1,88 @@
+//! Registering what a run uploaded as a dataset, so catalog and indexing
+//! systems learn about new data without being told by hand. Once every file
+//! of a run was uploaded or found there already, a JSON manifest of the files
+//! (names, sizes, checksums) and of the run is uploaded next to them, or
+//! POSTed to an endpoint.
+
+use std::time::SystemTime;
+
+use serde_json::{json, Value};
+
+/// where the dataset manifest goes, from `--dataset-manifest`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ManifestTarget {
+    /// uploaded as this name (relative to the path) to each destination
+    Upload(String),
+    /// POSTed to this url, without the fileservice token or session, tried
+    /// again like uploads on 429, 5xx and network failures
+    Post(String),
+}
+
+impl std::str::FromStr for ManifestTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(ManifestTarget::Post(s.to_string()))
+        } else if s.trim_matches('/').is_empty() {
+            Err("expected a file name or an http(s) url".to_string())
+        } else {
+            Ok(ManifestTarget::Upload(s.trim_matches('/').to_string()))
+        }
+    }
+}
+
+/// an uploaded file as listed in the manifest
+pub(crate) struct Entry<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) size: u64,
+    pub(crate) sha256: Option<&'a str>,
+}
+
+/// The manifest of `files` uploaded to `path` by a run that started at
+/// `started`, as part of `job` if it's kept.
+pub(crate) fn manifest(path: &str, job: Option<&str>, started: SystemTime, files: &[Entry]) -> Value {
+    let listed: Vec<_> = files.iter()
+        .map(|f| json!({ "name": f.name, "size": f.size, "sha256": f.sha256 }))
+        .collect();
+    json!({
+        "path": path,
+        "files": listed,
+        "count": files.len(),
+        "bytes": files.iter().map(|f| f.size).sum::<u64>(),
+        "run": {
+            "job": job,
+            "started": humantime::format_rfc3339_seconds(started).to_string(),
+            "finished": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            "tool": format!("sciserver-upload-rs {}", env!("CARGO_PKG_VERSION")),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target() {
+        assert_eq!("dataset.json".parse(), Ok(ManifestTarget::Upload("dataset.json".to_string())));
+        assert_eq!("https://catalog/api/datasets".parse(),
+            Ok(ManifestTarget::Post("https://catalog/api/datasets".to_string())));
+        assert!("/".parse::<ManifestTarget>().is_err());
+    }
+
+    #[test]
+    fn test_manifest() {
+        let files = [
+            Entry { name: "a.fits", size: 10, sha256: Some("ab") },
+            Entry { name: "raw/b.fits", size: 5, sha256: None },
+        ];
+        let value = manifest("Storage/u/p", Some("job-1"), SystemTime::UNIX_EPOCH, &files);
+        assert_eq!(value["count"], 2);
+        assert_eq!(value["bytes"], 15);
+        assert_eq!(value["files"][0]["sha256"], "ab");
+        assert!(value["files"][1]["sha256"].is_null());
+        assert_eq!(value["run"]["job"], "job-1");
+        assert_eq!(value["run"]["started"], "1970-01-01T00:00:00Z");
+    }
+}
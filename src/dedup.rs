@@ -0,0 +1,105 @@
+//! `--dedup`: not uploading files whose contents were uploaded before under
+//! any name. The SHA-256 of every upload is recorded in an index in the
+//! state directory, with the fileservice and remote path it went to, and a
+//! file hashing the same as one recorded under the destination path is left
+//! out as a duplicate of it, once it's found still there hashing the same.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+/// remote paths contents were uploaded to, by endpoint and checksum
+type Entries = HashMap<(String, String), Vec<String>>;
+
+/// Contents uploaded in earlier runs (and this one): remote paths by
+/// endpoint and checksum. Read from its file when first needed, each upload
+/// appended as it's recorded.
+pub struct ContentIndex {
+    path: PathBuf,
+    entries: OnceLock<Mutex<Entries>>,
+    log: Mutex<Option<File>>,
+}
+
+impl ContentIndex {
+    /// The index kept in the file at `path`, NUL terminated endpoint,
+    /// checksum and remote path of each upload.
+    pub fn new(path: PathBuf) -> Self {
+        ContentIndex { path, entries: OnceLock::new(), log: Mutex::new(None) }
+    }
+
+    fn entries(&self) -> &Mutex<Entries> {
+        self.entries.get_or_init(|| {
+            let data = std::fs::read_to_string(&self.path).unwrap_or_default();
+            let fields: Vec<_> = data.split_terminator('\0').collect();
+            let mut entries = Entries::new();
+            for entry in fields.chunks_exact(3) {
+                let paths = entries.entry((entry[0].to_string(), entry[1].to_string())).or_default();
+                if !paths.iter().any(|p| p == entry[2]) {
+                    paths.push(entry[2].to_string());
+                }
+            }
+            Mutex::new(entries)
+        })
+    }
+
+    /// Remote paths under `dir` that contents hashing `sha256` were uploaded
+    /// to on `endpoint`, latest first.
+    pub(crate) fn find(&self, endpoint: &str, sha256: &str, dir: &str) -> Vec<String> {
+        let dir = format!("{}/", dir.trim_matches('/'));
+        let entries = self.entries().lock().unwrap();
+        let paths = entries.get(&(endpoint.to_string(), sha256.to_string())).into_iter().flatten();
+        paths.rev().filter(|p| p.starts_with(&dir)).cloned().collect()
+    }
+
+    /// Record contents hashing `sha256` uploaded to `path` on `endpoint`,
+    /// right away so it's kept if the run dies.
+    pub(crate) fn record(&self, endpoint: &str, sha256: &str, path: &str) -> io::Result<()> {
+        {
+            let mut entries = self.entries().lock().unwrap();
+            let paths = entries.entry((endpoint.to_string(), sha256.to_string())).or_default();
+            if paths.iter().any(|p| p == path) {
+                return Ok(());
+            }
+            paths.push(path.to_string());
+        }
+        let mut log = self.log.lock().unwrap();
+        if log.is_none() {
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            *log = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        log.as_mut().unwrap().write_all(format!("{}\0{}\0{}\0", endpoint, sha256, path).as_bytes())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_index() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("state/contents");
+        let index = ContentIndex::new(path.clone());
+        assert!(index.find("http://a", "ab12", "Storage/u/p").is_empty());
+        index.record("http://a", "ab12", "Storage/u/p/run1/a.fits").unwrap();
+        index.record("http://a", "ab12", "Storage/u/p/run2/a.fits").unwrap();
+        index.record("http://a", "ab12", "Storage/u/p/run2/a.fits").unwrap();
+        index.record("http://b", "cd34", "Storage/u/p/b.fits").unwrap();
+
+        // read back by a later run
+        let index = ContentIndex::new(path);
+        assert_eq!(index.find("http://a", "ab12", "/Storage/u/p/"),
+            vec!["Storage/u/p/run2/a.fits", "Storage/u/p/run1/a.fits"]);
+        assert_eq!(index.find("http://a", "ab12", "Storage/u/p/run1"), vec!["Storage/u/p/run1/a.fits"]);
+        assert!(index.find("http://a", "ab12", "Storage/u/q").is_empty());
+        assert!(index.find("http://b", "ab12", "Storage/u/p").is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+//! Name resolution for the http client: a cache in front of the system
+//! resolver, so flaky dns doesn't fail uploads mid-run, address family
+//! selection, and curl style `--resolve` overrides.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use tokio::net::lookup_host;
+
+/// resolved addresses and when they were resolved
+type CacheEntry = (Vec<SocketAddr>, Instant);
+
+/// address family to restrict connections to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub(crate) fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Caches successful lookups for `ttl` (if set). When refreshing an expired
+/// entry fails the stale addresses are used instead, they are almost always
+/// still valid and certainly better than failing the upload. Addresses not of
+/// `family` (if set) are dropped, so no time is spent trying broken routes.
+pub(crate) struct CachingResolver {
+    ttl: Option<Duration>,
+    family: Option<IpFamily>,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+impl CachingResolver {
+    pub(crate) fn new(ttl: Option<Duration>, family: Option<IpFamily>) -> Self {
+        CachingResolver { ttl, family, cache: Arc::new(Mutex::new(HashMap::new())) }
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let name = name.as_str().to_string();
+        let ttl = self.ttl;
+        let family = self.family;
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            let cached = cache.lock().unwrap().get(&name).cloned();
+            if let (Some((addrs, time)), Some(ttl)) = (&cached, ttl)
+                && time.elapsed() < ttl {
+                return Ok(Box::new(addrs.clone().into_iter()) as Addrs);
+            }
+            let result = lookup_host((name.as_str(), 0)).await.map(|addrs| {
+                addrs.filter(|a| family.is_none_or(|f| f.matches(a))).collect::<Vec<_>>()
+            });
+            match result {
+                Ok(addrs) if addrs.is_empty() => {
+                    let family = family.map(|f| format!("{:?} ", f)).unwrap_or_default();
+                    Err(format!("no {}addresses for {}", family, name).into())
+                }
+                Ok(addrs) if ttl.is_none() => Ok(Box::new(addrs.into_iter()) as Addrs),
+                Ok(addrs) => {
+                    cache.lock().unwrap().insert(name, (addrs.clone(), Instant::now()));
+                    Ok(Box::new(addrs.into_iter()) as Addrs)
+                }
+                Err(e) => match cached {
+                    Some((addrs, _)) => Ok(Box::new(addrs.into_iter()) as Addrs),
+                    None => Err(e.into()),
+                },
+            }
+        })
+    }
+}
+
+/// Parse a curl style override `host:port:addr` or `host:addr`, the latter
+/// applying to any port. IPv6 addresses may be given in brackets.
+pub fn parse_resolve(s: &str) -> Result<(String, SocketAddr), String> {
+    let invalid = || format!("invalid resolve {:?}, expected host:[port:]addr", s);
+    let (host, rest) = s.split_once(':').ok_or_else(invalid)?;
+    let parse_ip = |ip: &str| ip.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>();
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    // port 0 tells reqwest to use the port from the url
+    if let Ok(ip) = parse_ip(rest) {
+        return Ok((host.to_string(), SocketAddr::new(ip, 0)));
+    }
+    let (port, addr) = rest.split_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let ip = parse_ip(addr).map_err(|_| invalid())?;
+    Ok((host.to_string(), SocketAddr::new(ip, port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolve() {
+        assert_eq!(parse_resolve("example.org:10.0.0.1"),
+            Ok(("example.org".to_string(), "10.0.0.1:0".parse().unwrap())));
+        assert_eq!(parse_resolve("example.org:443:10.0.0.1"),
+            Ok(("example.org".to_string(), "10.0.0.1:443".parse().unwrap())));
+        assert_eq!(parse_resolve("example.org:443:[::1]"),
+            Ok(("example.org".to_string(), "[::1]:443".parse().unwrap())));
+        assert_eq!(parse_resolve("example.org:::1"),
+            Ok(("example.org".to_string(), "[::1]:0".parse().unwrap())));
+        assert!(parse_resolve("example.org").is_err());
+        assert!(parse_resolve("example.org:443:nothost").is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! Encrypting files on their way to the fileservice, so sensitive data can be
+//! kept on shared volumes. Encryption runs on the blocking pool, reading the
+//! shared file with positional reads like [`FileReader`](crate::body) does,
+//! and hands the encrypted data to the upload body in chunks.
+//!
+//! age is built in. OpenPGP encryption is done by the gpg binary, as gpgme
+//! does, so the user's keyring and agent setup apply as they are.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use reqwest::Body;
+use tokio::sync::mpsc;
+use tokio_util::io::ReaderStream;
+
+use crate::body::{copy_file, Chunk, ChunkReader, ChunkWriter};
+
+/// how files are encrypted, from `--encrypt` arguments
+#[derive(Clone)]
+pub enum Encryption {
+    /// age, to x25519 recipients
+    Age(Vec<age::x25519::Recipient>),
+    /// OpenPGP with gpg, to the fingerprints of keys in the keyring
+    Gpg(Vec<String>),
+}
+
+impl Encryption {
+    /// Parse `scheme:recipient` arguments (e.g. `age:age1...`, `gpg:<keyid>`),
+    /// None if there are none. Every recipient can decrypt the files, they
+    /// must all use the same scheme. gpg recipients (key ids, fingerprints or
+    /// anything else gpg takes) must match exactly one key in the keyring.
+    pub fn parse(specs: &[String]) -> Result<Option<Self>, String> {
+        let Some(first) = specs.first() else { return Ok(None) };
+        let scheme = first.split_once(':').map_or("", |(scheme, _)| scheme);
+        let mut recipients = Vec::new();
+        for spec in specs {
+            match spec.split_once(':') {
+                Some((s, recipient)) if s == scheme => recipients.push(recipient),
+                Some(_) => return Err("all recipients must use the same encryption".to_string()),
+                None => return Err(format!("expected scheme:recipient (e.g. age:age1...), got {}", spec)),
+            }
+        }
+        match scheme {
+            "age" => {
+                let recipients = recipients.iter()
+                    .map(|r| r.parse().map_err(|e| format!("invalid age recipient {}: {}", r, e)))
+                    .collect::<Result<_, _>>()?;
+                Ok(Some(Encryption::Age(recipients)))
+            }
+            "gpg" => {
+                let fingerprints = recipients.into_iter().map(fingerprint).collect::<Result<_, _>>()?;
+                Ok(Some(Encryption::Gpg(fingerprints)))
+            }
+            _ => Err(format!("unknown encryption {}, expected age or gpg", scheme)),
+        }
+    }
+
+    /// appended to the names of encrypted files
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Encryption::Age(_) => ".age",
+            Encryption::Gpg(_) => ".gpg",
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// the keyring of the test running on this thread
+    static TEST_HOME: std::cell::RefCell<Option<std::path::PathBuf>> = const { std::cell::RefCell::new(None) };
+}
+
+/// gpg, with the test's keyring in tests
+fn gpg() -> Command {
+    let command = Command::new("gpg");
+    #[cfg(test)]
+    let command = TEST_HOME.with_borrow(|home| {
+        let mut command = command;
+        if let Some(home) = home {
+            command.arg("--homedir").arg(home);
+        }
+        command
+    });
+    command
+}
+
+/// Fingerprint of the one key in the keyring `recipient` matches. gpg takes
+/// any part of a user id, so a recipient matching several keys is refused
+/// rather than encrypting to keys no one meant.
+fn fingerprint(recipient: &str) -> Result<String, String> {
+    let output = gpg().args(["--batch", "--with-colons", "--list-keys", recipient])
+        .stderr(Stdio::null()).output()
+        .map_err(|e| format!("can't run gpg: {}", e))?;
+    // each key's fingerprint is on the first fpr line after its pub line,
+    // those after are of its subkeys
+    let (mut keys, mut primary) = (Vec::new(), false);
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let fields: Vec<_> = line.split(':').collect();
+        match fields[0] {
+            "pub" => primary = true,
+            "fpr" if primary => {
+                keys.extend(fields.get(9).map(|f| f.to_string()));
+                primary = false;
+            }
+            _ => (),
+        }
+    }
+    match keys.as_slice() {
+        [] => Err(format!("no public key for {} in the gpg keyring", recipient)),
+        [key] => Ok(key.clone()),
+        _ => Err(format!("{} matches {} keys in the gpg keyring, give the fingerprint of one", recipient,
+            keys.len())),
+    }
+}
+
+/// Body streaming the encrypted contents of `file` from the start, read
+/// going easy on the machine if `nice`.
+pub(crate) fn body(encryption: &Encryption, file: &Arc<File>, chunk_size: usize, nice: bool) -> Body {
+    // a couple of chunks encrypted ahead of the upload
+    let (tx, rx) = mpsc::channel(2);
+    let (encryption, file) = (encryption.clone(), file.clone());
+    tokio::task::spawn_blocking(move || {
+        let mut out = ChunkWriter::new(tx.clone(), chunk_size);
+        let last = match encrypt(&encryption, &file, &mut out, chunk_size, nice).and_then(|()| out.flush()) {
+            Ok(()) => Chunk::Done,
+            Err(e) => Chunk::Error(e),
+        };
+        let _ = tx.blocking_send(last);
+    });
+    Body::wrap_stream(ReaderStream::with_capacity(ChunkReader::new(rx), chunk_size))
+}
+
+fn encrypt(encryption: &Encryption, file: &File, out: &mut impl Write, chunk_size: usize, nice: bool)
+    -> io::Result<()> {
+    match encryption {
+        Encryption::Age(recipients) => {
+            let recipients = recipients.iter().map(|r| r as &dyn age::Recipient);
+            let encryptor = age::Encryptor::with_recipients(recipients).map_err(io::Error::other)?;
+            let mut writer = encryptor.wrap_output(out)?;
+            copy_file(file, &mut writer, chunk_size, nice)?;
+            writer.finish()?;
+            Ok(())
+        }
+        Encryption::Gpg(recipients) => gpg_encrypt(recipients, file, out, chunk_size, nice),
+    }
+}
+
+/// Encrypt with gpg, feeding it the file from another thread while its output
+/// is passed on. The keys, given by fingerprint, are trusted as they are,
+/// gpg can't ask in batch mode.
+fn gpg_encrypt(recipients: &[String], file: &File, out: &mut impl Write, chunk_size: usize, nice: bool)
+    -> io::Result<()> {
+    let mut command = gpg();
+    command.args(["--batch", "--quiet", "--trust-model", "always", "--encrypt", "--output", "-"]);
+    for recipient in recipients {
+        command.arg("--recipient").arg(recipient);
+    }
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let (mut stdin, mut stdout) = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+    let (copied, fed) = std::thread::scope(|scope| {
+        // stdin is closed when done, so gpg finishes
+        let feed = scope.spawn(move || copy_file(file, &mut stdin, chunk_size, nice));
+        let copied = io::copy(&mut stdout, out);
+        if copied.is_err() {
+            // unblocks the feeding thread too
+            let _ = child.kill();
+        }
+        (copied, feed.join().unwrap())
+    });
+    let status = child.wait()?;
+    copied?;
+    if !status.success() {
+        let mut errors = String::new();
+        child.stderr.take().unwrap().read_to_string(&mut errors)?;
+        return Err(io::Error::other(format!("gpg failed: {}", errors.trim())));
+    }
+    fed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert!(Encryption::parse(&[]).unwrap().is_none());
+        assert!(Encryption::parse(&["age1abc".to_string()]).is_err());
+        assert!(Encryption::parse(&["rot13:x".to_string()]).is_err());
+        assert!(Encryption::parse(&["age:nope".to_string()]).is_err());
+        assert!(Encryption::parse(&["gpg:nobody@nowhere.invalid".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_age() {
+        use std::io::Read;
+
+        let identity = age::x25519::Identity::generate();
+        let spec = format!("age:{}", identity.to_public());
+        let encryption = Encryption::parse(&[spec]).unwrap().unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("data");
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let mut encrypted = Vec::new();
+        encrypt(&encryption, &File::open(&path).unwrap(), &mut encrypted, 1000, false).unwrap();
+        let decryptor = age::Decryptor::new(encrypted.as_slice()).unwrap();
+        let mut decrypted = Vec::new();
+        decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity)).unwrap()
+            .read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_gpg() {
+        if Command::new("gpg").arg("--version").output().is_err() {
+            return;
+        }
+        let home = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(home.path(), std::os::unix::fs::PermissionsExt::from_mode(0o700)).unwrap();
+        TEST_HOME.set(Some(home.path().to_path_buf()));
+        for user in ["One <one@example.invalid>", "Two <two@example.invalid>"] {
+            let status = gpg()
+                .args(["--batch", "--quiet", "--pinentry-mode", "loopback", "--passphrase", ""])
+                .args(["--quick-gen-key", user, "default", "default", "never"])
+                .stderr(Stdio::null()).status().unwrap();
+            assert!(status.success());
+        }
+        let err = Encryption::parse(&["gpg:example.invalid".to_string()]).err().unwrap();
+        assert!(err.contains("matches 2 keys"), "{}", err);
+        let encryption = Encryption::parse(&["gpg:one@example.invalid".to_string()]).unwrap().unwrap();
+        assert!(matches!(&encryption, Encryption::Gpg(keys) if keys.len() == 1 && keys[0].len() == 40));
+
+        let path = home.path().join("data");
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let mut encrypted = Vec::new();
+        encrypt(&encryption, &File::open(&path).unwrap(), &mut encrypted, 1000, false).unwrap();
+        let mut decrypt = gpg().args(["--batch", "--quiet", "--decrypt"])
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap();
+        let mut stdin = decrypt.stdin.take().unwrap();
+        std::thread::spawn(move || stdin.write_all(&encrypted));
+        let decrypted = decrypt.wait_with_output().unwrap();
+        assert!(decrypted.status.success());
+        assert_eq!(decrypted.stdout, data);
+        let _ = Command::new("gpgconf").arg("--homedir").arg(home.path()).args(["--kill", "gpg-agent"]).status();
+    }
+}
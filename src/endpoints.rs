@@ -0,0 +1,90 @@
+//! Ordered list of fileservice endpoints with failover to the next one when
+//! the active endpoint keeps failing.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// consecutive failed attempts (across all uploads) before failing over
+const FAILOVER_THRESHOLD: usize = 5;
+
+pub(crate) struct Endpoints {
+    urls: Vec<String>,
+    current: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl Endpoints {
+    pub(crate) fn new(urls: Vec<String>) -> Self {
+        assert!(!urls.is_empty(), "at least one endpoint is required");
+        Endpoints { urls, current: AtomicUsize::new(0), failures: AtomicUsize::new(0) }
+    }
+
+    pub(crate) fn urls(&self) -> &[String] {
+        &self.urls
+    }
+
+    pub(crate) fn url(&self, index: usize) -> &str {
+        &self.urls[index]
+    }
+
+    /// index of the endpoint new attempts should go to
+    pub(crate) fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Note a failed attempt against `index`, returning the new endpoint index
+    /// if this failure caused a failover.
+    pub(crate) fn record_failure(&self, index: usize) -> Option<usize> {
+        // failures of in-flight attempts against an endpoint we already moved
+        // off of don't count against the new one
+        if self.urls.len() < 2 || index != self.current() {
+            return None;
+        }
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 < FAILOVER_THRESHOLD {
+            return None;
+        }
+        let next = (index + 1) % self.urls.len();
+        match self.current.compare_exchange(index, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => {
+                self.failures.store(0, Ordering::Relaxed);
+                Some(next)
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+/// A copy that starts on the endpoint this one is on, with failures counted
+/// afresh.
+impl Clone for Endpoints {
+    fn clone(&self) -> Self {
+        Endpoints { urls: self.urls.clone(), current: AtomicUsize::new(self.current()), failures: AtomicUsize::new(0) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failover() {
+        let endpoints = Endpoints::new(vec!["a".to_string(), "b".to_string()]);
+        for _ in 1..FAILOVER_THRESHOLD {
+            assert_eq!(endpoints.record_failure(0), None);
+        }
+        endpoints.record_success();
+        for _ in 1..FAILOVER_THRESHOLD {
+            assert_eq!(endpoints.record_failure(0), None);
+        }
+        assert_eq!(endpoints.record_failure(0), Some(1));
+        assert_eq!(endpoints.current(), 1);
+        // stale failures against the old endpoint don't move us again
+        for _ in 0..FAILOVER_THRESHOLD {
+            assert_eq!(endpoints.record_failure(0), None);
+        }
+        assert_eq!(endpoints.current(), 1);
+    }
+}
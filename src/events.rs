@@ -0,0 +1,123 @@
+//! Progress events as NDJSON over a Unix domain socket (`--progress-socket`),
+//! so a monitoring UI can attach to and detach from a running transfer
+//! without touching its stdout. Every client gets a `file` line per completed
+//! upload followed by the `progress` counts, clients connecting mid-run first
+//! get the counts so far, and the run ends with a `done` line.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+
+/// events kept for clients that fall behind, older ones are dropped for them
+const EVENT_BUFFER: usize = 1024;
+
+/// A listening socket streaming events to whoever connects, removed when
+/// dropped.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) struct ProgressSocket {
+    path: PathBuf,
+    events: broadcast::Sender<String>,
+    /// the last `progress` line, for clients connecting later
+    latest: watch::Sender<Option<String>>,
+    task: JoinHandle<()>,
+}
+
+impl ProgressSocket {
+    /// Listen at `path`, replacing a socket left behind by an earlier run.
+    /// Must be called within a tokio runtime.
+    #[cfg(unix)]
+    pub(crate) fn bind(path: &Path) -> io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+        use tokio::io::AsyncWriteExt;
+
+        // never anything but a stale socket
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        let (events, _) = broadcast::channel::<String>(EVENT_BUFFER);
+        let (latest, _) = watch::channel(None::<String>);
+        let (sender, watcher) = (events.clone(), latest.subscribe());
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut events = sender.subscribe();
+                let latest = watcher.borrow().clone();
+                tokio::spawn(async move {
+                    if let Some(line) = latest
+                        && stream.write_all(line.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    loop {
+                        let line = match events.recv().await {
+                            Ok(line) => line,
+                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::error::RecvError::Closed) => return,
+                        };
+                        // the client went away
+                        if stream.write_all(line.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(ProgressSocket { path: path.to_path_buf(), events, latest, task })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn bind(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "unix domain sockets aren't available here"))
+    }
+
+    /// Send `event` to the clients connected, remembered for later ones if
+    /// it's the counts.
+    pub(crate) fn send(&self, event: &Value) {
+        let line = event.to_string() + "\n";
+        if event["event"] == "progress" {
+            self.latest.send_replace(Some(line.clone()));
+        }
+        // nobody may be listening
+        let _ = self.events.send(line);
+    }
+}
+
+impl Drop for ProgressSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use serde_json::json;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_progress_socket() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("progress.sock");
+        let socket = ProgressSocket::bind(&path).unwrap();
+        socket.send(&json!({"event": "progress", "uploaded": 1}));
+
+        // attaching mid-run starts with the counts so far
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), r#"{"event":"progress","uploaded":1}"#);
+        socket.send(&json!({"event": "file", "name": "a.fits"}));
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), r#"{"event":"file","name":"a.fits"}"#);
+
+        drop(socket);
+        assert!(!path.exists());
+
+        // a socket left behind by an earlier run is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        assert!(ProgressSocket::bind(&path).is_ok());
+    }
+}
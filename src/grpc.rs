@@ -0,0 +1,139 @@
+//! The gRPC control API of `serve`, defined in `proto/upload.proto`: the
+//! operations of the REST API on the same jobs, plus watching a job's uploads
+//! complete as a stream of events.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures_util::Stream;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Request, Response, Status};
+
+use crate::serve::{self, Jobs};
+
+mod proto {
+    tonic::include_proto!("upload.v1");
+}
+
+use proto::uploads_server::{Uploads, UploadsServer};
+use proto::{FileEvent, JobId, JobList, JobStatus, ListRequest, SubmitRequest};
+
+impl From<serve::Status> for JobStatus {
+    fn from(status: serve::Status) -> Self {
+        JobStatus {
+            id: status.id,
+            path: status.path,
+            state: status.state.name().to_string(),
+            total: status.counts.total as u64,
+            uploaded: status.counts.uploaded as u64,
+            exist: status.counts.exist as u64,
+            failed: status.counts.failed as u64,
+            bytes: status.counts.bytes,
+        }
+    }
+}
+
+impl From<serve::FileEvent> for FileEvent {
+    fn from(event: serve::FileEvent) -> Self {
+        FileEvent {
+            path: event.path,
+            name: event.name,
+            bytes: event.bytes,
+            seconds: event.seconds,
+            retries: event.retries as u32,
+            error: event.error.unwrap_or_default().to_string(),
+        }
+    }
+}
+
+struct Service(Arc<Jobs>);
+
+fn no_such_job() -> Status {
+    Status::not_found("no such job")
+}
+
+#[tonic::async_trait]
+impl Uploads for Service {
+    async fn submit(&self, request: Request<SubmitRequest>) -> Result<Response<JobStatus>, Status> {
+        let SubmitRequest { path, files } = request.into_inner();
+        serve::check_job(&path, &files).map_err(Status::invalid_argument)?;
+        Ok(Response::new(self.0.submit(path, files).into()))
+    }
+
+    async fn status(&self, request: Request<JobId>) -> Result<Response<JobStatus>, Status> {
+        let status = self.0.status(request.into_inner().id).ok_or_else(no_such_job)?;
+        Ok(Response::new(status.into()))
+    }
+
+    async fn list(&self, _: Request<ListRequest>) -> Result<Response<JobList>, Status> {
+        Ok(Response::new(JobList { jobs: self.0.list().into_iter().map(Into::into).collect() }))
+    }
+
+    async fn cancel(&self, request: Request<JobId>) -> Result<Response<JobStatus>, Status> {
+        let status = self.0.cancel(request.into_inner().id).ok_or_else(no_such_job)?;
+        Ok(Response::new(status.into()))
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<FileEvent, Status>> + Send>>;
+
+    async fn watch(&self, request: Request<JobId>) -> Result<Response<Self::WatchStream>, Status> {
+        let id = request.into_inner().id;
+        let events = match self.0.subscribe(id) {
+            Some(events) => events,
+            None if self.0.status(id).is_some() => return Err(Status::failed_precondition("job isn't running")),
+            None => return Err(no_such_job()),
+        };
+        // ends with the job, or with an error for a watcher too slow to keep up
+        let stream = futures_util::stream::unfold(Some(events), |events| async move {
+            let mut events = events?;
+            match events.recv().await {
+                Ok(event) => Some((Ok(event.into()), Some(events))),
+                Err(RecvError::Lagged(n)) => Some((Err(Status::data_loss(format!("{} events missed", n))), None)),
+                Err(RecvError::Closed) => None,
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Serve the gRPC API on `listen` for `jobs`, to callers sending `secret`
+/// as bearer token in the `authorization` metadata.
+pub(crate) async fn serve(listen: SocketAddr, jobs: Arc<Jobs>, secret: Arc<str>) {
+    cli_eprintln!("Serving gRPC on {}", listen);
+    let authorize = move |request: Request<()>| {
+        match serve::has_secret(request.metadata().get("authorization").map(|value| value.as_bytes()), &secret) {
+            true => Ok(request),
+            false => Err(Status::unauthenticated("send the secret printed at start as bearer token")),
+        }
+    };
+    if let Err(e) = tonic::transport::Server::builder()
+        .add_service(UploadsServer::with_interceptor(Service(jobs), authorize))
+        .serve(listen)
+        .await {
+        cli_eprintln!("Failed to serve gRPC on {}: {}", listen, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Settings;
+
+    #[tokio::test]
+    async fn test_service() {
+        let jobs = Jobs::new(Settings::with_endpoint("http://127.0.0.1:1".to_string(), String::new())).unwrap();
+        let service = Service(Arc::new(jobs));
+        let request = SubmitRequest { path: "p".to_string(), files: vec![] };
+        let error = service.submit(Request::new(request)).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+        let error = service.status(Request::new(JobId { id: 1 })).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::NotFound);
+
+        let request = SubmitRequest { path: "p".to_string(), files: vec!["doesnotexist".to_string()] };
+        let status = service.submit(Request::new(request)).await.unwrap().into_inner();
+        assert_eq!((status.id, status.state.as_str()), (1, "running"));
+        let jobs = service.list(Request::new(ListRequest {})).await.unwrap().into_inner().jobs;
+        assert_eq!(jobs.len(), 1);
+    }
+}
@@ -0,0 +1,1030 @@
+//! Preparing the list of files to upload from what was given: walking
+//! directories, filtering and deduplicating.
+
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{Scope, ScopedJoinHandle};
+use std::time::SystemTime;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::archive::{self, ArchiveFormat};
+
+/// ignore file picked up from the root of uploaded directories
+pub(crate) const IGNORE_FILE: &str = ".uploadignore";
+
+/// a local file and the name (relative to the destination path) it is
+/// uploaded as
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Input {
+    pub(crate) path: String,
+    pub(crate) name: String,
+    /// of the path given it was found under, higher uploads first
+    pub(crate) priority: i32,
+}
+
+/// A file or directory to upload and its priority: files of higher priority
+/// are started before others, e.g. calibration files before bulk data.
+/// Strings convert to requests of priority 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UploadRequest {
+    pub path: String,
+    pub priority: i32,
+    /// directory under the destination path it's uploaded into, `/`
+    /// separated, directly into the path if empty
+    pub dest: String,
+}
+
+impl UploadRequest {
+    pub fn new(path: impl Into<String>) -> Self {
+        UploadRequest { path: path.into(), priority: 0, dest: String::new() }
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_dest(mut self, dest: impl Into<String>) -> Self {
+        self.dest = dest.into();
+        self
+    }
+
+    /// The request with its path split from `SRC:DEST` into the local path
+    /// and the directory it's uploaded into, e.g. `calib/:calibration/`. The
+    /// last `:` separates them, so windows paths like `C:\raw:raw` work.
+    pub fn split_dest(self) -> Result<Self, String> {
+        let Some((path, dest)) = self.path.rsplit_once(':') else {
+            return Err(format!("expected SRC:DEST, got {:?}", self.path));
+        };
+        let dest = remote_path(dest, cfg!(windows)).trim_matches('/').to_string();
+        if path.is_empty() || !(dest.is_empty() || valid_name(&dest)) {
+            return Err(format!("expected SRC:DEST with DEST a relative directory, got {:?}", self.path));
+        }
+        Ok(UploadRequest { path: path.to_string(), dest, ..self })
+    }
+
+    /// A request from `N:path`, or of priority 0 if there is no (integer)
+    /// prefix, so `3:calib/` has priority 3 and `data:v2/` is a path.
+    pub fn parse_prefixed(arg: &str) -> Self {
+        match arg.split_once(':').and_then(|(n, path)| Some((n.parse().ok()?, path))) {
+            Some((priority, path)) => UploadRequest::new(path).with_priority(priority),
+            None => UploadRequest::new(arg),
+        }
+    }
+}
+
+impl From<String> for UploadRequest {
+    fn from(path: String) -> Self {
+        UploadRequest::new(path)
+    }
+}
+
+impl From<&str> for UploadRequest {
+    fn from(path: &str) -> Self {
+        UploadRequest::new(path)
+    }
+}
+
+impl AsRef<Path> for Input {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.path)
+    }
+}
+
+/// files to upload found from the given paths
+#[derive(Default)]
+pub(crate) struct Collected {
+    pub(crate) inputs: Vec<Input>,
+    /// number of files and directories skipped by exclude patterns
+    pub(crate) excluded: usize,
+    /// number of files skipped by the size limits
+    pub(crate) filtered_size: usize,
+    /// number of files skipped by the modification time limits
+    pub(crate) filtered_time: usize,
+    /// number of hidden files and directories skipped while walking
+    pub(crate) hidden: usize,
+    /// sparse files found, with their size and the space they take on disk
+    pub(crate) sparse: Vec<(String, u64, u64)>,
+    /// symlinks not uploaded and why
+    pub(crate) skipped_links: Vec<(String, &'static str)>,
+    /// problems worth telling about, e.g. bad ignore file lines
+    pub(crate) warnings: Vec<String>,
+    /// the scan stopped at [`Filters::max_files`], there are more
+    pub(crate) over_max_files: bool,
+}
+
+impl Collected {
+    /// Add what was found by another walk, after what's here.
+    fn merge(&mut self, other: Collected) {
+        self.inputs.extend(other.inputs);
+        self.excluded += other.excluded;
+        self.filtered_size += other.filtered_size;
+        self.filtered_time += other.filtered_time;
+        self.hidden += other.hidden;
+        self.sparse.extend(other.sparse);
+        self.skipped_links.extend(other.skipped_links);
+        self.warnings.extend(other.warnings);
+        self.over_max_files |= other.over_max_files;
+    }
+}
+
+/// what to leave out when collecting files
+#[derive(Default)]
+pub(crate) struct Filters {
+    pub(crate) excludes: GlobSet,
+    pub(crate) min_size: Option<u64>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) newer_than: Option<SystemTime>,
+    pub(crate) older_than: Option<SystemTime>,
+    /// gitignore style files applying to every uploaded directory
+    pub(crate) ignore_files: Vec<String>,
+    /// walk into dot-files and directories
+    pub(crate) hidden: bool,
+    /// walk into symlinked directories
+    pub(crate) follow_symlinks: bool,
+    /// leave out sparse files rather than only warning about them
+    pub(crate) skip_sparse: bool,
+    /// collect each subdirectory of walked directories as one archive
+    pub(crate) archive: Option<ArchiveFormat>,
+    /// threads walking directories at once
+    pub(crate) walkers: usize,
+    /// told what walks found so far, for showing it
+    pub(crate) progress: Option<Arc<ScanProgress>>,
+    /// stop the scan once it found more files than this
+    pub(crate) max_files: Option<usize>,
+}
+
+/// What walking directories found so far, updated as it goes.
+#[derive(Default)]
+pub(crate) struct ScanProgress {
+    files: AtomicUsize,
+    bytes: AtomicU64,
+    /// the directory walked last
+    dir: Mutex<String>,
+}
+
+/// longest end of the directory shown in the progress line
+const SCAN_PROGRESS_DIR: usize = 60;
+
+impl ScanProgress {
+    /// e.g. `Scanning: 1234 files found (56.78 MB), in .../data/run42`
+    pub(crate) fn line(&self) -> String {
+        let dir = self.dir.lock().unwrap();
+        let chars = dir.chars().count();
+        let dir = match chars > SCAN_PROGRESS_DIR {
+            true => format!("...{}", dir.chars().skip(chars - SCAN_PROGRESS_DIR).collect::<String>()),
+            false => dir.clone(),
+        };
+        format!("Scanning: {} files found ({:.2} MB), in {}", self.files.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed) as f64 / (1024.0 * 1024.0), dir)
+    }
+}
+
+impl Filters {
+    fn size_ok(&self, size: u64) -> bool {
+        self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    fn time_ok(&self, metadata: &Metadata) -> bool {
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return true;
+        }
+        // without a modification time there's nothing to go by, keep the file
+        let Ok(modified) = metadata.modified() else { return true };
+        self.newer_than.is_none_or(|t| modified > t) && self.older_than.is_none_or(|t| modified < t)
+    }
+
+    /// Count the file as filtered if it doesn't pass, returning if it does.
+    /// Sparse files are noted either way.
+    fn check(&self, path: &str, metadata: &Metadata, collected: &mut Collected) -> bool {
+        if !self.size_ok(metadata.len()) {
+            collected.filtered_size += 1;
+            return false;
+        } else if !self.time_ok(metadata) {
+            collected.filtered_time += 1;
+            return false;
+        }
+        if let Some(allocated) = allocated(metadata).filter(|a| *a < metadata.len()) {
+            collected.sparse.push((path.to_string(), metadata.len(), allocated));
+            return !self.skip_sparse;
+        }
+        true
+    }
+}
+
+/// Space the file takes up on disk. Less than its size means holes (or
+/// filesystem compression), which the fileservice stores as plain zeros.
+#[cfg(unix)]
+fn allocated(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.blocks() * 512)
+}
+
+#[cfg(not(unix))]
+fn allocated(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Compile exclude globs, e.g. `*.tmp` or `.DS_Store`.
+pub(crate) fn build_excludes(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+/// A pattern excludes a path if it matches either its file name or its name
+/// relative to the upload root, so `.DS_Store` matches at any depth while
+/// `raw/*.fits` can target a subdirectory.
+fn is_excluded(excludes: &GlobSet, name: &str) -> bool {
+    if excludes.is_empty() {
+        return false;
+    }
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    excludes.is_match(file_name) || excludes.is_match(name)
+}
+
+/// Whether walking a directory would leave out the file `name` (relative to
+/// it) by its name: it or a directory it's in is excluded, or hidden.
+pub(crate) fn left_out(filters: &Filters, name: &str) -> bool {
+    let mut parts = name.match_indices('/').map(|(end, _)| end).chain([name.len()]);
+    parts.any(|end| {
+        let part = name[..end].rsplit('/').next().unwrap_or_default();
+        (!filters.hidden && part.starts_with('.')) || is_excluded(&filters.excludes, &name[..end])
+    })
+}
+
+/// Gitignore matcher for a directory being uploaded, from its .uploadignore
+/// (if any) and the given ignore files, with patterns relative to `root`.
+fn build_ignore(root: &Path, ignore_files: &[String], warnings: &mut Vec<String>) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(root);
+    let local = root.join(IGNORE_FILE);
+    let files = local.is_file().then_some(local.as_path()).into_iter()
+        .chain(ignore_files.iter().map(Path::new));
+    for file in files {
+        if let Some(e) = builder.add(file) {
+            warnings.push(format!("ignore file {}: {}", file.display(), e));
+        }
+    }
+    builder.build().unwrap_or_else(|e| {
+        warnings.push(format!("ignore rules for {}: {}", root.display(), e));
+        Gitignore::empty()
+    })
+}
+
+/// `path` for showing, without the `\\?\` prefix windows puts on canonical
+/// paths. Paths longer than MAX_PATH (260) don't need it added to be opened
+/// either, std adds it where needed.
+pub(crate) fn display_path(path: &Path) -> String {
+    let text = path.to_string_lossy();
+    match text.strip_prefix(r"\\?\UNC\") {
+        Some(share) => format!(r"\\{}", share),
+        None => text.strip_prefix(r"\\?\").unwrap_or(&text).to_string(),
+    }
+}
+
+/// `name` with `/` separating its components, also where it was made with
+/// `\` on `windows`, so the fileservice creates directories rather than
+/// files with backslashes in their names.
+pub(crate) fn remote_path(name: &str, windows: bool) -> String {
+    if windows { name.replace('\\', "/") } else { name.to_string() }
+}
+
+/// Whether every component of the remote `name` is one the fileservice
+/// stores as given: not empty, `.` or `..` (which would land the file
+/// elsewhere) and without control characters.
+pub(crate) fn valid_name(name: &str) -> bool {
+    name.split('/').all(|c| !c.is_empty() && c != "." && c != ".." && !c.chars().any(char::is_control))
+}
+
+/// `path` with `.` components dropped and each `..` taking out the component
+/// before it, without looking at the filesystem. A `..` with nothing before it
+/// is kept, or dropped right after the root (`/..` is `/`).
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    use std::path::Component;
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normal.components().next_back() {
+                Some(Component::Normal(_)) => { normal.pop(); }
+                Some(Component::RootDir | Component::Prefix(_)) => {}
+                _ => normal.push(".."),
+            },
+            _ => normal.push(component),
+        }
+    }
+    normal
+}
+
+/// Name a file given directly is uploaded as, the last component of its path
+/// whether relative or absolute, nothing of the directories above it is kept.
+/// A path ending in `.` or `..` names the directory it resolves to, so
+/// `data/raw/..` is `data`, from the filesystem where it exists (so through
+/// symlinks like the OS would) and else lexically. Symlinks given keep their
+/// own name. Empty if there is no (utf-8) name, like for `/`, which fails
+/// the upload.
+pub(crate) fn remote_name(path: &str) -> String {
+    let name = |p: &Path| p.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+    let path = Path::new(path);
+    if path.file_name().is_some() {
+        return name(path);
+    }
+    let resolved = match std::fs::canonicalize(path) {
+        Ok(resolved) => resolved,
+        Err(_) => std::path::absolute(path).map(|p| normalize(&p)).unwrap_or_else(|_| normalize(path)),
+    };
+    name(&resolved)
+}
+
+/// Whether a directory given is uploaded as its contents rather than itself:
+/// it's given with a trailing separator like with rsync, or as `.`, `..` or
+/// a path ending in them, or is the root.
+fn contents_only(path: &str) -> bool {
+    // `Path` drops a trailing `.` as if it wasn't there
+    let last = path.rsplit(['/', std::path::MAIN_SEPARATOR]).next().unwrap_or_default();
+    matches!(last, "" | "." | "..") || Path::new(path).file_name().is_none()
+}
+
+/// Turn the given paths into files to upload. Files given are uploaded under
+/// their [`remote_name`]. With `recursive` directories are walked and files in
+/// them keep their relative path under the directory name, or directly under
+/// the destination for [`contents_only`] paths (like rsync, so `dir/` or `.`
+/// upload the contents). Either way, relative or absolute, only names below
+/// the path given end up in remote names. Hidden entries are only skipped
+/// while walking, paths given are always used. Names of a request with a
+/// `dest` start with it.
+pub(crate) fn collect(paths: Vec<impl Into<UploadRequest>>, recursive: bool, filters: &Filters) -> Collected {
+    collect_into(paths, recursive, filters, None)
+}
+
+/// Like [`collect`], but handing each file to `found` as soon as it's found
+/// rather than keeping them, so uploads can start during a long scan. Files
+/// found by parallel walkers come in no particular order, and directories
+/// aren't archived. The scan stops early once `found` returns false.
+pub(crate) fn collect_streaming(paths: Vec<impl Into<UploadRequest>>, recursive: bool, filters: &Filters,
+    found: &(dyn Fn(Input) -> bool + Sync)) -> Collected {
+    collect_into(paths, recursive, filters, Some(found))
+}
+
+fn collect_into(paths: Vec<impl Into<UploadRequest>>, recursive: bool, filters: &Filters,
+    sink: Option<&(dyn Fn(Input) -> bool + Sync)>) -> Collected {
+    let mut collected = Collected::default();
+    // patterns of ignore files only see the name of files given directly
+    let explicit_ignore = build_ignore(Path::new(""), &filters.ignore_files, &mut collected.warnings);
+    // the sink wants no more files, or there are too many
+    let stopped = AtomicBool::new(false);
+    let files = AtomicUsize::new(0);
+    for request in paths {
+        if stopped.load(Ordering::Relaxed) {
+            break;
+        }
+        let UploadRequest { path, priority, dest } = request.into();
+        let found = collected.inputs.len();
+        // what's otherwise done once all files are found
+        let finish = |mut input: Input| {
+            input.priority = priority;
+            if !dest.is_empty() {
+                input.name = format!("{}/{}", dest, input.name);
+            }
+            input.name = remote_path(&input.name, cfg!(windows));
+            input
+        };
+        let forward = |input| sink.is_none_or(|sink| sink(finish(input)));
+        let name = remote_name(&path);
+        let metadata = std::fs::metadata(&path);
+        let is_dir = metadata.as_ref().is_ok_and(|m| m.is_dir());
+        if is_excluded(&filters.excludes, &name) || is_excluded(&filters.excludes, &path)
+            || explicit_ignore.matched(&name, is_dir).is_ignore() {
+            collected.excluded += 1;
+            continue;
+        }
+        if recursive && is_dir {
+            let prefix = if contents_only(&path) { String::new() } else { format!("{}/", name) };
+            let ignore = build_ignore(Path::new(&path), &filters.ignore_files, &mut collected.warnings);
+            let root = std::fs::canonicalize(&path).unwrap_or_else(|_| PathBuf::from(&path));
+            let idle = AtomicUsize::new(filters.walkers.saturating_sub(1));
+            let sink = sink.map(|_| &forward as &(dyn Fn(Input) -> bool + Sync));
+            let walk = Walk { filters, ignore: &ignore, root: &root, idle, sink, stopped: &stopped, files: &files };
+            walk.walk(Path::new(&path), &prefix, std::slice::from_ref(&root), &mut collected);
+            if let Some(format) = filters.archive {
+                let walked = collected.inputs.split_off(found);
+                collected.inputs.extend(archive::group(walked, Path::new(&path), &prefix, format));
+            }
+            for input in &mut collected.inputs[found..] {
+                input.priority = priority;
+                if !dest.is_empty() {
+                    input.name = format!("{}/{}", dest, input.name);
+                }
+            }
+            continue;
+        }
+        // unreadable files are kept, to be reported when attempted
+        if let Ok(m) = metadata
+            && m.is_file() && !filters.check(&path, &m, &mut collected) {
+            continue;
+        }
+        if !within_max_files(filters, &files, &stopped) {
+            collected.over_max_files = true;
+            break;
+        }
+        match sink {
+            Some(sink) => if !sink(finish(Input { path, name, priority })) {
+                stopped.store(true, Ordering::Relaxed);
+            },
+            None => {
+                let name = if dest.is_empty() { name } else { format!("{}/{}", dest, name) };
+                collected.inputs.push(Input { path, name, priority });
+            }
+        }
+    }
+    for input in &mut collected.inputs {
+        input.name = remote_path(&input.name, cfg!(windows));
+    }
+    collected
+}
+
+/// Count another file found in `files`, whether that's still within the
+/// [`Filters::max_files`]. Past it the scan is `stopped`.
+fn within_max_files(filters: &Filters, files: &AtomicUsize, stopped: &AtomicBool) -> bool {
+    let Some(max_files) = filters.max_files else { return true };
+    if files.fetch_add(1, Ordering::Relaxed) < max_files {
+        return true;
+    }
+    stopped.store(true, Ordering::Relaxed);
+    false
+}
+
+/// A directory tree being walked, by as many threads at once as
+/// [`Filters::walkers`] allows: subdirectories are walked on threads of their
+/// own while there are threads left, and in the thread finding them after.
+struct Walk<'a> {
+    filters: &'a Filters,
+    ignore: &'a Gitignore,
+    /// canonical path of the directory given
+    root: &'a Path,
+    /// threads that may still be started
+    idle: AtomicUsize,
+    /// takes files as they're found instead of collecting them, false once
+    /// it wants no more
+    sink: Option<&'a (dyn Fn(Input) -> bool + Sync)>,
+    /// set once the sink wants no more or there are too many files, walking
+    /// stops
+    stopped: &'a AtomicBool,
+    /// files found so far, by all walks
+    files: &'a AtomicUsize,
+}
+
+/// what was found below a subdirectory, or the thread finding it
+enum Part<'scope> {
+    Found(Collected),
+    Walking(ScopedJoinHandle<'scope, Collected>),
+}
+
+impl Walk<'_> {
+    /// Add the files below `dir` with names starting with `prefix`. Entries
+    /// are visited in name order and subdirectories walked in parallel add
+    /// theirs in that order too, so runs are reproducible. Symlinks to files
+    /// are uploaded as files, symlinked directories only followed if asked to
+    /// and as long as they stay within the root and don't point back at one
+    /// of the `ancestors` being walked, which would never end.
+    fn walk(&self, dir: &Path, prefix: &str, ancestors: &[PathBuf], collected: &mut Collected) {
+        if self.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(progress) = &self.filters.progress {
+            *progress.dir.lock().unwrap() = display_path(dir);
+        }
+        let mut entries: Vec<_> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).collect(),
+            Err(_) => {
+                // attempting the directory reports it as unreadable
+                let input = Input { path: dir.to_string_lossy().to_string(), name: prefix.to_string(), priority: 0 };
+                self.found(input, collected);
+                return;
+            }
+        };
+        entries.sort_by_key(|e| e.file_name());
+        let filters = self.filters;
+        std::thread::scope(|scope| {
+            // what was found before each subdirectory, and below it
+            let mut parts = Vec::new();
+            let mut current = Collected::default();
+            for entry in entries {
+                if self.stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let path = entry.path();
+                let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+                let Ok(file_type) = entry.file_type() else { continue };
+                if !filters.hidden && entry.file_name().to_string_lossy().starts_with('.') {
+                    current.hidden += 1;
+                    continue;
+                }
+                if is_excluded(&filters.excludes, &name) || self.ignore.matched(&path, file_type.is_dir()).is_ignore() {
+                    current.excluded += 1;
+                    continue;
+                }
+                if file_type.is_dir() {
+                    let target = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+                    parts.push(Part::Found(std::mem::take(&mut current)));
+                    parts.push(self.subdir(scope, path, format!("{}/", name), ancestors, target));
+                    continue;
+                }
+                // follows symlinks, so links to files count as files
+                let Ok(metadata) = std::fs::metadata(&path) else {
+                    if file_type.is_symlink() {
+                        current.skipped_links.push((path.to_string_lossy().to_string(), "broken symlink"));
+                    }
+                    continue;
+                };
+                if metadata.is_dir() {
+                    let target = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+                    let reason = if !filters.follow_symlinks {
+                        "symlinked directory, not followed without --follow-symlinks"
+                    } else if ancestors.contains(&target) {
+                        "symlink cycle, points to a directory being uploaded"
+                    } else if !target.starts_with(self.root) {
+                        "symlink points outside of the uploaded directory"
+                    } else {
+                        parts.push(Part::Found(std::mem::take(&mut current)));
+                        parts.push(self.subdir(scope, path, format!("{}/", name), ancestors, target));
+                        continue;
+                    };
+                    current.skipped_links.push((path.to_string_lossy().to_string(), reason));
+                    continue;
+                }
+                if !metadata.is_file() {
+                    continue;
+                }
+                let path = path.to_string_lossy().to_string();
+                if !filters.check(&path, &metadata, &mut current) {
+                    continue;
+                }
+                if let Some(progress) = &filters.progress {
+                    progress.files.fetch_add(1, Ordering::Relaxed);
+                    progress.bytes.fetch_add(metadata.len(), Ordering::Relaxed);
+                }
+                self.found(Input { path, name, priority: 0 }, &mut current);
+            }
+            parts.push(Part::Found(current));
+            for part in parts {
+                collected.merge(match part {
+                    Part::Found(found) => found,
+                    Part::Walking(thread) => thread.join().unwrap_or_else(|e| std::panic::resume_unwind(e)),
+                });
+            }
+        });
+    }
+
+    fn found(&self, input: Input, collected: &mut Collected) {
+        if !within_max_files(self.filters, self.files, self.stopped) {
+            collected.over_max_files = true;
+            return;
+        }
+        match self.sink {
+            Some(sink) => if !sink(input) {
+                self.stopped.store(true, Ordering::Relaxed);
+            },
+            None => collected.inputs.push(input),
+        }
+    }
+
+    /// Walk the subdirectory `dir` (canonical `target`) on a thread of its
+    /// own if one is left, or right away.
+    fn subdir<'scope>(&'scope self, scope: &'scope Scope<'scope, '_>, dir: PathBuf, prefix: String,
+        ancestors: &[PathBuf], target: PathBuf) -> Part<'scope> {
+        let mut ancestors = ancestors.to_vec();
+        ancestors.push(target);
+        let walk = move || {
+            let mut found = Collected::default();
+            self.walk(&dir, &prefix, &ancestors, &mut found);
+            found
+        };
+        if self.idle.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_err() {
+            return Part::Found(walk());
+        }
+        Part::Walking(scope.spawn(move || {
+            let found = walk();
+            self.idle.fetch_add(1, Ordering::SeqCst);
+            found
+        }))
+    }
+}
+
+/// Drop repeated paths, keeping the first occurrence, so the same file isn't
+/// uploaded twice at once. Paths are compared after resolving symlinks and
+/// relative components where possible, so `./a.txt` and `a.txt` are the same.
+/// Returns the unique files and the dropped duplicates.
+pub(crate) fn dedup_files<T: AsRef<Path>>(files: Vec<T>) -> (Vec<T>, Vec<T>) {
+    let mut seen = HashSet::new();
+    let mut unique = Vec::with_capacity(files.len());
+    let mut duplicates = Vec::new();
+    for file in files {
+        let key = std::fs::canonicalize(&file).unwrap_or_else(|_| file.as_ref().to_path_buf());
+        if seen.insert(key) {
+            unique.push(file);
+        } else {
+            duplicates.push(file);
+        }
+    }
+    (unique, duplicates)
+}
+
+/// Keep only the first name of files that are hardlinks to the same inode, so
+/// their content is uploaded once. Returns the kept files and the dropped
+/// alternate names along with the kept name they link to.
+#[cfg(unix)]
+pub(crate) fn dedup_hardlinks<T: AsRef<Path> + Clone>(files: Vec<T>) -> (Vec<T>, Vec<(T, T)>) {
+    use std::collections::HashMap;
+    use std::os::unix::fs::MetadataExt;
+
+    let mut inodes: HashMap<(u64, u64), T> = HashMap::new();
+    let mut unique = Vec::with_capacity(files.len());
+    let mut links = Vec::new();
+    for file in files {
+        match std::fs::metadata(&file) {
+            Ok(m) if m.is_file() && m.nlink() > 1 => match inodes.get(&(m.dev(), m.ino())) {
+                Some(original) => links.push((file, original.clone())),
+                None => {
+                    inodes.insert((m.dev(), m.ino()), file.clone());
+                    unique.push(file);
+                }
+            },
+            _ => unique.push(file),
+        }
+    }
+    (unique, links)
+}
+
+/// no portable inode numbers, nothing is treated as a hardlink
+#[cfg(not(unix))]
+pub(crate) fn dedup_hardlinks<T: AsRef<Path> + Clone>(files: Vec<T>) -> (Vec<T>, Vec<(T, T)>) {
+    (files, Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let file = tempdir.path().join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let file = file.to_str().unwrap().to_string();
+        let dotted = tempdir.path().join(".").join("a.txt").to_str().unwrap().to_string();
+        let files = vec![file.clone(), "missing.txt".to_string(), dotted.clone(), "missing.txt".to_string()];
+        let (unique, duplicates) = dedup_files(files);
+        assert_eq!(unique, vec![file, "missing.txt".to_string()]);
+        assert_eq!(duplicates, vec![dotted, "missing.txt".to_string()]);
+    }
+
+    #[test]
+    fn test_collect() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path().join("data");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        for name in ["a.txt", "b.tmp", "sub/c.txt", "sub/.DS_Store"] {
+            std::fs::write(root.join(name), name).unwrap();
+        }
+        let root = root.to_str().unwrap().to_string();
+        let excludes = build_excludes(&["*.tmp".to_string(), ".DS_Store".to_string()]).unwrap();
+        let filters = Filters { excludes, hidden: true, ..Default::default() };
+        let names = |c: Collected| c.inputs.into_iter().map(|i| i.name).collect::<Vec<_>>();
+
+        let collected = collect(vec![root.clone()], true, &filters);
+        assert_eq!(collected.excluded, 2);
+        assert_eq!(names(collected), vec!["data/a.txt", "data/sub/c.txt"]);
+        let collected = collect(vec![format!("{}/", root)], true, &filters);
+        assert_eq!(names(collected), vec!["a.txt", "sub/c.txt"]);
+        // explicit files are filtered too, directories aren't walked
+        let collected = collect(vec![format!("{}/b.tmp", root), root.clone()], false, &filters);
+        assert_eq!(collected.excluded, 1);
+        assert_eq!(names(collected), vec!["data"]);
+        // hidden entries are skipped while walking only
+        let filters = Filters::default();
+        let collected = collect(vec![root.clone(), format!("{}/sub/.DS_Store", root)], true, &filters);
+        assert_eq!(collected.hidden, 1);
+        assert_eq!(names(collected), vec!["data/a.txt", "data/b.tmp", "data/sub/c.txt", ".DS_Store"]);
+        // files found under a path get its priority
+        let requests = vec![UploadRequest::new(format!("{}/a.txt", root)), UploadRequest::new(format!("{}/sub", root)).with_priority(2)];
+        let priorities: Vec<_> = collect(requests, true, &filters).inputs.into_iter().map(|i| (i.name, i.priority)).collect();
+        assert_eq!(priorities, vec![("a.txt".to_string(), 0), ("sub/c.txt".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_left_out() {
+        let excludes = build_excludes(&["*.tmp".to_string(), "build".to_string()]).unwrap();
+        let filters = Filters { excludes, ..Default::default() };
+        assert!(left_out(&filters, "a.tmp") && left_out(&filters, "sub/a.tmp"));
+        assert!(left_out(&filters, "build/a.o") && left_out(&filters, "sub/build/a.o"));
+        assert!(left_out(&filters, ".git/config") && left_out(&filters, "sub/.DS_Store"));
+        assert!(!left_out(&filters, "sub/a.txt") && !left_out(&filters, "builds/a.o"));
+        let filters = Filters { hidden: true, ..filters };
+        assert!(!left_out(&filters, ".git/config"));
+    }
+
+    #[test]
+    fn test_collect_parallel() {
+        let tempdir = tempfile::tempdir().unwrap();
+        for dir in 0..6 {
+            for sub in 0..4 {
+                let sub = tempdir.path().join(format!("d{}/s{}", dir, sub));
+                std::fs::create_dir_all(&sub).unwrap();
+                for file in 0..5 {
+                    std::fs::write(sub.join(format!("f{}.txt", file)), "x").unwrap();
+                }
+                std::fs::write(sub.join(".hidden"), "x").unwrap();
+            }
+            std::fs::write(tempdir.path().join(format!("d{}.txt", dir)), "x").unwrap();
+        }
+        let root = format!("{}/", tempdir.path().to_str().unwrap());
+        let sequential = collect(vec![root.clone()], true, &Filters::default());
+        let parallel = collect(vec![root.clone()], true, &Filters { walkers: 4, ..Default::default() });
+        // in the same order, whichever thread found them
+        assert_eq!(parallel.inputs, sequential.inputs);
+        assert_eq!(parallel.inputs.len(), 6 * 4 * 5 + 6);
+        assert_eq!(parallel.inputs[..2].iter().map(|i| i.name.as_str()).collect::<Vec<_>>(), ["d0/s0/f0.txt", "d0/s0/f1.txt"]);
+        assert_eq!(parallel.hidden, 6 * 4);
+        // the walks stop once there are too many
+        let filters = Filters { walkers: 4, max_files: Some(10), ..Default::default() };
+        let limited = collect(vec![root.clone()], true, &filters);
+        assert!(limited.over_max_files);
+        assert_eq!(limited.inputs.len(), 10);
+        let all = collect(vec![root], true, &Filters { max_files: Some(6 * 4 * 5 + 6), ..Default::default() });
+        assert!(!all.over_max_files);
+    }
+
+    #[test]
+    fn test_scan_progress() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let deep = tempdir.path().join("a".repeat(40)).join("b".repeat(40));
+        std::fs::create_dir_all(&deep).unwrap();
+        std::fs::write(deep.join("f.txt"), vec![0; 1 << 20]).unwrap();
+        std::fs::write(tempdir.path().join("g.txt"), vec![0; 1 << 19]).unwrap();
+        let progress = Arc::new(ScanProgress::default());
+        let filters = Filters { progress: Some(progress.clone()), ..Default::default() };
+        collect(vec![tempdir.path().to_str().unwrap().to_string()], true, &filters);
+        let line = progress.line();
+        assert!(line.starts_with("Scanning: 2 files found (1.50 MB), in ..."), "{}", line);
+        assert!(line.ends_with(&format!("{}/{}", "a".repeat(19), "b".repeat(40))), "{}", line);
+    }
+
+    #[test]
+    fn test_normalize() {
+        for (path, normal) in [("a/./b/../c.fits", "a/c.fits"), ("./a", "a"), ("../a/..", ".."), ("a/../..", ".."),
+            ("/data/../..", "/"), ("/data/./raw/", "/data/raw"), (".", "")] {
+            assert_eq!(normalize(Path::new(path)), PathBuf::from(normal), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_remote_name() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let data = tempdir.path().join("data");
+        std::fs::create_dir_all(data.join("raw")).unwrap();
+        std::fs::write(data.join("a.fits"), "a").unwrap();
+        let data = data.to_str().unwrap();
+        // absolute or relative, only the last component
+        assert_eq!(remote_name(&format!("{}/a.fits", data)), "a.fits");
+        assert_eq!(remote_name("raw/../a.fits"), "a.fits");
+        assert_eq!(remote_name(&format!("{}/raw/", data)), "raw");
+        // the directory `.` and `..` resolve to
+        assert_eq!(remote_name(&format!("{}/raw/..", data)), "data");
+        assert_eq!(remote_name(&format!("{}/raw/.", data)), "raw");
+        assert_eq!(remote_name(&format!("{}/missing/..", data)), "data");
+        let parent = tempdir.path().file_name().unwrap().to_str().unwrap();
+        assert_eq!(remote_name(&format!("{}/missing/../..", data)), parent);
+        assert_eq!(remote_name("."), std::env::current_dir().unwrap().file_name().unwrap().to_str().unwrap());
+        assert_eq!(remote_name("/"), "");
+        for path in ["data/", ".", "data/.", "data/..", "/"] {
+            assert!(contents_only(path), "{}", path);
+        }
+        assert!(!contents_only("data") && !contents_only("data/raw/../a.fits"));
+    }
+
+    /// the names a tree gets in flat and hierarchy preserving uploads, however
+    /// it's given
+    #[test]
+    fn test_collect_paths() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let data = tempdir.path().join("data");
+        std::fs::create_dir_all(data.join("raw")).unwrap();
+        std::fs::write(data.join("a.fits"), "a").unwrap();
+        std::fs::write(data.join("raw/b.fits"), "b").unwrap();
+        let data = data.to_str().unwrap();
+        let names = |path: String, recursive| collect(vec![path], recursive, &Filters::default()).inputs
+            .into_iter().map(|i| i.name).collect::<Vec<_>>();
+
+        assert_eq!(names(format!("{}/raw/../a.fits", data), false), vec!["a.fits"]);
+        assert_eq!(names(format!("{}/raw/./b.fits", data), false), vec!["b.fits"]);
+        assert_eq!(names(data.to_string(), true), vec!["data/a.fits", "data/raw/b.fits"]);
+        assert_eq!(names(format!("{}/./raw", data), true), vec!["raw/b.fits"]);
+        assert_eq!(names(format!("{}/raw/../raw", data), true), vec!["raw/b.fits"]);
+        for contents in [format!("{}/", data), format!("{}/.", data), format!("{}/raw/..", data)] {
+            assert_eq!(names(contents.clone(), true), vec!["a.fits", "raw/b.fits"], "{}", contents);
+        }
+    }
+
+    #[test]
+    fn test_remote_path() {
+        assert_eq!(remote_path(r"run1\raw\a.fits", true), "run1/raw/a.fits");
+        // a legal file name elsewhere
+        assert_eq!(remote_path(r"a\b.fits", false), r"a\b.fits");
+        assert!(valid_name("run1/raw/a.fits"));
+        assert!(valid_name("..a.fits"));
+        for name in ["", "run1//a.fits", "/a.fits", "dir/", "../a.fits", "run1/./a.fits", "a\nb.fits"] {
+            assert!(!valid_name(name), "{:?}", name);
+        }
+    }
+
+    /// deep survey trees, past windows' MAX_PATH of 260
+    #[test]
+    fn test_collect_long_paths() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let deep: PathBuf = std::iter::repeat_n("observation-run-directory", 12).collect();
+        std::fs::create_dir_all(tempdir.path().join(&deep)).unwrap();
+        let file = tempdir.path().join(&deep).join("frame.fits");
+        std::fs::write(&file, "frame").unwrap();
+        assert!(file.as_os_str().len() > 300);
+        let root = format!("{}/", tempdir.path().display());
+        let collected = collect(vec![root], true, &Filters::default());
+        assert_eq!(collected.inputs.len(), 1);
+        assert_eq!(std::fs::read(&collected.inputs[0].path).unwrap(), b"frame");
+        assert!(collected.inputs[0].name.ends_with("observation-run-directory/frame.fits"));
+
+        assert_eq!(display_path(Path::new(r"\\?\C:\data\a.fits")), r"C:\data\a.fits");
+        assert_eq!(display_path(Path::new(r"\\?\UNC\server\share\a.fits")), r"\\server\share\a.fits");
+        assert_eq!(display_path(Path::new("/data/a.fits")), "/data/a.fits");
+    }
+
+    #[test]
+    fn test_collect_dest() {
+        let tempdir = tempfile::tempdir().unwrap();
+        for name in ["raw/a.fits", "calib/flat.fits"] {
+            std::fs::create_dir_all(tempdir.path().join(name).parent().unwrap()).unwrap();
+            std::fs::write(tempdir.path().join(name), name).unwrap();
+        }
+        let path = |name: &str| format!("{}/{}", tempdir.path().display(), name);
+        let requests = vec![
+            UploadRequest::new(path("raw/")).with_dest("raw"),
+            UploadRequest::new(path("calib")).with_dest("calibration/2024").with_priority(1),
+            UploadRequest::new(path("raw/a.fits")).with_dest("latest"),
+        ];
+        let names: Vec<_> = collect(requests, true, &Filters::default()).inputs.into_iter()
+            .map(|i| (i.name, i.priority))
+            .collect();
+        assert_eq!(names, vec![("raw/a.fits".to_string(), 0), ("calibration/2024/calib/flat.fits".to_string(), 1),
+            ("latest/a.fits".to_string(), 0)]);
+    }
+
+    #[test]
+    fn test_split_dest() {
+        let split = |arg: &str| UploadRequest::new(arg).split_dest();
+        assert_eq!(split("raw/:raw/"), Ok(UploadRequest::new("raw/").with_dest("raw")));
+        assert_eq!(split("calib:/calibration/2024/"), Ok(UploadRequest::new("calib").with_dest("calibration/2024")));
+        assert_eq!(split(r"C:\data\raw:raw"), Ok(UploadRequest::new(r"C:\data\raw").with_dest("raw")));
+        assert_eq!(split("a.fits:"), Ok(UploadRequest::new("a.fits")));
+        assert_eq!(UploadRequest::parse_prefixed("2:raw:raw").split_dest(),
+            Ok(UploadRequest::new("raw").with_priority(2).with_dest("raw")));
+        for arg in ["raw/", ":raw", "raw:../up", "raw:a//b"] {
+            assert!(split(arg).is_err(), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn test_parse_prefixed() {
+        assert_eq!(UploadRequest::parse_prefixed("10:calib/"), UploadRequest::new("calib/").with_priority(10));
+        assert_eq!(UploadRequest::parse_prefixed("-1:bulk.fits").priority, -1);
+        assert_eq!(UploadRequest::parse_prefixed("data:v2/"), UploadRequest::new("data:v2/"));
+        assert_eq!(UploadRequest::parse_prefixed("a.txt"), UploadRequest::new("a.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_symlinks() {
+        use std::os::unix::fs::symlink;
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path().join("data");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(tempdir.path().join("outside")).unwrap();
+        std::fs::write(root.join("sub/a.txt"), "a").unwrap();
+        std::fs::write(tempdir.path().join("outside/b.txt"), "b").unwrap();
+        symlink(root.join("sub"), root.join("linked")).unwrap();
+        symlink(&root, root.join("sub/loop")).unwrap();
+        symlink(tempdir.path().join("outside"), root.join("escape")).unwrap();
+        symlink(root.join("missing"), root.join("broken")).unwrap();
+        let root = format!("{}/", root.to_str().unwrap());
+        let reasons = |c: &Collected| c.skipped_links.iter()
+            .map(|(p, r)| (p.rsplit('/').next().unwrap().to_string(), *r))
+            .collect::<Vec<_>>();
+
+        let collected = collect(vec![root.clone()], true, &Filters::default());
+        assert_eq!(collected.inputs.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(), vec!["sub/a.txt"]);
+        assert_eq!(collected.skipped_links.len(), 4);
+        let filters = Filters { follow_symlinks: true, ..Default::default() };
+        let collected = collect(vec![root], true, &filters);
+        assert_eq!(collected.inputs.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(),
+            vec!["linked/a.txt", "sub/a.txt"]);
+        assert_eq!(reasons(&collected), vec![
+            ("broken".to_string(), "broken symlink"),
+            ("escape".to_string(), "symlink points outside of the uploaded directory"),
+            ("loop".to_string(), "symlink cycle, points to a directory being uploaded"),
+            ("loop".to_string(), "symlink cycle, points to a directory being uploaded"),
+        ]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_collect_sparse() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::File::create(tempdir.path().join("sparse")).unwrap().set_len(1 << 20).unwrap();
+        std::fs::write(tempdir.path().join("dense"), vec![1u8; 1 << 16]).unwrap();
+        let root = format!("{}/", tempdir.path().to_str().unwrap());
+        let collected = collect(vec![root.clone()], true, &Filters::default());
+        assert_eq!(collected.inputs.len(), 2);
+        assert_eq!(collected.sparse.len(), 1);
+        let (path, size, allocated) = &collected.sparse[0];
+        assert!(path.ends_with("sparse"));
+        assert!(*size == 1 << 20 && *allocated < *size);
+        let filters = Filters { skip_sparse: true, ..Default::default() };
+        let collected = collect(vec![root], true, &filters);
+        assert_eq!(collected.inputs.len(), 1);
+        assert_eq!(collected.inputs[0].name, "dense");
+    }
+
+    #[test]
+    fn test_collect_sizes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        for (name, size) in [("small", 10), ("medium", 100), ("large", 1000)] {
+            std::fs::write(tempdir.path().join(name), vec![0u8; size]).unwrap();
+        }
+        let root = format!("{}/", tempdir.path().to_str().unwrap());
+        let filters = Filters { min_size: Some(50), max_size: Some(500), ..Default::default() };
+        let collected = collect(vec![root], true, &filters);
+        assert_eq!(collected.filtered_size, 2);
+        assert_eq!(collected.inputs.len(), 1);
+        assert_eq!(collected.inputs[0].name, "medium");
+    }
+
+    #[test]
+    fn test_collect_times() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        for (name, age) in [("new", 60), ("old", 3600), ("ancient", 86400)] {
+            let file = std::fs::File::create(tempdir.path().join(name)).unwrap();
+            file.set_modified(now - std::time::Duration::from_secs(age)).unwrap();
+        }
+        let root = format!("{}/", tempdir.path().to_str().unwrap());
+        let filters = Filters {
+            newer_than: Some(now - std::time::Duration::from_secs(7200)),
+            older_than: Some(now - std::time::Duration::from_secs(600)),
+            ..Default::default()
+        };
+        let collected = collect(vec![root], true, &filters);
+        assert_eq!(collected.filtered_time, 2);
+        assert_eq!(collected.inputs.len(), 1);
+        assert_eq!(collected.inputs[0].name, "old");
+    }
+
+    #[test]
+    fn test_collect_ignore_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path().join("data");
+        std::fs::create_dir_all(root.join("raw")).unwrap();
+        std::fs::create_dir_all(root.join("scratch")).unwrap();
+        for name in ["a.fits", "raw/b.fits", "raw/b.log", "scratch/c.fits"] {
+            std::fs::write(root.join(name), name).unwrap();
+        }
+        std::fs::write(root.join(IGNORE_FILE), "scratch/\n*.log\n").unwrap();
+        let extra = tempdir.path().join("extra-ignore");
+        std::fs::write(&extra, "/a.fits\n").unwrap();
+        let filters = Filters { ignore_files: vec![extra.to_str().unwrap().to_string()], ..Default::default() };
+        let collected = collect(vec![root.to_str().unwrap().to_string()], true, &filters);
+        let names: Vec<_> = collected.inputs.iter().map(|i| i.name.as_str()).collect();
+        // the ignore file itself is hidden, so not uploaded by default
+        assert_eq!(names, vec!["data/raw/b.fits"]);
+        assert_eq!(collected.excluded, 3);
+        assert_eq!(collected.hidden, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dedup_hardlinks() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = |name: &str| tempdir.path().join(name).to_str().unwrap().to_string();
+        std::fs::write(path("a"), "a").unwrap();
+        std::fs::write(path("c"), "c").unwrap();
+        std::fs::hard_link(path("a"), path("b")).unwrap();
+        let (unique, links) = dedup_hardlinks(vec![path("a"), path("b"), path("c")]);
+        assert_eq!(unique, vec![path("a"), path("c")]);
+        assert_eq!(links, vec![(path("b"), path("a"))]);
+    }
+}
@@ -0,0 +1,292 @@
+//! Every upload run is a job with an ID, its arguments, plan (the files to
+//! upload) and progress kept in a state directory, so an interrupted run can
+//! be resumed exactly where it stopped, without walking directories again.
+//!
+//! Each job is a directory named by its ID holding `args` (the command line
+//! without the token), `info` (key=value lines), `plan` (file paths and
+//! names) and `done` (uploads finished, appended as they complete). Fields in
+//! `args`, `plan` and `done` are NUL terminated, as paths can hold anything
+//! else. Plans also hold the priority of each file, unless written before
+//! priorities were kept (`info` without `plan_fields=3`).
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::inputs::Input;
+
+/// Where jobs are kept by default, following the XDG base directory spec.
+pub fn default_state_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_STATE_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("sciserver-upload")
+}
+
+fn split_nul(data: &str) -> impl Iterator<Item = &str> {
+    data.split_terminator('\0')
+}
+
+fn read_info(dir: &Path) -> io::Result<Vec<(String, String)>> {
+    let info = std::fs::read_to_string(dir.join("info"))?;
+    Ok(info.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect())
+}
+
+/// A job being run, new or resumed.
+pub struct JobState {
+    id: String,
+    dir: PathBuf,
+    created: SystemTime,
+    plan: Option<Vec<Input>>,
+    done: HashSet<(usize, String)>,
+    log: Mutex<Option<File>>,
+}
+
+impl JobState {
+    /// Start a new job under `state_dir` for the given arguments.
+    pub fn create(state_dir: &Path, args: &[String]) -> io::Result<Self> {
+        let created = SystemTime::now();
+        let timestamp = humantime::format_rfc3339_seconds(created).to_string().replace(['-', ':'], "");
+        let id = format!("{}-{:04x}", timestamp, fastrand::u16(..));
+        let dir = state_dir.join(&id);
+        std::fs::create_dir_all(&dir)?;
+        let args: String = args.iter().map(|a| format!("{}\0", a)).collect();
+        std::fs::write(dir.join("args"), args)?;
+        Ok(JobState { id, dir, created, plan: None, done: HashSet::new(), log: Mutex::new(None) })
+    }
+
+    /// Open an earlier job to resume it.
+    pub fn open(state_dir: &Path, id: &str) -> io::Result<Self> {
+        let dir = state_dir.join(id);
+        let created = std::fs::metadata(dir.join("args"))?.modified()?;
+        let plan = std::fs::read_to_string(dir.join("plan"))?;
+        let fields: Vec<_> = split_nul(&plan).collect();
+        let with_priorities = read_info(&dir)?.iter().any(|(k, v)| k == "plan_fields" && v == "3");
+        let plan = fields.chunks_exact(if with_priorities { 3 } else { 2 })
+            .map(|f| Input {
+                path: f[0].to_string(),
+                name: f[1].to_string(),
+                priority: f.get(2).and_then(|p| p.parse().ok()).unwrap_or(0),
+            })
+            .collect();
+        let done = std::fs::read_to_string(dir.join("done")).unwrap_or_default();
+        let fields: Vec<_> = split_nul(&done).collect();
+        let done = fields.chunks_exact(2)
+            .filter_map(|f| Some((f[0].parse().ok()?, f[1].to_string())))
+            .collect();
+        Ok(JobState { id: id.to_string(), dir, created, plan: Some(plan), done, log: Mutex::new(None) })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// the arguments the job was started with
+    pub fn args(&self) -> io::Result<Vec<String>> {
+        let args = std::fs::read_to_string(self.dir.join("args"))?;
+        Ok(split_nul(&args).map(str::to_string).collect())
+    }
+
+    /// files to upload of a resumed job
+    pub(crate) fn plan(&self) -> Option<&[Input]> {
+        self.plan.as_deref()
+    }
+
+    /// if the upload of the file at `path` to `destination` finished earlier
+    pub(crate) fn is_done(&self, destination: usize, path: &str) -> bool {
+        self.done.contains(&(destination, path.to_string()))
+    }
+
+    /// Record the files to upload to `path`, each to `destinations`.
+    pub(crate) fn save_plan(&self, files: &[Input], path: &str, destinations: usize) -> io::Result<()> {
+        let plan: String = files.iter().map(|f| format!("{}\0{}\0{}\0", f.path, f.name, f.priority)).collect();
+        std::fs::write(self.dir.join("plan"), plan)?;
+        let created = humantime::format_rfc3339_seconds(self.created);
+        let uploads = files.len() * destinations;
+        let info = format!("created={}\npath={}\nuploads={}\nplan_fields=3\n", created, path, uploads);
+        std::fs::write(self.dir.join("info"), info)
+    }
+
+    /// Record a finished upload, right away so it's kept if the run dies.
+    pub(crate) fn record_done(&self, destination: usize, path: &str) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if log.is_none() {
+            *log = Some(OpenOptions::new().create(true).append(true).open(self.dir.join("done"))?);
+        }
+        log.as_mut().unwrap().write_all(format!("{}\0{}\0", destination, path).as_bytes())
+    }
+}
+
+/// An upload an earlier run did, as its `--report` tells, to skip while the
+/// local file and where it goes are the same.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Uploaded {
+    pub destination: usize,
+    /// local path, as given to that run
+    pub path: String,
+    /// destination path and name it was uploaded as
+    pub remote: String,
+    /// of the local file
+    pub size: u64,
+    /// of the local file, as RFC 3339 with nanoseconds
+    pub modified: Option<String>,
+}
+
+/// how modification times are written in reports
+pub(crate) fn format_modified(modified: SystemTime) -> String {
+    humantime::format_rfc3339_nanos(modified).to_string()
+}
+
+/// Uploads that succeeded (or found the file already there) according to the
+/// `--report` of an earlier run at `path`, to skip them without the job that
+/// run was. Reports of versions not telling the remote path, size and
+/// modification time have none to skip.
+pub fn uploaded_in_report(path: &Path) -> Result<HashSet<Uploaded>, String> {
+    let report = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let report: serde_json::Value = serde_json::from_str(&report).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let files = report["files"].as_array().ok_or_else(|| format!("{}: not a report, no files", path.display()))?;
+    Ok(files.iter()
+        .filter(|file| file["error"].is_null() || file["error"] == "already exists")
+        .filter_map(|file| Some(Uploaded {
+            destination: file["destination"].as_u64()? as usize,
+            path: file["path"].as_str()?.to_string(),
+            remote: file["remote"].as_str()?.to_string(),
+            size: file["bytes"].as_u64()?,
+            modified: file.get("modified")?.as_str().map(str::to_string),
+        }))
+        .collect())
+}
+
+/// a job as listed by `jobs`
+pub struct JobSummary {
+    pub id: String,
+    pub created: String,
+    pub path: String,
+    pub uploads: usize,
+    pub done: usize,
+}
+
+/// Jobs under `state_dir` that got as far as planning, oldest first.
+pub fn list_jobs(state_dir: &Path) -> io::Result<Vec<JobSummary>> {
+    let mut jobs = Vec::new();
+    let entries = match std::fs::read_dir(state_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(jobs),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let dir = entry?.path();
+        let Ok(info) = read_info(&dir) else { continue };
+        let get = |key: &str| info.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()).unwrap_or_default();
+        let done = std::fs::read_to_string(dir.join("done")).unwrap_or_default();
+        jobs.push(JobSummary {
+            id: dir.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            created: get("created"),
+            path: get("path"),
+            uploads: get("uploads").parse().unwrap_or(0),
+            done: split_nul(&done).count() / 2,
+        });
+    }
+    jobs.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(jobs)
+}
+
+/// Delete the jobs under `state_dir` started more than `age` ago, finished
+/// or not. The IDs of those deleted.
+pub fn prune_jobs(state_dir: &Path, age: Duration) -> io::Result<Vec<String>> {
+    let mut pruned = Vec::new();
+    let entries = match std::fs::read_dir(state_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(pruned),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let dir = entry?.path();
+        // only job directories, as told by their args
+        let Ok(created) = std::fs::metadata(dir.join("args")).and_then(|m| m.modified()) else { continue };
+        if created.elapsed().is_ok_and(|elapsed| elapsed > age) {
+            std::fs::remove_dir_all(&dir)?;
+            pruned.push(dir.file_name().unwrap_or_default().to_string_lossy().to_string());
+        }
+    }
+    pruned.sort();
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_state() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let args = vec!["Storage/u/p".to_string(), "a b.txt".to_string()];
+        let job = JobState::create(tempdir.path(), &args).unwrap();
+        let files = vec![
+            Input { path: "/data/a b.txt".to_string(), name: "a b.txt".to_string(), priority: 0 },
+            Input { path: "/data/c\n.txt".to_string(), name: "c\n.txt".to_string(), priority: -2 },
+            Input { path: "/data/calib".to_string(), name: "calib".to_string(), priority: 10 },
+        ];
+        job.save_plan(&files, "Storage/u/p", 2).unwrap();
+        job.record_done(0, "/data/a b.txt").unwrap();
+        job.record_done(1, "/data/c\n.txt").unwrap();
+
+        let resumed = JobState::open(tempdir.path(), job.id()).unwrap();
+        assert_eq!(resumed.args().unwrap(), args);
+        assert_eq!(resumed.plan().unwrap(), files.as_slice());
+        assert!(resumed.is_done(0, "/data/a b.txt") && resumed.is_done(1, "/data/c\n.txt"));
+        assert!(!resumed.is_done(1, "/data/a b.txt"));
+        let jobs = list_jobs(tempdir.path()).unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!((jobs[0].path.as_str(), jobs[0].uploads, jobs[0].done), ("Storage/u/p", 6, 2));
+        assert!(JobState::open(tempdir.path(), "nope").is_err());
+
+        // plans from before priorities were kept
+        let dir = tempdir.path().join(job.id());
+        std::fs::write(dir.join("plan"), "/data/a\0a\0/data/b\0b\0").unwrap();
+        let info = std::fs::read_to_string(dir.join("info")).unwrap().replace("plan_fields=3\n", "");
+        std::fs::write(dir.join("info"), info).unwrap();
+        let resumed = JobState::open(tempdir.path(), job.id()).unwrap();
+        let plan: Vec<_> = resumed.plan().unwrap().iter().map(|f| (f.name.as_str(), f.priority)).collect();
+        assert_eq!(plan, vec![("a", 0), ("b", 0)]);
+
+        std::fs::create_dir(tempdir.path().join("other")).unwrap();
+        assert!(prune_jobs(tempdir.path(), Duration::from_secs(3600)).unwrap().is_empty());
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(prune_jobs(tempdir.path(), Duration::ZERO).unwrap(), vec![job.id().to_string()]);
+        assert!(list_jobs(tempdir.path()).unwrap().is_empty());
+        assert!(tempdir.path().join("other").exists());
+    }
+
+    #[test]
+    fn test_uploaded_in_report() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("report.json");
+        let modified = "2025-06-30T14:00:00.123456789Z";
+        std::fs::write(&path, serde_json::json!({"summary": {}, "files": [
+            {"path": "/data/a", "remote": "Storage/u/p/a", "bytes": 3, "modified": modified, "destination": 0,
+                "error": null},
+            {"path": "/data/a", "remote": "Storage/u/q/a", "bytes": 3, "modified": modified, "destination": 1,
+                "error": "timed out"},
+            {"path": "/data/b", "remote": "Storage/u/p/b", "bytes": 0, "modified": null, "destination": 0,
+                "error": "already exists"},
+            // from a version not telling where it went
+            {"path": "/data/c", "destination": 0, "error": null},
+        ]}).to_string()).unwrap();
+        let uploaded = uploaded_in_report(&path).unwrap();
+        let a = Uploaded { destination: 0, path: "/data/a".to_string(), remote: "Storage/u/p/a".to_string(), size: 3,
+            modified: Some(modified.to_string()) };
+        let b = Uploaded { destination: 0, path: "/data/b".to_string(), remote: "Storage/u/p/b".to_string(), size: 0,
+            modified: None };
+        assert_eq!(uploaded, HashSet::from([a, b]));
+        std::fs::write(&path, "{}").unwrap();
+        assert!(uploaded_in_report(&path).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! `--keepalive`: pings keeping the token or login session alive through the
+//! idle stretches of long runs, e.g. waiting for the upload window, where a
+//! gateway would otherwise time it out. Each interval nothing was sent in
+//! the volumes api of every destination is asked, which needs auth and is
+//! cheap to answer. A session refused by it is renewed with `--login`.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use reqwest::{Client, StatusCode};
+use tokio::task::JoinHandle;
+
+use crate::transport::TransportError;
+use crate::{body, destination_client, Settings};
+
+/// Pinging in the background, stopped when dropped.
+pub(crate) struct Keepalive {
+    task: JoinHandle<()>,
+}
+
+impl Keepalive {
+    /// Ping the fileservices of `settings` with `client` each `interval`
+    /// the uploads of the run didn't add to `sent`, telling about the
+    /// failures on the console if `console`, with `--verbose` about every
+    /// ping.
+    pub(crate) fn start(client: Client, settings: Arc<Settings>, sent: body::Sent, interval: Duration, console: bool)
+        -> Self {
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            let mut before = sent.get();
+            loop {
+                ticks.tick().await;
+                let idle = sent.get() == before;
+                before = sent.get();
+                if idle {
+                    ping(&client, &settings, console).await;
+                }
+            }
+        });
+        Keepalive { task }
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Ask the volumes api of every destination, logging in again first if the
+/// session expired or after it was refused.
+async fn ping(client: &Client, settings: &Settings, console: bool) {
+    let mut endpoints = Vec::new();
+    for destination in 0..settings.destinations() {
+        let endpoint = settings.destination(destination).url(settings.destination(destination).current());
+        if !endpoints.iter().any(|(checked, _)| *checked == endpoint) {
+            endpoints.push((endpoint, destination_client(client, settings, destination)));
+        }
+    }
+    for (endpoint, client) in endpoints {
+        let client = match client {
+            Ok(client) => client,
+            // never pinged with another destination's token
+            Err(e) => {
+                if console {
+                    cli_eprintln!("\nKeepalive: {} failed: no http client with its token: {}", endpoint, e);
+                }
+                continue;
+            }
+        };
+        if let Some(Err(e)) = settings.log_in(&client, endpoint).await {
+            if console {
+                cli_eprintln!("\nKeepalive: failed to log in again: {}", e);
+            }
+            continue;
+        }
+        let (started, generation) = (Instant::now(), settings.session_generation());
+        let message = match client.get(settings.api_url(endpoint, "volumes")).send().await {
+            // older deployments without the api still check the token
+            Ok(response) if !matches!(response.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+                if !settings.verbose {
+                    continue;
+                }
+                format!("{} answered {} in {} ms", endpoint, response.status(), started.elapsed().as_millis())
+            }
+            Ok(response) => match (&settings.session, generation) {
+                (Some(session), Some(generation)) if response.status() == StatusCode::UNAUTHORIZED => {
+                    let status = response.status();
+                    match session.renew(&client, endpoint, generation).await {
+                        Ok(()) => format!("{} refused the session ({}), logged in again", endpoint, status),
+                        Err(e) => format!("{} refused the session ({}), failed to log in again: {}", endpoint, status, e),
+                    }
+                }
+                _ => format!("{} no longer accepts the token ({})", endpoint, response.status()),
+            },
+            Err(e) => format!("{} failed: {}", endpoint, TransportError::classify(&e).describe()),
+        };
+        if console {
+            cli_eprintln!("\nKeepalive: {}", message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Login;
+    use crate::test_util::MockFileservice;
+
+    #[tokio::test]
+    async fn test_keepalive() {
+        let mock = MockFileservice::start().await;
+        mock.set_login("u", "secret", None);
+        let login = Login { url: mock.login_url(), username: "u".to_string(), password: "secret".to_string() };
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), String::new()).with_login(Some(login)));
+        let client = crate::build_client(&settings).unwrap();
+        let keepalive = Keepalive::start(client, settings, body::Sent::default(), Duration::from_millis(20), false);
+        // the first ping logs in
+        let waiting = Instant::now();
+        while mock.logins() == 0 && waiting.elapsed() < Duration::from_secs(5) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the others find the session alive
+        assert_eq!(mock.logins(), 1);
+        // until it's refused
+        mock.end_session();
+        let waiting = Instant::now();
+        while mock.logins() == 1 && waiting.elapsed() < Duration::from_secs(5) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(mock.logins(), 2);
+        drop(keepalive);
+    }
+}
@@ -1,35 +1,212 @@
-use std::io::{self, Write};
-use std::path::Path;
-use std::sync::Arc;
-use std::time::Instant;
-
-use reqwest::header::HeaderMap;
-use reqwest::{Client, StatusCode};
-use tokio::io::AsyncSeekExt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use hdrhistogram::Histogram;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Certificate, Client, NoProxy, Proxy, StatusCode};
 use tokio::task::JoinSet;
 use tokio::fs::File;
 
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+compile_error!("one of the rustls or native-tls features must be enabled");
+
+// Console output (the status bar, reports and warnings) is the command line
+// tool's, built with the `cli` feature. Without it the library prints nothing.
+#[cfg(feature = "cli")]
+macro_rules! cli_println { ($($arg:tt)*) => { println!($($arg)*) } }
+#[cfg(feature = "cli")]
+macro_rules! cli_eprintln { ($($arg:tt)*) => { eprintln!($($arg)*) } }
+#[cfg(feature = "cli")]
+macro_rules! cli_eprint { ($($arg:tt)*) => { eprint!($($arg)*) } }
+#[cfg(not(feature = "cli"))]
+macro_rules! cli_println { () => {}; ($($arg:tt)*) => { { let _ = format_args!($($arg)*); } } }
+#[cfg(not(feature = "cli"))]
+macro_rules! cli_eprintln { () => {}; ($($arg:tt)*) => { { let _ = format_args!($($arg)*); } } }
+#[cfg(not(feature = "cli"))]
+macro_rules! cli_eprint { ($($arg:tt)*) => { { let _ = format_args!($($arg)*); } } }
+
+mod archive;
+mod body;
+mod casjobs;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod check;
+mod checksum;
+mod color;
+pub mod config;
+mod dataset;
+mod dedup;
+pub mod dns;
+pub mod encrypt;
+mod endpoints;
+mod events;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod inputs;
+pub mod jobs;
+mod keepalive;
+mod metadata;
+pub mod middleware;
+mod nice;
+mod outage;
+mod porcelain;
+mod random;
+mod remote;
+pub mod retry;
+mod scheduler;
+mod serve;
+pub mod session;
+pub mod site;
+mod sync;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+mod transport;
+pub mod units;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+mod volumes;
+
+use dns::{CachingResolver, IpFamily};
+use color::{paint, Colors};
+use encrypt::Encryption;
+use endpoints::Endpoints;
+use inputs::{Filters, Input};
+pub use inputs::UploadRequest;
+use jobs::JobState;
+use body::{SharedContents, Stamp};
+use checksum::Checksum;
+use scheduler::{Job, Scheduler, RATE_INTERVAL};
+use outage::Outage;
+use remote::RemoteFile;
+use transport::TransportError;
+pub use transport::AmbiguousRetry;
+pub use scheduler::{Order, RampUp, TimeWindow};
+pub use metadata::MetadataMode;
+pub use dataset::ManifestTarget;
+pub use porcelain::Porcelain;
+pub use archive::ArchiveFormat;
+pub use checksum::HashCache;
+pub use dedup::ContentIndex;
+pub use color::ColorChoice;
+pub use serve::serve;
 
 enum ErrorKind {
     ReadError,
     FileExists,
     Unauthorized,
+    /// the file changed while it was being uploaded, the copy may be torn
+    Modified,
+    /// the uploaded copy doesn't hash the same as the local file
+    Mismatch,
+    /// the upload took longer than its deadline and was abandoned
+    Deadline,
+    /// the remote name has a component the fileservice can't store as given
+    InvalidName,
+    /// the same contents were uploaded before, to this remote path
+    Duplicate(String),
+    /// the last attempt got no response
+    Transport(TransportError),
+    /// the retry policy gave up on the run at this upload
+    Aborted,
     Other,
 }
 
+impl ErrorKind {
+    /// what failures are counted as in the summary, by the last response for
+    /// uploads that kept getting error responses
+    fn category(&self, response: Option<StatusCode>) -> &'static str {
+        match self {
+            ErrorKind::ReadError => "read error",
+            ErrorKind::FileExists => "already exists",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::Modified => "modified",
+            ErrorKind::Mismatch => "mismatch",
+            ErrorKind::Deadline => "deadline exceeded",
+            ErrorKind::InvalidName => "invalid name",
+            ErrorKind::Duplicate(_) => "duplicate",
+            ErrorKind::Transport(transport) => transport.describe(),
+            ErrorKind::Aborted => "aborted",
+            ErrorKind::Other => match response {
+                Some(status) if status.is_server_error() => "server error (5xx)",
+                Some(status) if status.is_client_error() => "client error (4xx)",
+                _ => "other",
+            },
+        }
+    }
+}
+
+/// Failed attempts of an upload by when they failed. Connecting includes dns
+/// and tls, the transfer is anything after, until a response came.
+#[derive(Default)]
+struct FailedAttempts {
+    connecting: usize,
+    transferring: usize,
+    /// error responses
+    rejected: usize,
+}
+
+impl FailedAttempts {
+    fn add(&mut self, other: &FailedAttempts) {
+        self.connecting += other.connecting;
+        self.transferring += other.transferring;
+        self.rejected += other.rejected;
+    }
+}
+
+/// Where the time uploading a file went, in seconds. Waiting for a free slot
+/// (queued), until the request was sent (connecting) and sending the data
+/// until the response came (transferring), the last two over all attempts.
+/// Time spent hashing, verifying and between retries isn't in any of them.
+#[derive(Clone, Copy, Debug, Default)]
+struct Timings {
+    queued: f64,
+    connecting: f64,
+    transferring: f64,
+}
+
+impl Timings {
+    /// add the time of an attempt started at `attempt` and whose body was
+    /// first polled at `started`, if ever
+    fn add_attempt(&mut self, attempt: Instant, started: Option<&Instant>) {
+        let now = Instant::now();
+        match started {
+            Some(started) => {
+                self.connecting += started.duration_since(attempt).as_secs_f64();
+                self.transferring += now.duration_since(*started).as_secs_f64();
+            }
+            None => self.connecting += now.duration_since(attempt).as_secs_f64(),
+        }
+    }
+}
+
 #[allow(dead_code)]
 struct UploadInfo {
     path: String,
+    /// name relative to the destination path
+    name: String,
     time: f64,
     bytes: u64,
+    /// of the file, as of the last attempt
+    modified: Option<SystemTime>,
     error: Option<ErrorKind>,
     retries: usize,
+    destination: usize,
+    endpoint: Option<usize>,
+    checksum: Option<String>,
+    failed: FailedAttempts,
+    /// status and (start of the) body of the last error response
+    response: Option<(StatusCode, String)>,
+    timings: Timings,
     _timer: Instant,
 }
 
 impl UploadInfo {
     fn new(path: String) -> Self {
-        UploadInfo { path, time: 0.0, bytes: 0, error: Some(ErrorKind::Other), retries: 0, _timer: Instant::now() }
+        UploadInfo { path, name: String::new(), time: 0.0, bytes: 0, modified: None, error: Some(ErrorKind::Other), retries: 0, destination: 0, endpoint: None, checksum: None, failed: FailedAttempts::default(), response: None, timings: Timings::default(), _timer: Instant::now() }
     }
 
     fn set_bytes(&mut self, bytes: u64) {
@@ -42,7 +219,13 @@ impl UploadInfo {
     }
 
     fn with_error(self, kind: ErrorKind) -> Self {
-        UploadInfo { error: Some(kind), ..self }
+        let time = self._timer.elapsed().as_secs_f64();
+        UploadInfo { error: Some(kind), time, ..self }
+    }
+
+    /// bytes per second
+    fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.time.max(1e-6)
     }
 
     fn incr_retries(&mut self) -> usize {
@@ -52,57 +235,621 @@ impl UploadInfo {
 
 }
 
-async fn file_info(file_path: &str) -> Option<(File, &str, u64)> {
+/// most of an error response body kept for the report
+const MAX_RESPONSE_BODY: usize = 300;
+
+/// An error response body on one line, cut short if long.
+fn truncate_body(body: &str) -> String {
+    let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    match body.char_indices().nth(MAX_RESPONSE_BODY) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body,
+    }
+}
+
+async fn file_info(file_path: &str) -> Option<(File, u64)> {
     if let Ok(file) = File::open(file_path).await {
         let metadata = file.metadata().await.unwrap();
-        if !metadata.is_file() {
+        if !metadata.is_file() && !body::is_fifo(&metadata) {
             return None;
         }
-        let file_name = match Path::new(file_path).file_name() {
-            Some(name) => match name.to_str() {
-                Some(name) => name,
-                _ => return None,
+        return Some((file, metadata.len()));
+    }
+    None
+}
+
+/// size and modification time, to tell if a file changed
+fn stamp(metadata: &std::fs::Metadata) -> Stamp {
+    (metadata.len(), metadata.modified().ok())
+}
+
+/// most waits for a file to stop changing, after which it's uploaded as it is
+const STABLE_WAITS: usize = 100;
+
+/// Wait until `file` wasn't modified for `window`, by its modification time
+/// or, if that's in the future (e.g. clocks differing on network filesystems),
+/// by its size and time staying the same for the window. Gives up after
+/// [`STABLE_WAITS`] waits. Files whose time can't be read are taken as they
+/// are.
+async fn wait_stable(file: &File, window: Duration) {
+    let mut seen = None;
+    for _ in 0..STABLE_WAITS {
+        let Ok(metadata) = file.metadata().await else { return };
+        let Ok(modified) = metadata.modified() else { return };
+        // unchanged since the last wait, which lasted until the window passed
+        if seen == Some(stamp(&metadata)) {
+            return;
+        }
+        // in the future is as good as just modified
+        let age = modified.elapsed().unwrap_or_default();
+        if age >= window {
+            return;
+        }
+        seen = Some(stamp(&metadata));
+        tokio::time::sleep(window - age).await;
+    }
+}
+
+/// Whether the copy at `url` hashes `local`, downloading it unless it's
+/// `listed` with its checksum.
+async fn same_contents(client: &Client, url: &str, listed: Option<&RemoteFile>, local: Option<String>) -> bool {
+    let Some(local) = local else { return false };
+    let remote = match listed {
+        Some(file) => remote::checksum(client, file, url).await,
+        None => remote::sha256(client, url).await,
+    };
+    remote.is_ok_and(|remote| remote == local)
+}
+
+/// The checksum of the file of `job`, hashing it if that hasn't started.
+async fn job_checksum(settings: &Settings, job: &Job) -> Option<String> {
+    let checksum = job.checksum.clone().unwrap_or_default();
+    checksum::get(&checksum, &job.input.path, settings.hash_cache.as_ref(), settings.nice).await
+}
+
+/// The file uploaded as `name` (relative to the path) to `endpoint`, None if
+/// it isn't there, from a listing of its directory.
+async fn remote_file(client: &Client, settings: &Settings, endpoint: &str, name: &str)
+    -> Result<Option<RemoteFile>, String> {
+    let (dir, file) = name.rsplit_once('/').unwrap_or(("", name));
+    let path = format!("{}/{}", settings.path.trim_matches('/'), dir);
+    let tree_url = settings.api_url(endpoint, "jsonTree");
+    let mut files = remote::list(client, &tree_url, &path, 1, settings.listed_checksums).await?;
+    Ok(files.remove(file))
+}
+
+/// A remote path under the destination path on `endpoint` holding contents
+/// hashing `sha256`, as uploaded before according to `index` and still there
+/// with that size and checksum, as listed or downloaded. None if none can be
+/// checked, to upload the file after all.
+async fn find_duplicate(client: &Client, settings: &Settings, index: &ContentIndex, endpoint: &str, sha256: &str,
+    size: u64) -> Option<String> {
+    for path in index.find(endpoint, sha256, &settings.path) {
+        let Some((dir, name)) = path.rsplit_once('/') else { continue };
+        let tree_url = settings.api_url(endpoint, "jsonTree");
+        let Ok(mut files) = remote::list(client, &tree_url, dir, 1, settings.listed_checksums).await else { continue };
+        let Some(file) = files.remove(name).filter(|f| f.size == size) else { continue };
+        let url = settings.path_prefix(endpoint, &path);
+        if remote::checksum(client, &file, &url).await.is_ok_and(|remote| remote == sha256) {
+            return Some(path);
+        }
+    }
+    None
+}
+
+/// SHA-256 of the file `name` (relative to the path) on the first
+/// destination, as listed where the fileservice lists checksums and from
+/// downloading it otherwise.
+pub async fn remote_checksum(name: &str, settings: &Settings) -> Result<String, String> {
+    let client = build_client(settings).map_err(|e| e.to_string())?;
+    let endpoints = settings.destination(0);
+    let endpoint = endpoints.url(endpoints.current());
+    settings.log_in(&client, endpoint).await.transpose()?;
+    let name = name.trim_matches('/');
+    match remote_file(&client, settings, endpoint, name).await? {
+        Some(file) => remote::checksum(&client, &file, &format!("{}/{}", settings.prefix(endpoint), name)).await,
+        None => Err(format!("{} not found in {}", name, settings.path)),
+    }
+}
+
+/// `future`'s output, or None if it didn't complete by `deadline`
+async fn until<F: Future>(deadline: Option<tokio::time::Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Whether the fileservice at `endpoint` answers other than it does while
+/// down for maintenance.
+async fn service_up(client: &Client, settings: &Settings, endpoint: &str) -> bool {
+    match client.get(settings.api_url(endpoint, "volumes")).send().await {
+        Ok(response) => {
+            let status = response.status();
+            !outage::is_maintenance(status, &response.text().await.unwrap_or_default())
+        }
+        Err(_) => false,
+    }
+}
+
+/// Wait for the fileservice at `endpoint` to be back during an `outage`, out
+/// of the time `left` for the upload to wait. Whether it's back, None if the
+/// upload's `deadline` passed first.
+async fn wait_for_service(client: &Client, settings: &Settings, outage: &Outage, endpoint: &str, left: &mut Duration,
+    deadline: Option<tokio::time::Instant>) -> Option<bool> {
+    let waiting = Instant::now();
+    let back = until(deadline, outage.wait(*left, || service_up(client, settings, endpoint))).await;
+    *left = left.saturating_sub(waiting.elapsed());
+    back
+}
+
+/// What an attempt of an upload came to, short of giving up.
+enum Attempted {
+    /// the fileservice stored it
+    Stored,
+    /// to try again right away, logged in again or with the service back
+    /// from an outage
+    Again,
+    /// failed by the transport or else, if None, with the response kept in
+    /// the upload's info
+    Failed(Option<TransportError>),
+}
+
+/// What the attempts of an upload share: where they go, and what's left of
+/// the waits and retries.
+struct Attempts<'a> {
+    client: &'a Client,
+    settings: &'a Settings,
+    endpoints: &'a Endpoints,
+    outage: &'a Outage,
+    /// what the run sent so far
+    sent: &'a body::Sent,
+    deadline: Option<tokio::time::Instant>,
+    /// for the service to be back during outages, over all attempts
+    maintenance: Option<Duration>,
+    relogged: bool,
+    rng: fastrand::Rng,
+}
+
+impl<'a> Attempts<'a> {
+    fn new(client: &'a Client, settings: &'a Settings, endpoints: &'a Endpoints, outage: &'a Outage,
+        sent: &'a body::Sent, deadline: Option<tokio::time::Instant>, rng: fastrand::Rng) -> Self {
+        let maintenance = settings.maintenance_wait;
+        Attempts { client, settings, endpoints, outage, sent, deadline, maintenance, relogged: false, rng }
+    }
+
+    /// Wait for the service to be back if it's known to be down, rather than
+    /// adding to its requests.
+    async fn wait_if_down(&mut self) -> Result<(), ErrorKind> {
+        if let Some(left) = &mut self.maintenance
+            && self.outage.down_since().is_some() {
+            let endpoint = self.endpoints.url(self.endpoints.current());
+            let waited = wait_for_service(self.client, self.settings, self.outage, endpoint, left, self.deadline).await;
+            if waited.is_none() {
+                return Err(ErrorKind::Deadline);
+            }
+        }
+        Ok(())
+    }
+
+    /// What the attempt sending to `url` at `endpoint` with the session of
+    /// `generation` came to by what was `sent`, None if the deadline passed
+    /// first. Failures are counted in `info`, Err is what to give up with.
+    async fn outcome(&mut self, info: &mut UploadInfo, endpoint: usize, url: &str, generation: Option<usize>,
+        sent: Option<reqwest::Result<reqwest::Response>>) -> Result<Attempted, ErrorKind> {
+        let response = match sent {
+            Some(Ok(response)) => response,
+            Some(Err(e)) => {
+                if e.is_connect() {
+                    info.failed.connecting += 1;
+                } else {
+                    info.failed.transferring += 1;
+                }
+                return Ok(Attempted::Failed(Some(TransportError::classify(&e))));
             }
-            _ => return None,
+            None => return Err(ErrorKind::Deadline),
         };
-        return Some((file, file_name, metadata.len()));
+        match response.status() {
+            StatusCode::OK => {
+                self.endpoints.record_success();
+                Ok(Attempted::Stored)
+            }
+            // the session may have ended, tried once more on a new one
+            StatusCode::UNAUTHORIZED if !self.relogged && self.settings.relogin(self.client, url, generation).await => {
+                self.relogged = true;
+                info.incr_retries();
+                Ok(Attempted::Again)
+            }
+            StatusCode::UNAUTHORIZED => Err(ErrorKind::Unauthorized),
+            status => {
+                let body = response.text().await.unwrap_or_default();
+                if status == StatusCode::INTERNAL_SERVER_ERROR && body.contains("File already exists") {
+                    return Err(ErrorKind::FileExists);
+                }
+                // retryable
+                info.failed.rejected += 1;
+                info.response = Some((status, truncate_body(&body)));
+                // a planned outage doesn't use up retries
+                if let Some(left) = &mut self.maintenance
+                    && outage::is_maintenance(status, &body) {
+                    let (endpoint, deadline) = (self.endpoints.url(endpoint), self.deadline);
+                    match wait_for_service(self.client, self.settings, self.outage, endpoint, left, deadline).await {
+                        Some(true) => return Ok(Attempted::Again),
+                        Some(false) => (),
+                        None => return Err(ErrorKind::Deadline),
+                    }
+                }
+                Ok(Attempted::Failed(None))
+            }
+        }
+    }
+
+    /// After the attempt uploading as `name` to `endpoint` failed by
+    /// `transport`, with `started` sending its data: whether the fileservice
+    /// kept it whole anyway, where that's checked (`--ambiguous-retry
+    /// verify`). Only a copy of the same `size` (if known) hashing what was
+    /// `sent` is taken as the upload's. Anything else there is replaced
+    /// only when overwriting, Err is what to give up with.
+    async fn kept(&self, endpoint: usize, name: &str, transport: TransportError, started: bool, size: Option<u64>,
+        sent: impl Future<Output = Option<String>>) -> Result<bool, ErrorKind> {
+        if self.settings.ambiguous_retry != AmbiguousRetry::Verify || !transport.is_ambiguous(started) {
+            return Ok(false);
+        }
+        let endpoint = self.endpoints.url(endpoint);
+        match remote_file(self.client, self.settings, endpoint, &self.settings.remote_name(name)).await {
+            Ok(Some(listed)) if size.is_none_or(|size| listed.size == size) && {
+                let url = self.settings.upload_url(endpoint, name, false);
+                same_contents(self.client, &url, Some(&listed), sent.await).await
+            } => Ok(true),
+            Ok(Some(_)) if self.settings.overwrite => Ok(false),
+            Ok(Some(_)) => Err(ErrorKind::FileExists),
+            Ok(None) => Ok(false),
+            // uploading again could leave a partial copy in place
+            Err(_) => Err(ErrorKind::Transport(transport)),
+        }
+    }
+
+    /// After the attempt at `endpoint` failed by `transport` (or by the
+    /// response in `info`): fail over if the endpoint is failing, and wait as
+    /// long as the retry policy says, Err is what to give up with.
+    async fn back_off(&mut self, info: &mut UploadInfo, endpoint: usize, transport: Option<TransportError>)
+        -> Result<(), ErrorKind> {
+        let failed_over = self.endpoints.record_failure(endpoint).inspect(|next| {
+            cli_eprintln!("\nFailing over to endpoint {}", self.endpoints.url(*next));
+        });
+        let delay = retry_delay(self.settings, info, transport, failed_over.is_some(), &mut self.rng)?;
+        until(self.deadline, tokio::time::sleep(delay)).await.ok_or(ErrorKind::Deadline)
+    }
+}
+
+/// Whether what's sent needs hashing on the way, where it can't be read
+/// again to hash it later.
+fn hash_sent(settings: &Settings) -> bool {
+    settings.checksums.is_some() || settings.verify_uploads || settings.ambiguous_retry == AmbiguousRetry::Verify
+}
+
+/// Upload from a FIFO, whose data can be read only once: in a single attempt
+/// streaming it as it's written, hashing it on the way to verify it or with
+/// `--checksums`.
+async fn upload_stream(attempts: &mut Attempts<'_>, file: File, mut info: UploadInfo) -> UploadInfo {
+    let (client, settings, endpoints) = (attempts.client, attempts.settings, attempts.endpoints);
+    if settings.encryption.is_some() {
+        // encryption reads files with positional reads
+        return info.with_error(ErrorKind::ReadError);
+    }
+    // sent once, so there's no trying again after logging in or an outage
+    attempts.relogged = true;
+    attempts.maintenance = None;
+    if let Err(error) = attempts.wait_if_down().await {
+        return info.with_error(error);
+    }
+    let endpoint = endpoints.current();
+    info.endpoint = Some(endpoint);
+    let url = settings.upload_url(endpoints.url(endpoint), &info.name, settings.overwrite);
+    let hasher = hash_sent(settings).then(sha2::Digest::new);
+    let streamed = Arc::new(std::sync::Mutex::new(body::Streamed { hasher, ..Default::default() }));
+    let body = body::stream_body(file, settings.chunk_size, streamed.clone());
+    let (attempt, started) = (Instant::now(), Arc::new(OnceLock::new()));
+    let request = settings.upload_request(client, &url).body(body::timed(body, started.clone(), attempts.sent.clone()));
+    let generation = settings.session_generation();
+    let sent = until(attempts.deadline, settings.send(client, &url, request)).await;
+    info.timings.add_attempt(attempt, started.get());
+    let mut streamed = std::mem::take(&mut *streamed.lock().unwrap());
+    info.set_bytes(streamed.bytes);
+    let size = streamed.ended.then_some(streamed.bytes);
+    let checksum = streamed.checksum();
+    match attempts.outcome(&mut info, endpoint, &url, generation, sent).await {
+        Ok(Attempted::Stored) => (),
+        Ok(Attempted::Failed(Some(transport))) => {
+            endpoints.record_failure(endpoint);
+            let sent = std::future::ready(checksum.clone());
+            match attempts.kept(endpoint, &info.name, transport, started.get().is_some(), size, sent).await {
+                Ok(true) => (),
+                Ok(false) => return info.with_error(ErrorKind::Transport(transport)),
+                Err(error) => return info.with_error(error),
+            }
+        }
+        Ok(Attempted::Again | Attempted::Failed(None)) => return info.with_error(ErrorKind::Other),
+        Err(error) => return info.with_error(error),
+    }
+    if settings.verify_uploads {
+        let url = settings.upload_url(endpoints.url(endpoint), &info.name, false);
+        match until(attempts.deadline, same_contents(client, &url, None, checksum.clone())).await {
+            Some(true) => (),
+            Some(false) => return info.with_error(ErrorKind::Mismatch),
+            None => return info.with_error(ErrorKind::Deadline),
+        }
+    }
+    info.checksum = checksum.filter(|_| settings.checksums.is_some());
+    info.with_success()
+}
+
+/// Upload the directory at `dir` as an archive of the files in it, made again
+/// for each attempt and hashed on the way to verify it or with
+/// `--checksums`. The files are those collecting `dir/` finds, so exclude
+/// patterns match names relative to it.
+async fn upload_archive(attempts: &mut Attempts<'_>, format: ArchiveFormat, dir: &str, mut info: UploadInfo)
+    -> UploadInfo {
+    let (client, settings, endpoints) = (attempts.client, attempts.settings, attempts.endpoints);
+    let Ok(filters) = filters(settings) else { return info.with_error(ErrorKind::ReadError) };
+    let contents = format!("{}/", dir.trim_end_matches('/'));
+    let files = tokio::task::spawn_blocking(move || inputs::collect(vec![contents], true, &filters).inputs)
+        .await.unwrap();
+    // archives aren't encrypted, and one of nothing isn't worth uploading
+    if settings.encryption.is_some() || files.is_empty() {
+        return info.with_error(ErrorKind::ReadError);
+    }
+    info.set_bytes(files.iter().map(|f| std::fs::metadata(&f.path).map_or(0, |m| m.len())).sum());
+    // replacing what an earlier attempt uploaded that didn't verify
+    let mut replace = false;
+    loop {
+        if let Err(error) = attempts.wait_if_down().await {
+            return info.with_error(error);
+        }
+        let endpoint = endpoints.current();
+        info.endpoint = Some(endpoint);
+        let url = settings.upload_url(endpoints.url(endpoint), &info.name, settings.overwrite || replace);
+        let hasher = hash_sent(settings).then(sha2::Digest::new);
+        let streamed = Arc::new(std::sync::Mutex::new(body::Streamed { hasher, ..Default::default() }));
+        let body = archive::body(format, files.clone(), settings.chunk_size, settings.nice, streamed.clone());
+        let (attempt, started) = (Instant::now(), Arc::new(OnceLock::new()));
+        let body = body::timed(body, started.clone(), attempts.sent.clone());
+        let request = settings.upload_request(client, &url).body(body);
+        let generation = settings.session_generation();
+        let sent = until(attempts.deadline, settings.send(client, &url, request)).await;
+        info.timings.add_attempt(attempt, started.get());
+        let mut streamed = std::mem::take(&mut *streamed.lock().unwrap());
+        let size = streamed.ended.then_some(streamed.bytes);
+        let checksum = streamed.checksum();
+        let transport = match attempts.outcome(&mut info, endpoint, &url, generation, sent).await {
+            Ok(Attempted::Stored) => {
+                info.checksum = checksum.clone().filter(|_| settings.checksums.is_some());
+                if !settings.verify_uploads {
+                    return info.with_success();
+                }
+                let url = settings.upload_url(endpoints.url(endpoint), &info.name, false);
+                match until(attempts.deadline, same_contents(client, &url, None, checksum)).await {
+                    Some(true) => return info.with_success(),
+                    Some(false) => (),
+                    None => return info.with_error(ErrorKind::Deadline),
+                }
+                if info.incr_retries() >= settings.retries {
+                    return info.with_error(ErrorKind::Mismatch);
+                }
+                replace = true;
+                continue;
+            }
+            Ok(Attempted::Again) => continue,
+            Ok(Attempted::Failed(transport)) => transport,
+            Err(error) => return info.with_error(error),
+        };
+        if let Some(transport) = transport {
+            let sent = std::future::ready(checksum.clone());
+            match attempts.kept(endpoint, &info.name, transport, started.get().is_some(), size, sent).await {
+                Ok(true) => {
+                    info.checksum = checksum.filter(|_| settings.checksums.is_some());
+                    return info.with_success();
+                }
+                Ok(false) => (),
+                Err(error) => return info.with_error(error),
+            }
+        }
+        if let Err(error) = attempts.back_off(&mut info, endpoint, transport).await {
+            return info.with_error(error);
+        }
+    }
+}
+
+/// What follows a failed attempt of the upload `info`, by the retry policy:
+/// how long to wait before trying again, or what to give up with. Attempts
+/// retried count as retries, as does the last one unless the first already
+/// failed for good or it failed where trying again can't help (tls).
+fn retry_delay(settings: &Settings, info: &mut UploadInfo, transport: Option<TransportError>, failed_over: bool,
+    rng: &mut fastrand::Rng) -> Result<Duration, ErrorKind> {
+    let failure = match (transport, &info.response) {
+        (Some(transport), _) => retry::Failure::Transport(transport),
+        (None, Some((status, body))) => retry::Failure::Status(*status, body),
+        (None, None) => retry::Failure::Transport(TransportError::Other),
+    };
+    let attempt = retry::Attempt {
+        path: &info.path,
+        number: info.retries + 1,
+        elapsed: info._timer.elapsed(),
+        failure,
+        failed_over,
+        jitter: rng.f64(),
+    };
+    let default = retry::DefaultRetryPolicy { retries: settings.retries };
+    let decision = settings.retry_policy.as_deref().unwrap_or(&default).decide(&attempt);
+    let hopeless = matches!(failure, retry::Failure::Transport(t) if t.retry_delay(attempt.number, 0.0).is_none());
+    if matches!(decision, retry::RetryDecision::Retry(_)) || (attempt.number > 1 && !hopeless) {
+        info.incr_retries();
+    }
+    match decision {
+        retry::RetryDecision::Retry(delay) => Ok(delay),
+        retry::RetryDecision::Fail => Err(transport.map_or(ErrorKind::Other, ErrorKind::Transport)),
+        retry::RetryDecision::Abort => Err(ErrorKind::Aborted),
     }
-    None
 }
 
-async fn upload_file(client: Client, file_path: String, settings: Arc<Settings>) -> UploadInfo {
-    let mut info = UploadInfo::new(file_path.clone());
-    let (file, file_name) = match file_info(&file_path).await {
-        Some((file, name, bytes)) => { info.set_bytes(bytes); (file, name) },
+/// Upload a file as `job` says, queued for upload since `queued`, waiting
+/// for the fileservice to be back during an `outage`, counting what it sends
+/// in `sent`.
+async fn upload_file(client: Client, job: Job, settings: Arc<Settings>, queued: Instant, outage: Arc<Outage>,
+    sent: body::Sent) -> UploadInfo {
+    let deadline = settings.file_deadline.map(|deadline| tokio::time::Instant::now() + deadline);
+    let mut info = UploadInfo::new(job.input.path.clone());
+    info.timings.queued = queued.elapsed().as_secs_f64();
+    info.destination = job.destination;
+    let endpoints = settings.destination(job.destination);
+    let file_name = job.input.name.clone();
+    info.name = file_name.clone();
+    if file_name.is_empty() {
+        return info.with_error(ErrorKind::ReadError);
+    }
+    let rng = settings.rng(job.destination, &job.input.path);
+    let mut attempts = Attempts::new(&client, &settings, endpoints, &outage, &sent, deadline, rng);
+    if let Some(format) = settings.archive_per_dir
+        && std::fs::metadata(&job.input.path).is_ok_and(|m| m.is_dir()) {
+        return upload_archive(&mut attempts, format, &job.input.path, info).await;
+    }
+    let file = match file_info(&job.input.path).await {
+        Some((file, bytes)) => { info.set_bytes(bytes); file },
         None => return info.with_error(ErrorKind::ReadError),
     };
-    let mut url = format!("{}/{}", settings.prefix, file_name);
-    if settings.overwrite {
-        url = format!("{}?quiet=true", url);
+    if !inputs::valid_name(&file_name) {
+        return info.with_error(ErrorKind::InvalidName);
+    }
+    if file.metadata().await.is_ok_and(|m| body::is_fifo(&m)) {
+        return upload_stream(&mut attempts, file, info).await;
+    }
+    // files still being written are uploaded once they stop changing
+    if let Some(window) = settings.stable_window.filter(|_| !settings.follow)
+        && until(deadline, wait_stable(&file, window)).await.is_none() {
+        return info.with_error(ErrorKind::Deadline);
+    }
+    // shared by the bodies of all attempts
+    let shared = match file.try_clone().await {
+        Ok(f) => Arc::new(f.into_std().await),
+        Err(_) => return info.with_error(ErrorKind::ReadError),
+    };
+    // replacing what an earlier attempt uploaded of a file that changed
+    let mut replace = false;
+    let cache = settings.hash_cache.as_ref();
+    if let (Some(index), Some(checksum)) = (&settings.content_index, &job.checksum) {
+        info.checksum = checksum::get(checksum, &job.input.path, cache, settings.nice).await;
+        let endpoint = endpoints.url(endpoints.current());
+        if let Some(sha256) = &info.checksum
+            && let Some(original) = find_duplicate(&client, &settings, index, endpoint, sha256, info.bytes).await {
+            return info.with_error(ErrorKind::Duplicate(original));
+        }
     }
+    // files still being written change after the scan as expected
+    let mut scanned = job.stamp.filter(|_| settings.stable_window.is_none());
     loop {
-        let file_try = match file.try_clone().await {
-            Ok(mut f) => match f.rewind().await {
-                Ok(_) => f,
-                _ => continue,
-            },
-            _ => continue,
-        };
-        let result = client.put(&url).body(file_try).send().await;
-        if let Ok(response) = result {
-            match response.status() {
-                StatusCode::OK => { return info.with_success(); },
-                StatusCode::INTERNAL_SERVER_ERROR => {
-                    if response.text().await.unwrap().contains("File already exists") {
-                        return info.with_error(ErrorKind::FileExists);
+        if let Err(error) = attempts.wait_if_down().await {
+            return info.with_error(error);
+        }
+        let current = match file.metadata().await {
+            Ok(metadata) => stamp(&metadata),
+            Err(_) => return info.with_error(ErrorKind::ReadError),
+        };
+        // as the scan found it on the first attempt, so changes since count
+        let before = scanned.take().unwrap_or(current);
+        info.set_bytes(current.0);
+        info.modified = current.1;
+        let endpoint = endpoints.current();
+        info.endpoint = Some(endpoint);
+        let url = settings.upload_url(endpoints.url(endpoint), &file_name, settings.overwrite || replace);
+        // small enough to buffer, read once for every destination
+        let contents = match &job.contents {
+            Some(contents) if current.0 <= settings.upload_buffer() => contents.get(&job.input.path, current).await,
+            _ => None,
+        };
+        let body = match (&settings.encryption, contents) {
+            (Some(encryption), _) => encrypt::body(encryption, &shared, settings.chunk_size, settings.nice),
+            (None, Some(contents)) => reqwest::Body::from(contents),
+            (None, None) => body::file_body(&shared, &job.input.path, settings.chunk_size, settings.nice),
+        };
+        #[cfg(feature = "chaos")]
+        let body = match &settings.chaos {
+            Some(chaos) => chaos.body(body, current.0, &mut attempts.rng),
+            None => body,
+        };
+        let (attempt, started) = (Instant::now(), Arc::new(OnceLock::new()));
+        let body = body::timed(body, started.clone(), attempts.sent.clone());
+        let request = settings.upload_request(&client, &url).body(body);
+        let generation = settings.session_generation();
+        let send = settings.send(&client, &url, request);
+        #[cfg(feature = "chaos")]
+        let sent = match &settings.chaos {
+            Some(chaos) => until(deadline, chaos.send(send, &mut attempts.rng)).await,
+            None => until(deadline, send).await,
+        };
+        #[cfg(not(feature = "chaos"))]
+        let sent = until(deadline, send).await;
+        info.timings.add_attempt(attempt, started.get());
+        let transport = match attempts.outcome(&mut info, endpoint, &url, generation, sent).await {
+            Ok(Attempted::Stored) => {
+                let after = file.metadata().await.map(|m| stamp(&m)).ok();
+                // uploaded again whenever it grew, until it stays the same
+                if let Some(window) = settings.stable_window.filter(|_| settings.follow) {
+                    if until(deadline, tokio::time::sleep(window)).await.is_none() {
+                        return info.with_error(ErrorKind::Deadline);
                     }
-                },
-                StatusCode::UNAUTHORIZED => return info.with_error(ErrorKind::Unauthorized),
-                _ => (), // retryable
+                    let later = file.metadata().await.map(|m| stamp(&m)).ok();
+                    if after != Some(before) || later != after {
+                        replace = true;
+                        continue;
+                    }
+                }
+                if after == Some(before) {
+                    if let Some(checksum) = &job.checksum {
+                        info.checksum = checksum::get(checksum, &job.input.path, cache, settings.nice).await;
+                    }
+                    if !settings.verify_uploads {
+                        return info.with_success();
+                    }
+                    let url = settings.upload_url(endpoints.url(endpoint), &file_name, false);
+                    let local = job_checksum(&settings, &job).await;
+                    match until(deadline, same_contents(&client, &url, None, local)).await {
+                        Some(true) => return info.with_success(),
+                        Some(false) => (),
+                        None => return info.with_error(ErrorKind::Deadline),
+                    }
+                    if info.incr_retries() >= settings.retries {
+                        return info.with_error(ErrorKind::Mismatch);
+                    }
+                    replace = true;
+                    continue;
+                }
+                if !settings.reupload_modified || info.incr_retries() >= settings.retries {
+                    return info.with_error(ErrorKind::Modified);
+                }
+                replace = true;
+                continue;
+            }
+            Ok(Attempted::Again) => continue,
+            Ok(Attempted::Failed(transport)) => transport,
+            Err(error) => return info.with_error(error),
+        };
+        // the fileservice may have kept some or all of what was sent; the
+        // size of encrypted uploads isn't known, so they can't be told apart
+        if let Some(transport) = transport {
+            let sent = async { job_checksum(&settings, &job).await.filter(|_| settings.encryption.is_none()) };
+            match attempts.kept(endpoint, &file_name, transport, started.get().is_some(), Some(before.0), sent).await {
+                Ok(true) => {
+                    if let Some(checksum) = &job.checksum {
+                        info.checksum = checksum::get(checksum, &job.input.path, cache, settings.nice).await;
+                    }
+                    return info.with_success();
+                }
+                Ok(false) => (),
+                Err(error) => return info.with_error(error),
             }
         }
-        if info.incr_retries() >= settings.retries {
-            return info.with_error(ErrorKind::Other);
+        if let Err(error) = attempts.back_off(&mut info, endpoint, transport).await {
+            return info.with_error(error);
         }
     }
 }
@@ -111,9 +858,39 @@ struct UploadProgress {
     n_total: usize,
     n_successes: usize,
     n_errors: usize,
+    /// already on the destination, not errors as such
+    n_exists: usize,
+    /// contents already uploaded under another name, with `--dedup`
+    n_duplicates: usize,
+    /// more files are still being found, the total isn't final
+    scanning: bool,
     n_retries: usize,
     f_retries: usize,
     bytes: u64,
+    n_filtered_size: usize,
+    n_filtered_time: usize,
+    failed: FailedAttempts,
+    /// failed uploads by [`ErrorKind::category`]
+    errors_by_kind: BTreeMap<&'static str, usize>,
+    /// throughput of uploaded files in bytes/s, small files are mostly
+    /// latency and large ones bandwidth, which an average hides
+    throughput: Histogram<u64>,
+    /// least time between status bar redraws
+    redraw_interval: Duration,
+    last_drawn: Option<Instant>,
+    colors: Colors,
+    /// uploads with the worst throughput listed in the summary
+    top_slowest: usize,
+    /// told the counts as uploads complete, instead of drawing a status bar
+    observer: Option<serve::Observer>,
+    /// streams events to monitoring clients, next to the status bar
+    socket: Option<events::ProgressSocket>,
+    /// lines for wrapping tools on stdout instead of the status bar
+    porcelain: Option<Porcelain>,
+    /// shared by the uploads, to wait out the fileservice being down
+    outage: Arc<Outage>,
+    /// what the uploads sent so far
+    sent: body::Sent,
     timer: Instant,
     completed: Vec<UploadInfo>,
 }
@@ -124,147 +901,2735 @@ impl UploadProgress {
             n_total,
             n_successes: 0,
             n_errors: 0,
+            n_exists: 0,
+            n_duplicates: 0,
+            scanning: false,
             n_retries: 0,
             f_retries: 0,
             bytes: 0,
+            n_filtered_size: 0,
+            n_filtered_time: 0,
+            failed: FailedAttempts::default(),
+            errors_by_kind: BTreeMap::new(),
+            throughput: Histogram::new(3).unwrap(),
+            redraw_interval: Duration::ZERO,
+            last_drawn: None,
+            colors: Colors::default(),
+            top_slowest: 0,
+            observer: None,
+            socket: None,
+            porcelain: None,
+            outage: Arc::new(Outage::new(outage::POLL_INTERVAL)),
+            sent: body::Sent::default(),
             timer: Instant::now(),
             completed: Vec::with_capacity(n_total),
         }
     }
 
     fn update(&mut self, info: UploadInfo, write_status: bool) {
-        if info.error.is_some() {
+        if let Some(ErrorKind::FileExists) = &info.error {
+            self.n_exists += 1;
+        } else if let Some(ErrorKind::Duplicate(_)) = &info.error {
+            self.n_duplicates += 1;
+        } else if let Some(error) = &info.error {
             self.n_errors += 1;
+            *self.errors_by_kind.entry(error.category(info.response.as_ref().map(|r| r.0))).or_default() += 1;
         }
         else {
             self.n_successes += 1;
             self.bytes += info.bytes;
+            // grows to fit, only fails for values beyond u64
+            let _ = self.throughput.record(info.throughput() as u64);
         }
+        self.failed.add(&info.failed);
         if info.retries > 0 {
             self.n_retries += info.retries;
             self.f_retries += 1;
         }
 
+        if let Some(observer) = &self.observer {
+            // nobody may be listening
+            let _ = observer.files.send(serve::FileEvent {
+                path: info.path.clone(),
+                name: info.name.clone(),
+                bytes: info.bytes,
+                seconds: info.time,
+                retries: info.retries,
+                error: info.error.as_ref().map(|e| e.category(info.response.as_ref().map(|r| r.0))),
+            });
+        }
+        if let Some(socket) = &self.socket {
+            socket.send(&serde_json::json!({
+                "event": "file",
+                "path": info.path,
+                "name": info.name,
+                "destination": info.destination,
+                "bytes": info.bytes,
+                "seconds": info.time,
+                "retries": info.retries,
+                "error": info.error.as_ref().map(|e| e.category(info.response.as_ref().map(|r| r.0))),
+            }));
+            socket.send(&self.counts("progress"));
+        }
+        if let Some(porcelain) = self.porcelain {
+            cli_println!("{}", porcelain.file(&info));
+            cli_println!("{}", porcelain.counts("progress", &self.porcelain_counts()));
+        }
         self.completed.push(info);
 
-        if write_status {
-            self.write_status_bar();
+        if self.observer.is_some() {
+            self.notify();
+        } else if write_status {
+            self.redraw(false);
+        }
+    }
+
+    /// the counts so far as an `event` for the progress socket
+    fn counts(&self, event: &str) -> serde_json::Value {
+        serde_json::json!({
+            "event": event,
+            "total": self.n_total,
+            "uploaded": self.n_successes,
+            "exist": self.n_exists,
+            "failed": self.n_errors,
+            "retries": self.n_retries,
+            "bytes": self.bytes,
+            "seconds": self.timer.elapsed().as_secs_f64(),
+        })
+    }
+
+    fn porcelain_counts(&self) -> porcelain::Counts {
+        porcelain::Counts {
+            uploaded: self.n_successes,
+            exist: self.n_exists,
+            failed: self.n_errors,
+            total: self.n_total,
+            bytes: self.bytes,
+            seconds: self.timer.elapsed().as_secs_f64(),
+        }
+    }
+
+    /// tell progress socket clients and porcelain readers the run is over
+    fn finish(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            socket.send(&self.counts("done"));
+        }
+        if let Some(porcelain) = self.porcelain {
+            cli_println!("{}", porcelain.counts("done", &self.porcelain_counts()));
+        }
+    }
+
+    /// tell the observer, if any, the counts so far
+    fn notify(&self) {
+        if let Some(observer) = &self.observer {
+            observer.counts.send_replace(serve::Counts {
+                total: self.n_total,
+                uploaded: self.n_successes,
+                exist: self.n_exists,
+                failed: self.n_errors,
+                bytes: self.bytes,
+            });
         }
     }
 
+    #[cfg_attr(not(feature = "cli"), allow(dead_code))]
     fn status_bar(&self) -> String {
         let elapsed = self.timer.elapsed().as_secs_f64();
         let mbs = self.bytes as f64 / (1024.0 * 1024.0);
         let mbps = mbs / (elapsed + 1e-6);
 
-        format!("Uploaded {}/{} files, {} errors {}|{} retries {:.2} MB in {:.2} seconds ({:.2} MB/s)",
-               self.n_successes, self.n_total, self.n_errors, self.f_retries, self.n_retries, mbs, elapsed, mbps)
+        // only what needs attention stands out
+        let color = self.colors.stdout;
+        let errors = paint(color && self.n_errors > 0, color::RED, self.n_errors);
+        let retries = paint(color && self.n_retries > 0, color::YELLOW, format!("{}|{}", self.f_retries, self.n_retries));
+        let mut status = format!("Uploaded {}/{}{} files, ", paint(color, color::GREEN, self.n_successes), self.n_total,
+            if self.scanning { "+" } else { "" });
+        if self.n_exists > 0 {
+            status.push_str(&format!("{} exist, ", self.n_exists));
+        }
+        if self.n_duplicates > 0 {
+            status.push_str(&format!("{} duplicates, ", self.n_duplicates));
+        }
+        status.push_str(&format!("{} errors {} retries {:.2} MB in {:.2} seconds ({:.2} MB/s)",
+               errors, retries, mbs, elapsed, mbps));
+        status.push_str(&Self::skipped(self.n_filtered_size, self.n_filtered_time));
+        if let Some(down) = self.outage.down_since() {
+            let waiting = format!(", waiting for the service ({}s)", down.elapsed().as_secs());
+            status.push_str(&paint(color, color::YELLOW, waiting));
+        }
+        status
+    }
+
+    /// note on files left out by the size and time filters, if any
+    fn skipped(by_size: usize, by_time: usize) -> String {
+        let mut skipped = String::new();
+        if by_size > 0 {
+            skipped.push_str(&format!(", {} skipped by size", by_size));
+        }
+        if by_time > 0 {
+            skipped.push_str(&format!(", {} skipped by modification time", by_time));
+        }
+        skipped
     }
 
     fn write_status_bar(&self) {
-        let msg = self.status_bar();
-        print!("\r{}", msg);
-        io::stdout().flush().unwrap();
+        #[cfg(feature = "cli")]
+        {
+            use std::io::Write;
+            print!("\r{}", self.status_bar());
+            std::io::stdout().flush().unwrap();
+        }
+    }
+
+    /// Write the status bar unless it was just written, with thousands of
+    /// small files redrawing on every completion slows down the run.
+    fn redraw(&mut self, force: bool) {
+        if self.observer.is_some() || self.porcelain.is_some() {
+            return;
+        }
+        if force || self.last_drawn.is_none_or(|t| t.elapsed() >= self.redraw_interval) {
+            self.write_status_bar();
+            self.last_drawn = Some(Instant::now());
+        }
     }
 
-    fn write_error_report(&self) {
+    fn write_error_report(&self, settings: &Settings) {
         let mut heading_written = false;
         for info in &self.completed {
-            if let Some(error) = &info.error {
+            if let Some(error) = info.error.as_ref().filter(|e| !matches!(e, ErrorKind::FileExists | ErrorKind::Duplicate(_))) {
                 if !heading_written {
-                    eprintln!("Error Report:");
+                    cli_eprintln!("{}", paint(self.colors.stderr, color::RED, "Error Report:"));
                     heading_written = true;
                 }
+                // in mirror mode the same file can fail for one destination only
+                let path = if settings.destinations() == 1 {
+                    info.path.clone()
+                } else {
+                    let endpoint = settings.destination(info.destination).url(info.endpoint.unwrap_or(0));
+                    format!("{} -> {}", info.path, settings.destination_label(info.destination, endpoint))
+                };
                 match error {
-                    ErrorKind::ReadError => eprintln!(
-                        "  Failed to read file: {}", info.path),
-                    ErrorKind::FileExists => eprintln!(
-                        "  File already exists (use --force to overwrite): {}", info.path),
-                    ErrorKind::Unauthorized => eprintln!(
-                        "  Unauthorized (check your token): {}", info.path),
-                    ErrorKind::Other => eprintln!(
-                        "  Failed to upload file after {} retries: {}", info.retries, info.path),
+                    ErrorKind::ReadError => cli_eprintln!(
+                        "  Failed to read file: {}", path),
+                    ErrorKind::FileExists | ErrorKind::Duplicate(_) => (), // counted instead
+                    ErrorKind::Unauthorized => cli_eprintln!(
+                        "  Unauthorized (check your token): {}", path),
+                    ErrorKind::Modified => cli_eprintln!(
+                        "  File modified during transfer, upload may be inconsistent: {}", path),
+                    ErrorKind::Mismatch => cli_eprintln!(
+                        "  Uploaded copy differs from the file after {} retries: {}", info.retries, path),
+                    ErrorKind::Deadline => cli_eprintln!(
+                        "  Upload abandoned at its deadline after {} retries, a partial copy may be left: {}",
+                        info.retries, path),
+                    ErrorKind::InvalidName => cli_eprintln!(
+                        "  Invalid remote name {:?} (empty, . or .. components, or control characters): {}",
+                        info.name, path),
+                    ErrorKind::Transport(transport) if info.retries == 0 => cli_eprintln!(
+                        "  Failed to upload file, not retried ({}): {}", transport.describe(), path),
+                    ErrorKind::Transport(transport) => cli_eprintln!(
+                        "  Failed to upload file after {} retries ({}): {}", info.retries, transport.describe(), path),
+                    ErrorKind::Aborted => cli_eprintln!(
+                        "  Run aborted by the retry policy after {} retries: {}", info.retries, path),
+                    ErrorKind::Other => cli_eprintln!(
+                        "  Failed to upload file after {} retries: {}", info.retries, path),
+                }
+                if let (ErrorKind::Other | ErrorKind::Aborted, Some((status, body))) = (error, &info.response) {
+                    cli_eprintln!("    last response: {} {}", status, body);
+                }
+            }
+        }
+        if self.n_exists > 0 {
+            cli_eprintln!("{} file(s) already exist on the destination, not uploaded (use --force to overwrite)",
+                self.n_exists);
+        }
+        if self.n_duplicates > 0 {
+            cli_eprintln!("Duplicates, not uploaded (--dedup):");
+            for info in &self.completed {
+                if let Some(ErrorKind::Duplicate(original)) = &info.error {
+                    cli_eprintln!("  {} -> same as {}", info.path, original);
                 }
             }
         }
     }
-}
 
-pub struct Settings {
-    prefix: String,
-    token: String,
-    concurrency: usize,
-    retries: usize,
-    overwrite: bool,
-}
+    /// Rows of the end of run summary, (label, value). Throughput is per
+    /// uploaded file, concurrency is the time spent uploading over the run's.
+    fn summary(&self) -> Vec<(&'static str, String)> {
+        let elapsed = self.timer.elapsed().as_secs_f64();
+        let mbs = self.bytes as f64 / (1024.0 * 1024.0);
+        let mut rows = vec![
+            ("files", format!("{} uploaded, {} already existed, {} failed, of {}",
+                self.n_successes, self.n_exists, self.n_errors, self.n_total)),
+        ];
+        if self.n_duplicates > 0 {
+            rows.push(("duplicates", format!("{} not uploaded, the same contents were uploaded before",
+                self.n_duplicates)));
+        }
+        rows.extend([
+            ("data", format!("{:.2} MB in {:.2} seconds ({:.2} MB/s)", mbs, elapsed, mbs / (elapsed + 1e-6))),
+            ("retries", format!("{} in {} files", self.n_retries, self.f_retries)),
+        ]);
+        if !self.errors_by_kind.is_empty() {
+            // most common first, the one to look into
+            let mut kinds: Vec<_> = self.errors_by_kind.iter().collect();
+            kinds.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+            let kinds: Vec<_> = kinds.iter().map(|(kind, count)| format!("{} {}", count, kind)).collect();
+            rows.push(("errors", kinds.join(", ")));
+        }
+        if !self.throughput.is_empty() {
+            let percentiles: Vec<_> = THROUGHPUT_PERCENTILES.iter()
+                .map(|p| format!("p{} {:.2}", p, self.throughput.value_at_quantile(p / 100.0) as f64 / (1024.0 * 1024.0)))
+                .collect();
+            rows.push(("throughput", format!("{:.2} MB/s average per file, {}",
+                self.throughput.mean() / (1024.0 * 1024.0), percentiles.join(", "))));
+        }
+        if !self.completed.is_empty() {
+            // to tell a slow server or network from slow disks or too few slots
+            let n = self.completed.len() as f64;
+            let total = self.completed.iter().fold(Timings::default(), |mut total, i| {
+                total.queued += i.timings.queued;
+                total.connecting += i.timings.connecting;
+                total.transferring += i.timings.transferring;
+                total
+            });
+            rows.push(("per file", format!("{:.2} seconds queued, {:.2} connecting, {:.2} transferring on average",
+                total.queued / n, total.connecting / n, total.transferring / n)));
+        }
+        let busy: f64 = self.completed.iter().map(|i| i.time).sum();
+        rows.push(("concurrency", format!("{:.1} uploads at a time on average", busy / (elapsed + 1e-6))));
+        // by throughput, the same slow disk or network makes all its files
+        // slow whatever their size
+        let mut slowest: Vec<_> = self.completed.iter().filter(|i| i.error.is_none())
+            .map(|i| (i.throughput() / (1024.0 * 1024.0), i))
+            .collect();
+        slowest.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for (n, (rate, info)) in slowest.iter().take(self.top_slowest).enumerate() {
+            rows.push((if n == 0 { "slowest" } else { "" }, format!(
+                "{:.2} MB/s, {:.2} MB in {:.2} seconds ({:.2} connecting, {:.2} transferring), {} retries: {}",
+                rate, info.bytes as f64 / (1024.0 * 1024.0), info.time, info.timings.connecting,
+                info.timings.transferring, info.retries, info.path)));
+        }
+        rows
+    }
 
-impl Settings {
-    pub fn new(prefix: String, token: String, concurrency: usize, retries: usize, overwrite: bool) -> Arc<Self> {
-        Arc::new(Settings {
-            prefix,
-            token,
-            concurrency,
-            retries,
-            overwrite,
+    fn write_summary(&self) {
+        cli_eprintln!("{}", paint(self.colors.stderr, color::GREEN, "Summary:"));
+        for (label, value) in self.summary() {
+            let label = if label.is_empty() { String::new() } else { format!("{}:", label) };
+            cli_eprintln!("  {:<13}{}", label, value);
+        }
+    }
+
+    /// Checksums of the uploaded files in sha256sum format, by remote name so
+    /// downloaded copies can be checked with `sha256sum -c`.
+    fn write_checksums(&self, settings: &Settings) {
+        let Some(path) = &settings.checksums else { return };
+        let mut checksums: Vec<_> = self.completed.iter()
+            .filter(|i| i.error.is_none())
+            .filter_map(|i| Some((&i.name, i.checksum.as_ref()?)))
+            .collect();
+        // sorted for stable output, and each file once when mirroring
+        checksums.sort();
+        checksums.dedup();
+        let lines: String = checksums.iter().map(|(name, checksum)| format!("{}  {}\n", checksum, name)).collect();
+        if let Err(e) = std::fs::write(path, lines) {
+            cli_eprintln!("Failed to write checksums to {}: {}", path.display(), e);
+        }
+    }
+
+    /// The JSON report: the summary, throughput percentiles in bytes/s and
+    /// each upload's outcome and timings.
+    fn report(&self, settings: &Settings) -> serde_json::Value {
+        let percentiles: serde_json::Map<_, _> = THROUGHPUT_PERCENTILES.iter()
+            .map(|p| (format!("p{}", p), self.throughput.value_at_quantile(p / 100.0).into()))
+            .collect();
+        let files: Vec<_> = self.completed.iter().map(|i| serde_json::json!({
+            "path": i.path,
+            "name": i.name,
+            "remote": settings.remote_path(i.destination, &i.name),
+            "destination": i.destination,
+            "bytes": i.bytes,
+            "modified": i.modified.map(jobs::format_modified),
+            "seconds": i.time,
+            "queued": i.timings.queued,
+            "connecting": i.timings.connecting,
+            "transferring": i.timings.transferring,
+            "retries": i.retries,
+            "error": i.error.as_ref().map(|e| e.category(i.response.as_ref().map(|r| r.0))),
+            "duplicate_of": match &i.error {
+                Some(ErrorKind::Duplicate(original)) => Some(original),
+                _ => None,
+            },
+        })).collect();
+        serde_json::json!({
+            "summary": {
+                "files": self.n_total,
+                "uploaded": self.n_successes,
+                "exist": self.n_exists,
+                "duplicates": self.n_duplicates,
+                "failed": self.n_errors,
+                "bytes": self.bytes,
+                "seconds": self.timer.elapsed().as_secs_f64(),
+                "retries": self.n_retries,
+                "errors": self.errors_by_kind,
+                "throughput": {
+                    "mean": self.throughput.mean(),
+                    "min": self.throughput.min(),
+                    "max": self.throughput.max(),
+                    "percentiles": percentiles,
+                },
+            },
+            "files": files,
         })
     }
-}
 
-/// upload many files concurrently
-pub async fn upload_many(files: Vec<String>, settings: Arc<Settings>) {
-    if files.is_empty() {
-        eprintln!("No files to upload.");
-        return;
+    fn write_report(&self, settings: &Settings) {
+        let Some(path) = &settings.report else { return };
+        let report = serde_json::to_string_pretty(&self.report(settings)).unwrap_or_default() + "\n";
+        if let Err(e) = std::fs::write(path, report) {
+            cli_eprintln!("Failed to write the report to {}: {}", path.display(), e);
+        }
     }
 
-    let mut headers = HeaderMap::new();
-    headers.insert("x-auth-token", settings.token.parse().unwrap());
-    let client = Client::builder().default_headers(headers).build().unwrap();
+    /// per-destination totals when mirroring or copying, since each
+    /// destination is uploaded (and retried) independently
+    fn write_destination_report(&self, settings: &Settings) {
+        if settings.destinations() == 1 {
+            return;
+        }
+        cli_eprintln!("Destination Report:");
+        for destination in 0..settings.destinations() {
+            let (mut uploaded, mut failed, mut retries, mut bytes) = (0, 0, 0, 0);
+            for info in self.completed.iter().filter(|i| i.destination == destination) {
+                if info.error.is_none() {
+                    uploaded += 1;
+                    bytes += info.bytes;
+                } else {
+                    failed += 1;
+                }
+                retries += info.retries;
+            }
+            cli_eprintln!("  {}: {} uploaded, {} failed, {} retries, {:.2} MB",
+                settings.destination_label(destination, &settings.destination(destination).urls().join(" | ")),
+                uploaded, failed, retries, bytes as f64 / (1024.0 * 1024.0));
+        }
+    }
 
-    let mut progress = UploadProgress::new(files.len());
-    progress.status_bar();
+    /// Failed attempts (retried or not) by whether they failed connecting or
+    /// mid-transfer, which tells network trouble from a struggling server.
+    fn write_failure_report(&self) {
+        let failed = &self.failed;
+        if failed.connecting + failed.transferring + failed.rejected == 0 {
+            return;
+        }
+        cli_eprintln!("{}", paint(self.colors.stderr, color::YELLOW, "Failed Attempts:"));
+        cli_eprintln!("  connecting (dns, tls, refused): {}", failed.connecting);
+        cli_eprintln!("  mid-transfer: {}", failed.transferring);
+        cli_eprintln!("  error response: {}", failed.rejected);
+    }
 
-    let mut files_iter = files.into_iter();
-    let mut tasks = JoinSet::new();
-    // Start with the number of tasks equal to the concurrency limit, then feed
-    // in new tasks as they complete, on-by-one to establish as limit.
-    for _ in 0..settings.concurrency {
-        if let Some(file) = files_iter.next() {
-            tasks.spawn(upload_file(client.clone(), file, settings.clone()));
-        } else {
-            break;
+    /// which endpoint served each upload, only interesting when failover is
+    /// configured. Uploads served by the primary endpoint are only counted.
+    fn write_endpoint_report(&self, endpoints: &Endpoints) {
+        if endpoints.urls().len() < 2 {
+            return;
+        }
+        cli_eprintln!("Endpoint Report:");
+        for (index, url) in endpoints.urls().iter().enumerate() {
+            let (mut served, mut failed) = (0, 0);
+            for info in self.completed.iter().filter(|i| i.destination == 0 && i.endpoint == Some(index)) {
+                if info.error.is_none() { served += 1 } else { failed += 1 }
+            }
+            cli_eprintln!("  {}: {} uploaded, {} failed", url, served, failed);
+        }
+        for info in &self.completed {
+            if let Some(index) = info.endpoint.filter(|i| info.destination == 0 && *i > 0) {
+                cli_eprintln!("  {} -> {}", info.path, endpoints.url(index));
+            }
         }
     }
-    // main loop, will run into complete or stopped early due to unrecoverable
-    // error, feeding in new files as each upload completes. Progress updates
-    // emitted with each completed upload.
-    while let Some(result) = tasks.join_next().await {
-        match result {
-            Ok(info) => {
-                // Early stoppage since unath is expected to cause errors in all
-                // other uploads using the same token.
-                if let Some(ErrorKind::Unauthorized) = info.error {
-                    eprintln!("\nUnauthorized: Check your token.");
-                    progress.write_error_report();
-                    return;
-                }
-                // TODO: could also stop if the error rate after some point is too high
-                progress.update(info, true);
-            },
-            Err(e) => { eprintln!("Unexpected Join Error: {:?}", e); }
+}
+
+/// http protocol version to talk to the fileservice with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpVersion {
+    /// negotiated via tls alpn, http/1.1 for plain http
+    Auto,
+    /// force http/1.1 even if the server offers http/2
+    Http1,
+    /// use http/2 directly, also over plain http (prior knowledge)
+    Http2,
+}
+
+impl std::str::FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(HttpVersion::Auto),
+            "1" | "1.1" => Ok(HttpVersion::Http1),
+            "2" => Ok(HttpVersion::Http2),
+            _ => Err(format!("unknown http version {:?}, expected auto, 1.1 or 2", s)),
         }
-        if let Some(file) = files_iter.next() {
-            tasks.spawn(upload_file(client.clone(), file, settings.clone()));
+    }
+}
+
+/// how the token is sent to the fileservice
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthScheme {
+    /// `X-Auth-Token: <token>`, as SciServer itself expects
+    XAuthToken,
+    /// `Authorization: Bearer <token>`, for deployments behind a gateway
+    /// doing standard bearer auth
+    Bearer,
+    /// the token as is in this header
+    Header(HeaderName),
+}
+
+impl std::str::FromStr for AuthScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x-auth-token" => Ok(AuthScheme::XAuthToken),
+            "bearer" => Ok(AuthScheme::Bearer),
+            _ => match s.strip_prefix("header:").map(|name| HeaderName::from_bytes(name.as_bytes())) {
+                Some(Ok(name)) => Ok(AuthScheme::Header(name)),
+                _ => Err(format!("unknown auth scheme {:?}, expected x-auth-token, bearer or header:NAME", s)),
+            },
         }
     }
-    println!();
-    progress.write_error_report();
 }
 
+/// percentiles of the per-file throughput in the summary and report
+const THROUGHPUT_PERCENTILES: [f64; 5] = [10.0, 50.0, 90.0, 95.0, 99.0];
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// longest the status bar goes without a redraw
+const PROGRESS_HEARTBEAT: Duration = Duration::from_secs(2);
+/// scans taking longer than this show what they found so far
+const SCAN_PROGRESS_DELAY: Duration = Duration::from_secs(1);
+/// files a streaming scan finds ahead of the uploads, it waits beyond that
+const SCAN_BUFFER: usize = 1024;
+
+/// hyper's default limit on the write buffer of a connection
+const UPLOAD_WRITE_BUFFER: u64 = 400 << 10;
+/// http/2 initial stream window, also a fair guess when it's adaptive
+const DEFAULT_STREAM_WINDOW: u64 = 64 << 10;
+
+/// Asks the user a yes/no question, e.g. whether to go on with a run that
+/// looks mistaken.
+pub type Confirm = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Settings {
+    endpoints: Endpoints,
+    mirrors: Vec<Endpoints>,
+    mirror_tokens: Vec<Option<String>>,
+    path: String,
+    copies: Vec<String>,
+    token: String,
+    auth: AuthScheme,
+    session: Option<Arc<session::Session>>,
+    concurrency: usize,
+    retries: usize,
+    retry_policy: Option<Arc<dyn retry::RetryPolicy>>,
+    middleware: Vec<Arc<dyn middleware::Middleware>>,
+    file_deadline: Option<Duration>,
+    ambiguous_retry: AmbiguousRetry,
+    maintenance_wait: Option<Duration>,
+    keepalive: Option<Duration>,
+    verbose: bool,
+    overwrite: bool,
+    casjobs: bool,
+    proxy: Option<String>,
+    ca_certs: Option<Vec<u8>>,
+    insecure: bool,
+    http_version: HttpVersion,
+    http2_stream_window: Option<u32>,
+    http2_connection_window: Option<u32>,
+    pool_max_idle: Option<usize>,
+    pool_idle_timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+    resolve: Vec<(String, SocketAddr)>,
+    dns_cache_ttl: Option<Duration>,
+    ip_family: Option<IpFamily>,
+    large_threshold: u64,
+    large_concurrency: Option<usize>,
+    destination_limits: Vec<(String, usize)>,
+    order: Order,
+    window: Option<TimeWindow>,
+    nice: bool,
+    dedup_hardlinks: bool,
+    recursive: bool,
+    stream: bool,
+    archive_per_dir: Option<ArchiveFormat>,
+    excludes: Vec<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+    ignore_files: Vec<String>,
+    hidden: bool,
+    follow_symlinks: bool,
+    scan_threads: usize,
+    scan_progress: bool,
+    dry_run: bool,
+    reupload_modified: bool,
+    stable_window: Option<Duration>,
+    follow: bool,
+    skip_sparse: bool,
+    max_memory: Option<u64>,
+    max_total_bytes: Option<u64>,
+    max_files: Option<usize>,
+    confirm: Option<Confirm>,
+    max_duration: Option<Duration>,
+    /// uploads (destination, path) an earlier run did
+    skip_uploaded: HashSet<jobs::Uploaded>,
+    listed_checksums: bool,
+    /// bytes/s
+    target_rate: Option<u64>,
+    ramp_up: Option<RampUp>,
+    /// of the random choices, the same in every run if given
+    seed: Option<u64>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<chaos::Chaos>,
+    chunk_size: usize,
+    checksums: Option<PathBuf>,
+    hash_cache: Option<Arc<HashCache>>,
+    content_index: Option<Arc<ContentIndex>>,
+    report: Option<PathBuf>,
+    progress_socket: Option<PathBuf>,
+    porcelain: Option<Porcelain>,
+    move_files: bool,
+    verify_uploads: bool,
+    encryption: Option<Encryption>,
+    metadata: Option<MetadataMode>,
+    dataset_manifest: Option<ManifestTarget>,
+    xattrs: bool,
+    redraw_interval: Duration,
+    color: ColorChoice,
+    top_slowest: usize,
+    job: Option<Arc<JobState>>,
+}
+
+impl Settings {
+    /// Settings uploading to `prefix`, the file endpoint and remote path in
+    /// one (e.g. `https://apps.sciserver.org/fileservice/api/file/Storage/u/persistent`).
+    /// [`Settings::with_endpoint`] takes them apart, with defaults for the rest.
+    pub fn new(prefix: String, token: String, concurrency: usize, retries: usize, overwrite: bool) -> Arc<Self> {
+        let (endpoint, path) = match prefix.split_once("/api/file/") {
+            Some((base, path)) => (format!("{}/api/file", base), path.to_string()),
+            None => (prefix, String::new()),
+        };
+        let settings = Settings::with_endpoint(endpoint, token).with_path(path);
+        Arc::new(settings.with_concurrency(concurrency).with_retries(retries).with_overwrite(overwrite))
+    }
+
+    /// Settings uploading to the fileservice at `endpoint` with `token`,
+    /// the rest set with the `with_*` methods.
+    pub fn with_endpoint(endpoint: String, token: String) -> Self {
+        Settings {
+            endpoints: Endpoints::new(vec![endpoint]),
+            mirrors: Vec::new(),
+            mirror_tokens: Vec::new(),
+            copies: Vec::new(),
+            path: String::new(),
+            token,
+            auth: AuthScheme::XAuthToken,
+            session: None,
+            concurrency: 10,
+            retries: 3,
+            retry_policy: None,
+            middleware: Vec::new(),
+            file_deadline: None,
+            ambiguous_retry: AmbiguousRetry::Blind,
+            maintenance_wait: None,
+            keepalive: None,
+            verbose: false,
+            overwrite: false,
+            casjobs: false,
+            proxy: None,
+            ca_certs: None,
+            insecure: false,
+            http_version: HttpVersion::Auto,
+            http2_stream_window: None,
+            http2_connection_window: None,
+            pool_max_idle: None,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            resolve: Vec::new(),
+            dns_cache_ttl: Some(Duration::from_secs(300)),
+            ip_family: None,
+            large_threshold: 100 << 20,
+            large_concurrency: None,
+            destination_limits: Vec::new(),
+            order: Order::Given,
+            window: None,
+            nice: false,
+            dedup_hardlinks: false,
+            recursive: false,
+            stream: false,
+            archive_per_dir: None,
+            excludes: Vec::new(),
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            ignore_files: Vec::new(),
+            hidden: false,
+            follow_symlinks: false,
+            scan_threads: 8,
+            scan_progress: false,
+            dry_run: false,
+            reupload_modified: false,
+            stable_window: None,
+            follow: false,
+            skip_sparse: false,
+            max_memory: None,
+            max_total_bytes: None,
+            max_files: None,
+            confirm: None,
+            max_duration: None,
+            skip_uploaded: HashSet::new(),
+            listed_checksums: false,
+            target_rate: None,
+            ramp_up: None,
+            seed: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            chunk_size: 64 << 10,
+            checksums: None,
+            hash_cache: None,
+            content_index: None,
+            report: None,
+            progress_socket: None,
+            porcelain: None,
+            move_files: false,
+            verify_uploads: false,
+            encryption: None,
+            metadata: None,
+            dataset_manifest: None,
+            xattrs: false,
+            redraw_interval: Duration::from_millis(250),
+            color: ColorChoice::Auto,
+            top_slowest: 5,
+            job: None,
+        }
+    }
+
+    /// Endpoints to try in order, failing over to the next one when the active
+    /// endpoint becomes unreachable or keeps erroring. Must not be empty.
+    pub fn with_endpoints(self, endpoints: Vec<String>) -> Self {
+        Settings { endpoints: Endpoints::new(endpoints), ..self }
+    }
+
+    /// Additional endpoints every file is also uploaded to, each with its own
+    /// retry accounting, e.g. a backup deployment next to production.
+    pub fn with_mirrors(self, mirrors: Vec<String>) -> Self {
+        let mirrors = mirrors.into_iter().map(|m| Endpoints::new(vec![m])).collect();
+        Settings { mirrors, ..self }
+    }
+
+    /// Tokens of the mirrors, in the order of `with_mirrors`, None (or none
+    /// given) for a mirror taking the primary token.
+    pub fn with_mirror_tokens(self, mirror_tokens: Vec<Option<String>>) -> Self {
+        Settings { mirror_tokens, ..self }
+    }
+
+    /// remote path (e.g. Storage/user/persistent/dir) files are uploaded to
+    pub fn with_path(self, path: String) -> Self {
+        Settings { path, ..self }
+    }
+
+    /// Uploads run at once, at least 1.
+    pub fn with_concurrency(self, concurrency: usize) -> Self {
+        Settings { concurrency: concurrency.max(1), ..self }
+    }
+
+    pub fn with_retries(self, retries: usize) -> Self {
+        Settings { retries, ..self }
+    }
+
+    /// Decide what follows failed attempts with `retry_policy` rather than
+    /// the [`retry::DefaultRetryPolicy`] of the retries set.
+    pub fn with_retry_policy(self, retry_policy: Option<Arc<dyn retry::RetryPolicy>>) -> Self {
+        Settings { retry_policy, ..self }
+    }
+
+    /// Send each upload request through `middleware`, the first outermost,
+    /// e.g. to sign it. Listings and the other requests go out as they are.
+    pub fn with_middleware(self, middleware: Vec<Arc<dyn middleware::Middleware>>) -> Self {
+        Settings { middleware, ..self }
+    }
+
+    /// Give up on an upload this long after it started, retries included,
+    /// so a pathological file can't hold up the run indefinitely.
+    pub fn with_file_deadline(self, file_deadline: Option<Duration>) -> Self {
+        Settings { file_deadline, ..self }
+    }
+
+    /// Whether to upload again right away when an upload failed while its
+    /// data was being sent, or to check what the fileservice kept first.
+    /// Blind retries can't tell a partly written file from a missing one.
+    pub fn with_ambiguous_retry(self, ambiguous_retry: AmbiguousRetry) -> Self {
+        Settings { ambiguous_retry, ..self }
+    }
+
+    /// On a 503 or maintenance page, have the whole run wait up to
+    /// `maintenance_wait` for the fileservice to be back, polling it, rather
+    /// than each upload using up its retries. Off by default.
+    pub fn with_maintenance_wait(self, maintenance_wait: Option<Duration>) -> Self {
+        Settings { maintenance_wait, ..self }
+    }
+
+    /// Ping the fileservice after each `keepalive` of a run nothing was sent
+    /// in, so the token or login session doesn't time out while idle, e.g.
+    /// outside the upload window. Not for CasJobs. Off by default.
+    pub fn with_keepalive(self, keepalive: Option<Duration>) -> Self {
+        Settings { keepalive, ..self }
+    }
+
+    /// Tell about what goes on in the background too, such as keepalive
+    /// pings and the steps of the ramp-up.
+    pub fn with_verbose(self, verbose: bool) -> Self {
+        Settings { verbose, ..self }
+    }
+
+    pub fn with_overwrite(self, overwrite: bool) -> Self {
+        Settings { overwrite, ..self }
+    }
+
+    /// Load files as CSV tables into CasJobs instead, each into a table named
+    /// after the file in the context given as path (e.g. MyDB). Endpoints are
+    /// then of the CasJobs REST API (e.g. `.../casjobs/RestApi`).
+    pub fn with_casjobs(self, casjobs: bool) -> Self {
+        Settings { casjobs, ..self }
+    }
+
+    /// Also upload every file to each of these paths, on the primary
+    /// endpoints, e.g. a working copy and an archive copy. Files small enough
+    /// to be buffered are read once for all their uploads.
+    pub fn with_copies(self, copies: Vec<String>) -> Self {
+        Settings { copies, ..self }
+    }
+
+    /// endpoints of a destination, 0 being the primary one, then the mirrors
+    /// and the copies on the primary one
+    fn destination(&self, destination: usize) -> &Endpoints {
+        match destination {
+            0 => &self.endpoints,
+            n if n <= self.mirrors.len() => &self.mirrors[n - 1],
+            _ => &self.endpoints,
+        }
+    }
+
+    /// path a destination is uploaded to
+    fn destination_path(&self, destination: usize) -> &str {
+        match destination.checked_sub(1 + self.mirrors.len()) {
+            Some(copy) => &self.copies[copy],
+            None => &self.path,
+        }
+    }
+
+    /// token a destination is uploaded with
+    fn destination_token(&self, destination: usize) -> &str {
+        match destination.checked_sub(1).and_then(|mirror| self.mirror_tokens.get(mirror)) {
+            Some(Some(token)) if destination <= self.mirrors.len() => token,
+            _ => &self.token,
+        }
+    }
+
+    /// whether `destination` is a mirror
+    fn is_mirror(&self, destination: usize) -> bool {
+        (1..=self.mirrors.len()).contains(&destination)
+    }
+
+    /// the settings uploading to `destination`'s path with its token
+    fn at_destination(&self, destination: usize) -> Settings {
+        let path = self.destination_path(destination).to_string();
+        Settings { path, token: self.destination_token(destination).to_string(), ..self.clone() }
+    }
+
+    /// what a destination is told by at `endpoint`, its path too with copies
+    fn destination_label(&self, destination: usize, endpoint: &str) -> String {
+        match self.copies.is_empty() {
+            true => endpoint.to_string(),
+            false => self.path_prefix(endpoint, self.destination_path(destination)),
+        }
+    }
+
+    fn destinations(&self) -> usize {
+        1 + self.mirrors.len() + self.copies.len()
+    }
+
+    /// Send all requests through this proxy (e.g. http://proxy:3128, or
+    /// socks5h://localhost:1080 to tunnel over ssh -D), hosts in NO_PROXY
+    /// still bypass it. Without it the HTTP_PROXY, HTTPS_PROXY and
+    /// NO_PROXY environment variables are honored.
+    pub fn with_proxy(self, proxy: Option<String>) -> Self {
+        Settings { proxy, ..self }
+    }
+
+    /// Additionally trust the certificate(s) in this PEM bundle, e.g. the
+    /// internal CA of a private deployment.
+    pub fn with_ca_certs(self, ca_certs: Option<Vec<u8>>) -> Self {
+        Settings { ca_certs, ..self }
+    }
+
+    /// Skip tls certificate verification entirely. Only meant for test
+    /// deployments with self-signed certificates, this exposes the token to
+    /// anyone able to intercept the connection.
+    pub fn with_insecure(self, insecure: bool) -> Self {
+        Settings { insecure, ..self }
+    }
+
+    /// How the token is sent, `X-Auth-Token` by default.
+    pub fn with_auth(self, auth: AuthScheme) -> Self {
+        Settings { auth, ..self }
+    }
+
+    /// Log in with these credentials for a session cookie, and again once it
+    /// expires or an upload is refused with 401, which is then tried once
+    /// more before the run stops. The token is sent as well, unless empty.
+    pub fn with_login(self, login: Option<session::Login>) -> Self {
+        Settings { session: login.map(|login| Arc::new(session::Session::new(login))), ..self }
+    }
+
+    pub fn with_http_version(self, http_version: HttpVersion) -> Self {
+        Settings { http_version, ..self }
+    }
+
+    /// Fixed http/2 flow control windows (per stream and per connection) in
+    /// bytes. When neither is given windows are sized adaptively.
+    pub fn with_http2_windows(self, stream: Option<u32>, connection: Option<u32>) -> Self {
+        Settings { http2_stream_window: stream, http2_connection_window: connection, ..self }
+    }
+
+    /// Idle connections kept open per host (defaults to the concurrency, so
+    /// every upload slot can reuse a connection) and how long they are kept.
+    pub fn with_pool(self, max_idle: Option<usize>, idle_timeout: Option<Duration>) -> Self {
+        Settings { pool_max_idle: max_idle, pool_idle_timeout: idle_timeout, ..self }
+    }
+
+    /// interval of tcp keepalive probes, None to disable them
+    pub fn with_tcp_keepalive(self, tcp_keepalive: Option<Duration>) -> Self {
+        Settings { tcp_keepalive, ..self }
+    }
+
+    /// Fixed addresses for host names, bypassing dns. A port of 0 means the
+    /// port from the url.
+    pub fn with_resolve(self, resolve: Vec<(String, SocketAddr)>) -> Self {
+        Settings { resolve, ..self }
+    }
+
+    /// how long resolved addresses are reused, None to resolve every new
+    /// connection with the system resolver
+    pub fn with_dns_cache_ttl(self, dns_cache_ttl: Option<Duration>) -> Self {
+        Settings { dns_cache_ttl, ..self }
+    }
+
+    /// only connect over this address family, rather than trying both
+    pub fn with_ip_family(self, ip_family: Option<IpFamily>) -> Self {
+        Settings { ip_family, ..self }
+    }
+
+    /// Upload files of `threshold` bytes and more from a separate pool of
+    /// `concurrency` slots, so a batch of huge files doesn't hold up many tiny
+    /// ones or the other way around. No separate pool when None, at least 1
+    /// slot otherwise.
+    pub fn with_large_files(self, threshold: u64, concurrency: Option<usize>) -> Self {
+        Settings { large_threshold: threshold, large_concurrency: concurrency.map(|c| c.max(1)), ..self }
+    }
+
+    /// Run at most the given number of uploads at once into each remote path
+    /// (e.g. `Storage/u/archive`, a volume on slower storage), taking their
+    /// slots from the pools all uploads share. Files under several of them
+    /// count against the longest.
+    pub fn with_destination_limits(self, destination_limits: Vec<(String, usize)>) -> Self {
+        Settings { destination_limits, ..self }
+    }
+
+    /// order in which files are started
+    pub fn with_order(self, order: Order) -> Self {
+        Settings { order, ..self }
+    }
+
+    /// Only start uploads within these hours of the day, waiting for the
+    /// window to open. Uploads running when it closes are finished.
+    pub fn with_window(self, window: Option<TimeWindow>) -> Self {
+        Settings { window, ..self }
+    }
+
+    /// Go easy on the machine uploads run on: reads get the idle io class on
+    /// linux, elsewhere fewer uploads run at once and each reads slowly.
+    pub fn with_nice(self, nice: bool) -> Self {
+        Settings { nice, ..self }
+    }
+
+    /// upload files hardlinked under several names only once, under the first
+    /// name given
+    pub fn with_dedup_hardlinks(self, dedup_hardlinks: bool) -> Self {
+        Settings { dedup_hardlinks, ..self }
+    }
+
+    /// Upload the contents of directories, keeping their structure below the
+    /// destination. A directory given with a trailing slash has its contents
+    /// placed directly in the destination, otherwise under its own name.
+    pub fn with_recursive(self, recursive: bool) -> Self {
+        Settings { recursive, ..self }
+    }
+
+    /// Start uploading files as they're found rather than once directories
+    /// were walked whole, and in the order they're found. Runs that need all
+    /// files first fail: dry runs, archives per directory, metadata,
+    /// deduplicating hardlinks, orders other than the one given, a most
+    /// files to find or CasJobs tables (checked for names colliding first).
+    /// Resumed jobs upload the files planned when they started.
+    pub fn with_stream(self, stream: bool) -> Self {
+        Settings { stream, ..self }
+    }
+
+    /// whether files are uploaded as they're found, see [`Settings::with_stream`]
+    fn streams(&self) -> bool {
+        self.stream && self.job.as_ref().is_none_or(|job| job.plan().is_none())
+    }
+
+    /// the option that needs all files found first, if streaming can't be
+    /// combined with one
+    fn stream_conflict(&self) -> Option<&'static str> {
+        [
+            (self.dry_run, "--dry-run"),
+            (self.archive_per_dir.is_some(), "--archive-per-dir"),
+            (self.metadata.is_some(), "--metadata"),
+            (self.dedup_hardlinks, "--dedup-hardlinks"),
+            (self.order != Order::Given, "--order"),
+            (self.max_files.is_some(), "--max-files"),
+            (self.casjobs, "--casjobs"),
+        ].into_iter().find(|(set, _)| *set).map(|(_, option)| option)
+    }
+
+    /// Upload each subdirectory of uploaded directories as one archive, made
+    /// on the fly, rather than its files one by one. Files directly in the
+    /// directories are uploaded as they are.
+    pub fn with_archive_per_dir(self, archive_per_dir: Option<ArchiveFormat>) -> Self {
+        Settings { archive_per_dir, ..self }
+    }
+
+    /// Glob patterns (e.g. `*.tmp`) of files and directories not to upload,
+    /// matched against both file names and paths relative to the destination.
+    pub fn with_excludes(self, excludes: Vec<String>) -> Self {
+        Settings { excludes, ..self }
+    }
+
+    /// only upload files of at least `min` and at most `max` bytes
+    pub fn with_size_limits(self, min: Option<u64>, max: Option<u64>) -> Self {
+        Settings { min_size: min, max_size: max, ..self }
+    }
+
+    /// only upload files modified after `newer` and before `older`
+    pub fn with_time_limits(self, newer: Option<SystemTime>, older: Option<SystemTime>) -> Self {
+        Settings { newer_than: newer, older_than: older, ..self }
+    }
+
+    /// Gitignore style files with rules for what not to upload, applied in
+    /// addition to the .uploadignore in the root of uploaded directories.
+    pub fn with_ignore_files(self, ignore_files: Vec<String>) -> Self {
+        Settings { ignore_files, ..self }
+    }
+
+    /// Include dot-files and directories when walking directories, they are
+    /// skipped by default. Files given explicitly are always uploaded.
+    pub fn with_hidden(self, hidden: bool) -> Self {
+        Settings { hidden, ..self }
+    }
+
+    /// Walk into symlinked directories, as long as they point within the
+    /// directory being uploaded and don't lead back to a parent.
+    pub fn with_follow_symlinks(self, follow_symlinks: bool) -> Self {
+        Settings { follow_symlinks, ..self }
+    }
+
+    /// Walk this many directories at once when uploading recursively, which
+    /// mostly waits on the filesystem (network ones especially). 8 by
+    /// default, 1 walks one directory after the other.
+    pub fn with_scan_threads(self, scan_threads: usize) -> Self {
+        Settings { scan_threads, ..self }
+    }
+
+    /// Show what walking directories found so far while it takes long, so
+    /// huge trees don't look like the run hung before the first upload. Only
+    /// drawn when stderr is a terminal.
+    pub fn with_scan_progress(self, scan_progress: bool) -> Self {
+        Settings { scan_progress, ..self }
+    }
+
+    /// only print what would be uploaded where, and why files were left out
+    pub fn with_dry_run(self, dry_run: bool) -> Self {
+        Settings { dry_run, ..self }
+    }
+
+    /// Upload files that changed during their upload again (counting against
+    /// the retries), instead of only reporting them.
+    pub fn with_reupload_modified(self, reupload_modified: bool) -> Self {
+        Settings { reupload_modified, ..self }
+    }
+
+    /// For files still being written, e.g. logs: only upload them once they
+    /// weren't modified for `window`, or with `follow` right away and again
+    /// (replacing the copy) whenever they changed, until they stay unchanged
+    /// for `window`.
+    pub fn with_stable_window(self, window: Option<Duration>, follow: bool) -> Self {
+        Settings { stable_window: window, follow, ..self }
+    }
+
+    /// Leave out sparse files, they take their full size on the fileservice
+    /// (counting against quota). By default they're uploaded with a warning.
+    pub fn with_skip_sparse(self, skip_sparse: bool) -> Self {
+        Settings { skip_sparse, ..self }
+    }
+
+    /// Cap the memory buffered by running uploads, holding back uploads until
+    /// earlier ones finish, so high concurrency can't run small nodes out of
+    /// memory.
+    pub fn with_max_memory(self, max_memory: Option<u64>) -> Self {
+        Settings { max_memory, ..self }
+    }
+
+    /// Stop starting uploads once the next one would take the bytes uploaded
+    /// in this run over `max_total_bytes`, e.g. for a limited quota. What's
+    /// left can be uploaded by resuming the job.
+    pub fn with_max_total_bytes(self, max_total_bytes: Option<u64>) -> Self {
+        Settings { max_total_bytes, ..self }
+    }
+
+    /// Stop the scan and upload nothing once more than `max_files` files are
+    /// found, unless the [`Settings::with_confirm`] callback agrees, e.g. for
+    /// a mistyped path pointing at a home directory rather than the data in
+    /// it. Applies to uploads, syncs and jobs of the server.
+    pub fn with_max_files(self, max_files: Option<usize>) -> Self {
+        Settings { max_files, ..self }
+    }
+
+    /// Ask `confirm` whether to go on with runs that look mistaken, such as
+    /// ones over [`Settings::with_max_files`]. Without it they stop.
+    pub fn with_confirm(self, confirm: Option<Confirm>) -> Self {
+        Settings { confirm, ..self }
+    }
+
+    /// Stop starting uploads `max_duration` after they started, e.g. within
+    /// the time limit of a batch job, letting running ones finish. What's
+    /// left can be uploaded by resuming the job.
+    pub fn with_max_duration(self, max_duration: Option<Duration>) -> Self {
+        Settings { max_duration, ..self }
+    }
+
+    /// Skip the uploads an earlier run did, e.g. as found in its report by
+    /// [`jobs::uploaded_in_report`], of files still the same size and
+    /// modification time going to the same remote path.
+    pub fn with_skip_uploaded(self, skip_uploaded: HashSet<jobs::Uploaded>) -> Self {
+        Settings { skip_uploaded, ..self }
+    }
+
+    /// Tune how many uploads run at once (up to the concurrency) so the run
+    /// sends `target_rate` bytes/s without going over, e.g. to stay within a
+    /// traffic agreement.
+    pub fn with_target_rate(self, target_rate: Option<u64>) -> Self {
+        Settings { target_rate, ..self }
+    }
+
+    /// Start with a single upload and allow more as `ramp_up` goes by, up to
+    /// the concurrency, rather than starting them all at once.
+    pub fn with_ramp_up(self, ramp_up: Option<RampUp>) -> Self {
+        Settings { ramp_up, ..self }
+    }
+
+    /// Make the random choices (retry jitter, random order, injected
+    /// failures) from `seed`, the same in every run.
+    pub fn with_seed(self, seed: Option<u64>) -> Self {
+        Settings { seed, ..self }
+    }
+
+    /// Inject failures into uploads at random, to try out the settings for
+    /// failures.
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(self, chaos: Option<chaos::Chaos>) -> Self {
+        Settings { chaos, ..self }
+    }
+
+    /// Size of the reads files are streamed in, 64 KiB by default. Larger
+    /// reads suit network filesystems and spinning disks, fewer round trips
+    /// and seeks, while smaller ones keep memory down.
+    pub fn with_chunk_size(self, chunk_size: usize) -> Self {
+        Settings { chunk_size: chunk_size.max(1), ..self }
+    }
+
+    /// Write the SHA-256 checksums of uploaded files to `checksums`. Files are
+    /// hashed ahead of their upload, in parallel with the transfers.
+    pub fn with_checksums(self, checksums: Option<PathBuf>) -> Self {
+        Settings { checksums, ..self }
+    }
+
+    /// Take the checksums of files that didn't change since an earlier run
+    /// hashed them from `hash_cache`, adding those hashed now.
+    pub fn with_hash_cache(self, hash_cache: Option<HashCache>) -> Self {
+        Settings { hash_cache: hash_cache.map(Arc::new), ..self }
+    }
+
+    /// Leave out files whose contents were uploaded under the destination
+    /// path before, as recorded in `content_index` and still there with the
+    /// same checksum, recording those uploaded now. Every file is hashed
+    /// before its upload.
+    pub fn with_content_index(self, content_index: Option<ContentIndex>) -> Self {
+        Settings { content_index: content_index.map(Arc::new), ..self }
+    }
+
+    /// Write back the checksums cache, if any.
+    fn save_hash_cache(&self) {
+        if let Some(cache) = &self.hash_cache
+            && let Err(e) = cache.save() {
+            cli_eprintln!("Warning: failed to save checksums to {}: {}", cache.path().display(), e);
+        }
+    }
+
+    /// Write a JSON report of the run to `report`: the summary and the outcome
+    /// of each upload.
+    pub fn with_report(self, report: Option<PathBuf>) -> Self {
+        Settings { report, ..self }
+    }
+
+    /// Stream progress as NDJSON to clients of a Unix domain socket at
+    /// `path`, for monitoring tools to attach to while the run goes on.
+    pub fn with_progress_socket(self, progress_socket: Option<PathBuf>) -> Self {
+        Settings { progress_socket, ..self }
+    }
+
+    /// Write progress for wrapping tools on stdout instead of the status bar,
+    /// in a format that stays the same for each [`Porcelain`] version.
+    pub fn with_porcelain(self, porcelain: Option<Porcelain>) -> Self {
+        Settings { porcelain, ..self }
+    }
+
+    /// Delete each local file once it's uploaded to every destination.
+    pub fn with_move_files(self, move_files: bool) -> Self {
+        Settings { move_files, ..self }
+    }
+
+    /// Compare the SHA-256 some deployments list for each file in `jsonTree`
+    /// answers rather than downloading files to hash them, when verifying,
+    /// diffing and checking what a failed upload left. The field isn't part
+    /// of the documented fileservice API, so it's ignored unless asked for.
+    pub fn with_listed_checksums(self, listed_checksums: bool) -> Self {
+        Settings { listed_checksums, ..self }
+    }
+
+    /// Download every uploaded file to check it hashes the same as the local
+    /// file, uploading it again if not.
+    pub fn with_verify_uploads(self, verify_uploads: bool) -> Self {
+        Settings { verify_uploads, ..self }
+    }
+
+    /// Encrypt files before uploading them, adding the scheme's extension to
+    /// their names.
+    pub fn with_encryption(self, encryption: Option<Encryption>) -> Self {
+        Settings { encryption, ..self }
+    }
+
+    /// Upload metadata of the files (permissions, owner, times, local path)
+    /// as JSON next to them, see [`MetadataMode`].
+    pub fn with_metadata(self, metadata: Option<MetadataMode>) -> Self {
+        Settings { metadata, ..self }
+    }
+
+    /// After a run in which every file was uploaded or found there already,
+    /// upload a manifest of the files and the run next to them or POST it to
+    /// an endpoint (without the token), for catalogs to pick up. Checksums
+    /// are listed with [`Settings::with_checksums`].
+    pub fn with_dataset_manifest(self, dataset_manifest: Option<ManifestTarget>) -> Self {
+        Settings { dataset_manifest, ..self }
+    }
+
+    /// Include extended attributes in the metadata (unix).
+    pub fn with_xattrs(self, xattrs: bool) -> Self {
+        Settings { xattrs, ..self }
+    }
+
+    /// Redraw the status bar at most `rate` times a second, kept between
+    /// once every 5 seconds and 1000 times a second; the default of 4 if
+    /// it's not a number.
+    pub fn with_refresh_rate(self, rate: f64) -> Self {
+        let rate = if rate.is_nan() { 4.0 } else { rate.clamp(0.2, 1000.0) };
+        Settings { redraw_interval: Duration::from_secs_f64(1.0 / rate), ..self }
+    }
+
+    /// when to color the status bar and reports
+    pub fn with_color(self, color: ColorChoice) -> Self {
+        Settings { color, ..self }
+    }
+
+    /// List the `n` uploads with the worst throughput in the summary.
+    pub fn with_top_slowest(self, n: usize) -> Self {
+        Settings { top_slowest: n, ..self }
+    }
+
+    /// Keep the plan and progress of the run in `job`, resuming it if it was
+    /// opened from an earlier run.
+    pub fn with_job(self, job: Option<JobState>) -> Self {
+        Settings { job: job.map(Arc::new), ..self }
+    }
+
+    /// Most memory a single upload buffers: the chunk being read, what hyper
+    /// queues for writing and, over http/2, the data the stream window lets
+    /// be in flight.
+    fn upload_buffer(&self) -> u64 {
+        self.chunk_size as u64 + UPLOAD_WRITE_BUFFER
+            + self.http2_stream_window.map_or(DEFAULT_STREAM_WINDOW, u64::from)
+    }
+
+    fn prefix(&self, endpoint: &str) -> String {
+        self.path_prefix(endpoint, &self.path)
+    }
+
+    fn path_prefix(&self, endpoint: &str, path: &str) -> String {
+        if self.casjobs {
+            return casjobs::tables_url(endpoint, path);
+        }
+        match path.trim_matches('/') {
+            "" => endpoint.trim_end_matches('/').to_string(),
+            path => format!("{}/{}", endpoint.trim_end_matches('/'), path),
+        }
+    }
+
+    /// name a file is uploaded as, given its name relative to the path
+    fn remote_name(&self, name: &str) -> String {
+        if self.casjobs {
+            return casjobs::table_name(name);
+        }
+        match &self.encryption {
+            Some(encryption) => format!("{}{}", name, encryption.extension()),
+            None => name.to_string(),
+        }
+    }
+
+    /// where a file is uploaded to as `name` on `destination`, its path and
+    /// remote name, as told in reports
+    fn remote_path(&self, destination: usize, name: &str) -> String {
+        match self.destination_path(destination).trim_matches('/') {
+            "" => self.remote_name(name),
+            path => format!("{}/{}", path, self.remote_name(name)),
+        }
+    }
+
+    /// Whether the report of an earlier run has `input` uploaded to
+    /// `destination` as it is now, as of `stamp`.
+    fn in_skipped_report(&self, destination: usize, input: &Input, stamp: Option<Stamp>) -> bool {
+        let Some((size, modified)) = stamp.filter(|_| !self.skip_uploaded.is_empty()) else { return false };
+        let remote = self.remote_path(destination, &input.name);
+        let modified = modified.map(jobs::format_modified);
+        let uploaded = jobs::Uploaded { destination, path: input.path.clone(), remote, size, modified };
+        self.skip_uploaded.contains(&uploaded)
+    }
+
+    /// url a file is uploaded to as `name` on `endpoint`, `replace`-ing any
+    /// copy there (which CasJobs tables can't be)
+    fn upload_url(&self, endpoint: &str, name: &str, replace: bool) -> String {
+        let url = format!("{}/{}", self.prefix(endpoint), self.remote_name(name));
+        if replace && !self.casjobs { format!("{}?quiet=true", url) } else { url }
+    }
+
+    /// random choices for uploading `path` to `destination`
+    fn rng(&self, destination: usize, path: &str) -> fastrand::Rng {
+        random::rng(self.seed, &format!("{}:{}", destination, path))
+    }
+
+    /// Send `request` to `url` through the middleware, logging in again first
+    /// if the session expired. If that fails the request goes without, to be
+    /// refused like any other unauthorized one.
+    async fn send(&self, client: &Client, url: &str, request: reqwest::RequestBuilder)
+        -> reqwest::Result<reqwest::Response> {
+        if let Some(Err(e)) = self.log_in(client, url).await {
+            cli_eprintln!("Failed to log in again: {}", e);
+        }
+        middleware::send(client, &self.middleware, request).await
+    }
+
+    /// the login the session is from, if there's a login
+    fn session_generation(&self) -> Option<usize> {
+        self.session.as_ref().map(|session| session.generation())
+    }
+
+    /// After a request to `url` with the session of `generation` got a 401,
+    /// log in again to try once more, unless another upload did since.
+    /// Whether to try, never without a login.
+    async fn relogin(&self, client: &Client, url: &str, generation: Option<usize>) -> bool {
+        let (Some(session), Some(generation)) = (&self.session, generation) else { return false };
+        match session.renew(client, url, generation).await {
+            Ok(()) => true,
+            Err(e) => {
+                cli_eprintln!("\nFailed to log in again: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Log in with `client` if there's a login and no session for `url`.
+    async fn log_in(&self, client: &Client, url: &str) -> Option<Result<(), String>> {
+        Some(self.session.as_ref()?.ensure(client, url).await)
+    }
+
+    /// request uploading to `url`, CasJobs takes tables by POST
+    fn upload_request(&self, client: &Client, url: &str) -> reqwest::RequestBuilder {
+        if self.casjobs { client.post(url) } else { client.put(url) }
+    }
+
+    /// url of another fileservice api next to the file endpoint, e.g. `volumes`
+    fn api_url(&self, endpoint: &str, service: &str) -> String {
+        let endpoint = endpoint.trim_end_matches('/');
+        let base = endpoint.strip_suffix("/file").unwrap_or(endpoint);
+        format!("{}/{}", base, service)
+    }
+}
+
+/// `client`, or one with the token of `destination` if it has its own. Never
+/// `client` for a destination with another token, its token isn't theirs.
+fn destination_client(client: &Client, settings: &Settings, destination: usize) -> reqwest::Result<Client> {
+    if settings.destination_token(destination) == settings.token {
+        return Ok(client.clone());
+    }
+    build_client(&settings.at_destination(destination))
+}
+
+/// client with auth headers and network options set up for talking to the
+/// fileservice
+fn build_client(settings: &Settings) -> reqwest::Result<Client> {
+    let mut headers = HeaderMap::new();
+    let (name, value) = match &settings.auth {
+        AuthScheme::XAuthToken => (HeaderName::from_static("x-auth-token"), settings.token.clone()),
+        AuthScheme::Bearer => (reqwest::header::AUTHORIZATION, format!("Bearer {}", settings.token)),
+        AuthScheme::Header(name) => (name.clone(), settings.token.clone()),
+    };
+    if !settings.token.is_empty() {
+        let mut value: HeaderValue = value.parse().unwrap();
+        value.set_sensitive(true);
+        headers.insert(name, value);
+    }
+    let mut builder = Client::builder().default_headers(headers);
+    if let Some(session) = &settings.session {
+        builder = builder.cookie_provider(session.jar());
+    }
+    // rustls is on by default, so asking for native-tls means preferring it
+    #[cfg(feature = "native-tls")]
+    {
+        builder = builder.use_native_tls();
+    }
+    if let Some(proxy) = &settings.proxy {
+        builder = builder.proxy(Proxy::all(proxy)?.no_proxy(NoProxy::from_env()));
+    }
+    if let Some(pem) = &settings.ca_certs {
+        for cert in Certificate::from_pem_bundle(pem)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if settings.insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    match settings.http_version {
+        HttpVersion::Http1 => builder = builder.http1_only(),
+        HttpVersion::Http2 => builder = builder.http2_prior_knowledge(),
+        HttpVersion::Auto => (),
+    }
+    if settings.http2_stream_window.is_none() && settings.http2_connection_window.is_none() {
+        // many uploads multiplexed over one connection, let hyper grow the
+        // windows by measured bandwidth-delay rather than the small defaults
+        builder = builder.http2_adaptive_window(true);
+    } else {
+        builder = builder
+            .http2_initial_stream_window_size(settings.http2_stream_window)
+            .http2_initial_connection_window_size(settings.http2_connection_window);
+    }
+    builder = builder
+        .pool_max_idle_per_host(settings.pool_max_idle.unwrap_or(settings.concurrency))
+        .pool_idle_timeout(settings.pool_idle_timeout)
+        .tcp_keepalive(settings.tcp_keepalive);
+    if settings.dns_cache_ttl.is_some() || settings.ip_family.is_some() {
+        let resolver = CachingResolver::new(settings.dns_cache_ttl, settings.ip_family);
+        builder = builder.dns_resolver(Arc::new(resolver));
+    }
+    for (host, addr) in &settings.resolve {
+        builder = builder.resolve(host, *addr);
+    }
+    builder.build()
+}
+
+/// Print the upload plan, what goes where and what was left out, on stdout.
+fn write_dry_run(files: &[Input], collected: &inputs::Collected, settings: &Settings) {
+    cli_println!("Dry run, nothing is uploaded.");
+    if settings.recursive {
+        if settings.hidden {
+            cli_println!("Hidden files: included (--hidden)");
+        } else {
+            cli_println!("Hidden files: skipped, {} found (use --hidden to include)", collected.hidden);
+        }
+    }
+    cli_println!("Excluded by patterns or ignore files: {}", collected.excluded);
+    cli_println!("Skipped by size: {}", collected.filtered_size);
+    cli_println!("Skipped by modification time: {}", collected.filtered_time);
+    if settings.skip_sparse {
+        cli_println!("Skipped as sparse: {}", collected.sparse.len());
+    }
+    if settings.move_files {
+        cli_println!("Local files are deleted once uploaded (--move)");
+    }
+    if let Some(rate) = settings.target_rate {
+        cli_println!("Uploads at once: tuned to send at most {:.2} MB/s", rate as f64 / (1024.0 * 1024.0));
+    }
+    if let Some(ramp_up) = settings.ramp_up {
+        cli_println!("Uploads at once: starting with 1, ramping up {}", ramp_up);
+    }
+    if let Some(format) = settings.archive_per_dir {
+        cli_println!("Subdirectories: uploaded as name{} archives", format.extension());
+    }
+    match settings.metadata {
+        Some(MetadataMode::Sidecar) => cli_println!("Metadata: uploaded as name{} next to each file", metadata::SIDECAR_SUFFIX),
+        Some(MetadataMode::Manifest) => cli_println!("Metadata: uploaded as {} in each directory", metadata::MANIFEST),
+        None => (),
+    }
+    cli_println!("Would upload {} file(s):", files.len());
+    for file in files {
+        for destination in 0..settings.destinations() {
+            let endpoints = settings.destination(destination);
+            let prefix = settings.path_prefix(endpoints.url(endpoints.current()), settings.destination_path(destination));
+            cli_println!("  {} -> {}/{}", file.path, prefix, settings.remote_name(&file.name));
+        }
+    }
+}
+
+/// what `settings` leave out of uploaded directories
+fn filters(settings: &Settings) -> Result<Filters, globset::Error> {
+    Ok(Filters {
+        excludes: inputs::build_excludes(&settings.excludes)?,
+        min_size: settings.min_size,
+        max_size: settings.max_size,
+        newer_than: settings.newer_than,
+        older_than: settings.older_than,
+        ignore_files: settings.ignore_files.clone(),
+        hidden: settings.hidden,
+        follow_symlinks: settings.follow_symlinks,
+        skip_sparse: settings.skip_sparse,
+        archive: None,
+        walkers: settings.scan_threads,
+        progress: None,
+        max_files: None,
+    })
+}
+
+/// Files to upload from the paths given, telling what was left out. The scan
+/// stops at more than `max_files`, going on only if confirmed (see
+/// [`Settings::with_max_files`]). None if exclude patterns are invalid or too
+/// many files were found.
+async fn collect_files(files: Vec<impl Into<UploadRequest>>, recursive: bool, max_files: Option<usize>,
+    settings: &Settings) -> Option<(Vec<Input>, inputs::Collected)> {
+    let files: Vec<UploadRequest> = files.into_iter().map(Into::into).collect();
+    // dry runs find them all, only to warn
+    let limit = max_files.filter(|_| !settings.dry_run);
+    let mut collected = scan_files(files.clone(), recursive, limit, settings).await?;
+    if let Some(max_files) = limit.filter(|_| collected.over_max_files) {
+        if !confirm_max_files(max_files, settings) {
+            return None;
+        }
+        collected = scan_files(files, recursive, None, settings).await?;
+    }
+    report_collected(&collected, settings);
+    let (files, duplicates) = inputs::dedup_files(std::mem::take(&mut collected.inputs));
+    if !duplicates.is_empty() {
+        cli_eprintln!("Ignoring {} duplicate input(s):", duplicates.len());
+        for file in &duplicates {
+            cli_eprintln!("  {}", file.path);
+        }
+    }
+    let files = if settings.dedup_hardlinks {
+        let (files, links) = inputs::dedup_hardlinks(files);
+        if !links.is_empty() {
+            cli_eprintln!("Not uploading {} hardlink(s) to files already uploaded:", links.len());
+            for (link, original) in &links {
+                cli_eprintln!("  {} (same as {})", link.path, original.path);
+            }
+        }
+        files
+    } else {
+        files
+    };
+    if let Some(max_files) = max_files.filter(|max| settings.dry_run && files.len() > *max) {
+        cli_eprintln!("Warning: Found {} files to upload, more than --max-files {}", files.len(), max_files);
+    }
+    Some((files, collected))
+}
+
+/// Walk the paths given, stopping at more than `max_files`. None if exclude
+/// patterns are invalid.
+async fn scan_files(files: Vec<UploadRequest>, recursive: bool, max_files: Option<usize>, settings: &Settings)
+    -> Option<inputs::Collected> {
+    // redrawn in place, which only makes sense on a terminal
+    let progress = (settings.scan_progress && recursive && std::io::stderr().is_terminal())
+        .then(|| Arc::new(inputs::ScanProgress::default()));
+    let filters = match filters(settings) {
+        Ok(filters) => Filters { archive: settings.archive_per_dir, progress: progress.clone(), max_files, ..filters },
+        Err(e) => {
+            cli_eprintln!("Invalid exclude pattern: {}", e);
+            return None;
+        }
+    };
+    let scan = tokio::task::spawn_blocking(move || inputs::collect(files, recursive, &filters));
+    Some(match progress {
+        Some(progress) => show_scan(&progress, scan, settings.redraw_interval).await,
+        None => scan.await.unwrap(),
+    })
+}
+
+/// Wait for `scan`, from [`SCAN_PROGRESS_DELAY`] on redrawing a line of what
+/// it found so far on stderr every `interval`, cleared once it's over.
+async fn show_scan(progress: &inputs::ScanProgress, scan: tokio::task::JoinHandle<inputs::Collected>,
+    interval: Duration) -> inputs::Collected {
+    tokio::pin!(scan);
+    let start = tokio::time::Instant::now() + SCAN_PROGRESS_DELAY;
+    let mut redraw = tokio::time::interval_at(start, interval.max(Duration::from_millis(100)));
+    // of the line drawn last, to blank it out
+    let mut width = 0;
+    let collected = loop {
+        tokio::select! {
+            collected = &mut scan => break collected.unwrap(),
+            _ = redraw.tick() => {
+                let line = progress.line();
+                cli_eprint!("\r{:<width$}", line, width = width);
+                width = line.chars().count();
+            }
+        }
+    };
+    if width > 0 {
+        cli_eprint!("\r{:width$}\r", "", width = width);
+    }
+    collected
+}
+
+/// Tell what a scan left out, and the problems it ran into.
+fn report_collected(collected: &inputs::Collected, settings: &Settings) {
+    for warning in &collected.warnings {
+        cli_eprintln!("Warning: {}", warning);
+    }
+    if collected.excluded > 0 {
+        cli_eprintln!("Excluded {} file(s) or directories matching exclude patterns or ignore files", collected.excluded);
+    }
+    if !collected.sparse.is_empty() {
+        let action = if settings.skip_sparse { "Skipped" } else { "Uploading" };
+        cli_eprintln!("{} {} sparse file(s), the fileservice stores holes as zeros:", action, collected.sparse.len());
+        for (path, size, allocated) in &collected.sparse {
+            cli_eprintln!("  {} ({:.2} MB, {:.2} MB on disk)", path,
+                *size as f64 / (1024.0 * 1024.0), *allocated as f64 / (1024.0 * 1024.0));
+        }
+    }
+    if !collected.skipped_links.is_empty() {
+        cli_eprintln!("Skipped {} symlink(s):", collected.skipped_links.len());
+        for (link, reason) in &collected.skipped_links {
+            cli_eprintln!("  {} ({})", link, reason);
+        }
+    }
+}
+
+/// How the local file differs from the `remote` one at `url`, by size and
+/// with `checksums` also by content, downloading at most `limit` files at
+/// once unless the fileservice lists their checksums.
+async fn compare_file(client: &Client, settings: &Settings, file: &Input, remote: &RemoteFile, url: &str,
+    checksums: bool, limit: &tokio::sync::Semaphore) -> Option<String> {
+    let remote_size = remote.size;
+    let local_size = match tokio::fs::metadata(&file.path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return Some(format!("can't read local file ({})", e)),
+    };
+    if local_size != remote_size {
+        return Some(format!("size differs (local {}, remote {})", local_size, remote_size));
+    }
+    if !checksums {
+        return None;
+    }
+    let _permit = limit.acquire().await.unwrap();
+    let local = checksum::get(&Checksum::default(), &file.path, settings.hash_cache.as_ref(), settings.nice).await;
+    match (local, remote::checksum(client, remote, url).await) {
+        (None, _) => Some("can't read local file".to_string()),
+        (_, Err(e)) => Some(format!("no remote checksum ({})", e)),
+        (Some(local), Ok(remote)) if local != remote => Some("content differs".to_string()),
+        _ => None,
+    }
+}
+
+/// Compare the files against what's on each destination, by size and with
+/// `checksums` also by content (downloading each file), without uploading
+/// anything. Returns whether all files match.
+pub async fn verify_many(files: Vec<String>, settings: Arc<Settings>, checksums: bool) -> bool {
+    let Some((files, _)) = collect_files(files, settings.recursive, None, &settings).await else { return false };
+    let client = match build_client(&settings) {
+        Ok(client) => client,
+        Err(e) => {
+            cli_eprintln!("Failed to set up http client: {}", e);
+            return false;
+        }
+    };
+    if !log_in(&client, &settings).await {
+        return false;
+    }
+    let depth = files.iter().map(|f| f.name.matches('/').count() + 1).max().unwrap_or(1);
+    let mut all_match = true;
+    for destination in 0..settings.destinations() {
+        let endpoints = settings.destination(destination);
+        let endpoint = endpoints.url(endpoints.current());
+        let path = settings.destination_path(destination);
+        let client = match destination_client(&client, &settings, destination) {
+            Ok(client) => client,
+            Err(e) => {
+                cli_eprintln!("Failed to set up http client for {}: {}", endpoint, e);
+                return false;
+            }
+        };
+        let tree_url = settings.api_url(endpoint, "jsonTree");
+        let remote = match remote::list(&client, &tree_url, path, depth, settings.listed_checksums).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                cli_eprintln!("Failed to list {} on {}: {}", path, endpoint, e);
+                return false;
+            }
+        };
+        let mut tasks = JoinSet::new();
+        let limit = Arc::new(tokio::sync::Semaphore::new(settings.concurrency));
+        for file in &files {
+            let listed = remote.get(&file.name).cloned();
+            let url = format!("{}/{}", settings.path_prefix(endpoint, path), file.name);
+            let (file, client, limit, settings) = (file.clone(), client.clone(), limit.clone(), settings.clone());
+            tasks.spawn(async move {
+                let problem = match listed {
+                    Some(listed) => compare_file(&client, &settings, &file, &listed, &url, checksums, &limit).await,
+                    None => Some("missing".to_string()),
+                };
+                (file, problem)
+            });
+        }
+        let mut problems: Vec<_> = tasks.join_all().await.into_iter()
+            .filter_map(|(file, problem)| Some((file, problem?)))
+            .collect();
+        problems.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+        if settings.destinations() > 1 {
+            cli_eprintln!("{}:", settings.destination_label(destination, endpoint));
+        }
+        if !problems.is_empty() {
+            cli_eprintln!("Verify Report:");
+        }
+        for (file, problem) in &problems {
+            cli_eprintln!("  {}: {} -> {}", problem, file.path, file.name);
+        }
+        cli_eprintln!("{} of {} files match, {} missing or different",
+            files.len() - problems.len(), files.len(), problems.len());
+        all_match &= problems.is_empty();
+    }
+    settings.save_hash_cache();
+    all_match
+}
+
+/// Compare the contents of the local directory `dir` with the destination
+/// path, listing files only there (`+`, would be uploaded), only on the
+/// fileservice (`-`) and differing (`~`) by size or with `checksums` by
+/// content. Returns whether there are no differences.
+pub async fn diff(dir: String, settings: Arc<Settings>, checksums: bool) -> bool {
+    let dir = format!("{}/", dir.trim_end_matches('/'));
+    let Some((files, _)) = collect_files(vec![dir], true, None, &settings).await else { return false };
+    let client = match build_client(&settings) {
+        Ok(client) => client,
+        Err(e) => {
+            cli_eprintln!("Failed to set up http client: {}", e);
+            return false;
+        }
+    };
+    if !log_in(&client, &settings).await {
+        return false;
+    }
+    let mut same = true;
+    for destination in 0..settings.destinations() {
+        let endpoints = settings.destination(destination);
+        let endpoint = endpoints.url(endpoints.current());
+        let url = settings.api_url(endpoint, "jsonTree");
+        let path = settings.destination_path(destination);
+        let client = match destination_client(&client, &settings, destination) {
+            Ok(client) => client,
+            Err(e) => {
+                cli_eprintln!("Failed to set up http client for {}: {}", endpoint, e);
+                return false;
+            }
+        };
+        let remote = match remote::list(&client, &url, path, remote::MAX_DEPTH, settings.listed_checksums).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                cli_eprintln!("Failed to list {} on {}: {}", path, endpoint, e);
+                return false;
+            }
+        };
+        let mut tasks = JoinSet::new();
+        let limit = Arc::new(tokio::sync::Semaphore::new(settings.concurrency));
+        for file in &files {
+            let listed = remote.get(&file.name).cloned();
+            let url = format!("{}/{}", settings.path_prefix(endpoint, path), file.name);
+            let (file, client, limit, settings) = (file.clone(), client.clone(), limit.clone(), settings.clone());
+            tasks.spawn(async move {
+                let line = match listed {
+                    Some(listed) => compare_file(&client, &settings, &file, &listed, &url, checksums, &limit).await
+                        .map(|problem| format!("~ {}: {}", file.name, problem)),
+                    None => Some(format!("+ {}", file.name)),
+                };
+                (file.name, line)
+            });
+        }
+        let local: std::collections::HashSet<_> = files.iter().map(|f| f.name.as_str()).collect();
+        let mut lines: Vec<_> = tasks.join_all().await.into_iter()
+            .filter_map(|(name, line)| Some((name, line?)))
+            .chain(remote.keys().filter(|name| !local.contains(name.as_str())).map(|name| (name.clone(), format!("- {}", name))))
+            .collect();
+        lines.sort();
+        if settings.destinations() > 1 {
+            cli_println!("{}:", settings.destination_label(destination, endpoint));
+        }
+        for (_, line) in &lines {
+            cli_println!("{}", line);
+        }
+        same &= lines.is_empty();
+    }
+    settings.save_hash_cache();
+    same
+}
+
+/// Sync the local directory `dir` and the destination path both ways, see
+/// [`sync`](crate::sync). Only the primary destination takes part. With
+/// `delete` remote files missing locally are deleted once `confirm` agrees to
+/// the list, else nothing is synced. Returns whether everything got in sync,
+/// without conflicts or errors.
+pub async fn sync(dir: String, settings: Arc<Settings>, delete: bool, confirm: impl FnOnce(&[String]) -> bool)
+    -> bool {
+    if settings.destinations() > 1 {
+        cli_eprintln!("Mirrors and copies can't be synced, sync each destination separately");
+        return false;
+    }
+    let root = PathBuf::from(&dir);
+    let contents = format!("{}/", dir.trim_end_matches('/'));
+    let collected = collect_files(vec![contents.clone()], true, settings.max_files, &settings).await;
+    let Some((files, collected)) = collected else { return false };
+    let files: Vec<_> = files.into_iter().filter(|f| f.name != sync::STATE_FILE).collect();
+    let local_files = |files: &[Input]| -> HashMap<String, sync::LocalFile> {
+        files.iter()
+            .filter_map(|f| {
+                let metadata = std::fs::metadata(&f.path).ok()?;
+                Some((f.name.clone(), sync::LocalFile { size: metadata.len(), modified: metadata.modified().ok() }))
+            })
+            .collect()
+    };
+    let Some(client) = connect(&settings).await else { return false };
+    let endpoint = settings.endpoints.url(settings.endpoints.current());
+    let tree_url = settings.api_url(endpoint, "jsonTree");
+    let listed = remote::list(&client, &tree_url, &settings.path, remote::MAX_DEPTH, settings.listed_checksums).await;
+    let remote_files = match listed {
+        Ok(remote) => remote,
+        Err(e) => {
+            cli_eprintln!("Failed to list {} on {}: {}", settings.path, endpoint, e);
+            return false;
+        }
+    };
+    let snapshot = sync::Snapshot::load(&root);
+    let actions = sync::plan(&local_files(&files), &remote_files, &snapshot, delete);
+    let Ok(filters) = filters(&settings) else { return false };
+    // remote files of what the filters leave out locally are left alone, be
+    // it excluded by name or only by size or time
+    let left_out = |name: &str| inputs::left_out(&filters, name) || root.join(name).symlink_metadata().is_ok();
+
+    let mut ok = true;
+    let mut uploads = Vec::new();
+    let mut downloads = Vec::new();
+    let mut deletes = Vec::new();
+    let mut conflicts = HashSet::new();
+    let mut left_alone = 0;
+    let by_name: HashMap<&str, &Input> = files.iter().map(|f| (f.name.as_str(), f)).collect();
+    let limit = Arc::new(tokio::sync::Semaphore::new(settings.concurrency));
+    // files changed on both sides are compared all at once, as far as the
+    // concurrency goes
+    let mut tasks = JoinSet::new();
+    for (name, _) in actions.iter().filter(|(_, action)| matches!(action, sync::Action::Compare)) {
+        let (Some(file), Some(listed)) = (by_name.get(name.as_str()), remote_files.get(name)) else { continue };
+        let url = format!("{}/{}", settings.prefix(endpoint), name);
+        let (name, file, listed) = (name.clone(), (*file).clone(), listed.clone());
+        let (client, limit, settings) = (client.clone(), limit.clone(), settings.clone());
+        tasks.spawn(async move {
+            let differs = compare_file(&client, &settings, &file, &listed, &url, true, &limit).await.is_some();
+            (name, differs)
+        });
+    }
+    let differing: HashSet<String> = tasks.join_all().await.into_iter()
+        .filter_map(|(name, differs)| differs.then_some(name))
+        .collect();
+    for (name, action) in actions {
+        let action = match action {
+            sync::Action::Compare if differing.contains(&name) => sync::Action::Conflict("changed on both sides"),
+            sync::Action::Compare => continue,
+            action => action,
+        };
+        match action {
+            sync::Action::Upload => uploads.extend(by_name.get(name.as_str()).map(|file| (*file).clone())),
+            sync::Action::Download if !remote::is_safe_name(&name) => {
+                cli_eprintln!("Not downloading {}, it would be written outside of {}", name, dir);
+                ok = false;
+            }
+            sync::Action::Download | sync::Action::Delete if left_out(&name) => left_alone += 1,
+            sync::Action::Download => downloads.push(name),
+            sync::Action::Delete => deletes.push(name),
+            sync::Action::Conflict(reason) => {
+                cli_eprintln!("Conflict, not synced: {} ({})", name, reason);
+                conflicts.insert(name);
+                ok = false;
+            }
+            // compared above
+            sync::Action::Compare => (),
+        }
+    }
+    if left_alone > 0 {
+        cli_eprintln!("Leaving alone {} remote file(s) the filters leave out locally", left_alone);
+    }
+    if delete {
+        cli_eprintln!("{} file(s) to upload, {} to delete", uploads.len(), deletes.len());
+    } else {
+        cli_eprintln!("{} file(s) to upload, {} to download", uploads.len(), downloads.len());
+    }
+    if settings.dry_run {
+        for file in &uploads {
+            cli_println!("upload {} -> {}", file.path, file.name);
+        }
+        for name in &downloads {
+            cli_println!("download {} -> {}", name, root.join(name).display());
+        }
+        for name in &deletes {
+            cli_println!("delete {}", name);
+        }
+        return ok;
+    }
+    if !deletes.is_empty() && !confirm(&deletes) {
+        cli_eprintln!("Not confirmed, nothing synced");
+        return false;
+    }
+
+    let mut tasks = JoinSet::new();
+    for name in downloads {
+        let url = format!("{}/{}", settings.prefix(endpoint), name);
+        let dest = root.join(&name);
+        let modified = remote_files.get(&name).and_then(sync::remote_modified);
+        let (client, limit) = (client.clone(), limit.clone());
+        tasks.spawn(async move {
+            let _permit = limit.acquire().await.unwrap();
+            let result = remote::download(&client, &url, &dest).await;
+            if let (Ok(()), Some(modified)) = (&result, modified) {
+                let _ = sync::set_modified(&dest, modified);
+            }
+            (name, result)
+        });
+    }
+    for (name, result) in tasks.join_all().await {
+        if let Err(e) = result {
+            cli_eprintln!("Failed to download {}: {}", name, e);
+            ok = false;
+        }
+    }
+    if !uploads.is_empty() && !upload_inputs(&client, uploads, &collected, settings.clone(), None, None).await {
+        ok = false;
+    }
+    // only once everything local is up there
+    let data_url = format!("{}/{}", settings.api_url(endpoint, "data"), settings.path.trim_matches('/'));
+    let mut tasks = JoinSet::new();
+    for name in deletes {
+        let url = format!("{}/{}", data_url, name);
+        let (client, limit) = (client.clone(), limit.clone());
+        tasks.spawn(async move {
+            let _permit = limit.acquire().await.unwrap();
+            (name, remote::delete(&client, &url).await)
+        });
+    }
+    let mut deleted = 0;
+    for (name, result) in tasks.join_all().await {
+        match result {
+            Ok(()) => deleted += 1,
+            Err(e) => {
+                cli_eprintln!("Failed to delete {}: {}", name, e);
+                ok = false;
+            }
+        }
+    }
+    if delete {
+        cli_eprintln!("Deleted {} remote file(s)", deleted);
+    }
+
+    // what's the same on both sides now is the base of the next sync
+    let Some((files, _)) = collect_files(vec![contents], true, None, &settings).await else { return false };
+    match remote::list(&client, &tree_url, &settings.path, remote::MAX_DEPTH, settings.listed_checksums).await {
+        Ok(remote_files) => {
+            if let Err(e) = snapshot.save(&root, &local_files(&files), &remote_files, &conflicts) {
+                cli_eprintln!("Failed to save sync state in {}: {}", dir, e);
+            }
+        }
+        Err(e) => cli_eprintln!("Failed to list {} after syncing, sync state not saved: {}", settings.path, e),
+    }
+    ok
+}
+
+/// Upload many files concurrently, those of higher priority first (paths
+/// given as strings have priority 0).
+pub async fn upload_many(files: Vec<impl Into<UploadRequest>>, settings: Arc<Settings>) {
+    if settings.streams() {
+        match settings.stream_conflict() {
+            Some(option) => cli_eprintln!("--stream can't be combined with {}, it needs all files found first", option),
+            None => upload_streaming(files, settings).await,
+        }
+        return;
+    }
+    let (files, collected) = match settings.job.as_ref().and_then(|job| job.plan()) {
+        Some(plan) => (plan.to_vec(), inputs::Collected::default()),
+        None => match collect_files(files, settings.recursive, settings.max_files, &settings).await {
+            Some(collected) => collected,
+            None => return,
+        },
+    };
+    if settings.dry_run {
+        write_dry_run(&files, &collected, &settings);
+        return;
+    }
+    if files.is_empty() {
+        cli_eprintln!("No files to upload{}.", UploadProgress::skipped(collected.filtered_size, collected.filtered_time));
+        return;
+    }
+    if let Some(job) = &settings.job
+        && job.plan().is_none()
+        && let Err(e) = job.save_plan(&files, &settings.path, settings.destinations()) {
+        cli_eprintln!("Warning: failed to save the plan of job {}, it can't be resumed: {}", job.id(), e);
+    }
+    let Some(client) = connect(&settings).await else { return };
+    upload_inputs(&client, files, &collected, settings, None, None).await;
+}
+
+/// Which of the destination limits an upload as `name` counts against, the
+/// one of the longest path it's under.
+fn destination_limit(settings: &Settings, name: &str) -> Option<usize> {
+    let path = format!("{}/{}", settings.path.trim_matches('/'), name);
+    settings.destination_limits.iter().enumerate()
+        .map(|(index, (dir, _))| (index, dir.trim_matches('/')))
+        .filter(|(_, dir)| path.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/')))
+        .max_by_key(|(_, dir)| dir.len())
+        .map(|(index, _)| index)
+}
+
+/// Whether uploads of a file to several destinations read it once, where
+/// it's small enough. Encrypted uploads are each encrypted on their own.
+fn shares_contents(settings: &Settings) -> bool {
+    settings.destinations() > 1 && settings.encryption.is_none()
+}
+
+/// Whether to go on after a scan found more than `max_files` files, if the
+/// [`Settings::with_confirm`] callback agrees.
+fn confirm_max_files(max_files: usize, settings: &Settings) -> bool {
+    let found = format!("Found more than --max-files {} files to upload", max_files);
+    if settings.confirm.as_ref().is_some_and(|confirm| confirm(&format!("{}. Upload them all?", found))) {
+        return true;
+    }
+    cli_eprintln!("{}, not uploading anything. Check the paths, or raise --max-files if that many are meant", found);
+    false
+}
+
+/// Upload files as a scan of them finds them, see [`Settings::with_stream`].
+async fn upload_streaming(files: Vec<impl Into<UploadRequest>>, settings: Arc<Settings>) {
+    let filters = match filters(&settings) {
+        Ok(filters) => filters,
+        Err(e) => {
+            cli_eprintln!("Invalid exclude pattern: {}", e);
+            return;
+        }
+    };
+    let Some(client) = connect(&settings).await else { return };
+    let files: Vec<UploadRequest> = files.into_iter().map(Into::into).collect();
+    let (sender, found) = tokio::sync::mpsc::channel(SCAN_BUFFER);
+    let recursive = settings.recursive;
+    let done = tokio::task::spawn_blocking(move || {
+        // the scan stops once nobody takes more files
+        inputs::collect_streaming(files, recursive, &filters, &|input: Input| {
+            let key = std::fs::canonicalize(&input.path).unwrap_or_else(|_| PathBuf::from(&input.path));
+            let metadata = std::fs::metadata(&input.path).ok();
+            sender.blocking_send(Found { input, key, metadata }).is_ok()
+        })
+    });
+    let scan = Scan { found, done };
+    upload_inputs(&client, Vec::new(), &inputs::Collected::default(), settings, None, Some(scan)).await;
+}
+
+/// Log in on every destination with the login of `settings`, if any.
+/// Whether that went fine.
+async fn log_in(client: &Client, settings: &Settings) -> bool {
+    for destination in 0..settings.destinations() {
+        let endpoints = settings.destination(destination);
+        // uploads to a mirror without a client fail, as told then
+        let Ok(client) = destination_client(client, settings, destination) else { continue };
+        if let Some(Err(e)) = settings.log_in(&client, endpoints.url(endpoints.current())).await {
+            cli_eprintln!("Failed to log in: {}", e);
+            return false;
+        }
+    }
+    true
+}
+
+/// A client for the fileservice, once the destination path checks out on
+/// every destination.
+async fn connect(settings: &Settings) -> Option<Client> {
+    let client = match build_client(settings) {
+        Ok(client) => client,
+        Err(e) => {
+            cli_eprintln!("Failed to set up http client: {}", e);
+            return None;
+        }
+    };
+    if !log_in(&client, settings).await || !check_destinations(&client, settings).await {
+        return None;
+    }
+    Some(client)
+}
+
+/// Whether the destination path of `settings` checks out against the
+/// volumes api on every destination, telling why not.
+async fn check_destinations(client: &Client, settings: &Settings) -> bool {
+    // tables go to a context, not onto a volume
+    for destination in (0..settings.destinations()).filter(|_| !settings.casjobs) {
+        let endpoints = settings.destination(destination);
+        let endpoint = endpoints.url(endpoints.current());
+        let path = settings.destination_path(destination);
+        // uploads to a mirror without a client fail, as told then
+        let Ok(client) = destination_client(client, settings, destination) else { continue };
+        if let Err(e) = volumes::preflight(&client, &settings.api_url(endpoint, "volumes"), path).await {
+            cli_eprintln!("Invalid destination {} at {}: {}", path, endpoint, e);
+            return false;
+        }
+    }
+    true
+}
+
+/// files a scan is still finding, to upload during it
+struct Scan {
+    found: tokio::sync::mpsc::Receiver<Found>,
+    /// what it left out, once it's over
+    done: tokio::task::JoinHandle<inputs::Collected>,
+}
+
+/// a file a scan found, looked up on the scan's thread
+struct Found {
+    input: Input,
+    /// canonical path, to tell files given twice
+    key: PathBuf,
+    metadata: Option<std::fs::Metadata>,
+}
+
+/// metadata of the files that can be read
+async fn describe_files(files: Vec<Input>, xattrs: bool) -> Vec<(Input, serde_json::Value)> {
+    tokio::task::spawn_blocking(move || {
+        files.into_iter()
+            .filter_map(|f| match metadata::describe(&f.path, xattrs) {
+                Ok(value) => Some((f, value)),
+                Err(e) => {
+                    cli_eprintln!("Failed to read metadata of {}: {}", f.path, e);
+                    None
+                }
+            })
+            .collect()
+    }).await.unwrap()
+}
+
+/// Upload metadata of the files on each destination, those that didn't fail
+/// to upload in this run.
+async fn upload_metadata(client: &Client, mode: MetadataMode, described: &[(Input, serde_json::Value)],
+    completed: &[UploadInfo], settings: &Settings) {
+    let failed: HashSet<_> = completed.iter()
+        .filter(|i| i.error.is_some())
+        .map(|i| (i.destination, i.path.as_str()))
+        .collect();
+    let limit = Arc::new(tokio::sync::Semaphore::new(settings.concurrency));
+    let mut tasks = JoinSet::new();
+    for destination in 0..settings.destinations() {
+        let endpoints = settings.destination(destination);
+        let prefix = settings.path_prefix(endpoints.url(endpoints.current()), settings.destination_path(destination));
+        // its uploads all failed, with nothing to describe
+        let Ok(client) = destination_client(client, settings, destination) else { continue };
+        let files = described.iter()
+            .filter(|(f, _)| !failed.contains(&(destination, f.path.as_str())))
+            .map(|(f, value)| (settings.remote_name(&f.name), value.clone()))
+            .collect();
+        for (name, document) in metadata::documents(mode, files) {
+            let url = format!("{}/{}", prefix, name);
+            let (client, limit) = (client.clone(), limit.clone());
+            tasks.spawn(async move {
+                let _permit = limit.acquire().await.unwrap();
+                // a manifest of earlier runs keeps the files they uploaded
+                let document = match mode {
+                    MetadataMode::Manifest => match client.get(&url).send().await {
+                        Ok(r) if r.status() == StatusCode::NOT_FOUND => document,
+                        Ok(r) if r.status() == StatusCode::OK => {
+                            let existing = r.text().await.map_err(|e| e.to_string());
+                            match existing.and_then(|existing| metadata::merge(&existing, &document)) {
+                                Ok(merged) => merged,
+                                Err(e) => return (url, Err(format!("can't merge with the existing one: {}", e))),
+                            }
+                        }
+                        Ok(r) => return (url, Err(format!("can't read the existing one: {}", r.status()))),
+                        Err(e) => return (url, Err(format!("can't read the existing one: {}", e))),
+                    },
+                    MetadataMode::Sidecar => document,
+                };
+                let result = client.put(format!("{}?quiet=true", url)).body(document).send().await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| e.to_string());
+                (url, result)
+            });
+        }
+    }
+    for (url, result) in tasks.join_all().await {
+        if let Err(e) = result {
+            cli_eprintln!("Failed to upload metadata {}: {}", url, e);
+        }
+    }
+}
+
+/// Upload or POST the manifest of the files uploaded in a run that started
+/// at `started`, or found there already, to each destination if uploaded.
+async fn register_dataset(client: &Client, target: &ManifestTarget, completed: &[UploadInfo], started: SystemTime,
+    settings: &Settings) {
+    let mut files: Vec<_> = completed.iter()
+        .filter(|i| matches!(i.error, None | Some(ErrorKind::FileExists)))
+        .map(|i| dataset::Entry { name: &i.name, size: i.bytes, sha256: i.checksum.as_deref() })
+        .collect();
+    // each file once when mirroring
+    files.sort_by_key(|f| f.name);
+    files.dedup_by_key(|f| f.name);
+    let job = settings.job.as_ref().map(|job| job.id());
+    let manifest = dataset::manifest(&settings.path, job, started, &files);
+    let requests: Vec<_> = match target {
+        // the catalog is no fileservice, it gets neither the token nor the
+        // session cookies
+        ManifestTarget::Post(url) => {
+            let anonymous = Settings { token: String::new(), session: None, ..settings.clone() };
+            match build_client(&anonymous) {
+                Ok(client) => vec![client.post(url).json(&manifest)],
+                Err(e) => {
+                    cli_eprintln!("Failed to register the dataset: {}", e);
+                    return;
+                }
+            }
+        }
+        ManifestTarget::Upload(name) => (0..settings.destinations())
+            .filter_map(|destination| {
+                let endpoints = settings.destination(destination);
+                let prefix = settings.path_prefix(endpoints.url(endpoints.current()), settings.destination_path(destination));
+                let url = format!("{}/{}?quiet=true", prefix, name);
+                let document = serde_json::to_string_pretty(&manifest).unwrap_or_default() + "\n";
+                let client = destination_client(client, settings, destination)
+                    .inspect_err(|e| cli_eprintln!("Failed to register the dataset at {}: {}", prefix, e))
+                    .ok()?;
+                Some(client.put(url).body(document))
+            })
+            .collect(),
+    };
+    for request in requests {
+        match send_retrying(request, settings.retries).await {
+            Ok(()) => cli_eprintln!("Registered the dataset of {} file(s)", files.len()),
+            Err(e) => cli_eprintln!("Failed to register the dataset: {}", e),
+        }
+    }
+}
+
+/// Send `request` up to `retries` times while it gets no response or a 429
+/// or 5xx, backing off in between. The error of the last attempt otherwise.
+async fn send_retrying(request: reqwest::RequestBuilder, retries: usize) -> Result<(), String> {
+    let mut number = 0;
+    loop {
+        number += 1;
+        let Some(attempt) = request.try_clone() else { return Err("can't resend a streamed body".to_string()) };
+        let (failure, delay) = match attempt.send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                let again = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+                (status.to_string(), if again { TransportError::Other.retry_delay(number, 0.5) } else { None })
+            }
+            Err(e) => {
+                let transport = TransportError::classify(&e);
+                (transport.describe().to_string(), transport.retry_delay(number, 0.5))
+            }
+        };
+        match delay {
+            Some(delay) if number < retries => tokio::time::sleep(delay).await,
+            _ => return Err(failure),
+        }
+    }
+}
+
+/// Write the local paths of the files `left` over by the limits of a run
+/// without a job, one per line, next to the report as `<report>.left` if
+/// there is one, else into the state directory. Where they went.
+fn write_left(left: &[&Job], settings: &Settings) -> std::io::Result<PathBuf> {
+    let path = match &settings.report {
+        Some(report) => {
+            let mut path = report.clone().into_os_string();
+            path.push(".left");
+            PathBuf::from(path)
+        }
+        None => {
+            let dir = jobs::default_state_dir();
+            std::fs::create_dir_all(&dir)?;
+            let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+            dir.join(format!("left-{}-{}", started.as_secs(), std::process::id()))
+        }
+    };
+    // each file once, however many destinations it's left for
+    let mut listed = HashSet::new();
+    let lines: String = left.iter()
+        .filter(|job| listed.insert(&job.input.path))
+        .map(|job| format!("{}\n", job.input.path))
+        .collect();
+    std::fs::write(&path, lines)?;
+    Ok(path)
+}
+
+/// Upload the files to each destination, skipping those a resumed job did
+/// already, reporting progress and errors as it goes, to `observer` instead
+/// of the status bar if given. Files a `scan` finds are uploaded after them.
+/// Returns whether all uploads succeeded, or were left out as duplicates.
+async fn upload_inputs(client: &Client, files: Vec<Input>, collected: &inputs::Collected, settings: Arc<Settings>,
+    observer: Option<serve::Observer>, mut scan: Option<Scan>) -> bool {
+    // tables are named after files, several loading into one would mix them
+    let collisions = casjobs::collisions(files.iter().map(|f| f.name.as_str()).filter(|_| settings.casjobs));
+    if !collisions.is_empty() {
+        for (table, names) in &collisions {
+            cli_eprintln!("Files {} would all load into table {}, rename all but one", names.join(", "), table);
+        }
+        return false;
+    }
+    // each file is a separate upload per destination
+    let destinations = settings.destinations();
+    let needs_sizes = settings.large_concurrency.is_some() || settings.max_memory.is_some()
+        || settings.max_total_bytes.is_some() || settings.order.needs_sizes();
+    // as of the scan, to tell files changing before their upload is done;
+    // unreadable files get size 0, they are reported once attempted
+    let mut files: Vec<((Input, Option<Stamp>), u64)> = tokio::task::spawn_blocking(move || {
+        files.into_iter()
+            .map(|f| {
+                let stamp = std::fs::metadata(&f.path).ok().map(|m| stamp(&m));
+                let size = stamp.filter(|_| needs_sizes).map_or(0, |(size, _)| size);
+                ((f, stamp), size)
+            })
+            .collect()
+    }).await.unwrap();
+    settings.order.sort(&mut files, &mut random::rng(settings.seed, "order"));
+    // stable, so files of the same priority stay in that order
+    files.sort_by_key(|((file, _), _)| std::cmp::Reverse(file.priority));
+    // before --move deletes any of them
+    let described = match settings.metadata {
+        Some(_) => describe_files(files.iter().map(|((file, _), _)| file.clone()).collect(), settings.xattrs).await,
+        None => Vec::new(),
+    };
+    // FIFOs are hashed as they upload, reading them ahead would consume them,
+    // and archives aren't hashed
+    let hashes = settings.checksums.is_some() || settings.content_index.is_some();
+    let hashed = |metadata: Option<&std::fs::Metadata>| hashes
+        && !metadata.is_some_and(|m| body::is_fifo(m) || m.is_dir());
+    let checksums: Vec<_> = files.iter()
+        .map(|((file, _), _)| {
+            hashed(std::fs::metadata(&file.path).ok().as_ref()).then(|| (file.path.clone(), Checksum::default()))
+        })
+        .collect();
+    let planned = files.len() * destinations;
+    let jobs: Vec<_> = files.into_iter().zip(&checksums)
+        .flat_map(|(((file, stamp), size), checksum)| {
+            let contents = shares_contents(&settings).then(SharedContents::default);
+            (0..destinations).map(move |destination| {
+                let checksum = checksum.as_ref().map(|(_, c)| c.clone());
+                Job { input: file.clone(), destination, size, stamp, checksum, contents: contents.clone() }
+            })
+        })
+        .filter(|job| !settings.job.as_ref().is_some_and(|j| j.is_done(job.destination, &job.input.path)))
+        .collect();
+    if let Some(job) = settings.job.as_ref().filter(|j| j.plan().is_some()) {
+        cli_eprintln!("Resuming job {}, {} of {} uploads done earlier", job.id(), planned - jobs.len(), planned);
+    }
+    let (jobs, skipped): (Vec<_>, Vec<_>) = jobs.into_iter()
+        .partition(|job| !settings.in_skipped_report(job.destination, &job.input, job.stamp));
+    if !skipped.is_empty() {
+        cli_eprintln!("Skipping {} upload(s) done in an earlier run's report", skipped.len());
+    }
+    // uploads left of each file, it's deleted when they all succeeded
+    let mut pending: HashMap<String, usize> = HashMap::new();
+    if settings.move_files {
+        for job in &jobs {
+            *pending.entry(job.input.path.clone()).or_default() += 1;
+        }
+    }
+    let mut moved = 0;
+    let mut progress = UploadProgress::new(jobs.len());
+    progress.redraw_interval = settings.redraw_interval;
+    progress.colors = settings.color.resolve();
+    progress.top_slowest = settings.top_slowest;
+    progress.observer = observer;
+    progress.notify();
+    progress.socket = settings.progress_socket.as_ref().and_then(|path| {
+        events::ProgressSocket::bind(path)
+            .inspect_err(|e| cli_eprintln!("Warning: can't stream progress to {}: {}", path.display(), e))
+            .ok()
+    });
+    if let Some(socket) = &progress.socket {
+        socket.send(&progress.counts("progress"));
+    }
+    progress.porcelain = settings.porcelain.filter(|_| progress.observer.is_none());
+    if let Some(porcelain) = progress.porcelain {
+        cli_println!("{}", porcelain.header());
+    }
+    progress.n_filtered_size = collected.filtered_size;
+    progress.n_filtered_time = collected.filtered_time;
+    progress.scanning = scan.is_some();
+    // of files found by the scan: all for the job's plan, the paths to tell
+    // repeated ones, and those left out
+    let (mut plan, mut seen, mut duplicates, mut skipped) = (Vec::new(), HashSet::new(), Vec::new(), skipped.len());
+    // files still being written are hashed once they stopped changing
+    let ahead = if settings.stable_window.is_some() { Vec::new() } else { checksums.into_iter().flatten().collect() };
+    // hashes upcoming files while earlier ones upload, stops when dropped
+    let _hashing = checksum::precompute(ahead, settings.hash_cache.clone(), settings.nice);
+    let concurrency = |c| if settings.nice { nice::concurrency(c) } else { c };
+    let mut scheduler = match settings.large_concurrency {
+        Some(large_concurrency) => {
+            Scheduler::by_size(jobs, concurrency(settings.concurrency), settings.large_threshold,
+                concurrency(large_concurrency))
+        }
+        None => Scheduler::new(jobs, concurrency(settings.concurrency)),
+    };
+    if let Some(max_memory) = settings.max_memory {
+        scheduler = scheduler.with_memory_limit(max_memory, settings.upload_buffer());
+    }
+    if let Some(max_total_bytes) = settings.max_total_bytes {
+        scheduler = scheduler.with_byte_limit(max_total_bytes);
+    }
+    if let Some(target_rate) = settings.target_rate {
+        scheduler = scheduler.with_rate_target(target_rate, progress.sent.get());
+    }
+    if let Some(ramp_up) = settings.ramp_up {
+        scheduler = scheduler.with_ramp_up(ramp_up);
+    }
+    // uploads to each destination go to its path
+    let targets: Vec<_> = (0..destinations).map(|destination| Arc::new(settings.at_destination(destination))).collect();
+    // mirrors refusing their token, the rest of their uploads fail right away
+    let refused = std::sync::Mutex::new(HashSet::new());
+    // with their own tokens on mirrors that have one, those it can't be set
+    // up for are refused from the start
+    let clients: Vec<_> = (0..destinations)
+        .map(|destination| {
+            destination_client(client, &settings, destination)
+                .inspect_err(|e| {
+                    let endpoints = settings.destination(destination);
+                    cli_eprintln!("{}", paint(progress.colors.stderr, color::RED, format!(
+                        "Failed to set up http client for mirror {}: {}, not uploading there",
+                        endpoints.url(endpoints.current()), e)));
+                    refused.lock().unwrap().insert(destination);
+                })
+                .ok()
+        })
+        .collect();
+    if !settings.destination_limits.is_empty() {
+        let limits = settings.destination_limits.iter().map(|(_, limit)| concurrency(*limit)).collect();
+        let targets = targets.clone();
+        scheduler = scheduler.with_lanes(limits, move |job| {
+            destination_limit(&targets[job.destination], &job.input.name)
+        });
+    }
+    let queued = Instant::now();
+    let mut tasks = JoinSet::new();
+    // pool and size of each running task, to free its slot even if the task
+    // panicked
+    let mut pools = HashMap::new();
+    let (outage, sent) = (progress.outage.clone(), progress.sent.clone());
+    let console = progress.observer.is_none() && progress.porcelain.is_none();
+    let _keepalive = settings.keepalive.filter(|_| !settings.casjobs)
+        .map(|interval| keepalive::Keepalive::start(client.clone(), settings.clone(), sent.clone(), interval, console));
+    // Start as many tasks as the pool limits allow, then feed in new tasks as
+    // they complete to keep within the limits.
+    let spawn = |tasks: &mut JoinSet<UploadInfo>, pools: &mut HashMap<_, _>, scheduler: &mut Scheduler| {
+        scheduler.adjust(sent.get());
+        if let Some((limit, max)) = scheduler.ramp_step().filter(|_| settings.verbose && console) {
+            cli_eprintln!("\nRamping up: {} of {} upload(s) at once", limit, max);
+        }
+        while let Some((pool, job)) = scheduler.next() {
+            let size = job.size;
+            let settings = targets[job.destination].clone();
+            let client = clients[job.destination].clone().filter(|_| !refused.lock().unwrap().contains(&job.destination));
+            let task = match client {
+                Some(client) => tasks.spawn(upload_file(client, job, settings, queued, outage.clone(), sent.clone())),
+                None => {
+                    let mut info = UploadInfo::new(job.input.path.clone());
+                    (info.destination, info.name) = (job.destination, job.input.name);
+                    tasks.spawn(async move { info.with_error(ErrorKind::Unauthorized) })
+                }
+            };
+            pools.insert(task.id(), (pool, size));
+        }
+    };
+    // uploads only start within the window, if any
+    let window_open = || settings.window.is_none_or(|w| w.until_open(TimeWindow::now()).is_zero());
+    // main loop, will run into complete or stopped early due to unrecoverable
+    // error, feeding in new files as each upload completes. Progress updates
+    // emitted with each completed upload.
+    // redrawn now and then even if nothing completes, so elapsed time and
+    // speed keep moving during long uploads
+    let mut heartbeat = tokio::time::interval(PROGRESS_HEARTBEAT);
+    let mut rate_tick = tokio::time::interval_at(tokio::time::Instant::now() + RATE_INTERVAL, RATE_INTERVAL);
+    // no more uploads start after it, running ones are finished
+    let stop_at = settings.max_duration.map(|duration| tokio::time::Instant::now() + duration);
+    let stop = async {
+        match stop_at {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(stop);
+    let mut timed_out = false;
+    loop {
+        if !progress.scanning && let Some(scan) = scan.take() {
+            let collected = scan.done.await.unwrap();
+            if progress.observer.is_none() && progress.porcelain.is_none() {
+                cli_eprintln!();
+            }
+            report_collected(&collected, &settings);
+            if !duplicates.is_empty() {
+                cli_eprintln!("Ignored {} duplicate input(s):", duplicates.len());
+                for path in &duplicates {
+                    cli_eprintln!("  {}", path);
+                }
+            }
+            if skipped > 0 {
+                cli_eprintln!("Skipped {} upload(s) done in an earlier run's report", skipped);
+            }
+            if let Some(job) = &settings.job
+                && let Err(e) = job.save_plan(&plan, &settings.path, destinations) {
+                cli_eprintln!("Warning: failed to save the plan of job {}, it can't be resumed: {}", job.id(), e);
+            }
+            progress.n_filtered_size = collected.filtered_size;
+            progress.n_filtered_time = collected.filtered_time;
+        }
+        if tasks.is_empty() {
+            // outside the window once running uploads finished, wait for it
+            if let Some(window) = settings.window.filter(|_| !scheduler.is_empty()) {
+                let mut wait = window.until_open(TimeWindow::now());
+                if !wait.is_zero() {
+                    let wait = humantime::format_duration(Duration::from_secs(wait.as_secs()));
+                    cli_eprintln!("\nOutside the upload window {}, waiting {} for it to open", window, wait);
+                }
+                while !wait.is_zero() {
+                    if until(stop_at, tokio::time::sleep(wait)).await.is_none() {
+                        timed_out = true;
+                        scheduler.stop();
+                        break;
+                    }
+                    wait = window.until_open(TimeWindow::now());
+                }
+            }
+            spawn(&mut tasks, &mut pools, &mut scheduler);
+            // held back by the rate target, the next adjustment resumes
+            if tasks.is_empty() && !scheduler.is_paused() && scan.is_none() {
+                break;
+            }
+        }
+        let found = async {
+            match &mut scan {
+                Some(scan) => scan.found.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        let result = tokio::select! {
+            result = tasks.join_next_with_id(), if !tasks.is_empty() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                progress.redraw(true);
+                // the window may have opened while uploads ran
+                if settings.window.is_some() && window_open() {
+                    spawn(&mut tasks, &mut pools, &mut scheduler);
+                }
+                continue;
+            }
+            found = found, if progress.scanning => {
+                let Some(Found { input, key, metadata }) = found else {
+                    progress.scanning = false;
+                    continue;
+                };
+                if !seen.insert(key) {
+                    duplicates.push(input.path);
+                    continue;
+                }
+                if settings.job.is_some() {
+                    plan.push(input.clone());
+                }
+                let stamp = metadata.as_ref().map(stamp);
+                let size = stamp.filter(|_| needs_sizes).map_or(0, |(size, _)| size);
+                let checksum = hashed(metadata.as_ref()).then(Checksum::default);
+                let contents = shares_contents(&settings).then(SharedContents::default);
+                for destination in 0..destinations {
+                    if settings.in_skipped_report(destination, &input, stamp) {
+                        skipped += 1;
+                        continue;
+                    }
+                    if settings.move_files {
+                        *pending.entry(input.path.clone()).or_default() += 1;
+                    }
+                    progress.n_total += 1;
+                    let contents = contents.clone();
+                    let checksum = checksum.clone();
+                    scheduler.push(Job { input: input.clone(), destination, size, stamp, checksum, contents });
+                }
+                if window_open() {
+                    spawn(&mut tasks, &mut pools, &mut scheduler);
+                }
+                continue;
+            }
+            _ = &mut stop, if !timed_out && (progress.scanning || !scheduler.is_empty()) => {
+                timed_out = true;
+                scheduler.stop();
+                // dropped, the scan stops, what it didn't get to isn't uploaded either
+                let scanning = scan.take().is_some();
+                progress.scanning = false;
+                if progress.observer.is_none() && progress.porcelain.is_none() {
+                    let scan = if scanning { "stopping the scan and " } else { "" };
+                    cli_eprintln!("\nReached the time limit, {}finishing {} running upload(s)", scan, tasks.len());
+                }
+                continue;
+            }
+            // retuned to the rate target or ramping up, more may start
+            _ = rate_tick.tick(), if scheduler.has_rate_target() || scheduler.is_ramping() => {
+                if window_open() {
+                    spawn(&mut tasks, &mut pools, &mut scheduler);
+                }
+                continue;
+            }
+        };
+        match result {
+            Ok((id, info)) => {
+                let (pool, size) = pools.remove(&id).unwrap();
+                scheduler.finished(pool, size);
+                // a mirror refusing its token only stops uploading there
+                if matches!(info.error, Some(ErrorKind::Unauthorized)) && settings.is_mirror(info.destination)
+                    && refused.lock().unwrap().insert(info.destination) {
+                    let endpoints = settings.destination(info.destination);
+                    cli_eprintln!("\n{}", paint(progress.colors.stderr, color::RED, format!(
+                        "Unauthorized at mirror {}: check its token, not uploading there anymore",
+                        endpoints.url(endpoints.current()))));
+                }
+                // Early stoppage since unath is expected to cause errors in all
+                // other uploads using the same token.
+                if let Some(error @ (ErrorKind::Unauthorized | ErrorKind::Aborted)) = &info.error
+                    && !settings.is_mirror(info.destination) {
+                    let message = match error {
+                        ErrorKind::Unauthorized => "Unauthorized: Check your token.",
+                        _ => "Aborted by the retry policy.",
+                    };
+                    cli_eprintln!("\n{}", paint(progress.colors.stderr, color::RED, message));
+                    progress.write_summary();
+                    progress.write_error_report(&settings);
+                    progress.write_endpoint_report(&settings.endpoints);
+                    progress.write_failure_report();
+                    progress.write_destination_report(&settings);
+                    progress.write_report(&settings);
+                    progress.finish();
+                    return false;
+                }
+                if info.error.is_none()
+                    && let Some(job) = &settings.job
+                    && let Err(e) = job.record_done(info.destination, &info.path) {
+                    cli_eprintln!("\nWarning: failed to record progress of job {}: {}", job.id(), e);
+                }
+                if info.error.is_none()
+                    && let (Some(index), Some(sha256)) = (&settings.content_index, &info.checksum) {
+                    let endpoint = settings.destination(info.destination).url(info.endpoint.unwrap_or(0));
+                    let path = settings.destination_path(info.destination).trim_matches('/');
+                    let path = format!("{}/{}", path, settings.remote_name(&info.name));
+                    if let Err(e) = index.record(endpoint, sha256, &path) {
+                        cli_eprintln!("\nWarning: failed to record contents in {}: {}", index.path().display(), e);
+                    }
+                }
+                if info.error.is_some() {
+                    pending.remove(&info.path);
+                } else if let Some(left) = pending.get_mut(&info.path) {
+                    *left -= 1;
+                    if *left == 0 {
+                        pending.remove(&info.path);
+                        match tokio::fs::remove_file(&info.path).await {
+                            Ok(()) => moved += 1,
+                            Err(e) => cli_eprintln!("\nWarning: failed to delete {} after uploading it: {}", info.path, e),
+                        }
+                    }
+                }
+                // TODO: could also stop if the error rate after some point is too high
+                progress.update(info, true);
+            },
+            Err(e) => {
+                let (pool, size) = pools.remove(&e.id()).unwrap();
+                scheduler.finished(pool, size);
+                cli_eprintln!("Unexpected Join Error: {:?}", e);
+            }
+        }
+        if window_open() {
+            spawn(&mut tasks, &mut pools, &mut scheduler);
+        }
+    }
+    if progress.observer.is_none() && progress.porcelain.is_none() {
+        progress.redraw(true);
+        cli_println!();
+    }
+    let left: Vec<_> = scheduler.left().collect();
+    if !left.is_empty() {
+        let resume = match &settings.job {
+            Some(job) => format!(", continue with: upload resume {}", job.id()),
+            None => match write_left(&left, &settings) {
+                Ok(path) => format!(", listed in {}", path.display()),
+                Err(e) => format!(", failed to list them: {}", e),
+            },
+        };
+        let limit = if timed_out { "time limit of the run" } else { "limit of bytes per run" };
+        let left_bytes: u64 = left.iter().map(|job| job.size).sum();
+        cli_eprintln!("Reached the {}, {} upload(s) of {:.2} MB left{}", limit, left.len(),
+            left_bytes as f64 / (1024.0 * 1024.0), resume);
+    }
+    progress.write_summary();
+    if let Some(mode) = settings.metadata {
+        upload_metadata(client, mode, &described, &progress.completed, &settings).await;
+    }
+    if let Some(target) = &settings.dataset_manifest
+        && progress.n_successes + progress.n_exists == progress.n_total {
+        let started = SystemTime::now() - progress.timer.elapsed();
+        register_dataset(client, target, &progress.completed, started, &settings).await;
+    }
+    if settings.move_files {
+        cli_eprintln!("Deleted {} uploaded local file(s)", moved);
+    }
+    progress.write_error_report(&settings);
+    progress.write_endpoint_report(&settings.endpoints);
+    progress.write_failure_report();
+    progress.write_destination_report(&settings);
+    progress.write_checksums(&settings);
+    progress.write_report(&settings);
+    progress.finish();
+    settings.save_hash_cache();
+    // duplicates left out are as good as uploaded
+    progress.n_successes + progress.n_duplicates == progress.n_total
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
     fn test_progress_bar() {
@@ -284,18 +3649,1027 @@ mod tests {
         assert!(status.starts_with("Uploaded 3/10 files, 1 errors 1|2 retries 0.00 MB"));
     }
 
+    #[test]
+    fn test_truncate_body() {
+        assert_eq!(truncate_body("<html>\n  <h1>Bad Gateway</h1>\n</html>\n"), "<html> <h1>Bad Gateway</h1> </html>");
+        let long = "é".repeat(1000);
+        assert_eq!(truncate_body(&long), format!("{}...", "é".repeat(MAX_RESPONSE_BODY)));
+    }
+
+    #[test]
+    fn test_failed_attempts() {
+        let mut progress = UploadProgress::new(2);
+        let mut info = UploadInfo::new("test1.txt".to_string());
+        info.failed.connecting = 2;
+        progress.update(info.with_error(ErrorKind::Transport(TransportError::Refused)), false);
+        let mut info = UploadInfo::new("test2.txt".to_string());
+        info.failed.transferring = 1;
+        info.failed.rejected = 1;
+        progress.update(info.with_success(), false);
+        assert_eq!((progress.failed.connecting, progress.failed.transferring, progress.failed.rejected), (2, 1, 1));
+    }
+
+    #[test]
+    fn test_redraw() {
+        let mut progress = UploadProgress::new(2);
+        progress.redraw_interval = Duration::from_secs(3600);
+        progress.redraw(false);
+        let drawn = progress.last_drawn.unwrap();
+        progress.update(UploadInfo::new("a".to_string()).with_success(), true);
+        assert_eq!(progress.last_drawn, Some(drawn));
+        progress.redraw(true);
+        assert!(progress.last_drawn.unwrap() > drawn);
+    }
+
+    #[test]
+    fn test_errors_by_kind() {
+        let mut progress = UploadProgress::new(4);
+        progress.update(UploadInfo::new("a".to_string()).with_error(ErrorKind::FileExists), false);
+        progress.update(UploadInfo::new("b".to_string()).with_error(ErrorKind::FileExists), false);
+        let mut info = UploadInfo::new("c".to_string());
+        info.response = Some((StatusCode::BAD_GATEWAY, String::new()));
+        progress.update(info.with_error(ErrorKind::Other), false);
+        progress.update(UploadInfo::new("d".to_string()).with_error(ErrorKind::Transport(TransportError::Timeout)), false);
+        // already on the destination is fine, not an error
+        assert!(progress.status_bar().starts_with("Uploaded 0/4 files, 2 exist, 2 errors"));
+        let kinds: Vec<_> = progress.errors_by_kind.into_iter().collect();
+        assert_eq!(kinds, [("server error (5xx)", 1), ("timed out", 1)]);
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut progress = UploadProgress::new(3);
+        for (path, time) in [("fast", 0.5), ("slow", 2.0)] {
+            let mut info = UploadInfo::new(path.to_string());
+            info.set_bytes(1024 * 1024);
+            let mut info = info.with_success();
+            info.time = time;
+            progress.update(info, false);
+        }
+        progress.update(UploadInfo::new("c".to_string()).with_error(ErrorKind::ReadError), false);
+        let rows = progress.summary();
+        let row = |label| rows.iter().find(|r| r.0 == label).map(|r| r.1.as_str()).unwrap();
+        assert_eq!(row("files"), "2 uploaded, 0 already existed, 1 failed, of 3");
+        assert_eq!(row("errors"), "1 read error");
+        assert!(row("throughput").starts_with("1.25 MB/s average per file, p10 0.50, p50 0.50, p90 2.00"));
+        let report = progress.report(&Settings::with_endpoint(String::new(), String::new()).with_path("p".to_string()));
+        assert_eq!(report["summary"]["failed"], 1);
+        assert_eq!(report["summary"]["errors"]["read error"], 1);
+        assert_eq!(report["summary"]["throughput"]["max"].as_u64().unwrap() >> 20, 2);
+        assert_eq!(report["files"][1]["path"], "slow");
+        assert_eq!(report["files"][2]["error"], "read error");
+        assert!(!rows.iter().any(|r| r.0 == "slowest"));
+        progress.top_slowest = 1;
+        let rows = progress.summary();
+        assert_eq!(rows.last().unwrap().0, "slowest");
+        assert!(rows.last().unwrap().1.starts_with("0.50 MB/s, 1.00 MB in 2.00 seconds"));
+        assert!(rows.last().unwrap().1.ends_with("0 retries: slow"));
+
+        let mut timings = Timings::default();
+        let attempt = Instant::now() - Duration::from_secs(3);
+        timings.add_attempt(attempt, Some(&(attempt + Duration::from_secs(1))));
+        timings.add_attempt(Instant::now() - Duration::from_secs(1), None);
+        assert!((timings.connecting - 2.0).abs() < 0.1);
+        assert!((timings.transferring - 2.0).abs() < 0.1);
+    }
+
+    #[tokio::test]
+    async fn test_upload_file() {
+        use test_util::{MockFileservice, Reply};
+
+        let mock = MockFileservice::start().await;
+        mock.set_token("token");
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let job = || Job {
+            input: Input { path: path.to_str().unwrap().to_string(), name: "a.txt".to_string(), priority: 0 },
+            destination: 0,
+            size: 5,
+            stamp: None,
+            checksum: None,
+            contents: None,
+        };
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string()));
+        let client = build_client(&settings).unwrap();
+        let outage = Arc::new(Outage::new(Duration::from_millis(10)));
+        let upload = |settings: &Arc<Settings>| {
+            upload_file(client.clone(), job(), settings.clone(), Instant::now(), outage.clone(), body::Sent::default())
+        };
+
+        // throttled, then through on the retry
+        mock.push_replies([Reply::TooManyRequests]);
+        let info = upload(&settings).await;
+        assert!(info.error.is_none());
+        assert_eq!(info.retries, 1);
+        assert_eq!(info.failed.rejected, 1);
+        assert_eq!(mock.file("Storage/u/p/a.txt").unwrap(), "hello");
+
+        // already there
+        let info = upload(&settings).await;
+        assert!(matches!(info.error, Some(ErrorKind::FileExists)));
+        assert_eq!(info.retries, 0);
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string()).with_overwrite(true));
+        assert!(upload(&settings).await.error.is_none());
+
+        // gives up after the retries, keeping the last response
+        mock.push_replies([Reply::Unavailable; 3]);
+        let info = upload(&settings).await;
+        assert!(matches!(info.error, Some(ErrorKind::Other)));
+        assert_eq!(info.response.unwrap().0, StatusCode::SERVICE_UNAVAILABLE);
+
+        // waits out the outage, the retries are left for other failures
+        let maintenance = Arc::new(Settings::clone(&settings).with_maintenance_wait(Some(Duration::from_secs(5))));
+        mock.push_replies([Reply::Unavailable; 3]);
+        let info = upload(&maintenance).await;
+        assert!(info.error.is_none());
+        assert_eq!(info.retries, 0);
+        assert_eq!(info.failed.rejected, 3);
+
+        // abandoned at the deadline rather than waiting for the slow service
+        mock.set_latency(Duration::from_secs(5));
+        let deadline = Arc::new(Settings::clone(&settings).with_file_deadline(Some(Duration::from_millis(100))));
+        let info = upload(&deadline).await;
+        assert!(matches!(info.error, Some(ErrorKind::Deadline)));
+        assert!(info.time < 1.0);
+        mock.set_latency(Duration::ZERO);
+
+        // the response to a stored upload is lost: uploading again blindly
+        // finds it there, checking first finds it complete
+        let blind = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/b".to_string()));
+        mock.push_replies([Reply::StoreAndHangUp]);
+        assert!(matches!(upload(&blind).await.error, Some(ErrorKind::FileExists)));
+        let verify = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/v".to_string()).with_ambiguous_retry(AmbiguousRetry::Verify));
+        let uploads = mock.uploads();
+        mock.push_replies([Reply::StoreAndHangUp]);
+        assert!(upload(&verify).await.error.is_none());
+        assert_eq!(mock.uploads(), uploads + 1);
+        // only part of it was kept, which isn't replaced without overwriting
+        mock.push_replies([Reply::StorePartAndHangUp]);
+        assert!(matches!(upload(&verify).await.error, Some(ErrorKind::FileExists)));
+        assert_eq!(mock.file("Storage/u/v/a.txt").unwrap(), "he");
+        let replacing = Arc::new(Settings::clone(&verify).with_overwrite(true));
+        mock.push_replies([Reply::StorePartAndHangUp]);
+        let info = upload(&replacing).await;
+        assert!(info.error.is_none());
+        assert_eq!(info.retries, 1);
+        assert_eq!(mock.file("Storage/u/v/a.txt").unwrap(), "hello");
+        // another file of the same size is there, not a copy of this one
+        mock.insert("Storage/u/w/a.txt", "jello");
+        let other = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/w".to_string()).with_ambiguous_retry(AmbiguousRetry::Verify));
+        mock.push_replies([Reply::HangUp]);
+        assert!(matches!(upload(&other).await.error, Some(ErrorKind::FileExists)));
+        assert_eq!(mock.file("Storage/u/w/a.txt").unwrap(), "jello");
+
+        // changed after the scan but before its upload started
+        let scanned = Job { stamp: Some((5, Some(SystemTime::UNIX_EPOCH))), ..job() };
+        let info = upload_file(client.clone(), scanned, settings.clone(), Instant::now(),
+            outage.clone(), body::Sent::default()).await;
+        assert!(matches!(info.error, Some(ErrorKind::Modified)));
+        let reupload = Arc::new(Settings::clone(&settings).with_reupload_modified(true));
+        let scanned = Job { stamp: Some((5, Some(SystemTime::UNIX_EPOCH))), ..job() };
+        let info = upload_file(client.clone(), scanned, reupload, Instant::now(),
+            outage.clone(), body::Sent::default()).await;
+        assert!(info.error.is_none());
+        assert_eq!(info.retries, 1);
+
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "bad".to_string())
+            .with_path("Storage/u/p".to_string()));
+        let info = upload_file(build_client(&settings).unwrap(), job(), settings, Instant::now(),
+            outage.clone(), body::Sent::default()).await;
+        assert!(matches!(info.error, Some(ErrorKind::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn test_upload_growing() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("obs.log");
+        std::fs::write(&path, "line 1\n").unwrap();
+        let job = || Job {
+            input: Input { path: path.to_str().unwrap().to_string(), name: "obs.log".to_string(), priority: 0 },
+            destination: 0,
+            size: 0,
+            stamp: None,
+            checksum: None,
+            contents: None,
+        };
+        let outage = Arc::new(Outage::new(Duration::from_millis(10)));
+        let append = || {
+            let path = path.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let mut log = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+                std::io::Write::write_all(&mut log, b"line 2\n").unwrap();
+            })
+        };
+
+        // waits for the file to stop changing, then uploads it once
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/w".to_string()).with_stable_window(Some(Duration::from_millis(300)), false));
+        let appended = append();
+        let info = upload_file(build_client(&settings).unwrap(), job(), settings, Instant::now(),
+            outage.clone(), body::Sent::default()).await;
+        appended.await.unwrap();
+        assert!(info.error.is_none());
+        assert_eq!(mock.uploads(), 1);
+        assert_eq!(mock.file("Storage/u/w/obs.log").unwrap(), "line 1\nline 2\n");
+
+        // uploads right away, and again once it grew
+        std::fs::write(&path, "line 1\n").unwrap();
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/f".to_string()).with_stable_window(Some(Duration::from_millis(300)), true));
+        let appended = append();
+        let info = upload_file(build_client(&settings).unwrap(), job(), settings, Instant::now(),
+            outage, body::Sent::default()).await;
+        appended.await.unwrap();
+        assert!(info.error.is_none());
+        assert_eq!(info.retries, 0);
+        assert_eq!(mock.uploads(), 3);
+        assert_eq!(mock.file("Storage/u/f/obs.log").unwrap(), "line 1\nline 2\n");
+    }
+
+    #[tokio::test]
+    async fn test_wait_stable() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("obs.log");
+        std::fs::write(&path, "line 1\n").unwrap();
+        let window = Duration::from_millis(100);
+        // a modification time ahead of the clock doesn't hold it up forever
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(future).unwrap();
+        let file = File::open(&path).await.unwrap();
+        let waiting = Instant::now();
+        tokio::time::timeout(Duration::from_secs(5), wait_stable(&file, window)).await.unwrap();
+        assert!(waiting.elapsed() >= window);
+        // long unchanged
+        let past = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(past).unwrap();
+        let waiting = Instant::now();
+        wait_stable(&file, window).await;
+        assert!(waiting.elapsed() < window);
+    }
+
+    #[tokio::test]
+    async fn test_upload_archive() {
+        use std::io::Read;
+        use test_util::{MockFileservice, Reply};
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let root = tempdir.path().join("run");
+        std::fs::create_dir_all(root.join("raw/night1")).unwrap();
+        std::fs::write(root.join("notes.txt"), "notes").unwrap();
+        std::fs::write(root.join("raw/a.fits"), "a").unwrap();
+        std::fs::write(root.join("raw/night1/b.fits"), "b").unwrap();
+        std::fs::write(root.join("raw/b.tmp"), "tmp").unwrap();
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string()).with_recursive(true).with_excludes(vec!["*.tmp".to_string()])
+            .with_archive_per_dir(Some(ArchiveFormat::Zip)));
+        upload_many(vec![root.to_str().unwrap()], settings).await;
+        assert_eq!(mock.uploads(), 2);
+        assert_eq!(mock.file("Storage/u/p/run/notes.txt").unwrap(), "notes");
+        let data = mock.file("Storage/u/p/run/raw.zip").unwrap();
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).unwrap();
+        let names: Vec<_> = archive.file_names().map(|name| name.unwrap().to_string()).collect();
+        assert_eq!(names, ["a.fits", "night1/b.fits"]);
+        let mut contents = String::new();
+        archive.by_name("night1/b.fits").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "b");
+
+        // attempts wait out outages, verify and check what a failure left as
+        // those of files do
+        let job = |name: &str| Job {
+            input: Input { path: root.join("raw").to_str().unwrap().to_string(), name: name.to_string(), priority: 0 },
+            destination: 0,
+            size: 0,
+            stamp: None,
+            checksum: None,
+            contents: None,
+        };
+        let outage = Arc::new(Outage::new(Duration::from_millis(10)));
+        let upload = |settings: Settings, name: &str| {
+            let settings = Arc::new(settings.with_archive_per_dir(Some(ArchiveFormat::Zip)));
+            upload_file(build_client(&settings).unwrap(), job(name), settings, Instant::now(),
+                outage.clone(), body::Sent::default())
+        };
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/a".to_string());
+        mock.push_replies([Reply::Unavailable; 3]);
+        let waiting = Settings::clone(&settings).with_maintenance_wait(Some(Duration::from_secs(5)));
+        let info = upload(waiting, "1.zip").await;
+        assert!(info.error.is_none());
+        assert_eq!((info.retries, info.failed.rejected), (0, 3));
+        let info = upload(Settings::clone(&settings).with_verify_uploads(true), "2.zip").await;
+        assert!(info.error.is_none());
+        let verify = Settings::clone(&settings).with_ambiguous_retry(AmbiguousRetry::Verify);
+        let uploads = mock.uploads();
+        mock.push_replies([Reply::StoreAndHangUp]);
+        assert!(upload(Settings::clone(&verify), "3.zip").await.error.is_none());
+        assert_eq!(mock.uploads(), uploads + 1);
+        mock.push_replies([Reply::StorePartAndHangUp]);
+        assert!(matches!(upload(verify, "4.zip").await.error, Some(ErrorKind::FileExists)));
+    }
+
+    #[tokio::test]
+    async fn test_upload_casjobs() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("stars-v2.csv");
+        std::fs::write(&path, "ra,dec\n1.5,2.5\n").unwrap();
+        let settings = Arc::new(Settings::with_endpoint(mock.casjobs_endpoint(), "token".to_string())
+            .with_path("MyDB".to_string()).with_casjobs(true));
+        upload_many(vec![path.to_str().unwrap()], settings.clone()).await;
+        assert_eq!(mock.file("MyDB/Tables/stars_v2").unwrap(), "ra,dec\n1.5,2.5\n");
+
+        // files that would load into one table load into none
+        let other = tempdir.path().join("stars.v2.csv");
+        std::fs::write(&other, "ra,dec\n3.5,4.5\n").unwrap();
+        let uploads = mock.uploads();
+        upload_many(vec![path.to_str().unwrap(), other.to_str().unwrap()], settings).await;
+        assert_eq!(mock.uploads(), uploads);
+    }
+
+    #[tokio::test]
+    async fn test_register_dataset() {
+        use test_util::{MockFileservice, Reply};
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let (a, b) = (tempdir.path().join("a.fits"), tempdir.path().join("b.fits"));
+        std::fs::write(&a, "aaa").unwrap();
+        std::fs::write(&b, "b").unwrap();
+        let files = || vec![a.to_str().unwrap().to_string(), b.to_str().unwrap().to_string()];
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_dataset_manifest(Some(ManifestTarget::Upload("dataset.json".to_string())));
+        upload_many(files(), Arc::new(Settings::clone(&settings).with_path("Storage/u/p".to_string()))).await;
+        let manifest = mock.file("Storage/u/p/dataset.json").unwrap();
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+        assert_eq!(manifest["path"], "Storage/u/p");
+        assert_eq!(manifest["bytes"], 4);
+        assert_eq!(manifest["files"][0]["name"], "a.fits");
+
+        // not after a run with failures
+        mock.push_replies([Reply::Unavailable; 4]);
+        upload_many(files(), Arc::new(Settings::clone(&settings).with_path("Storage/u/q".to_string()).with_retries(1)))
+            .await;
+        assert!(mock.file("Storage/u/q/dataset.json").is_none());
+
+        // files that were there already count
+        mock.insert("Storage/u/r/a.fits", "aaa");
+        upload_many(files(), Arc::new(settings.with_path("Storage/u/r".to_string()))).await;
+        let manifest = mock.file("Storage/u/r/dataset.json").unwrap();
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+        assert_eq!(manifest["count"], 2);
+
+        // POSTed to the catalog without the fileservice token, again after a 503
+        mock.set_token("token");
+        mock.push_replies([Reply::Store, Reply::Store, Reply::Unavailable]);
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/s".to_string()).with_retries(3)
+            .with_dataset_manifest(Some(ManifestTarget::Post(mock.catalog_url())));
+        upload_many(files(), Arc::new(settings)).await;
+        let registrations = mock.registrations();
+        assert_eq!(registrations.len(), 2);
+        for (headers, body) in registrations {
+            assert!(headers.get("x-auth-token").is_none() && headers.get("authorization").is_none());
+            let manifest: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(manifest["path"], "Storage/u/s");
+            assert_eq!(manifest["count"], 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_checksum() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        mock.insert("Storage/u/p/sub/a.txt", "hello");
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string());
+        let sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert_eq!(remote_checksum("sub/a.txt", &settings).await.unwrap(), sha256);
+        mock.set_checksums(true);
+        let settings = settings.with_listed_checksums(true);
+        assert_eq!(remote_checksum("sub/a.txt", &settings).await.unwrap(), sha256);
+        assert!(remote_checksum("sub/b.txt", &settings).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_upload_fifo() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("pipe");
+        assert!(std::process::Command::new("mkfifo").arg(&path).status().unwrap().success());
+        let writer = std::thread::spawn({
+            let path = path.clone();
+            move || std::fs::write(path, "streamed")
+        });
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string()).with_checksums(Some(tempdir.path().join("sums"))));
+        let job = Job {
+            input: Input { path: path.to_str().unwrap().to_string(), name: "out.txt".to_string(), priority: 0 },
+            destination: 0,
+            size: 0,
+            stamp: None,
+            checksum: None,
+            contents: None,
+        };
+        let outage = Arc::new(Outage::new(Duration::from_millis(10)));
+        let info = upload_file(build_client(&settings).unwrap(), job, settings, Instant::now(),
+            outage, body::Sent::default()).await;
+        writer.join().unwrap().unwrap();
+        assert!(info.error.is_none());
+        assert_eq!(info.bytes, 8);
+        // hashed on the way, it can't be read again
+        assert_eq!(info.checksum.as_deref(), Some("97a78c00831554f7cc9745e8f6732edcfb571cf548a8d12b48a6e3fc31e5e3e6"));
+        assert_eq!(mock.file("Storage/u/p/out.txt").unwrap(), "streamed");
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        use test_util::{MockFileservice, Reply};
+
+        /// retries what the fileservice answers 503 for without a limit,
+        /// giving up on the run when throttled
+        struct Patient;
+
+        impl retry::RetryPolicy for Patient {
+            fn decide(&self, attempt: &retry::Attempt) -> retry::RetryDecision {
+                match attempt.failure {
+                    retry::Failure::Status(StatusCode::SERVICE_UNAVAILABLE, _) => {
+                        retry::RetryDecision::Retry(Duration::ZERO)
+                    }
+                    retry::Failure::Status(StatusCode::TOO_MANY_REQUESTS, _) => retry::RetryDecision::Abort,
+                    _ => retry::DefaultRetryPolicy { retries: 3 }.decide(attempt),
+                }
+            }
+        }
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let job = || Job {
+            input: Input { path: path.to_str().unwrap().to_string(), name: "a.txt".to_string(), priority: 0 },
+            destination: 0,
+            size: 5,
+            stamp: None,
+            checksum: None,
+            contents: None,
+        };
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string()).with_retry_policy(Some(Arc::new(Patient))));
+        let client = build_client(&settings).unwrap();
+        let outage = Arc::new(Outage::new(Duration::from_millis(10)));
+        mock.push_replies([Reply::Unavailable; 5]);
+        let info = upload_file(client.clone(), job(), settings.clone(), Instant::now(),
+            outage.clone(), body::Sent::default()).await;
+        assert!(info.error.is_none());
+        assert_eq!(info.retries, 5);
+        mock.push_replies([Reply::TooManyRequests]);
+        let settings = Arc::new(Settings::clone(&settings).with_overwrite(true));
+        let info = upload_file(client, job(), settings, Instant::now(), outage, body::Sent::default()).await;
+        assert!(matches!(info.error, Some(ErrorKind::Aborted)));
+        assert_eq!(info.retries, 0);
+        // a tls failure after a retry doesn't use up another
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string());
+        let mut info = UploadInfo::new("a.txt".to_string());
+        info.incr_retries();
+        let delay = retry_delay(&settings, &mut info, Some(TransportError::Tls), false, &mut fastrand::Rng::new());
+        assert!(matches!(delay, Err(ErrorKind::Transport(TransportError::Tls))));
+        assert_eq!(info.retries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_upload_middleware() {
+        use test_util::{MockFileservice, Reply};
+
+        /// counts the attempts, adding a header to each
+        struct Counted(Arc<std::sync::atomic::AtomicUsize>);
+
+        impl middleware::Middleware for Counted {
+            fn handle<'a>(&'a self, mut request: reqwest::Request, next: middleware::Next<'a>)
+                -> middleware::BoxFuture<'a, reqwest::Result<reqwest::Response>> {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                request.headers_mut().insert("X-Signature", reqwest::header::HeaderValue::from_static("signed"));
+                next.run(request)
+            }
+        }
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string())
+            .with_middleware(vec![Arc::new(Counted(count.clone()))]);
+        mock.push_replies([Reply::Unavailable]);
+        upload_many(vec![path.to_str().unwrap().to_string()], Arc::new(settings)).await;
+        assert_eq!(mock.file("Storage/u/p/a.txt").unwrap(), "hello");
+        // the retry too
+        assert_eq!(count.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_upload_auth() {
+        use test_util::MockFileservice;
+
+        assert_eq!("bearer".parse(), Ok(AuthScheme::Bearer));
+        assert_eq!("header:X-Api-Key".parse(), Ok(AuthScheme::Header(HeaderName::from_static("x-api-key"))));
+        assert!("header:".parse::<AuthScheme>().is_err());
+        assert!("basic".parse::<AuthScheme>().is_err());
+
+        let mock = MockFileservice::start().await;
+        mock.set_auth("authorization", "Bearer token");
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string());
+        let upload = |settings: Settings| upload_many(vec![path.to_str().unwrap().to_string()], Arc::new(settings));
+        upload(settings.clone()).await;
+        assert!(mock.file("Storage/u/p/a.txt").is_none());
+        upload(settings.clone().with_auth(AuthScheme::Bearer)).await;
+        assert_eq!(mock.file("Storage/u/p/a.txt").unwrap(), "hello");
+
+        mock.set_auth("x-api-key", "token");
+        std::fs::write(&path, "hello again").unwrap();
+        upload(settings.with_auth("header:X-Api-Key".parse().unwrap()).with_overwrite(true)).await;
+        assert_eq!(mock.file("Storage/u/p/a.txt").unwrap(), "hello again");
+    }
+
+    #[tokio::test]
+    async fn test_upload_mirror_token() {
+        use test_util::MockFileservice;
+
+        let (primary, mirror) = (MockFileservice::start().await, MockFileservice::start().await);
+        primary.set_token("token");
+        mirror.set_token("mirror token");
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let settings = Settings::with_endpoint(primary.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string())
+            .with_mirrors(vec![mirror.endpoint()]);
+        let upload = |settings: Settings| upload_many(vec![path.to_str().unwrap().to_string()], Arc::new(settings));
+        // the mirror refusing the primary token doesn't stop the primary
+        upload(settings.clone()).await;
+        assert_eq!(primary.file("Storage/u/p/a.txt").unwrap(), "hello");
+        assert!(mirror.file("Storage/u/p/a.txt").is_none());
+
+        std::fs::write(&path, "hello again").unwrap();
+        let settings = settings.with_mirror_tokens(vec![Some("mirror token".to_string())]).with_overwrite(true);
+        upload(settings).await;
+        assert_eq!(primary.file("Storage/u/p/a.txt").unwrap(), "hello again");
+        assert_eq!(mirror.file("Storage/u/p/a.txt").unwrap(), "hello again");
+    }
+
+    #[tokio::test]
+    async fn test_upload_manifest() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.txt");
+        std::fs::write(&path, "hello").unwrap();
+        mock.insert("Storage/u/p/.upload-metadata.json", r#"{"files": {"old.txt": {"size": 3}}}"#);
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string())
+            .with_metadata(Some(MetadataMode::Manifest));
+        upload_many(vec![path.to_str().unwrap().to_string()], Arc::new(settings)).await;
+        let manifest = mock.file("Storage/u/p/.upload-metadata.json").unwrap();
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+        // the earlier run's files are kept
+        assert_eq!(manifest["files"]["old.txt"]["size"], 3);
+        assert_eq!(manifest["files"]["a.txt"]["size"], 5);
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        mock.set_token("token");
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("a.txt"), "local").unwrap();
+        std::fs::write(tempdir.path().join("b.tmp"), "left out").unwrap();
+        mock.insert("Storage/u/s/b.tmp", "remote");
+        mock.insert("Storage/u/s/c.txt", "remote");
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/s".to_string())
+            .with_excludes(vec!["*.tmp".to_string()]));
+        let dir = tempdir.path().to_str().unwrap().to_string();
+        // the path is checked first
+        let invalid = Arc::new(Settings::clone(&settings).with_path("Storage/u/../s".to_string()));
+        assert!(!sync(dir.clone(), invalid, false, |_| true).await);
+        assert_eq!(mock.uploads(), 0);
+        assert!(sync(dir, settings, false, |_| true).await);
+        assert_eq!(mock.file("Storage/u/s/a.txt").unwrap(), "local");
+        assert_eq!(std::fs::read_to_string(tempdir.path().join("c.txt")).unwrap(), "remote");
+        // excluded locally, so not replaced by the remote copy
+        assert_eq!(std::fs::read_to_string(tempdir.path().join("b.tmp")).unwrap(), "left out");
+        assert!(!tempdir.path().join("c.txt.uploadpart").exists());
+    }
+
+    #[tokio::test]
+    async fn test_sync_delete() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        mock.set_token("token");
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("a.txt"), "local").unwrap();
+        std::fs::write(tempdir.path().join("b.tmp"), "excluded").unwrap();
+        std::fs::write(tempdir.path().join("big.txt"), "too large").unwrap();
+        for name in ["b.tmp", "big.txt", "build/c.o", "gone.txt"] {
+            mock.insert(&format!("Storage/u/m/{}", name), "remote");
+        }
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/m".to_string())
+            .with_excludes(vec!["*.tmp".to_string(), "build".to_string()])
+            .with_size_limits(None, Some(5)));
+        let dir = tempdir.path().to_str().unwrap().to_string();
+        assert!(sync(dir, settings, true, |deletes| deletes == ["gone.txt"]).await);
+        assert!(mock.file("Storage/u/m/gone.txt").is_none());
+        assert_eq!(mock.file("Storage/u/m/a.txt").unwrap(), "local");
+        // left out locally, by name or size, so not deleted remotely
+        for name in ["b.tmp", "big.txt", "build/c.o"] {
+            assert_eq!(mock.file(&format!("Storage/u/m/{}", name)).unwrap(), "remote");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_login() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        mock.set_login("u", "secret", Some(Duration::from_secs(3600)));
+        let tempdir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            std::fs::write(tempdir.path().join(name), name).unwrap();
+        }
+        let login = |password: &str| session::Login {
+            url: mock.login_url(), username: "u".to_string(), password: password.to_string(),
+        };
+        let settings = Settings::with_endpoint(mock.endpoint(), String::new()).with_path("Storage/u/p".to_string());
+        let upload = |settings: &Settings, name| {
+            upload_many(vec![tempdir.path().join(name).to_str().unwrap().to_string()], Arc::new(settings.clone()))
+        };
+        let session = settings.clone().with_login(Some(login("secret")));
+        upload(&session, "a.txt").await;
+        assert_eq!(mock.file("Storage/u/p/a.txt").unwrap(), "a.txt");
+        upload(&session, "b.txt").await;
+        assert_eq!(mock.logins(), 1);
+
+        // logged in again once the cookie expired, other cookies don't make
+        // a session
+        mock.expire_cookie();
+        upload(&session, "c.txt").await;
+        assert_eq!(mock.file("Storage/u/p/c.txt").unwrap(), "c.txt");
+        assert_eq!(mock.logins(), 2);
+
+        // nothing is uploaded without a session
+        let uploads = mock.uploads();
+        upload(&settings.with_login(Some(login("wrong"))).with_overwrite(true), "a.txt").await;
+        assert_eq!((mock.logins(), mock.uploads()), (2, uploads));
+    }
+
+    #[tokio::test]
+    async fn test_upload_relogin() {
+        use test_util::{MockFileservice, Reply};
+
+        let mock = MockFileservice::start().await;
+        mock.set_login("u", "secret", None);
+        let tempdir = tempfile::tempdir().unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            std::fs::write(tempdir.path().join(name), name).unwrap();
+        }
+        let login = session::Login { url: mock.login_url(), username: "u".to_string(), password: "secret".to_string() };
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), String::new())
+            .with_path("Storage/u/p".to_string())
+            .with_login(Some(login)));
+        let upload = |name| {
+            upload_many(vec![tempdir.path().join(name).to_str().unwrap().to_string()], settings.clone())
+        };
+        upload("a.txt").await;
+        assert_eq!(mock.logins(), 1);
+
+        // the session ended mid-run, a new one is started for the retry
+        mock.end_session();
+        upload("b.txt").await;
+        assert_eq!(mock.file("Storage/u/p/b.txt").unwrap(), "b.txt");
+        assert_eq!(mock.logins(), 2);
+
+        // refused on the new session too, only logged in again once
+        mock.push_replies([Reply::Unauthorized, Reply::Unauthorized, Reply::Unauthorized]);
+        upload("c.txt").await;
+        assert!(mock.file("Storage/u/p/c.txt").is_none());
+        assert_eq!(mock.logins(), 3);
+    }
+
+    /// Local read failures count against the retries and end up reported,
+    /// rather than attempts going on without end.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_upload_read_failure() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let settings = Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string()));
+        // opens as a regular file but can't be read from the start
+        let job = Job {
+            input: Input { path: "/proc/self/mem".to_string(), name: "mem".to_string(), priority: 0 },
+            destination: 0,
+            size: 0,
+            stamp: None,
+            checksum: None,
+            contents: None,
+        };
+        let outage = Arc::new(Outage::new(Duration::from_millis(10)));
+        let info = upload_file(build_client(&settings).unwrap(), job, settings, Instant::now(),
+            outage, body::Sent::default()).await;
+        assert!(matches!(info.error, Some(ErrorKind::Transport(TransportError::Body))));
+        assert_eq!(info.retries, 3);
+        assert!(mock.file("Storage/u/p/mem").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_upload_preflight() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        mock.set_volumes(serde_json::json!({
+            "rootVolumes": [{"name": "Storage", "userVolumes": [{"name": "persistent", "owner": "u"}]}],
+        }));
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.txt");
+        std::fs::write(&path, "a").unwrap();
+        let settings = |path: &str| Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path(path.to_string()));
+        upload_many(vec![path.to_str().unwrap()], settings("Storge/u/persistent")).await;
+        upload_many(vec![path.to_str().unwrap()], settings("Storage/u/../persistent")).await;
+        assert_eq!(mock.uploads(), 0);
+        upload_many(vec![path.to_str().unwrap()], settings("Storage/u/persistent/run1")).await;
+        assert_eq!(mock.file("Storage/u/persistent/run1/a.txt").unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn test_upload_max_duration() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        mock.set_latency(Duration::from_millis(300));
+        let tempdir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..4).map(|i| {
+            let path = tempdir.path().join(format!("{}.txt", i));
+            std::fs::write(&path, "a").unwrap();
+            path.to_string_lossy().to_string()
+        }).collect();
+        let report = tempdir.path().join("report.json");
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string())
+            .with_concurrency(1)
+            .with_max_duration(Some(Duration::from_millis(100)))
+            .with_report(Some(report.clone()));
+        upload_many(paths.clone(), Arc::new(settings)).await;
+        // the running upload finished, none started after the limit
+        assert_eq!(mock.uploads(), 1);
+        // without a job to resume, the rest are listed next to the report
+        let left = std::fs::read_to_string(tempdir.path().join("report.json.left")).unwrap();
+        assert_eq!(left.lines().collect::<Vec<_>>(), paths[1..]);
+    }
+
+    #[tokio::test]
+    async fn test_skip_from_report() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let (a, b) = (tempdir.path().join("a.txt"), tempdir.path().join("b.txt"));
+        std::fs::write(&a, "a").unwrap();
+        std::fs::write(&b, "b").unwrap();
+        let files = || vec![a.to_str().unwrap().to_string(), b.to_str().unwrap().to_string()];
+        let report = tempdir.path().join("report.json");
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string()).with_overwrite(true);
+        upload_many(files(), Arc::new(Settings::clone(&settings).with_report(Some(report.clone())))).await;
+        let uploaded = jobs::uploaded_in_report(&report).unwrap();
+        assert_eq!(uploaded.len(), 2);
+        let uploads = mock.uploads();
+        upload_many(files(), Arc::new(Settings::clone(&settings).with_skip_uploaded(uploaded.clone()))).await;
+        assert_eq!(mock.uploads(), uploads);
+        // changed since, or going elsewhere
+        std::fs::write(&b, "bb").unwrap();
+        upload_many(files(), Arc::new(Settings::clone(&settings).with_skip_uploaded(uploaded.clone()))).await;
+        assert_eq!(mock.uploads(), uploads + 1);
+        assert_eq!(mock.file("Storage/u/p/b.txt").unwrap(), "bb");
+        upload_many(files(), Arc::new(settings.with_path("Storage/u/q".to_string()).with_skip_uploaded(uploaded)))
+            .await;
+        assert_eq!(mock.uploads(), uploads + 3);
+    }
+
+    #[tokio::test]
+    async fn test_upload_copies() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let large = vec![b'x'; 2 << 20];
+        std::fs::write(tempdir.path().join("small.txt"), "small").unwrap();
+        std::fs::write(tempdir.path().join("large.bin"), &large).unwrap();
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/work".to_string())
+            .with_copies(vec!["Storage/u/archive/run1".to_string()])
+            .with_recursive(true);
+        assert_eq!(settings.destinations(), 2);
+        upload_many(vec![format!("{}/", tempdir.path().to_str().unwrap())], Arc::new(settings)).await;
+        for path in ["Storage/u/work", "Storage/u/archive/run1"] {
+            assert_eq!(mock.file(&format!("{}/small.txt", path)).unwrap(), "small");
+            assert_eq!(mock.file(&format!("{}/large.bin", path)).unwrap(), large);
+        }
+        assert_eq!(mock.uploads(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_upload_dedup() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let index = tempdir.path().join("contents");
+        let write = |name: &str, data: &str| {
+            let path = tempdir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let settings = |path: &str| Arc::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path(path.to_string())
+            .with_content_index(Some(ContentIndex::new(index.clone()))));
+        upload_many(vec![write("a.fits", "product")], settings("Storage/u/p")).await;
+        // the same contents regenerated under another name
+        upload_many(vec![write("b.fits", "product"), write("c.fits", "other")], settings("Storage/u/p")).await;
+        assert!(mock.file("Storage/u/p/b.fits").is_none());
+        assert_eq!(mock.file("Storage/u/p/c.fits").unwrap(), "other");
+        // not under the path uploaded to
+        upload_many(vec![write("b.fits", "product")], settings("Storage/u/q")).await;
+        assert_eq!(mock.file("Storage/u/q/b.fits").unwrap(), "product");
+        // changed on the fileservice since, if only the contents
+        mock.insert("Storage/u/p/a.fits", "produkt");
+        upload_many(vec![write("d.fits", "product")], settings("Storage/u/p")).await;
+        assert_eq!(mock.file("Storage/u/p/d.fits").unwrap(), "product");
+        assert_eq!(mock.uploads(), 4);
+        // a run leaving out duplicates succeeds
+        let settings = settings("Storage/u/p");
+        let client = build_client(&settings).unwrap();
+        let (files, collected) = collect_files(vec![write("e.fits", "product")], false, None, &settings).await.unwrap();
+        assert!(upload_inputs(&client, files, &collected, settings, None, None).await);
+        assert_eq!(mock.uploads(), 4);
+    }
+
+    #[test]
+    fn test_destination_limit() {
+        let settings = Settings::with_endpoint("http://a".to_string(), "token".to_string())
+            .with_path("/Storage/u/p/".to_string())
+            .with_destination_limits(vec![("Storage/u/p".to_string(), 4), ("Storage/u/p/raw".to_string(), 1)]);
+        assert_eq!(destination_limit(&settings, "calib/a.fits"), Some(0));
+        assert_eq!(destination_limit(&settings, "raw/b/a.fits"), Some(1));
+        assert_eq!(destination_limit(&settings, "raw2/a.fits"), Some(0));
+        let settings = settings.with_path("Storage/u/q".to_string());
+        assert_eq!(destination_limit(&settings, "raw/a.fits"), None);
+    }
+
+    #[tokio::test]
+    async fn test_upload_max_files() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(tempdir.path().join(name), name).unwrap();
+        }
+        let root = tempdir.path().to_str().unwrap().to_string();
+        let asked = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let settings = |answer: bool| {
+            let asked = asked.clone();
+            Settings::with_endpoint(mock.endpoint(), "token".to_string())
+                .with_path("Storage/u/p".to_string())
+                .with_recursive(true)
+                .with_max_files(Some(2))
+                .with_confirm(Some(Arc::new(move |question: &str| {
+                    asked.lock().unwrap().push(question.to_string());
+                    answer
+                })))
+        };
+        upload_many(vec![root.clone()], Arc::new(settings(false))).await;
+        assert_eq!(mock.uploads(), 0);
+        assert_eq!(*asked.lock().unwrap(), ["Found more than --max-files 2 files to upload. Upload them all?"]);
+        // nobody to ask
+        upload_many(vec![root.clone()], Arc::new(settings(true).with_confirm(None))).await;
+        assert_eq!(mock.uploads(), 0);
+        upload_many(vec![root.clone()], Arc::new(settings(true))).await;
+        assert_eq!(mock.uploads(), 3);
+        upload_many(vec![root.clone()], Arc::new(settings(false).with_max_files(Some(3)).with_overwrite(true))).await;
+        assert_eq!(mock.uploads(), 6);
+        assert_eq!(asked.lock().unwrap().len(), 2);
+        // syncs too
+        std::fs::write(tempdir.path().join("d"), "d").unwrap();
+        let settings = Arc::new(settings(false).with_max_files(Some(3)).with_overwrite(true));
+        assert!(!sync(root.clone(), settings.clone(), false, |_| false).await);
+        assert_eq!(mock.uploads(), 6);
+        // and can't stream, nothing could be asked before uploads start
+        upload_many(vec![root], Arc::new(settings.as_ref().clone().with_max_files(Some(5)).with_stream(true))).await;
+        assert_eq!(mock.uploads(), 6);
+        assert_eq!(asked.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_upload_stream() {
+        use test_util::MockFileservice;
+
+        let mock = MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        for dir in ["a", "b", "b/c"] {
+            std::fs::create_dir_all(tempdir.path().join(dir)).unwrap();
+            std::fs::write(tempdir.path().join(dir).join("f.txt"), dir).unwrap();
+        }
+        let root = tempdir.path().to_str().unwrap();
+        let settings = Settings::with_endpoint(mock.endpoint(), "token".to_string())
+            .with_path("Storage/u/p".to_string())
+            .with_recursive(true)
+            .with_stream(true)
+            .with_scan_threads(2);
+        // given twice, uploaded once
+        upload_many(vec![format!("{}/", root), format!("{}/b", root)], Arc::new(settings.clone())).await;
+        assert_eq!(mock.file("Storage/u/p/a/f.txt").unwrap(), "a");
+        assert_eq!(mock.file("Storage/u/p/b/c/f.txt").unwrap(), "b/c");
+        assert_eq!(mock.uploads(), 3);
+        // the time limit stops the scan too
+        mock.set_latency(Duration::from_millis(300));
+        let settings = settings.with_path("Storage/u/q".to_string())
+            .with_concurrency(1)
+            .with_max_duration(Some(Duration::from_millis(100)));
+        upload_many(vec![format!("{}/", root)], Arc::new(settings)).await;
+        assert_eq!(mock.uploads(), 4);
+    }
+
     #[tokio::test]
     async fn test_file_info() {
         let info = file_info("paththatdoesnotexist.txt").await;
         assert!(info.is_none());
         let tempdir = tempfile::tempdir().unwrap();
         let file_path = tempdir.path().join("testfile.txt");
-        std::fs::write(file_path, "Hello, world!").unwrap();
-        if let Some((_, name, bytes)) = file_info("testfile.txt").await {
-            assert_eq!(name, "testfile.txt");
+        std::fs::write(&file_path, "Hello, world!").unwrap();
+        if let Some((_, bytes)) = file_info(file_path.to_str().unwrap()).await {
+            assert_eq!(inputs::remote_name(file_path.to_str().unwrap()), "testfile.txt");
             assert_eq!(bytes, 13);
         } else {
             panic!("File info should not be None");
         }
     }
+
+    #[test]
+    fn test_settings_urls() {
+        let endpoint = "https://example.org/fileservice/api/file/".to_string();
+        let settings = Settings::with_endpoint(endpoint, "t".to_string())
+            .with_path("/Storage/user/persistent/".to_string());
+        let endpoint = settings.endpoints.url(0);
+        assert_eq!(settings.prefix(endpoint), "https://example.org/fileservice/api/file/Storage/user/persistent");
+        assert_eq!(settings.api_url(endpoint, "volumes"), "https://example.org/fileservice/api/volumes");
+
+        // the prefix of the original constructor, taken apart
+        let prefix = "https://example.org/fileservice/api/file/Storage/user/persistent".to_string();
+        let settings = Settings::new(prefix, "t".to_string(), 4, 2, true);
+        let endpoint = settings.endpoints.url(0);
+        assert_eq!(settings.upload_url(endpoint, "a.csv", false),
+            "https://example.org/fileservice/api/file/Storage/user/persistent/a.csv");
+        assert_eq!(settings.api_url(endpoint, "volumes"), "https://example.org/fileservice/api/volumes");
+        assert_eq!((settings.concurrency, settings.retries, settings.overwrite), (4, 2, true));
+        let settings = Settings::new("http://localhost/upload/".to_string(), "t".to_string(), 4, 2, false);
+        assert_eq!(settings.upload_url(settings.endpoints.url(0), "a.csv", false), "http://localhost/upload/a.csv");
+    }
+
+    #[test]
+    fn test_refresh_rate() {
+        let settings = || Settings::with_endpoint("http://localhost/".to_string(), "t".to_string());
+        assert_eq!(settings().with_refresh_rate(4.0).redraw_interval, Duration::from_millis(250));
+        assert_eq!(settings().with_refresh_rate(f64::NAN).redraw_interval, Duration::from_millis(250));
+        assert_eq!(settings().with_refresh_rate(0.0).redraw_interval, Duration::from_secs(5));
+        assert_eq!(settings().with_refresh_rate(-1.0).redraw_interval, Duration::from_secs(5));
+        assert_eq!(settings().with_refresh_rate(f64::INFINITY).redraw_interval, Duration::from_millis(1));
+    }
 }
@@ -1,46 +1,901 @@
-use clap::Parser;
-use upload::{upload_many, Settings};
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use clap::{ArgGroup, Parser, Subcommand};
+use upload::check::{check_endpoints, format_check_table};
+use upload::config::{default_config_path, Config, Profile};
+use upload::dns::{parse_resolve, IpFamily};
+use upload::encrypt::Encryption;
+use upload::jobs::{default_state_dir, list_jobs, prune_jobs, uploaded_in_report, JobState};
+use upload::session::Login;
+use upload::site::discover_endpoints;
+use upload::units::{self, parse_duration, parse_size, parse_time};
+use upload::{diff, serve, sync, upload_many, verify_many, AmbiguousRetry, ArchiveFormat, AuthScheme, ColorChoice,
+    ContentIndex, HashCache, HttpVersion, ManifestTarget, MetadataMode, Order, Porcelain, RampUp, Settings, TimeWindow,
+    UploadRequest};
 
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true)]
+#[command(group(ArgGroup::new("verifiable").args(["verify_only", "move_files"]).multiple(true)))]
 struct Args {
-    /// sciserver fileservice http endpoint, defaults to that of jhu-prod
-    #[clap(short, long)]
-    endpoint: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// sciserver fileservice http endpoint, or its name from the config file
+    /// (jhu-prod is built in), defaults to jhu-prod. Repeat to give failover
+    /// endpoints, tried in order
+    #[clap(short, long, global = true)]
+    endpoint: Vec<String>,
+    /// find the fileservice endpoint in the service registry of this
+    /// SciServer deployment, by name (idies) or the url of its login portal
+    #[clap(long, global = true, conflicts_with = "endpoint")]
+    site: Option<String>,
     /// sciserver token, defaults to SCISERVER_TOKEN env var
-    #[clap(short, long, env = "SCISERVER_TOKEN")]
+    #[clap(short, long, env = "SCISERVER_TOKEN", global = true)]
     token: Option<String>,
+    /// how the token is sent: x-auth-token (as SciServer expects), bearer
+    /// (Authorization: Bearer, e.g. behind a gateway) or header:NAME
+    #[clap(long, value_name = "SCHEME", global = true)]
+    auth: Option<AuthScheme>,
+    /// log in at this url (posting username and password as a form) for a
+    /// session cookie, for deployments behind an SSO gateway, again when it
+    /// expires. The password is read from SCISERVER_PASSWORD
+    #[clap(long, value_name = "URL", global = true, requires = "username")]
+    login: Option<String>,
+    /// username to --login with
+    #[clap(long, env = "SCISERVER_USERNAME", global = true)]
+    username: Option<String>,
+    /// also upload every file to this endpoint or named endpoint (same path),
+    /// with the token after a comma if it takes another one (e.g.
+    /// backup,TOKEN), can be repeated to mirror to several deployments
+    #[clap(short, long, value_name = "ENDPOINT[,TOKEN]", global = true)]
+    mirror: Vec<String>,
+    /// also upload every file to this path (e.g. an archive copy next to the
+    /// working one), small files are read once for both, can be repeated
+    #[clap(long, value_name = "PATH")]
+    copy_to: Vec<String>,
+    /// proxy for all requests (e.g. http://proxy:3128 or socks5h://host:1080),
+    /// defaults to HTTP_PROXY/HTTPS_PROXY env vars
+    #[clap(long, global = true)]
+    proxy: Option<String>,
+    /// pem file with additional CA certificate(s) to trust
+    #[clap(long, global = true)]
+    ca_cert: Option<PathBuf>,
+    /// DANGEROUS: disable tls certificate verification, for test deployments
+    /// with self-signed certificates only
+    #[clap(long, global = true)]
+    insecure: bool,
+    /// http version: auto (negotiated), 1.1 or 2 (also over plain http)
+    #[clap(long, global = true, default_value = "auto")]
+    http: HttpVersion,
+    /// http/2 per-stream flow control window (e.g. 4M), adaptive by default
+    #[clap(long, global = true, value_parser = parse_window)]
+    http2_stream_window: Option<u32>,
+    /// http/2 per-connection flow control window (e.g. 16M), adaptive by default
+    #[clap(long, global = true, value_parser = parse_window)]
+    http2_connection_window: Option<u32>,
+    /// idle connections kept open for reuse, defaults to the concurrency
+    #[clap(long)]
+    pool_max_idle: Option<usize>,
+    /// close pooled connections idle for longer than this (e.g. 90s, 0 to
+    /// never), defaults to 90s
+    #[clap(long, value_parser = parse_duration)]
+    pool_idle_timeout: Option<Duration>,
+    /// tcp keepalive interval (e.g. 60s, 0 to disable), defaults to 60s
+    #[clap(long, global = true, value_parser = parse_duration)]
+    tcp_keepalive: Option<Duration>,
+    /// use this address for a host instead of dns, as host:[port:]addr
+    /// (curl style), can be repeated
+    #[clap(long, global = true, value_parser = parse_resolve)]
+    resolve: Vec<(String, SocketAddr)>,
+    /// reuse resolved addresses for this long (e.g. 5m, 0 to disable the
+    /// cache), stale addresses are used if dns fails, defaults to 5m
+    #[clap(long, global = true, value_parser = parse_duration)]
+    dns_cache_ttl: Option<Duration>,
+    /// only connect over IPv4
+    #[clap(short = '4', long, global = true, conflicts_with = "ipv6")]
+    ipv4: bool,
+    /// only connect over IPv6
+    #[clap(short = '6', long, global = true)]
+    ipv6: bool,
     /// number of concurrent uploads, defaults to 10
-    #[clap(short, long)]
+    #[clap(short, long, value_parser = parse_cons)]
     cons: Option<usize>,
+    /// upload files of --large-size and above from a separate pool of this
+    /// many concurrent uploads, next to the --cons for smaller files
+    #[clap(long, value_parser = parse_cons)]
+    large_cons: Option<usize>,
+    /// size from which files count as large (e.g. 100M), defaults to 100M
+    #[clap(long, value_parser = parse_size)]
+    large_size: Option<u64>,
+    /// run at most N of the concurrent uploads into this remote path, as
+    /// PATH=N (e.g. Storage/u/archive=2 for a volume on slower storage), can
+    /// be repeated
+    #[clap(long, value_name = "PATH=N", value_parser = parse_dest_cons)]
+    dest_cons: Vec<(String, usize)>,
+    /// order files are uploaded in: given, largest-first, smallest-first or
+    /// random
+    #[clap(long, default_value = "given")]
+    order: Order,
+    /// files given may start with a priority, e.g. 10:calib/, files of higher
+    /// priority start before others (0 if none is given)
+    #[clap(long)]
+    priorities: bool,
+    /// files are given as SRC:DEST, each uploaded into the directory DEST
+    /// under the path, e.g. raw/:raw/ calib/:calibration/ (after the priority
+    /// with --priorities)
+    #[clap(long, conflicts_with = "verify_only")]
+    map: bool,
+    /// only start uploads between these local times, e.g. 22:00-06:00,
+    /// waiting for the window to open (running uploads are finished)
+    #[clap(long, value_name = "HH:MM-HH:MM")]
+    window: Option<TimeWindow>,
+    /// go easy on a shared machine: reads get the idle io priority on linux,
+    /// elsewhere fewer uploads run at once and each reads slowly
+    #[clap(long)]
+    nice: bool,
+    /// upload files hardlinked under several names only once, listing the
+    /// other names
+    #[clap(long)]
+    dedup_hardlinks: bool,
+    /// don't upload files whose contents were uploaded under the path before
+    /// under any name, by their checksum as recorded in the state directory
+    #[clap(long, conflicts_with_all = ["verify_only", "encrypt", "casjobs"])]
+    dedup: bool,
+    /// upload directories recursively, keeping their structure. `dir` uploads
+    /// into dir/ at the destination, `dir/` uploads its contents directly
+    #[clap(short = 'R', long)]
+    recursive: bool,
+    /// with -R, start uploading files as they're found rather than after
+    /// walking all directories, in the order they're found
+    #[clap(long, requires = "recursive",
+        conflicts_with_all = ["verify_only", "dry_run", "archive_per_dir", "metadata", "dedup_hardlinks", "order",
+            "casjobs"])]
+    stream: bool,
+    /// with -R, upload each subdirectory of uploaded directories as one
+    /// archive (zip) made on the fly, keeping the structure inside it
+    #[clap(long, value_name = "FORMAT", requires = "recursive",
+        conflicts_with_all = ["verify_only", "move_files", "encrypt"])]
+    archive_per_dir: Option<ArchiveFormat>,
+    /// skip files and directories matching this glob (e.g. '*.tmp'), can be
+    /// repeated
+    #[clap(long)]
+    exclude: Vec<String>,
+    /// gitignore style file of what not to upload, in addition to any
+    /// .uploadignore in the root of uploaded directories
+    #[clap(long)]
+    ignore_file: Vec<String>,
+    /// include hidden files and directories when uploading recursively
+    #[clap(long, overrides_with = "no_hidden")]
+    hidden: bool,
+    /// skip hidden files and directories when uploading recursively (default)
+    #[clap(long)]
+    no_hidden: bool,
+    /// follow symlinked directories when uploading recursively, skipping links
+    /// leading out of the uploaded directory or back into a parent
+    #[clap(long)]
+    follow_symlinks: bool,
+    /// directories walked at once when uploading recursively, more help on
+    /// network filesystems with millions of files
+    #[clap(long, default_value_t = 8)]
+    scan_threads: usize,
+    /// upload files again that changed since they were found or while being
+    /// uploaded, by default they are reported as errors
+    #[clap(long)]
+    reupload_modified: bool,
+    /// for files still being written (e.g. logs): only upload them once they
+    /// weren't modified for this long (e.g. 30s)
+    #[clap(long, value_parser = parse_duration)]
+    stable_for: Option<Duration>,
+    /// with --stable-for, upload files right away and again whenever they
+    /// changed, until they stay unchanged for that long
+    #[clap(long, requires = "stable_for")]
+    follow: bool,
+    /// don't upload sparse files, whose holes would take up space (and quota)
+    /// on the fileservice, by default they are uploaded with a warning
+    #[clap(long)]
+    skip_sparse: bool,
+    /// limit the memory buffered by running uploads (e.g. 512M), starting
+    /// fewer uploads at once if needed, defaults to no limit
+    #[clap(long, value_parser = parse_size)]
+    max_memory: Option<u64>,
+    /// stop starting uploads once the next one would take the bytes uploaded
+    /// over this (e.g. 500G), what's left can be resumed in a later run
+    #[clap(long, value_parser = parse_size)]
+    max_total_bytes: Option<u64>,
+    /// upload nothing if more than this many files are found, unless
+    /// confirmed at a prompt, in case a path points at far more than meant
+    #[clap(long, value_name = "N", conflicts_with = "stream")]
+    max_files: Option<usize>,
+    /// stop starting uploads this long (e.g. 11h) after they started and
+    /// finish the running ones, e.g. within the time limit of a batch job.
+    /// What's left can be resumed in a later run
+    #[clap(long, value_parser = parse_duration)]
+    max_duration: Option<Duration>,
+    /// tune how many uploads run at once (up to --cons) to send about this
+    /// much without going over (e.g. 200MB/s)
+    #[clap(long, value_name = "RATE", value_parser = units::parse_rate)]
+    target_rate: Option<u64>,
+    /// start with a single upload and run more and more until --cons over
+    /// this long (e.g. 30s) or as this many complete (e.g. 100files), for
+    /// servers rate limiting a sudden burst of connections
+    #[clap(long, value_name = "DURATION|Nfiles")]
+    ramp_up: Option<RampUp>,
+    /// make the random choices (retry jitter, --order random, --chaos) from
+    /// this number, the same in every run, to reproduce one
+    #[clap(long)]
+    seed: Option<u64>,
+    /// inject failures at random to try out the retry settings, as
+    /// probabilities per attempt, e.g. read-error=0.01,delay=0.1,5xx=0.05
+    /// (max-delay=1s by default)
+    #[cfg(feature = "chaos")]
+    #[clap(long, value_name = "KIND=P,...")]
+    chaos: Option<upload::chaos::Chaos>,
+    /// size of the reads files are streamed in (e.g. 1M for network
+    /// filesystems), defaults to 64K
+    #[clap(long, value_parser = parse_chunk_size)]
+    chunk_size: Option<usize>,
+    /// write SHA-256 checksums of the uploaded files to this file, in
+    /// sha256sum format, hashing files alongside the uploads
+    #[clap(long, value_name = "FILE")]
+    checksums: Option<PathBuf>,
+    /// write a JSON report of the run to this file: totals, per-file
+    /// throughput percentiles and each upload's outcome and timings
+    #[clap(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+    /// skip the files a previous run uploaded according to its --report, to
+    /// finish it without its job
+    #[clap(long, value_name = "FILE", conflicts_with = "verify_only")]
+    skip_from_report: Option<PathBuf>,
+    /// stream progress as NDJSON (a line per completed upload and the
+    /// counts) to monitoring tools connecting to a unix socket at this path
+    #[clap(long, value_name = "PATH")]
+    progress_socket: Option<PathBuf>,
+    /// write progress for scripts on stdout instead of the status bar, tab
+    /// separated lines whose format stays the same for a version (v1)
+    #[clap(long, value_name = "VERSION", num_args = 0..=1, require_equals = true, default_missing_value = "v1")]
+    porcelain: Option<Porcelain>,
+    /// upload nothing, only check that the files are on the destination with
+    /// the same size, exiting with an error if any are missing or differ
+    #[clap(long)]
+    verify_only: bool,
+    /// with --verify-only also compare contents, by downloading each file.
+    /// With --move, check each upload this way before deleting the file
+    #[clap(long, requires = "verifiable")]
+    verify_checksums: bool,
+    /// delete each local file once it's uploaded (to every destination), to
+    /// drain a staging directory. Directories are left in place
+    #[clap(long = "move", conflicts_with = "verify_only")]
+    move_files: bool,
+    /// encrypt files before uploading them, as age:<recipient> or
+    /// gpg:<keyid>, adding .age or .gpg to their names. Repeat for more
+    /// recipients
+    #[clap(long, value_name = "SCHEME:RECIPIENT", conflicts_with_all = ["verify_only", "verify_checksums"])]
+    encrypt: Vec<String>,
+    /// upload each file's permissions, owner, times and local path as JSON,
+    /// a name.meta.json sidecar per file or a .upload-metadata.json manifest
+    /// per directory
+    #[clap(long, value_name = "sidecar|manifest")]
+    metadata: Option<MetadataMode>,
+    /// once every file was uploaded or found there already, upload a JSON
+    /// manifest of the files and the run as this name next to them, or POST
+    /// it to this http(s) url (without the token)
+    #[clap(long, value_name = "NAME|URL")]
+    dataset_manifest: Option<ManifestTarget>,
+    /// include extended attributes in the --metadata (unix)
+    #[clap(long, requires = "metadata")]
+    xattrs: bool,
+    /// color the status bar and reports: auto (on terminals, unless NO_COLOR
+    /// is set), always or never
+    #[clap(long, value_name = "WHEN", default_value = "auto", global = true)]
+    color: ColorChoice,
+    /// most status bar redraws per second, defaults to 4
+    #[clap(long, value_parser = parse_rate)]
+    refresh_rate: Option<f64>,
+    /// list the N files with the worst throughput in the summary, with their
+    /// sizes and retries
+    #[clap(long, value_name = "N", default_value_t = 5)]
+    top_slowest: usize,
+    /// only show what would be uploaded, and what is skipped and why
+    #[clap(short = 'n', long, global = true)]
+    dry_run: bool,
+    /// only upload files of at least this size (e.g. 1M)
+    #[clap(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+    /// only upload files of at most this size (e.g. 2G)
+    #[clap(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// only upload files modified after this time, either a duration ago
+    /// (e.g. 2h, 7d) or a UTC timestamp (e.g. 2025-06-30 or
+    /// 2025-06-30T14:00:00Z)
+    #[clap(long, value_parser = parse_time)]
+    newer_than: Option<SystemTime>,
+    /// only upload files modified before this time, like --newer-than
+    #[clap(long, value_parser = parse_time)]
+    older_than: Option<SystemTime>,
     /// number of retries for each upload, defaults to 3
     #[clap(short, long)]
     retries: Option<usize>,
+    /// give up on an upload this long after it started, retries included
+    /// (e.g. 30m), defaults to no deadline
+    #[clap(long, value_parser = parse_duration)]
+    file_deadline: Option<Duration>,
+    /// after an upload failed while its data was being sent, when the
+    /// fileservice may have kept part of it: blind uploads again, verify
+    /// lists the file first and replaces a partial copy only with --force
+    #[clap(long, default_value = "blind", value_name = "MODE")]
+    ambiguous_retry: AmbiguousRetry,
+    /// on a 503 or maintenance page wait up to this long for the service to
+    /// be back, polling it, rather than using up retries (0 to not wait)
+    #[clap(long, default_value = "1h", value_parser = parse_duration)]
+    maintenance_wait: Duration,
+    /// ping the fileservice after each this long nothing was uploaded (e.g.
+    /// 10m), keeping the token or login session alive while idle, such as
+    /// outside the --window
+    #[clap(long, value_parser = parse_duration)]
+    keepalive: Option<Duration>,
+    /// also tell about what goes on in the background, such as keepalive pings
+    /// and the steps of --ramp-up
+    #[clap(short, long, global = true)]
+    verbose: bool,
     /// overwrite existing files, defaults to false
     #[clap(short, long)]
     force: bool,
+    /// load the files as CSV tables into CasJobs instead, into the context
+    /// given as path (e.g. MyDB), each table named after its file. The
+    /// endpoint defaults to the CasJobs REST API of jhu-prod
+    #[clap(long, conflicts_with_all = ["verify_only", "force", "encrypt", "archive_per_dir", "metadata", "site"])]
+    casjobs: bool,
     /// path to upload files to
-    path: String,
+    #[clap(required = true)]
+    path: Option<String>,
     /// files to upload
     files: Vec<String>,
+    /// directory jobs are kept in for resuming, defaults to
+    /// ~/.local/state/sciserver-upload
+    #[clap(long, env = "UPLOAD_STATE_DIR", global = true)]
+    state_dir: Option<PathBuf>,
+    /// compare the SHA-256 the fileservice lists for each file, where it
+    /// does, rather than downloading files to hash them
+    #[clap(long, global = true)]
+    listed_checksums: bool,
+    /// hash every file again rather than taking the checksums of files that
+    /// didn't change (by size and modification time) from the state directory
+    #[clap(long, global = true)]
+    no_hash_cache: bool,
+    /// configuration file, with names for endpoints in its [endpoints]
+    /// section, defaults to ~/.config/sciserver-upload/config
+    #[clap(long, env = "UPLOAD_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    /// deployment profile from the [env.NAME] section of the config file:
+    /// its endpoint, token and auth scheme unless given otherwise, and the
+    /// volume paths given are relative to (unless starting with /)
+    #[clap(long, value_name = "NAME", env = "SCISERVER_ENV", global = true)]
+    env: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// check dns, tls, auth and latency against the endpoint
+    Check,
+    /// continue an interrupted upload, skipping the files already uploaded
+    Resume {
+        /// job ID, as printed when the upload started or listed by jobs
+        id: String,
+    },
+    /// list upload jobs and how far they got
+    Jobs {
+        /// delete the jobs started longer ago than this (e.g. 30d) instead,
+        /// finished or not
+        #[clap(long, value_name = "AGE", value_parser = parse_duration)]
+        prune: Option<Duration>,
+    },
+    /// compare a local directory with the path on the fileservice: files only
+    /// local (+, would be uploaded), only remote (-) or differing (~)
+    Diff {
+        /// path on the fileservice
+        path: String,
+        /// local directory
+        dir: String,
+        /// also compare contents, by downloading each file
+        #[clap(long)]
+        checksums: bool,
+    },
+    /// keep a local directory and a path on the fileservice in sync both
+    /// ways, uploading and downloading what's missing or newer
+    Sync {
+        /// path on the fileservice
+        path: String,
+        /// local directory
+        dir: String,
+        /// mirror the directory: delete remote files that aren't local
+        /// instead of downloading them, after confirmation
+        #[clap(long)]
+        delete: bool,
+        /// delete without asking for confirmation
+        #[clap(long, requires = "delete")]
+        yes: bool,
+    },
+    /// run a local REST API taking upload jobs (POST /jobs), telling their
+    /// status (GET /jobs/<id>) and cancelling them (DELETE /jobs/<id>), for
+    /// callers sending the secret it prints at start as bearer token
+    Serve {
+        /// address to listen on, anyone who can connect and knows the secret
+        /// can upload with the token
+        #[clap(long, default_value = "127.0.0.1:7878")]
+        listen: SocketAddr,
+        /// also serve the same operations over gRPC on this address, with
+        /// per-file progress streamed to watchers (see proto/upload.proto)
+        #[cfg(feature = "grpc")]
+        #[clap(long, value_name = "ADDR")]
+        grpc: Option<SocketAddr>,
+    },
+}
+
+/// Ask to type "delete" before deleting remote files, refusing if there's no
+/// one to ask.
+fn confirm_delete(path: &str, names: &[String]) -> bool {
+    if !std::io::stdin().is_terminal() {
+        eprintln!("Not deleting {} remote file(s) without confirmation, pass --yes", names.len());
+        return false;
+    }
+    for name in names.iter().take(20) {
+        eprintln!("  {}", name);
+    }
+    if names.len() > 20 {
+        eprintln!("  ... and {} more", names.len() - 20);
+    }
+    eprint!("Type 'delete' to delete these {} file(s) from {}: ", names.len(), path);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && answer.trim() == "delete"
+}
+
+/// Ask `question` on the terminal, no if there's no one to ask.
+fn confirm(question: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// The command line without the token, which isn't written to disk.
+fn without_token(argv: impl IntoIterator<Item = String>) -> Vec<String> {
+    // short options taking a value, the rest of a -abc group is that value
+    const SHORT_VALUES: &str = "etmcr";
+    // the mirror without its token
+    let endpoint = |mirror: &str| mirror.split(',').next().unwrap_or_default().to_string();
+    let mut kept = Vec::new();
+    let mut argv = argv.into_iter();
+    while let Some(arg) = argv.next() {
+        if arg == "--" {
+            kept.push(arg);
+            kept.extend(argv);
+            break;
+        } else if arg == "--token" {
+            argv.next();
+        } else if arg.starts_with("--token=") {
+        } else if arg == "--mirror" {
+            kept.push(arg);
+            kept.extend(argv.next().as_deref().map(endpoint));
+        } else if let Some(mirror) = arg.strip_prefix("--mirror=") {
+            kept.push(format!("--mirror={}", endpoint(mirror)));
+        } else if let Some(group) = arg.strip_prefix('-').filter(|g| !g.starts_with('-') && !g.is_empty()) {
+            let end = group.find(|c| SHORT_VALUES.contains(c)).unwrap_or(group.len());
+            if group[end..].starts_with('t') {
+                // -t, -ft, -tvalue or -ftvalue
+                if group.len() == end + 1 {
+                    argv.next();
+                }
+                if end > 0 {
+                    kept.push(format!("-{}", &group[..end]));
+                }
+            } else if group[end..].starts_with('m') {
+                // -m, -fm, -mvalue or -fmvalue
+                kept.push(format!("-{}{}", &group[..=end], endpoint(&group[end + 1..])));
+                if group.len() == end + 1 {
+                    kept.extend(argv.next().as_deref().map(endpoint));
+                }
+            } else {
+                kept.push(arg);
+            }
+        } else {
+            kept.push(arg);
+        }
+    }
+    kept
+}
+
+/// The token given with --token or SCISERVER_TOKEN, else the one of the
+/// profile, like its other settings. None is needed with a `login`, the
+/// session may be all the auth there is.
+fn choose_token(given: Option<String>, profile: &Profile, login: bool) -> Result<String, String> {
+    match (given, &profile.token) {
+        (Some(token), _) => Ok(token),
+        (None, Some(source)) => source.read(),
+        (None, None) if login => Ok(String::new()),
+        (None, None) => panic!("token not set"),
+    }
+}
+
+fn parse_window(s: &str) -> Result<u32, String> {
+    let size = parse_size(s)?;
+    u32::try_from(size).map_err(|_| format!("window too large: {}", s))
+}
+
+fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    match usize::try_from(parse_size(s)?) {
+        Ok(0) => Err("chunk size must be at least 1 byte".to_string()),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("chunk size too large: {}", s)),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("expected a positive number, got {}", s)),
+    }
+}
+
+fn parse_cons(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("at least 1 upload has to run at once".to_string()),
+        Ok(cons) => Ok(cons),
+        Err(e) => Err(format!("{}: {}", e, s)),
+    }
+}
+
+fn parse_dest_cons(s: &str) -> Result<(String, usize), String> {
+    let invalid = || format!("expected PATH=N, e.g. Storage/u/archive=2, got {}", s);
+    let (path, limit) = s.rsplit_once('=').ok_or_else(invalid)?;
+    match limit.trim().parse() {
+        Ok(limit) if limit > 0 && !path.trim_matches('/').is_empty() => Ok((path.trim_matches('/').to_string(), limit)),
+        _ => Err(invalid()),
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
-    let endpoint = args.endpoint.unwrap_or("https://apps.sciserver.org/fileservice/api/file".to_string());
-    let prefix = format!("{}/{}", endpoint.trim_matches('/'), args.path.trim_matches('/'));
-    let cons = args.cons.unwrap_or(10);
-    let retries = args.cons.unwrap_or(3);
-    let token = args.token.expect("token not set");
+    let mut args = Args::parse();
+    let state_dir = args.state_dir.clone().unwrap_or_else(default_state_dir);
+    let mut job = None;
+    match args.command {
+        Some(Command::Jobs { prune: Some(age) }) => {
+            let pruned = prune_jobs(&state_dir, age).unwrap_or_else(|e| {
+                eprintln!("Failed to delete jobs in {}: {}", state_dir.display(), e);
+                std::process::exit(1);
+            });
+            println!("Deleted {} job(s) started over {} ago", pruned.len(), humantime::format_duration(age));
+            return;
+        }
+        Some(Command::Jobs { prune: None }) => {
+            let jobs = list_jobs(&state_dir).unwrap_or_else(|e| {
+                eprintln!("Failed to list jobs in {}: {}", state_dir.display(), e);
+                std::process::exit(1);
+            });
+            for job in jobs {
+                let status = if job.done >= job.uploads { "complete" } else { "incomplete" };
+                println!("{}  {}  {}/{} uploads ({})  {}", job.id, job.created, job.done, job.uploads, status, job.path);
+            }
+            return;
+        }
+        Some(Command::Resume { ref id }) => {
+            let resumed = JobState::open(&state_dir, id).unwrap_or_else(|e| {
+                eprintln!("Failed to open job {} in {}: {}", id, state_dir.display(), e);
+                std::process::exit(1);
+            });
+            let stored = resumed.args().unwrap_or_default();
+            let mut resumed_args = Args::try_parse_from(std::iter::once("upload".to_string()).chain(stored))
+                .unwrap_or_else(|e| e.exit());
+            resumed_args.token = args.token;
+            // neither are mirror tokens, those given again are used
+            for mirror in &mut resumed_args.mirror {
+                if let Some(given) = args.mirror.iter().find(|m| m.split_once(',').is_some_and(|(e, _)| e == mirror)) {
+                    *mirror = given.clone();
+                }
+            }
+            args = resumed_args;
+            job = Some(resumed);
+        }
+        _ => (),
+    }
+    let config_path = args.config.clone().unwrap_or_else(default_config_path);
+    let config = Config::load(&config_path).unwrap_or_else(|e| {
+        eprintln!("Failed to read the config file {}: {}", config_path.display(), e);
+        std::process::exit(1);
+    });
+    let endpoint_urls = |endpoints: Vec<String>| endpoints.iter()
+        .map(|e| config.endpoint(e))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid endpoint: {}", e);
+            std::process::exit(1);
+        });
+    let profile = args.env.as_ref().map_or(Ok(Profile::default()), |env| config.profile(env)).unwrap_or_else(|e| {
+        eprintln!("Invalid --env: {}", e);
+        std::process::exit(1);
+    });
+    if args.endpoint.is_empty() && args.site.is_none() {
+        args.endpoint.extend(profile.endpoint.clone());
+    }
+    let (mirrors, mirror_tokens): (Vec<_>, Vec<_>) = args.mirror.into_iter()
+        .map(|mirror| match mirror.split_once(',') {
+            Some((endpoint, token)) => (endpoint.to_string(), Some(token.to_string())),
+            None => (mirror, None),
+        })
+        .unzip();
+    let (endpoints, mirrors) = (endpoint_urls(args.endpoint), endpoint_urls(mirrors));
+    let token = choose_token(args.token, &profile, args.login.is_some()).unwrap_or_else(|e| {
+        eprintln!("Failed to read the token of environment {}: {}", args.env.as_deref().unwrap_or_default(), e);
+        std::process::exit(1);
+    });
+    let login = args.login.map(|url| Login {
+        url,
+        username: args.username.unwrap_or_default(),
+        password: std::env::var("SCISERVER_PASSWORD").unwrap_or_else(|_| {
+            eprintln!("--login needs the password in SCISERVER_PASSWORD");
+            std::process::exit(1);
+        }),
+    });
+    let endpoint = match args.casjobs {
+        true => "https://apps.sciserver.org/casjobs/RestApi",
+        false => "https://apps.sciserver.org/fileservice/api/file",
+    };
+    let mut settings = Settings::with_endpoint(endpoint.to_string(), token);
+    if !endpoints.is_empty() {
+        settings = settings.with_endpoints(endpoints);
+    }
+    let ca_certs = args.ca_cert.map(|path| std::fs::read(&path).unwrap_or_else(|e| {
+        eprintln!("Failed to read CA certificate {}: {}", path.display(), e);
+        std::process::exit(1);
+    }));
+    settings = settings
+        .with_mirrors(mirrors)
+        .with_mirror_tokens(mirror_tokens)
+        .with_proxy(args.proxy)
+        .with_ca_certs(ca_certs)
+        .with_insecure(args.insecure)
+        .with_auth(args.auth.or(profile.auth.clone()).unwrap_or(AuthScheme::XAuthToken))
+        .with_login(login)
+        .with_http_version(args.http)
+        .with_http2_windows(args.http2_stream_window, args.http2_connection_window);
+    let idle_timeout = args.pool_idle_timeout.unwrap_or(Duration::from_secs(90));
+    let keepalive = args.tcp_keepalive.unwrap_or(Duration::from_secs(60));
+    settings = settings
+        .with_pool(args.pool_max_idle, Some(idle_timeout).filter(|d| !d.is_zero()))
+        .with_tcp_keepalive(Some(keepalive).filter(|d| !d.is_zero()));
+    let dns_cache_ttl = args.dns_cache_ttl.unwrap_or(Duration::from_secs(300));
+    settings = settings
+        .with_resolve(args.resolve)
+        .with_dns_cache_ttl(Some(dns_cache_ttl).filter(|d| !d.is_zero()));
+    if args.ipv4 {
+        settings = settings.with_ip_family(Some(IpFamily::V4));
+    } else if args.ipv6 {
+        settings = settings.with_ip_family(Some(IpFamily::V6));
+    }
+    if args.insecure {
+        eprintln!("WARNING: --insecure disables tls certificate verification, your token and");
+        eprintln!("WARNING: data can be intercepted. Never use this against production endpoints!");
+    }
+    if let Some(site) = &args.site {
+        let endpoints = discover_endpoints(&settings, site).await.unwrap_or_else(|e| {
+            eprintln!("Failed to find the fileservice of {}: {}", site, e);
+            std::process::exit(1);
+        });
+        if endpoints.len() > 1 {
+            eprintln!("{} lists {} fileservices, using {} (pass --endpoint for another)", site, endpoints.len(),
+                endpoints[0]);
+        }
+        settings = settings.with_endpoints(endpoints[..1].to_vec());
+    }
 
-    let settings = Settings::new(
-        prefix,
-        token.clone(),
-        cons,
-        retries,
-        args.force
-    );
+    if let Some(Command::Check) = args.command {
+        let mut failed = false;
+        for (endpoint, results) in check_endpoints(&settings).await {
+            println!("{}", endpoint);
+            print!("{}", format_check_table(&results));
+            failed |= results.iter().any(|r| !r.ok);
+        }
+        if failed {
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    upload_many(args.files, settings).await;
+    let encryption = Encryption::parse(&args.encrypt).unwrap_or_else(|e| {
+        eprintln!("Invalid --encrypt: {}", e);
+        std::process::exit(1);
+    });
+    if encryption.is_some() && matches!(args.command, Some(Command::Diff { .. } | Command::Sync { .. })) {
+        eprintln!("--encrypt can't be used with diff or sync, encrypted copies don't compare with local files");
+        std::process::exit(1);
+    }
+    if args.casjobs && matches!(args.command, Some(Command::Diff { .. } | Command::Sync { .. })) {
+        eprintln!("--casjobs can't be used with diff or sync, tables don't compare with local files");
+        std::process::exit(1);
+    }
+    if args.archive_per_dir.is_some() && matches!(args.command, Some(Command::Diff { .. } | Command::Sync { .. })) {
+        eprintln!("--archive-per-dir can't be used with diff or sync, archives don't compare with local files");
+        std::process::exit(1);
+    }
+    let settings = settings
+        .with_path(profile.path(&args.path.unwrap_or_default()))
+        .with_copies(args.copy_to.iter().map(|path| profile.path(path)).collect())
+        .with_concurrency(args.cons.unwrap_or(10))
+        .with_retries(args.retries.unwrap_or(3))
+        .with_file_deadline(args.file_deadline)
+        .with_ambiguous_retry(args.ambiguous_retry)
+        .with_maintenance_wait(Some(args.maintenance_wait).filter(|wait| !wait.is_zero()))
+        .with_keepalive(args.keepalive)
+        .with_verbose(args.verbose)
+        .with_overwrite(args.force)
+        .with_casjobs(args.casjobs)
+        .with_large_files(args.large_size.unwrap_or(100 << 20), args.large_cons)
+        .with_destination_limits(args.dest_cons)
+        .with_order(args.order)
+        .with_window(args.window)
+        .with_nice(args.nice)
+        .with_dedup_hardlinks(args.dedup_hardlinks)
+        .with_recursive(args.recursive)
+        .with_stream(args.stream)
+        .with_archive_per_dir(args.archive_per_dir)
+        .with_excludes(args.exclude)
+        .with_ignore_files(args.ignore_file)
+        .with_hidden(args.hidden && !args.no_hidden)
+        .with_follow_symlinks(args.follow_symlinks)
+        .with_scan_threads(args.scan_threads)
+        .with_scan_progress(args.porcelain.is_none())
+        .with_dry_run(args.dry_run)
+        .with_reupload_modified(args.reupload_modified)
+        .with_stable_window(args.stable_for, args.follow)
+        .with_skip_sparse(args.skip_sparse)
+        .with_max_memory(args.max_memory)
+        .with_max_total_bytes(args.max_total_bytes)
+        .with_max_files(args.max_files)
+        .with_confirm(Some(Arc::new(confirm)))
+        .with_max_duration(args.max_duration)
+        .with_target_rate(args.target_rate)
+        .with_ramp_up(args.ramp_up)
+        .with_seed(args.seed)
+        .with_chunk_size(args.chunk_size.unwrap_or(64 << 10))
+        .with_checksums(args.checksums)
+        .with_hash_cache(Some(HashCache::new(state_dir.join("hashes"))).filter(|_| !args.no_hash_cache))
+        .with_listed_checksums(args.listed_checksums)
+        .with_content_index(args.dedup.then(|| ContentIndex::new(state_dir.join("contents"))))
+        .with_report(args.report)
+        .with_progress_socket(args.progress_socket)
+        .with_porcelain(args.porcelain)
+        .with_move_files(args.move_files)
+        .with_verify_uploads(args.move_files && args.verify_checksums)
+        .with_encryption(encryption)
+        .with_metadata(args.metadata)
+        .with_dataset_manifest(args.dataset_manifest)
+        .with_xattrs(args.xattrs)
+        .with_refresh_rate(args.refresh_rate.unwrap_or(4.0))
+        .with_color(args.color)
+        .with_top_slowest(args.top_slowest)
+        .with_size_limits(args.min_size, args.max_size)
+        .with_time_limits(args.newer_than, args.older_than);
+    let settings = match &args.skip_from_report {
+        Some(report) => match uploaded_in_report(report) {
+            Ok(uploaded) => settings.with_skip_uploaded(uploaded),
+            Err(e) => {
+                eprintln!("Invalid --skip-from-report: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => settings,
+    };
+    #[cfg(feature = "chaos")]
+    let settings = {
+        if let Some(chaos) = &args.chaos {
+            eprintln!("Injecting failures (--chaos): {}", chaos);
+        }
+        settings.with_chaos(args.chaos)
+    };
+    if let Some(Command::Diff { path, dir, checksums }) = args.command {
+        if !diff(dir, Arc::new(settings.with_path(profile.path(&path))), checksums).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Sync { path, dir, delete, yes }) = args.command {
+        let confirm = |names: &[String]| yes || confirm_delete(&path, names);
+        // only files found newer than the remote copy are uploaded
+        let settings = Arc::new(settings.with_path(profile.path(&path)).with_overwrite(true));
+        if !sync(dir, settings, delete, confirm).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Serve { listen, #[cfg(feature = "grpc")] grpc }) = args.command {
+        #[cfg(not(feature = "grpc"))]
+        let grpc = None;
+        if !serve(listen, grpc, settings).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.verify_only {
+        if !verify_many(args.files, Arc::new(settings), args.verify_checksums).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let files = args.files.iter()
+        .map(|f| if args.priorities { UploadRequest::parse_prefixed(f) } else { UploadRequest::new(f.as_str()) })
+        .map(|request| if args.map { request.split_dest() } else { Ok(request) })
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid --map file: {}", e);
+            std::process::exit(1);
+        });
+    if job.is_none() && !args.dry_run {
+        let argv = without_token(std::env::args().skip(1));
+        job = JobState::create(&state_dir, &argv)
+            .inspect(|job| eprintln!("Job ID: {} (resume with: upload resume {})", job.id(), job.id()))
+            .inspect_err(|e| eprintln!("Warning: can't keep job state in {}: {}", state_dir.display(), e))
+            .ok();
+    }
+    let settings = settings.with_job(job);
+    upload_many(files, Arc::new(settings)).await;
 }
 
+#[cfg(test)]
+mod tests {
+    use upload::config::TokenSource;
+
+    use super::*;
+
+    #[test]
+    fn test_without_token() {
+        let strip = |args: &str| without_token(args.split(' ').map(str::to_string)).join(" ");
+        assert_eq!(strip("-t x -f path a"), "-f path a");
+        assert_eq!(strip("--token x path a"), "path a");
+        assert_eq!(strip("--token=x path a"), "path a");
+        assert_eq!(strip("-tx path a"), "path a");
+        assert_eq!(strip("-ft x path a"), "-f path a");
+        assert_eq!(strip("-Rftx path a"), "-Rf path a");
+        assert_eq!(strip("-c10t path a"), "-c10t path a");
+        assert_eq!(strip("path -- -t a"), "path -- -t a");
+        assert_eq!(strip("-m backup,x -t y path a"), "-m backup path a");
+        assert_eq!(strip("--mirror backup,x path a"), "--mirror backup path a");
+        assert_eq!(strip("--mirror=backup,x path a"), "--mirror=backup path a");
+        assert_eq!(strip("-fmbackup,x path a"), "-fmbackup path a");
+        assert_eq!(strip("-m backup path a"), "-m backup path a");
+    }
+
+    #[test]
+    fn test_choose_token() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("token"), "profile-token\n").unwrap();
+        let profile = Profile { token: Some(TokenSource::File(tempdir.path().join("token"))), ..Profile::default() };
+        assert_eq!(choose_token(Some("given".to_string()), &profile, false), Ok("given".to_string()));
+        assert_eq!(choose_token(None, &profile, false), Ok("profile-token".to_string()));
+        // not read when given
+        let unset = TokenSource::Env("UPLOAD_TEST_UNSET_TOKEN".to_string());
+        let unset = Profile { token: Some(unset), ..Profile::default() };
+        assert_eq!(choose_token(Some("given".to_string()), &unset, false), Ok("given".to_string()));
+        assert!(choose_token(None, &unset, false).is_err());
+        assert_eq!(choose_token(None, &Profile::default(), true), Ok(String::new()));
+    }
+
+    #[test]
+    fn test_parse_cons() {
+        assert_eq!(parse_cons("4"), Ok(4));
+        assert!(parse_cons("0").is_err());
+        assert!(parse_cons("-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0.5"), Ok(0.5));
+        for rate in ["0", "-1", "NaN", "inf", "x"] {
+            assert!(parse_rate(rate).is_err(), "{}", rate);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Recording what an http PUT loses of a file (permissions, owner, times and
+//! where it came from) so archives can be restored as they were. The metadata
+//! is uploaded as JSON next to the files, either a sidecar per file or a
+//! manifest per directory listing its files by name. Extended attributes can
+//! be included too (unix), e.g. provenance tags kept as xattrs.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use serde_json::{json, Map, Value};
+
+/// how metadata of uploaded files is recorded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetadataMode {
+    /// `name.meta.json` next to each file
+    Sidecar,
+    /// `.upload-metadata.json` in each directory
+    Manifest,
+}
+
+impl std::str::FromStr for MetadataMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sidecar" => Ok(MetadataMode::Sidecar),
+            "manifest" => Ok(MetadataMode::Manifest),
+            _ => Err(format!("unknown metadata mode {:?}, expected sidecar or manifest", s)),
+        }
+    }
+}
+
+pub(crate) const MANIFEST: &str = ".upload-metadata.json";
+pub(crate) const SIDECAR_SUFFIX: &str = ".meta.json";
+
+fn format_time(time: io::Result<std::time::SystemTime>) -> Value {
+    time.map_or(Value::Null, |t| humantime::format_rfc3339(t).to_string().into())
+}
+
+/// Extended attributes of the file at `path`, values as text if they are
+/// utf-8 and else as `{"hex": ...}`. None if the filesystem has none.
+#[cfg(unix)]
+fn xattrs(path: &str) -> io::Result<Option<Map<String, Value>>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut attrs = Map::new();
+    for name in names {
+        // removed since listed
+        let Some(value) = xattr::get(path, &name)? else { continue };
+        let value = match String::from_utf8(value) {
+            Ok(text) => text.into(),
+            Err(e) => json!({ "hex": crate::checksum::to_hex(e.as_bytes()) }),
+        };
+        attrs.insert(name.to_string_lossy().to_string(), value);
+    }
+    Ok(Some(attrs))
+}
+
+/// Metadata of the local file at `path`, with its extended attributes if
+/// `xattrs` and the platform has them.
+pub(crate) fn describe(path: &str, xattrs: bool) -> io::Result<Value> {
+    let metadata = std::fs::metadata(path)?;
+    let original = std::fs::canonicalize(path).map_or_else(|_| path.to_string(), |p| crate::inputs::display_path(&p));
+    let mut value = json!({
+        "path": original,
+        "size": metadata.len(),
+        "modified": format_time(metadata.modified()),
+        "accessed": format_time(metadata.accessed()),
+        "created": format_time(metadata.created()),
+    });
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        value["mode"] = format!("{:04o}", metadata.mode() & 0o7777).into();
+        value["uid"] = metadata.uid().into();
+        value["gid"] = metadata.gid().into();
+        if xattrs && let Some(attrs) = self::xattrs(path)? {
+            value["xattrs"] = attrs.into();
+        }
+    }
+    #[cfg(windows)]
+    {
+        value["readonly"] = metadata.permissions().readonly().into();
+        let _ = xattrs;
+    }
+    Ok(value)
+}
+
+/// The documents to upload for files described as (remote name, metadata):
+/// (name, json) pairs of a sidecar per file or a manifest per directory.
+pub(crate) fn documents(mode: MetadataMode, files: Vec<(String, Value)>) -> Vec<(String, String)> {
+    match mode {
+        MetadataMode::Sidecar => files.into_iter()
+            .map(|(name, value)| (format!("{}{}", name, SIDECAR_SUFFIX), pretty(&value)))
+            .collect(),
+        MetadataMode::Manifest => {
+            let mut dirs: BTreeMap<&str, Map<String, Value>> = BTreeMap::new();
+            for (name, value) in &files {
+                let (dir, file) = name.rsplit_once('/').unwrap_or(("", name));
+                dirs.entry(dir).or_default().insert(file.to_string(), value.clone());
+            }
+            dirs.into_iter()
+                .map(|(dir, entries)| {
+                    let name = if dir.is_empty() { MANIFEST.to_string() } else { format!("{}/{}", dir, MANIFEST) };
+                    (name, pretty(&json!({ "files": entries })))
+                })
+                .collect()
+        }
+    }
+}
+
+/// The manifest `document` with the files of the `existing` one (uploaded by
+/// an earlier run) it doesn't describe, so uploading it doesn't lose them.
+pub(crate) fn merge(existing: &str, document: &str) -> Result<String, String> {
+    let existing: Value = serde_json::from_str(existing).map_err(|e| format!("not a manifest: {}", e))?;
+    let Some(existing) = existing["files"].as_object() else { return Err("not a manifest, no files".to_string()) };
+    let mut merged: Value = serde_json::from_str(document).map_err(|e| e.to_string())?;
+    let files = merged["files"].as_object_mut().ok_or("no files")?;
+    for (name, value) in existing {
+        files.entry(name.clone()).or_insert_with(|| value.clone());
+    }
+    Ok(pretty(&merged))
+}
+
+fn pretty(value: &Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_default() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("data.fits");
+        std::fs::write(&path, "hello").unwrap();
+        let value = describe(path.to_str().unwrap(), false).unwrap();
+        assert_eq!(value["size"], 5);
+        assert!(value["path"].as_str().unwrap().ends_with("data.fits"));
+        assert!(value["modified"].is_string());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+            assert_eq!(describe(path.to_str().unwrap(), false).unwrap()["mode"], "0640");
+        }
+        assert!(describe("doesnotexist", false).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_xattrs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("data.fits");
+        std::fs::write(&path, "hello").unwrap();
+        // not every filesystem has user xattrs (tmpfs on older kernels)
+        if xattr::set(&path, "user.provenance", b"survey=sdss").is_err() {
+            return;
+        }
+        xattr::set(&path, "user.raw", &[0xff, 0x00]).unwrap();
+        let value = describe(path.to_str().unwrap(), true).unwrap();
+        assert_eq!(value["xattrs"]["user.provenance"], "survey=sdss");
+        assert_eq!(value["xattrs"]["user.raw"]["hex"], "ff00");
+        assert!(describe(path.to_str().unwrap(), false).unwrap().get("xattrs").is_none());
+    }
+
+    #[test]
+    fn test_documents() {
+        let files = vec![
+            ("a.txt".to_string(), json!({"size": 1})),
+            ("d/b.txt".to_string(), json!({"size": 2})),
+            ("d/c.txt".to_string(), json!({"size": 3})),
+        ];
+        let sidecars = documents(MetadataMode::Sidecar, files.clone());
+        let names: Vec<_> = sidecars.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a.txt.meta.json", "d/b.txt.meta.json", "d/c.txt.meta.json"]);
+
+        let manifests = documents(MetadataMode::Manifest, files);
+        let names: Vec<_> = manifests.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, [".upload-metadata.json", "d/.upload-metadata.json"]);
+        let manifest: Value = serde_json::from_str(&manifests[1].1).unwrap();
+        assert_eq!(manifest["files"]["c.txt"]["size"], 3);
+
+        let earlier = pretty(&json!({"files": {"a.txt": {"size": 9}, "e.txt": {"size": 5}}}));
+        let merged: Value = serde_json::from_str(&merge(&earlier, &manifests[0].1).unwrap()).unwrap();
+        assert_eq!(merged["files"], json!({"a.txt": {"size": 1}, "e.txt": {"size": 5}}));
+        assert!(merge("{}", &manifests[0].1).is_err());
+    }
+}
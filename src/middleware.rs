@@ -0,0 +1,98 @@
+//! Hooks around the requests uploading files, for library users to sign
+//! them, add telemetry or answer some from a cache without changing the
+//! upload loop. Each [`Middleware`] gets the request ready to send and passes
+//! it on (changed or not) to the next, the last passing it to the client, or
+//! answers it itself. They see every attempt, retries included.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use reqwest::{Client, Request, Response};
+
+/// what a middleware answers with, in time
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A layer around the upload requests, e.g.
+///
+/// ```
+/// use upload::middleware::{BoxFuture, Middleware, Next};
+///
+/// struct Signed;
+///
+/// impl Middleware for Signed {
+///     fn handle<'a>(&'a self, mut request: reqwest::Request, next: Next<'a>)
+///         -> BoxFuture<'a, reqwest::Result<reqwest::Response>> {
+///         request.headers_mut().insert("X-Signature", reqwest::header::HeaderValue::from_static("..."));
+///         next.run(request)
+///     }
+/// }
+/// ```
+pub trait Middleware: Send + Sync {
+    /// Send `request` on with `next`, or answer it.
+    fn handle<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, reqwest::Result<Response>>;
+}
+
+/// the middleware after one, then the client
+pub struct Next<'a> {
+    client: &'a Client,
+    rest: &'a [Arc<dyn Middleware>],
+}
+
+impl<'a> Next<'a> {
+    /// Send `request` through the rest of the middleware.
+    pub fn run(self, request: Request) -> BoxFuture<'a, reqwest::Result<Response>> {
+        match self.rest.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, Next { client: self.client, rest }),
+            None => Box::pin(self.client.execute(request)),
+        }
+    }
+}
+
+/// Send `request` with `client` through `middleware`, the first outermost.
+pub(crate) async fn send(client: &Client, middleware: &[Arc<dyn Middleware>], request: reqwest::RequestBuilder)
+    -> reqwest::Result<Response> {
+    if middleware.is_empty() {
+        return request.send().await;
+    }
+    Next { client, rest: middleware }.run(request.build()?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// notes the requests going through it, as `name`
+    struct Log(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Log {
+        fn handle<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, reqwest::Result<Response>> {
+            self.1.lock().unwrap().push(format!("{} {}", self.0, request.url().path()));
+            next.run(request)
+        }
+    }
+
+    /// answers every request itself
+    struct Cached;
+
+    impl Middleware for Cached {
+        fn handle<'a>(&'a self, _: Request, _: Next<'a>) -> BoxFuture<'a, reqwest::Result<Response>> {
+            Box::pin(async { Ok(hyper::Response::new("cached").into()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let middleware: Vec<Arc<dyn Middleware>> =
+            vec![Arc::new(Log("outer", log.clone())), Arc::new(Log("inner", log.clone())), Arc::new(Cached)];
+        let client = Client::new();
+        // never sent, nothing listens there
+        let response = send(&client, &middleware, client.put("http://127.0.0.1:9/a")).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "cached");
+        assert_eq!(*log.lock().unwrap(), ["outer /a", "inner /a"]);
+        assert!(send(&client, &[], client.put("http://127.0.0.1:9/a")).await.is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//! Going easy on shared machines (`--nice`), e.g. compute nodes where other
+//! users work interactively. On linux reads of files for uploads and their
+//! checksums get the idle io class, so they're only served when the disk has
+//! nothing else to do. Elsewhere fewer uploads run at once and each reads at
+//! a modest rate.
+
+/// most uploads running at once where reads can't get a lower priority
+#[cfg(not(target_os = "linux"))]
+const CONCURRENCY: usize = 2;
+/// bytes per second each upload reads where reads can't get a lower priority
+#[cfg(not(target_os = "linux"))]
+const READ_RATE: f64 = 20.0 * 1024.0 * 1024.0;
+
+/// how many uploads may run at once, given `concurrency` otherwise
+pub(crate) fn concurrency(concurrency: usize) -> usize {
+    #[cfg(not(target_os = "linux"))]
+    return concurrency.min(CONCURRENCY);
+    #[cfg(target_os = "linux")]
+    concurrency
+}
+
+/// Held by a blocking thread while it reads a file for an upload: lowers
+/// the thread's io priority until dropped, as the thread goes on to run other
+/// tasks of the pool after.
+#[cfg(target_os = "linux")]
+pub(crate) struct Reading {
+    /// the priority to restore, if it was lowered
+    before: Option<libc::c_int>,
+}
+
+// IOPRIO_WHO_PROCESS of the calling thread
+#[cfg(target_os = "linux")]
+const WHO_PROCESS: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const CLASS_IDLE: libc::c_int = 3 << 13;
+
+#[cfg(target_os = "linux")]
+fn ioprio_set(ioprio: libc::c_int) -> bool {
+    // SAFETY: plain syscall with integer arguments, failing harmlessly (e.g.
+    // blocked by seccomp) with reads keeping their priority
+    unsafe { libc::syscall(libc::SYS_ioprio_set, WHO_PROCESS, 0, ioprio) == 0 }
+}
+
+#[cfg(target_os = "linux")]
+fn ioprio_get() -> Option<libc::c_int> {
+    // SAFETY: as above
+    let ioprio = unsafe { libc::syscall(libc::SYS_ioprio_get, WHO_PROCESS, 0) };
+    (ioprio >= 0).then_some(ioprio as libc::c_int)
+}
+
+/// Called by a blocking thread before it reads `len` bytes of a file, the
+/// read getting the idle io class while the result is held.
+#[cfg(target_os = "linux")]
+pub(crate) fn reading(_len: usize) -> Reading {
+    let before = ioprio_get().filter(|_| ioprio_set(CLASS_IDLE));
+    Reading { before }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Reading {
+    fn drop(&mut self) {
+        // older kernels refuse some priorities they report, the default
+        // (following the cpu nice value) is the next best
+        if let Some(before) = self.before
+            && !ioprio_set(before) {
+            ioprio_set(0);
+        }
+    }
+}
+
+/// Held by a blocking thread while it reads a file for an upload.
+#[cfg(not(target_os = "linux"))]
+pub(crate) struct Reading;
+
+/// Called by a blocking thread before it reads `len` bytes of a file, pacing
+/// the read.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn reading(len: usize) -> Reading {
+    std::thread::sleep(std::time::Duration::from_secs_f64(len as f64 / READ_RATE));
+    Reading
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reading() {
+        // either lowers this thread's io priority or paces it, never fails
+        #[cfg(target_os = "linux")]
+        let before = ioprio_get();
+        {
+            let _reading = reading(1024);
+            #[cfg(target_os = "linux")]
+            assert!(ioprio_get() == before || ioprio_get() == Some(CLASS_IDLE));
+        }
+        // the thread goes back to other tasks as it was
+        #[cfg(target_os = "linux")]
+        assert_eq!(ioprio_get(), before);
+        drop(reading(1024));
+        assert!(concurrency(10) >= 1 && concurrency(10) <= 10);
+        assert_eq!(concurrency(1), 1);
+    }
+}
@@ -0,0 +1,123 @@
+//! Riding out planned outages of the fileservice. A 503 or a maintenance page
+//! means the service is down for a while, so rather than each upload using up
+//! its retries the whole run waits: the first upload to notice polls the
+//! service until it's back, the others wait for it to say so.
+
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// how often the service is polled while it's down
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether a response says the service is down for maintenance: a 503, or a
+/// server error page mentioning maintenance (e.g. from a proxy in front).
+pub(crate) fn is_maintenance(status: StatusCode, body: &str) -> bool {
+    status == StatusCode::SERVICE_UNAVAILABLE
+        || status.is_server_error() && body.to_lowercase().contains("maintenance")
+}
+
+/// whether the service is down, shared by the uploads of a run
+pub(crate) struct Outage {
+    /// when the service was found down, None while it's up
+    down: watch::Sender<Option<Instant>>,
+    poll: Duration,
+}
+
+/// Marks the service up again when polling ends, however it ends, so waiting
+/// uploads don't wait on a poller that was cancelled.
+struct Polling<'a>(&'a watch::Sender<Option<Instant>>);
+
+impl Drop for Polling<'_> {
+    fn drop(&mut self) {
+        self.0.send_replace(None);
+    }
+}
+
+impl Outage {
+    pub(crate) fn new(poll: Duration) -> Self {
+        Outage { down: watch::Sender::new(None), poll }
+    }
+
+    /// since when the service is down, if it is
+    pub(crate) fn down_since(&self) -> Option<Instant> {
+        *self.down.borrow()
+    }
+
+    /// Wait for the service to be back, at most `limit`, polling it with
+    /// `probe` (true once it's up) unless another upload already does.
+    /// Returns whether it's back, false if waiting timed out.
+    pub(crate) async fn wait<F, Fut>(&self, limit: Duration, mut probe: F) -> bool
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let deadline = Instant::now() + limit;
+        let mut waiting = self.down.subscribe();
+        // the first to find the service up marks it down and polls
+        let polls = self.down.send_if_modified(|down| {
+            if down.is_some() {
+                return false;
+            }
+            *down = Some(Instant::now());
+            true
+        });
+        if !polls {
+            let back = waiting.wait_for(Option::is_none);
+            return tokio::time::timeout_at(deadline, back).await.is_ok_and(|r| r.is_ok());
+        }
+        let _polling = Polling(&self.down);
+        while Instant::now() < deadline {
+            tokio::time::sleep_until((Instant::now() + self.poll).min(deadline)).await;
+            if probe().await {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_is_maintenance() {
+        assert!(is_maintenance(StatusCode::SERVICE_UNAVAILABLE, ""));
+        assert!(is_maintenance(StatusCode::BAD_GATEWAY, "<h1>Down for Maintenance</h1>"));
+        assert!(!is_maintenance(StatusCode::BAD_GATEWAY, "upstream error"));
+        assert!(!is_maintenance(StatusCode::NOT_FOUND, "maintenance"));
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let outage = Arc::new(Outage::new(Duration::from_millis(10)));
+        let probes = Arc::new(AtomicUsize::new(0));
+        // up on the third probe
+        let probe = {
+            let probes = probes.clone();
+            move || {
+                let probes = probes.clone();
+                async move { probes.fetch_add(1, Ordering::SeqCst) >= 2 }
+            }
+        };
+        let poller = tokio::spawn({
+            let (outage, probe) = (outage.clone(), probe.clone());
+            async move { outage.wait(Duration::from_secs(5), probe).await }
+        });
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(outage.down_since().is_some());
+        // a second upload waits for the first one's polling
+        assert!(outage.wait(Duration::from_secs(5), probe).await);
+        assert!(poller.await.unwrap());
+        assert_eq!(probes.load(Ordering::SeqCst), 3);
+        assert!(outage.down_since().is_none());
+        // gives up at the limit
+        assert!(!outage.wait(Duration::from_millis(30), || async { false }).await);
+        assert!(outage.down_since().is_none());
+    }
+}
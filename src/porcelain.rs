@@ -0,0 +1,97 @@
+//! `--porcelain`: progress on stdout for tools wrapping the upload, in place
+//! of the status bar, one tab-separated line per event. The format is
+//! versioned like git's porcelain modes: the lines and fields of a version
+//! never change, anything new comes as a new version. Version 1 writes:
+//!
+//! - `porcelain<TAB>1` first
+//! - `file<TAB>destination<TAB>outcome<TAB>bytes<TAB>seconds<TAB>retries<TAB>name<TAB>path`
+//!   as each upload completes, the outcome `ok`, `exists` or the category of
+//!   the error (e.g. `timed out`)
+//! - `progress<TAB>uploaded<TAB>exist<TAB>failed<TAB>total<TAB>bytes<TAB>seconds`
+//!   after each `file` line, and the same as `done` at the end
+//!
+//! Tabs, newlines and backslashes in names and paths are written as `\t`,
+//! `\n` and `\\`.
+
+use crate::{ErrorKind, UploadInfo};
+
+/// version of the porcelain format, from `--porcelain`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Porcelain {
+    V1,
+}
+
+impl std::str::FromStr for Porcelain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" | "1" => Ok(Porcelain::V1),
+            _ => Err(format!("unknown porcelain version {:?}, expected v1", s)),
+        }
+    }
+}
+
+/// counts of a `progress` or `done` line
+pub(crate) struct Counts {
+    pub(crate) uploaded: usize,
+    pub(crate) exist: usize,
+    pub(crate) failed: usize,
+    pub(crate) total: usize,
+    pub(crate) bytes: u64,
+    pub(crate) seconds: f64,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+impl Porcelain {
+    pub(crate) fn header(&self) -> String {
+        match self {
+            Porcelain::V1 => "porcelain\t1".to_string(),
+        }
+    }
+
+    /// the line of a completed upload
+    pub(crate) fn file(&self, info: &UploadInfo) -> String {
+        let outcome = match &info.error {
+            None => "ok",
+            Some(ErrorKind::FileExists) => "exists",
+            Some(error) => error.category(info.response.as_ref().map(|r| r.0)),
+        };
+        match self {
+            Porcelain::V1 => format!("file\t{}\t{}\t{}\t{:.3}\t{}\t{}\t{}", info.destination, outcome, info.bytes,
+                info.time, info.retries, escape(&info.name), escape(&info.path)),
+        }
+    }
+
+    /// a line of the counts so far, `kind` `progress` or `done`
+    pub(crate) fn counts(&self, kind: &str, counts: &Counts) -> String {
+        match self {
+            Porcelain::V1 => format!("{}\t{}\t{}\t{}\t{}\t{}\t{:.3}", kind, counts.uploaded, counts.exist,
+                counts.failed, counts.total, counts.bytes, counts.seconds),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the v1 format is a promise, these lines must never change
+    #[test]
+    fn test_v1() {
+        let v1 = "v1".parse::<Porcelain>().unwrap();
+        assert_eq!(v1.header(), "porcelain\t1");
+        let mut info = UploadInfo::new("/data/raw/a b.fits".to_string()).with_success();
+        (info.name, info.bytes, info.time, info.retries) = ("raw/a b.fits".to_string(), 1024, 0.5, 1);
+        assert_eq!(v1.file(&info), "file\t0\tok\t1024\t0.500\t1\traw/a b.fits\t/data/raw/a b.fits");
+        let mut info = UploadInfo::new("c:\\odd\tname".to_string()).with_error(ErrorKind::Deadline);
+        (info.name, info.destination, info.time) = ("odd\tname".to_string(), 1, 2.0);
+        assert_eq!(v1.file(&info), "file\t1\tdeadline exceeded\t0\t2.000\t0\todd\\tname\tc:\\\\odd\\tname");
+        let counts = Counts { uploaded: 3, exist: 1, failed: 0, total: 5, bytes: 2048, seconds: 1.25 };
+        assert_eq!(v1.counts("progress", &counts), "progress\t3\t1\t0\t5\t2048\t1.250");
+        assert!("v2".parse::<Porcelain>().is_err());
+    }
+}
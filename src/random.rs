@@ -0,0 +1,31 @@
+//! Random choices of a run: retry jitter, `--order random` and `--chaos`.
+//! With `--seed` they are the same in every run, to reproduce one that went
+//! wrong. Each upload draws from its own generator, seeded from the seed and
+//! what it uploads, so its choices don't depend on how uploads interleave.
+
+use fastrand::Rng;
+
+/// Generator for the choices about `key` (e.g. an upload), seeded by `seed`
+/// if given and random otherwise.
+pub(crate) fn rng(seed: Option<u64>, key: &str) -> Rng {
+    match seed {
+        // FNV-1a, stable across builds unlike std's hashers
+        Some(seed) => Rng::with_seed(key.bytes().fold(seed ^ 0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })),
+        None => Rng::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng() {
+        let draw = |seed, key| rng(seed, key).u64(..);
+        assert_eq!(draw(Some(42), "0:data/a"), draw(Some(42), "0:data/a"));
+        assert_ne!(draw(Some(42), "0:data/a"), draw(Some(42), "1:data/a"));
+        assert_ne!(draw(Some(42), "0:data/a"), draw(Some(43), "0:data/a"));
+    }
+}
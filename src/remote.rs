@@ -0,0 +1,153 @@
+//! Reading what's on the fileservice: listing a remote directory tree with
+//! the `jsonTree` api, checksums of remote files and downloading files.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use reqwest::{Client, StatusCode};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::checksum::to_hex;
+
+/// levels to list when the whole tree is wanted
+pub(crate) const MAX_DEPTH: usize = 100;
+
+/// a file on the fileservice
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RemoteFile {
+    pub(crate) size: u64,
+    /// as reported, e.g. `2025-06-30T14:00:00Z`
+    pub(crate) modified: Option<String>,
+    /// SHA-256 of the contents, from deployments listing checksums when
+    /// they're trusted
+    pub(crate) sha256: Option<String>,
+}
+
+/// Add the files of a `jsonTree` folder to `files` by their path relative to
+/// the listed directory, with the `sha256` some deployments list if
+/// `checksums`. That field isn't part of the documented `jsonTree` answer,
+/// so it's only taken when asked for.
+fn add_folder(folder: &Value, prefix: &str, checksums: bool, files: &mut HashMap<String, RemoteFile>) {
+    for file in folder["files"].as_array().into_iter().flatten() {
+        let Some(name) = file["name"].as_str() else { continue };
+        let size = file["size"].as_u64().unwrap_or(0);
+        let modified = file["lastModified"].as_str().map(str::to_string);
+        let sha256 = file["sha256"].as_str().filter(|_| checksums).map(str::to_ascii_lowercase);
+        files.insert(format!("{}{}", prefix, name), RemoteFile { size, modified, sha256 });
+    }
+    for sub in folder["folders"].as_array().into_iter().flatten() {
+        let Some(name) = sub["name"].as_str() else { continue };
+        add_folder(sub, &format!("{}{}/", prefix, name), checksums, files);
+    }
+}
+
+/// Parse a `jsonTree` response, the listed folder being under `root`, with
+/// listed `checksums`.
+pub(crate) fn parse_tree(tree: &Value, checksums: bool) -> HashMap<String, RemoteFile> {
+    let mut files = HashMap::new();
+    add_folder(tree.get("root").unwrap_or(tree), "", checksums, &mut files);
+    files
+}
+
+/// Files below `path` at most `depth` directories down, from the `jsonTree`
+/// api at `url`, with listed `checksums`. A directory that doesn't exist has
+/// no files.
+pub(crate) async fn list(client: &Client, url: &str, path: &str, depth: usize, checksums: bool)
+    -> Result<HashMap<String, RemoteFile>, String> {
+    let url = format!("{}/{}?level={}", url.trim_end_matches('/'), path.trim_matches('/'), depth);
+    let response = client.get(&url).send().await.map_err(|e| e.to_string())?;
+    match response.status() {
+        StatusCode::OK => (),
+        StatusCode::NOT_FOUND => return Ok(HashMap::new()),
+        status => return Err(format!("listing {} failed: {}", path, status)),
+    }
+    let tree: Value = response.json().await.map_err(|e| format!("invalid listing of {}: {}", path, e))?;
+    Ok(parse_tree(&tree, checksums))
+}
+
+/// SHA-256 of the file at `url`, hashed as it downloads.
+pub(crate) async fn sha256(client: &Client, url: &str) -> Result<String, String> {
+    let mut response = client.get(url).send().await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("download failed: {}", e))?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| format!("download failed: {}", e))? {
+        hasher.update(&chunk);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// SHA-256 of the remote `file` at `url`, as listed if the fileservice lists
+/// checksums, downloading it otherwise.
+pub(crate) async fn checksum(client: &Client, file: &RemoteFile, url: &str) -> Result<String, String> {
+    match &file.sha256 {
+        Some(sha256) => Ok(sha256.clone()),
+        None => sha256(client, url).await,
+    }
+}
+
+/// Download the file at `url` to `dest`, through a temporary file so an
+/// interrupted download doesn't leave a partial file behind.
+pub(crate) async fn download(client: &Client, url: &str, dest: &Path) -> Result<(), String> {
+    let mut response = client.get(url).send().await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| e.to_string())?;
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
+    }
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".uploadpart");
+    let partial = PathBuf::from(partial);
+    let mut file = tokio::fs::File::create(&partial).await.map_err(|e| e.to_string())?;
+    let result = async {
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+        }
+        file.flush().await.map_err(|e| e.to_string())?;
+        tokio::fs::rename(&partial, dest).await.map_err(|e| e.to_string())
+    }.await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+    result
+}
+
+/// Delete the file at `url` (of the data api), fine if it's already gone.
+pub(crate) async fn delete(client: &Client, url: &str) -> Result<(), String> {
+    let response = client.delete(url).send().await.map_err(|e| e.to_string())?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    response.error_for_status().map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// if a name from a listing is safe to write below a local directory, i.e.
+/// relative and not leading out of it
+pub(crate) fn is_safe_name(name: &str) -> bool {
+    Path::new(name).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tree() {
+        let tree = serde_json::json!({"root": {
+            "name": "p",
+            "files": [{"name": "a.txt", "size": 3, "lastModified": "2025-06-30T14:00:00Z", "sha256": "AB12"}],
+            "folders": [{"name": "sub", "files": [{"name": "b.txt", "size": 5}], "folders": []}],
+        }});
+        let files = parse_tree(&tree, true);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files["a.txt"].modified.as_deref(), Some("2025-06-30T14:00:00Z"));
+        assert_eq!(files["a.txt"].sha256.as_deref(), Some("ab12"));
+        // not taken unless trusted
+        assert_eq!(parse_tree(&tree, false)["a.txt"].sha256, None);
+        assert_eq!(files["sub/b.txt"], RemoteFile { size: 5, modified: None, sha256: None });
+        assert!(is_safe_name("sub/b.txt"));
+        assert!(!is_safe_name("../b.txt") && !is_safe_name("/etc/passwd"));
+    }
+}
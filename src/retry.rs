@@ -0,0 +1,104 @@
+//! Deciding whether a failed upload attempt is tried again, and when. The
+//! built-in [`DefaultRetryPolicy`] retries up to `--retries` attempts, backing
+//! off on network failures. Library users can give their own [`RetryPolicy`]
+//! to encode what they know about their fileservice, e.g. that a 507 from it
+//! won't go away, falling back on the built-in one for the rest.
+
+use std::time::Duration;
+
+use reqwest::StatusCode;
+
+pub use crate::transport::TransportError;
+
+/// how an attempt failed, more kinds may come
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub enum Failure<'a> {
+    /// the fileservice answered with this status and (the start of the) body
+    Status(StatusCode, &'a str),
+    /// no response came
+    Transport(TransportError),
+}
+
+/// a failed attempt of an upload, for a [`RetryPolicy`] to decide on, more
+/// may be told about it
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Attempt<'a> {
+    /// local path of the file uploaded
+    pub path: &'a str,
+    /// of the attempt that failed, from 1
+    pub number: usize,
+    /// since the upload started
+    pub elapsed: Duration,
+    pub failure: Failure<'a>,
+    /// the next attempt goes to another endpoint, worth trying right away
+    pub failed_over: bool,
+    /// random in [0, 1), to spread out retries so uploads don't retry in
+    /// lockstep. The same in every run with `--seed`.
+    pub jitter: f64,
+}
+
+/// what follows a failed attempt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryDecision {
+    /// try again after this long
+    Retry(Duration),
+    /// give up on the file, reporting the failure
+    Fail,
+    /// give up on the whole run, e.g. where every upload will fail the same
+    Abort,
+}
+
+/// Decides what follows each failed attempt of an upload.
+pub trait RetryPolicy: Send + Sync {
+    fn decide(&self, attempt: &Attempt) -> RetryDecision;
+}
+
+/// Up to `retries` attempts, waiting longer and longer between those that
+/// got no response (except at failover, or if it can't help, as for tls
+/// failures), and none between those that got an error response.
+#[derive(Clone, Copy, Debug)]
+pub struct DefaultRetryPolicy {
+    pub retries: usize,
+}
+
+impl RetryPolicy for DefaultRetryPolicy {
+    fn decide(&self, attempt: &Attempt) -> RetryDecision {
+        let delay = match attempt.failure {
+            Failure::Transport(transport) if !attempt.failed_over => {
+                match transport.retry_delay(attempt.number, attempt.jitter) {
+                    Some(delay) => delay,
+                    None => return RetryDecision::Fail,
+                }
+            }
+            _ => Duration::ZERO,
+        };
+        match attempt.number >= self.retries {
+            true => RetryDecision::Fail,
+            false => RetryDecision::Retry(delay),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_policy() {
+        let policy = DefaultRetryPolicy { retries: 3 };
+        let attempt = |number, failure| Attempt {
+            path: "a", number, elapsed: Duration::ZERO, failure, failed_over: false, jitter: 0.5,
+        };
+        let unavailable = Failure::Status(StatusCode::SERVICE_UNAVAILABLE, "");
+        assert_eq!(policy.decide(&attempt(1, unavailable)), RetryDecision::Retry(Duration::ZERO));
+        assert_eq!(policy.decide(&attempt(3, unavailable)), RetryDecision::Fail);
+        let refused = Failure::Transport(TransportError::Refused);
+        assert_eq!(policy.decide(&attempt(2, refused)), RetryDecision::Retry(Duration::from_secs(2)));
+        assert_eq!(policy.decide(&attempt(1, Failure::Transport(TransportError::Tls))), RetryDecision::Fail);
+        // another endpoint is tried right away
+        let failover = Attempt { failed_over: true, ..attempt(1, Failure::Transport(TransportError::Tls)) };
+        assert_eq!(policy.decide(&failover), RetryDecision::Retry(Duration::ZERO));
+    }
+}
@@ -0,0 +1,776 @@
+//! Decides which upload starts next as slots free up. Uploads are split into
+//! pools (e.g. small and large files), each with its own concurrency limit,
+//! so neither kind can starve the other. Optionally the memory uploads buffer
+//! is capped overall, holding back uploads until enough is freed, how many
+//! uploads run at once is tuned to a target throughput, and they can ramp up
+//! from one at the start rather than all starting at once. Uploads to some
+//! destinations (e.g. a volume on slower storage) can be limited further,
+//! still taking their slots from the shared pools.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::body::{SharedContents, Stamp};
+use crate::checksum::Checksum;
+use crate::inputs::Input;
+
+/// order in which files are started
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    /// as given on the command line
+    Given,
+    /// improves the tail of the run, no huge file starting at the very end
+    LargestFirst,
+    /// gets many files done early on
+    SmallestFirst,
+    /// avoids pathological orderings, e.g. a directory of slow files
+    Random,
+}
+
+impl Order {
+    pub(crate) fn needs_sizes(&self) -> bool {
+        matches!(self, Order::LargestFirst | Order::SmallestFirst)
+    }
+
+    /// sort `(file, size)` pairs, shuffling with `rng`
+    pub(crate) fn sort<T>(&self, files: &mut [(T, u64)], rng: &mut fastrand::Rng) {
+        match self {
+            Order::Given => (),
+            Order::LargestFirst => files.sort_by_key(|(_, size)| std::cmp::Reverse(*size)),
+            Order::SmallestFirst => files.sort_by_key(|(_, size)| *size),
+            Order::Random => rng.shuffle(files),
+        }
+    }
+}
+
+impl std::str::FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "given" => Ok(Order::Given),
+            "largest-first" => Ok(Order::LargestFirst),
+            "smallest-first" => Ok(Order::SmallestFirst),
+            "random" => Ok(Order::Random),
+            _ => Err(format!("unknown order {:?}, expected given, largest-first, smallest-first or random", s)),
+        }
+    }
+}
+
+const DAY: u32 = 24 * 3600;
+
+/// Hours of the day (local time) uploads may start in, e.g. `22:00-06:00`
+/// for the night.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeWindow {
+    /// seconds since midnight
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    /// seconds since local midnight
+    pub(crate) fn now() -> u32 {
+        use chrono::Timelike;
+        chrono::Local::now().num_seconds_from_midnight()
+    }
+
+    fn contains(&self, time: u32) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            // across midnight
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// how long from `time` (seconds since midnight) until the window opens,
+    /// zero if it is open
+    pub(crate) fn until_open(&self, time: u32) -> Duration {
+        if self.contains(time) {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(((self.start + DAY - time) % DAY) as u64)
+        }
+    }
+}
+
+impl std::str::FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time window {:?}, expected e.g. 22:00-06:00", s);
+        let time = |t: &str| -> Option<u32> {
+            let (hours, minutes) = t.trim().split_once(':')?;
+            let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+            (hours < 24 && minutes < 60).then_some(hours * 3600 + minutes * 60)
+        };
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let (start, end) = (time(start).ok_or_else(invalid)?, time(end).ok_or_else(invalid)?);
+        if start == end {
+            return Err(format!("empty time window {:?}", s));
+        }
+        Ok(TimeWindow { start, end })
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 3600, self.start % 3600 / 60, self.end / 3600, self.end % 3600 / 60)
+    }
+}
+
+/// How the uploads running at once ramp up from one to all of them at the
+/// start of a run, so servers rate limiting a sudden burst of connections
+/// don't fail the first uploads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RampUp {
+    /// evenly over this long
+    Time(Duration),
+    /// evenly as this many uploads complete
+    Files(usize),
+}
+
+impl RampUp {
+    /// Uploads that may run out of `max`, `elapsed` and `completed` into the
+    /// run.
+    fn limit(&self, max: usize, elapsed: Duration, completed: usize) -> usize {
+        let progress = match *self {
+            RampUp::Time(duration) => elapsed.as_secs_f64() / duration.as_secs_f64(),
+            RampUp::Files(files) => completed as f64 / files as f64,
+        };
+        (1 + (max.saturating_sub(1) as f64 * progress.min(1.0)) as usize).min(max)
+    }
+}
+
+impl std::str::FromStr for RampUp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let ramp = match s.strip_suffix("files").or_else(|| s.strip_suffix("file")) {
+            Some(files) => RampUp::Files(files.trim().parse()
+                .map_err(|_| format!("invalid ramp-up {:?}, expected e.g. 30s or 100files", s))?),
+            None => RampUp::Time(crate::units::parse_duration(s)
+                .map_err(|_| format!("invalid ramp-up {:?}, expected e.g. 30s or 100files", s))?),
+        };
+        if matches!(ramp, RampUp::Time(Duration::ZERO) | RampUp::Files(0)) {
+            return Err(format!("ramp-up must be more than 0: {:?}", s));
+        }
+        Ok(ramp)
+    }
+}
+
+impl std::fmt::Display for RampUp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RampUp::Time(duration) => write!(f, "over {}", humantime::format_duration(*duration)),
+            RampUp::Files(files) => write!(f, "over the first {} uploads", files),
+        }
+    }
+}
+
+/// one upload of a file to a destination
+pub(crate) struct Job {
+    pub(crate) input: Input,
+    pub(crate) destination: usize,
+    pub(crate) size: u64,
+    /// size and modification time when the scan found it, if it could tell
+    pub(crate) stamp: Option<Stamp>,
+    /// shared by the uploads of a file to each destination
+    pub(crate) checksum: Option<Checksum>,
+    /// contents read once for the uploads to each destination, if several
+    pub(crate) contents: Option<SharedContents>,
+}
+
+struct Pool {
+    /// jobs waiting, by lane (those in none first, then one queue per lane),
+    /// each with its place in the order they were added, which they start
+    /// in along with their priority
+    queues: Vec<VecDeque<(usize, Job)>>,
+    /// place of the next job added
+    added: usize,
+    limit: usize,
+    active: usize,
+}
+
+impl Pool {
+    /// `jobs` in no lane, already in the order to start them
+    fn new(jobs: Vec<Job>, limit: usize) -> Self {
+        let added = jobs.len();
+        Pool { queues: vec![jobs.into_iter().enumerate().collect()], added, limit, active: 0 }
+    }
+
+    /// Add `job` to the queue of index `queue`, ahead of those of lower
+    /// priority and after the rest.
+    fn add(&mut self, queue: usize, job: Job) {
+        let queue = &mut self.queues[queue];
+        let position = queue.partition_point(|(_, queued)| queued.input.priority >= job.input.priority);
+        queue.insert(position, (self.added, job));
+        self.added += 1;
+    }
+
+    /// Index of the queue whose first job starts before the others, of
+    /// those `open` tells may start one.
+    fn first(&self, open: impl Fn(usize) -> bool) -> Option<usize> {
+        (0..self.queues.len())
+            .filter(|&queue| open(queue))
+            .filter_map(|queue| self.queues[queue].front().map(|(added, job)| (queue, *added, job.input.priority)))
+            .min_by_key(|&(_, added, priority)| (std::cmp::Reverse(priority), added))
+            .map(|(queue, _, _)| queue)
+    }
+
+    /// priority of the job that starts first, lanes permitting
+    fn priority(&self) -> Option<i32> {
+        self.first(|_| true).map(|queue| self.queues[queue][0].1.input.priority)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+}
+
+/// the lane of a job, if any
+type LaneOf = Box<dyn Fn(&Job) -> Option<usize> + Send + Sync>;
+
+/// limits on the uploads running to some destinations, over all pools
+struct Lanes {
+    limits: Vec<usize>,
+    active: Vec<usize>,
+    lane: LaneOf,
+}
+
+impl Lanes {
+    fn has_room(&self, lane: usize) -> bool {
+        self.active[lane] < self.limits[lane]
+    }
+}
+
+/// cap on the bytes buffered by running uploads
+struct Memory {
+    limit: u64,
+    /// most an upload buffers, smaller files buffer at most their size
+    per_upload: u64,
+    used: u64,
+}
+
+impl Memory {
+    fn cost(&self, size: u64) -> u64 {
+        size.min(self.per_upload)
+    }
+}
+
+/// Tunes how many uploads run at once so throughput approaches a target
+/// without going over it: fewer in proportion as soon as it's over, one more
+/// at a time while it's clearly under and the run's average is at most the
+/// target. When even one upload is too fast, as on a fast network with a
+/// low target, none start until the average is down to the target.
+pub(crate) struct RateControl {
+    /// bytes/s
+    target: f64,
+    max: usize,
+    limit: usize,
+    /// smoothed bytes/s, none until first measured
+    rate: Option<f64>,
+    /// when and how many bytes were sent at the start and the last update
+    start: (Instant, u64),
+    last: (Instant, u64),
+}
+
+/// shortest time throughput is measured over, also how often it's retuned
+/// while nothing completes
+pub(crate) const RATE_INTERVAL: Duration = Duration::from_millis(250);
+/// below this share of the target another upload is started
+const RATE_HEADROOM: f64 = 0.9;
+/// weight of the latest measurement in the smoothed rate
+const RATE_SMOOTHING: f64 = 0.5;
+
+impl RateControl {
+    /// Starting at one upload, `sent` bytes being sent so far.
+    pub(crate) fn new(target: u64, max: usize, sent: u64) -> Self {
+        let start = (Instant::now(), sent);
+        RateControl { target: target as f64, max, limit: 1.min(max), rate: None, start, last: start }
+    }
+
+    /// The number of uploads to run, given `sent` bytes had been sent by
+    /// `now` and whether that many were `busy` running (no point in allowing
+    /// more otherwise).
+    pub(crate) fn update(&mut self, now: Instant, sent: u64, busy: bool) -> usize {
+        let elapsed = now.duration_since(self.last.0);
+        if elapsed < RATE_INTERVAL {
+            return self.limit;
+        }
+        let elapsed = elapsed.as_secs_f64();
+        let measured = sent.saturating_sub(self.last.1) as f64 / elapsed;
+        self.last = (now, sent);
+        let rate = self.rate.map_or(measured, |r| r + RATE_SMOOTHING * (measured - r));
+        self.rate = Some(rate);
+        let average = sent.saturating_sub(self.start.1) as f64 / now.duration_since(self.start.0).as_secs_f64();
+        if rate > self.target {
+            self.limit = (self.limit as f64 * self.target / rate) as usize;
+        } else if rate < self.target * RATE_HEADROOM && average <= self.target && busy {
+            self.limit = (self.limit + 1).min(self.max);
+        }
+        self.limit
+    }
+}
+
+pub(crate) struct Scheduler {
+    pools: Vec<Pool>,
+    /// size from which jobs go into the pool of large ones, if there is one
+    threshold: u64,
+    /// most uploads running over all pools, tuned by `rate`
+    cap: Option<usize>,
+    rate: Option<RateControl>,
+    /// ramp-up and when it started
+    ramp: Option<(RampUp, Instant)>,
+    /// what the ramp-up let run when last told by [`Scheduler::ramp_step`]
+    ramp_told: usize,
+    /// uploads completed so far
+    completed: usize,
+    memory: Option<Memory>,
+    /// bytes uploads may still start with, if capped
+    budget: Option<u64>,
+    /// a job didn't fit the budget, none start anymore
+    exhausted: bool,
+    lanes: Option<Lanes>,
+}
+
+impl Scheduler {
+    /// All jobs in a single pool limited to `concurrency`.
+    pub(crate) fn new(jobs: Vec<Job>, concurrency: usize) -> Self {
+        Scheduler {
+            pools: vec![Pool::new(jobs, concurrency)],
+            threshold: u64::MAX,
+            cap: None,
+            rate: None,
+            ramp: None,
+            ramp_told: 0,
+            completed: 0,
+            memory: None,
+            budget: None,
+            exhausted: false,
+            lanes: None,
+        }
+    }
+
+    /// Jobs of `threshold` bytes and above go into a separate pool limited to
+    /// `large_concurrency`, the rest share `concurrency`.
+    pub(crate) fn by_size(jobs: Vec<Job>, concurrency: usize, threshold: u64, large_concurrency: usize) -> Self {
+        let (large, small): (Vec<Job>, Vec<Job>) = jobs.into_iter().partition(|j| j.size >= threshold);
+        Scheduler {
+            pools: vec![
+                Pool::new(small, concurrency),
+                Pool::new(large, large_concurrency),
+            ],
+            threshold,
+            cap: None,
+            rate: None,
+            ramp: None,
+            ramp_told: 0,
+            completed: 0,
+            memory: None,
+            budget: None,
+            exhausted: false,
+            lanes: None,
+        }
+    }
+
+    /// Only start uploads while the memory they buffer, up to `per_upload`
+    /// each, stays within `limit`. An upload always starts if none is
+    /// running, so a limit below `per_upload` doesn't stall the run.
+    pub(crate) fn with_memory_limit(self, limit: u64, per_upload: u64) -> Self {
+        Scheduler { memory: Some(Memory { limit, per_upload, used: 0 }), ..self }
+    }
+
+    /// Stop starting uploads once the next one would take the bytes started
+    /// over `limit`, leaving it and the rest for a later run.
+    pub(crate) fn with_byte_limit(self, limit: u64) -> Self {
+        Scheduler { budget: Some(limit), ..self }
+    }
+
+    /// Also limit the jobs running in each lane to its entry of `limits`,
+    /// jobs being in the lane `lane` tells, if any. Jobs of a full lane wait
+    /// while others from the same pool start past them.
+    pub(crate) fn with_lanes(mut self, limits: Vec<usize>,
+        lane: impl Fn(&Job) -> Option<usize> + Send + Sync + 'static) -> Self {
+        for pool in &mut self.pools {
+            let mut queues: Vec<_> = (0..=limits.len()).map(|_| VecDeque::new()).collect();
+            for (added, job) in pool.queues.drain(..).flatten() {
+                queues[lane(&job).map_or(0, |lane| lane + 1)].push_back((added, job));
+            }
+            pool.queues = queues;
+        }
+        let lanes = Lanes { active: vec![0; limits.len()], limits, lane: Box::new(lane) };
+        Scheduler { lanes: Some(lanes), ..self }
+    }
+
+    /// Tune the number of uploads running over all pools to send `target`
+    /// bytes/s, as measured by [`Scheduler::adjust`].
+    pub(crate) fn with_rate_target(self, target: u64, sent: u64) -> Self {
+        let rate = RateControl::new(target, self.max(), sent);
+        Scheduler { cap: Some(rate.limit), rate: Some(rate), ..self }
+    }
+
+    pub(crate) fn has_rate_target(&self) -> bool {
+        self.rate.is_some()
+    }
+
+    /// Start with a single upload, allowing more and more over all pools as
+    /// `ramp` goes by.
+    pub(crate) fn with_ramp_up(self, ramp: RampUp) -> Self {
+        Scheduler { ramp: Some((ramp, Instant::now())), ..self }
+    }
+
+    /// most uploads running over all pools
+    fn max(&self) -> usize {
+        self.pools.iter().map(|p| p.limit).sum()
+    }
+
+    /// uploads the ramp-up lets run now, if ramping up
+    fn ramp_limit(&self) -> Option<usize> {
+        let (ramp, start) = self.ramp?;
+        Some(ramp.limit(self.max(), start.elapsed(), self.completed))
+    }
+
+    /// Uploads the ramp-up lets run out of all of them, if that changed since
+    /// the last call, to tell about each step.
+    pub(crate) fn ramp_step(&mut self) -> Option<(usize, usize)> {
+        let limit = self.ramp_limit()?;
+        (std::mem::replace(&mut self.ramp_told, limit) != limit).then_some((limit, self.max()))
+    }
+
+    /// whether a ramp-up over time still holds uploads back, so more may
+    /// start later without any completing
+    pub(crate) fn is_ramping(&self) -> bool {
+        matches!(self.ramp, Some((RampUp::Time(_), _))) && self.ramp_limit().is_some_and(|limit| limit < self.max())
+    }
+
+    /// whether jobs are left but held back by the rate target for now
+    pub(crate) fn is_paused(&self) -> bool {
+        self.cap == Some(0) && !self.is_empty()
+    }
+
+    /// Retune the uploads running to the rate target given `sent` bytes sent
+    /// so far. Running uploads over a lowered cap finish, only fewer start.
+    pub(crate) fn adjust(&mut self, sent: u64) {
+        let running: usize = self.pools.iter().map(|p| p.active).sum();
+        let busy = self.cap.is_some_and(|cap| running >= cap);
+        if let Some(rate) = &mut self.rate {
+            self.cap = Some(rate.update(Instant::now(), sent, busy));
+        }
+    }
+
+    /// Next job that may start now along with its slot (its pool, and lane if
+    /// any), which must be handed back to `finished` with the job size once
+    /// the job completes. Pools are tried by the priority of their next job,
+    /// so urgent files of any pool get a free slot first.
+    pub(crate) fn next(&mut self) -> Option<(usize, Job)> {
+        if self.exhausted {
+            return None;
+        }
+        let running: usize = self.pools.iter().map(|p| p.active).sum();
+        if self.cap.is_some_and(|cap| running >= cap) || self.ramp_limit().is_some_and(|limit| running >= limit) {
+            return None;
+        }
+        let mut order: Vec<usize> = (0..self.pools.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.pools[i].priority().unwrap_or(i32::MIN)));
+        for index in order {
+            let pool = &mut self.pools[index];
+            if pool.active >= pool.limit {
+                continue;
+            }
+            let lanes = &self.lanes;
+            // the first job of those in no lane or one with room
+            let Some(queue) = pool.first(|queue| {
+                queue.checked_sub(1).is_none_or(|lane| lanes.as_ref().is_some_and(|l| l.has_room(lane)))
+            }) else { continue };
+            let job = &pool.queues[queue][0].1;
+            if self.budget.is_some_and(|budget| job.size > budget) {
+                self.exhausted = true;
+                return None;
+            }
+            if let Some(memory) = &mut self.memory {
+                let cost = memory.cost(job.size);
+                if running > 0 && memory.used + cost > memory.limit {
+                    continue;
+                }
+                memory.used += cost;
+            }
+            if let Some(budget) = &mut self.budget {
+                *budget -= job.size;
+            }
+            pool.active += 1;
+            let (_, job) = pool.queues[queue].pop_front().unwrap();
+            let lane = queue.checked_sub(1);
+            if let (Some(lanes), Some(lane)) = (&mut self.lanes, lane) {
+                lanes.active[lane] += 1;
+            }
+            return Some((self.slot(index, lane), job));
+        }
+        None
+    }
+
+    /// pools, then each pool for each lane
+    fn slot(&self, pool: usize, lane: Option<usize>) -> usize {
+        pool + self.pools.len() * lane.map_or(0, |lane| lane + 1)
+    }
+
+    /// Add a job found after the others to its pool, ahead of those of lower
+    /// priority and after the rest, like jobs given at the start are ordered.
+    pub(crate) fn push(&mut self, job: Job) {
+        let queue = self.lanes.as_ref().and_then(|lanes| (lanes.lane)(&job)).map_or(0, |lane| lane + 1);
+        self.pools[if job.size >= self.threshold { 1 } else { 0 }].add(queue, job);
+    }
+
+    /// Start no more jobs, leaving the rest like the byte limit does.
+    pub(crate) fn stop(&mut self) {
+        self.exhausted = true;
+    }
+
+    /// whether all jobs that may start were started
+    pub(crate) fn is_empty(&self) -> bool {
+        self.exhausted || self.pools.iter().all(Pool::is_empty)
+    }
+
+    /// the jobs left over by the byte limit or stopping, of each pool in the
+    /// order they were added
+    pub(crate) fn left(&self) -> impl Iterator<Item = &Job> {
+        self.pools.iter().filter(|_| self.exhausted).flat_map(|pool| {
+            let mut left: Vec<_> = pool.queues.iter().flatten().collect();
+            left.sort_by_key(|(added, _)| *added);
+            left.into_iter().map(|(_, job)| job)
+        })
+    }
+
+    pub(crate) fn finished(&mut self, slot: usize, size: u64) {
+        let (pool, lane) = (slot % self.pools.len(), (slot / self.pools.len()).checked_sub(1));
+        self.pools[pool].active -= 1;
+        if let (Some(lanes), Some(lane)) = (&mut self.lanes, lane) {
+            lanes.active[lane] -= 1;
+        }
+        self.completed += 1;
+        if let Some(memory) = &mut self.memory {
+            memory.used -= memory.cost(size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMALL: usize = 0;
+    const LARGE: usize = 1;
+
+    fn jobs(sizes: &[u64]) -> Vec<Job> {
+        sizes.iter().enumerate()
+            .map(|(i, size)| {
+                let input = Input { path: format!("f{}", i), name: format!("f{}", i), priority: 0 };
+                Job { input, destination: 0, size: *size, stamp: None, checksum: None, contents: None }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pools() {
+        let mut scheduler = Scheduler::by_size(jobs(&[1, 100, 2, 200, 3, 300]), 2, 100, 1);
+        let started: Vec<_> = std::iter::from_fn(|| scheduler.next()).collect();
+        let files: Vec<_> = started.iter().map(|(p, j)| (*p, j.input.path.as_str())).collect();
+        assert_eq!(files, vec![(SMALL, "f0"), (SMALL, "f2"), (LARGE, "f1")]);
+        // a large file finishing lets only another large file start
+        scheduler.finished(LARGE, 100);
+        let (pool, job) = scheduler.next().unwrap();
+        assert_eq!((pool, job.input.path.as_str()), (LARGE, "f3"));
+        assert!(scheduler.next().is_none());
+        // jobs found later go into the pool of their size
+        let mut scheduler = Scheduler::by_size(Vec::new(), 1, 100, 1);
+        assert!(scheduler.is_empty());
+        for job in jobs(&[500, 5]) {
+            scheduler.push(job);
+        }
+        let started: Vec<_> = std::iter::from_fn(|| scheduler.next()).map(|(p, j)| (p, j.size)).collect();
+        assert_eq!(started, vec![(SMALL, 5), (LARGE, 500)]);
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut jobs = jobs(&[1, 100, 2, 200]);
+        jobs[1].input.priority = 5;
+        let mut scheduler = Scheduler::by_size(jobs, 1, 100, 1);
+        let started: Vec<_> = std::iter::from_fn(|| scheduler.next()).map(|(_, j)| j.input.path).collect();
+        // the urgent large file goes before the small ones
+        assert_eq!(started, vec!["f1", "f0"]);
+        scheduler.finished(SMALL, 1);
+        scheduler.finished(LARGE, 100);
+        assert_eq!(scheduler.next().unwrap().1.input.path, "f2");
+        // jobs found later go ahead of those of lower priority only
+        let mut scheduler = Scheduler::new(Vec::new(), 1);
+        let mut found = self::jobs(&[1, 2, 3, 4]);
+        (found[1].input.priority, found[2].input.priority) = (5, 5);
+        for job in found {
+            scheduler.push(job);
+        }
+        let started: Vec<_> = std::iter::from_fn(|| {
+            let (slot, job) = scheduler.next()?;
+            scheduler.finished(slot, job.size);
+            Some(job.input.path)
+        }).collect();
+        assert_eq!(started, vec!["f1", "f2", "f0", "f3"]);
+    }
+
+    #[test]
+    fn test_lanes() {
+        // f0, f1, f2 to a slow destination
+        let slow = |job: &Job| (job.input.name.as_str() < "f3").then_some(0);
+        let mut scheduler = Scheduler::by_size(jobs(&[1, 2, 3, 4, 5, 500]), 3, 100, 2).with_lanes(vec![1], slow);
+        let started: Vec<_> = std::iter::from_fn(|| scheduler.next()).collect();
+        let files: Vec<_> = started.iter().map(|(_, j)| j.input.path.as_str()).collect();
+        // the rest of the small pool starts past the slow files waiting
+        assert_eq!(files, vec!["f0", "f3", "f4", "f5"]);
+        let slot = started[0].0;
+        scheduler.finished(started[1].0, 4);
+        assert!(scheduler.next().is_none());
+        // the slow slot freed starts the next slow file
+        scheduler.finished(slot, 1);
+        let (next, job) = scheduler.next().unwrap();
+        assert_eq!((next, job.input.path.as_str()), (slot, "f1"));
+        // jobs found later wait in their lane too
+        let mut late = self::jobs(&[7]).remove(0);
+        (late.input.path, late.input.name) = ("late".to_string(), "f1x".to_string());
+        scheduler.push(late);
+        assert!(scheduler.next().is_none());
+        scheduler.finished(slot, 2);
+        assert_eq!(scheduler.next().unwrap().1.input.path, "f2");
+        scheduler.finished(slot, 3);
+        assert_eq!(scheduler.next().unwrap().1.input.path, "late");
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut scheduler = Scheduler::new(jobs(&[10, 500, 60, 30]), 10).with_memory_limit(100, 50);
+        let started: Vec<_> = std::iter::from_fn(|| scheduler.next()).map(|(_, j)| j.input.path).collect();
+        // 10 + 50, another 50 would exceed the limit
+        assert_eq!(started, vec!["f0", "f1"]);
+        scheduler.finished(SMALL, 10);
+        assert_eq!(scheduler.next().unwrap().1.input.path, "f2");
+        assert!(scheduler.next().is_none());
+        scheduler.finished(SMALL, 500);
+        assert_eq!(scheduler.next().unwrap().1.input.path, "f3");
+        // nothing running, so a job over the limit still starts
+        let mut scheduler = Scheduler::new(jobs(&[500]), 10).with_memory_limit(10, 50);
+        assert!(scheduler.next().is_some());
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let mut scheduler = Scheduler::new(jobs(&[40, 50, 20, 5]), 10).with_byte_limit(100);
+        let started: Vec<_> = std::iter::from_fn(|| scheduler.next()).map(|(_, j)| j.input.path).collect();
+        // the third would go over, so it and everything after it is left
+        assert_eq!(started, vec!["f0", "f1"]);
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.left().map(|j| j.size).collect::<Vec<_>>(), vec![20, 5]);
+        scheduler.finished(SMALL, 40);
+        assert!(scheduler.next().is_none());
+        let mut scheduler = Scheduler::new(jobs(&[40, 50]), 10).with_byte_limit(100);
+        assert_eq!(std::iter::from_fn(|| scheduler.next()).count(), 2);
+        assert_eq!(scheduler.left().count(), 0);
+    }
+
+    #[test]
+    fn test_rate_control() {
+        const MB: u64 = 1 << 20;
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut control = RateControl::new(100 * MB, 10, 0);
+        (control.start.0, control.last.0, control.limit) = (start, start, 5);
+        // under the target while busy: one more at a time, up to the max
+        assert_eq!(control.update(at(1), 50 * MB, true), 6);
+        assert_eq!(control.update(at(2), 110 * MB, true), 7);
+        // not all running, more wouldn't help
+        assert_eq!(control.update(at(3), 150 * MB, false), 7);
+        // over: fewer in proportion right away, smoothed 174MB/s
+        assert_eq!(control.update(at(4), 450 * MB, true), 4);
+        // still over on average
+        assert_eq!(control.update(at(5), 500 * MB, true), 3);
+        // close enough, kept
+        assert_eq!(control.update(at(6), 585 * MB, true), 3);
+        for second in 7..30 {
+            control.update(at(second), 585 * MB + (second - 6) * 10 * MB, true);
+        }
+        assert_eq!(control.limit, 10);
+        // one upload too fast, none start until the average is down
+        let mut control = RateControl::new(10 * MB, 10, 0);
+        (control.start.0, control.last.0) = (start, start);
+        assert_eq!(control.limit, 1);
+        assert_eq!(control.update(at(1), 30 * MB, true), 0);
+        assert_eq!(control.update(at(2), 30 * MB, true), 0);
+        assert_eq!(control.update(at(3), 31 * MB, true), 0);
+        assert_eq!(control.update(at(4), 31 * MB, true), 1);
+
+        let mut scheduler = Scheduler::new(jobs(&[1; 8]), 4).with_rate_target(100, 0);
+        assert_eq!(std::iter::from_fn(|| scheduler.next()).count(), 1);
+        let second_ago = Instant::now() - Duration::from_secs(1);
+        let control = scheduler.rate.as_mut().unwrap();
+        (control.start.0, control.last.0) = (second_ago, second_ago);
+        scheduler.adjust(0);
+        // nothing sent with all running, so one more may run
+        assert_eq!(std::iter::from_fn(|| scheduler.next()).count(), 1);
+        scheduler.rate.as_mut().unwrap().last.0 = second_ago;
+        scheduler.adjust(1000);
+        assert!(scheduler.is_paused() && scheduler.next().is_none());
+    }
+
+    #[test]
+    fn test_ramp_up() {
+        let ramp: RampUp = "30s".parse().unwrap();
+        assert_eq!(ramp, RampUp::Time(Duration::from_secs(30)));
+        assert_eq!(ramp.limit(8, Duration::ZERO, 0), 1);
+        assert_eq!(ramp.limit(8, Duration::from_secs(15), 0), 4);
+        assert_eq!(ramp.limit(8, Duration::from_secs(60), 0), 8);
+        let ramp: RampUp = "100files".parse().unwrap();
+        assert_eq!(ramp, RampUp::Files(100));
+        assert_eq!(ramp.limit(11, Duration::from_secs(60), 50), 6);
+        assert_eq!(ramp.to_string(), "over the first 100 uploads");
+        assert!("0s".parse::<RampUp>().is_err());
+        assert!("many files".parse::<RampUp>().is_err());
+
+        let mut scheduler = Scheduler::new(jobs(&[1; 8]), 4).with_ramp_up(RampUp::Files(2));
+        assert_eq!(scheduler.ramp_step(), Some((1, 4)));
+        assert_eq!(std::iter::from_fn(|| scheduler.next()).count(), 1);
+        assert!(!scheduler.is_ramping());
+        assert_eq!(scheduler.ramp_step(), None);
+        scheduler.finished(SMALL, 1);
+        // one of two done, halfway to all 4
+        assert_eq!(scheduler.ramp_step(), Some((2, 4)));
+        assert_eq!(std::iter::from_fn(|| scheduler.next()).count(), 2);
+        scheduler.finished(SMALL, 1);
+        assert_eq!(std::iter::from_fn(|| scheduler.next()).count(), 3);
+        let scheduler = Scheduler::new(jobs(&[1; 8]), 4).with_ramp_up(RampUp::Time(Duration::from_secs(60)));
+        assert!(scheduler.is_ramping());
+    }
+
+    #[test]
+    fn test_time_window() {
+        let night: TimeWindow = "22:00-06:00".parse().unwrap();
+        assert_eq!(night.to_string(), "22:00-06:00");
+        assert_eq!(night.until_open(23 * 3600), Duration::ZERO);
+        assert_eq!(night.until_open(3600), Duration::ZERO);
+        assert_eq!(night.until_open(6 * 3600), Duration::from_secs(16 * 3600));
+        let lunch: TimeWindow = "12:00-13:30".parse().unwrap();
+        assert_eq!(lunch.until_open(13 * 3600), Duration::ZERO);
+        assert_eq!(lunch.until_open(13 * 3600 + 1800), Duration::from_secs(22 * 3600 + 1800));
+        assert!("22:00".parse::<TimeWindow>().is_err());
+        assert!("25:00-06:00".parse::<TimeWindow>().is_err());
+        assert!("06:00-06:00".parse::<TimeWindow>().is_err());
+    }
+
+    #[test]
+    fn test_order() {
+        let mut files = vec![("a".to_string(), 2), ("b".to_string(), 3), ("c".to_string(), 1)];
+        Order::LargestFirst.sort(&mut files, &mut fastrand::Rng::new());
+        assert_eq!(files.iter().map(|(f, _)| f.as_str()).collect::<String>(), "bac");
+        Order::SmallestFirst.sort(&mut files, &mut fastrand::Rng::new());
+        assert_eq!(files.iter().map(|(f, _)| f.as_str()).collect::<String>(), "cab");
+    }
+}
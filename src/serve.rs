@@ -0,0 +1,445 @@
+//! A small local REST API to hand uploads to a long-running instance, which
+//! keeps its connection pool (and token) warm across jobs:
+//!
+//! - `POST /jobs` with `{"path": "...", "files": ["..."]}` starts uploading
+//!   the files (or directories, with `--recursive`) to the path
+//! - `GET /jobs` and `GET /jobs/<id>` tell how jobs are doing
+//! - `DELETE /jobs/<id>` cancels a job, uploads in flight are dropped
+//!
+//! The same operations are offered over gRPC if built with the `grpc`
+//! feature, see `proto/upload.proto`.
+//!
+//! Jobs use the settings the server was started with. Relative file paths
+//! are relative to where it runs. Anyone who can connect could upload with
+//! its token, so it listens on localhost unless told otherwise, and takes
+//! only requests with the secret it prints at start as bearer token. Ones
+//! from web pages (with an `Origin` other than the server's own) are refused,
+//! so a page visited meanwhile can't submit jobs.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use reqwest::Client;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, watch};
+use tokio::task::AbortHandle;
+
+use crate::Settings;
+
+/// largest request body taken, a job's list of files
+const MAX_REQUEST: usize = 16 << 20;
+
+/// file events kept for watchers that fall behind
+const EVENT_BUFFER: usize = 1024;
+
+/// how far a job got, updated as its uploads complete
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Counts {
+    pub(crate) total: usize,
+    pub(crate) uploaded: usize,
+    pub(crate) exist: usize,
+    pub(crate) failed: usize,
+    pub(crate) bytes: u64,
+}
+
+/// an upload of a job that completed, streamed to gRPC watchers
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+pub(crate) struct FileEvent {
+    pub(crate) path: String,
+    pub(crate) name: String,
+    pub(crate) bytes: u64,
+    pub(crate) seconds: f64,
+    pub(crate) retries: usize,
+    /// [`ErrorKind::category`](crate::ErrorKind) of a failed upload
+    pub(crate) error: Option<&'static str>,
+}
+
+/// told about a job's progress instead of drawing a status bar
+pub(crate) struct Observer {
+    pub(crate) counts: watch::Sender<Counts>,
+    pub(crate) files: broadcast::Sender<FileEvent>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum State {
+    Running,
+    /// every upload succeeded
+    Done,
+    /// some uploads failed, or there was nothing to upload
+    Failed,
+    Cancelled,
+}
+
+impl State {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Done => "done",
+            State::Failed => "failed",
+            State::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// a job as it is at the moment
+#[derive(Clone, Debug)]
+pub(crate) struct Status {
+    pub(crate) id: u64,
+    pub(crate) path: String,
+    pub(crate) state: State,
+    pub(crate) counts: Counts,
+}
+
+impl Status {
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "path": self.path,
+            "state": self.state.name(),
+            "total": self.counts.total,
+            "uploaded": self.counts.uploaded,
+            "exist": self.counts.exist,
+            "failed": self.counts.failed,
+            "bytes": self.counts.bytes,
+        })
+    }
+}
+
+struct Job {
+    path: String,
+    state: State,
+    counts: watch::Receiver<Counts>,
+    /// to subscribe to file events while running, dropped when it ends so
+    /// subscriptions end too
+    files: Option<broadcast::Sender<FileEvent>>,
+    task: AbortHandle,
+}
+
+impl Job {
+    fn status(&self, id: u64) -> Status {
+        Status { id, path: self.path.clone(), state: self.state, counts: self.counts.borrow().clone() }
+    }
+}
+
+/// The jobs of a server, run with one client and the server's settings.
+pub(crate) struct Jobs {
+    client: Client,
+    settings: Settings,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+}
+
+impl Jobs {
+    pub(crate) fn new(settings: Settings) -> reqwest::Result<Self> {
+        // nobody is looking at the terminal
+        let settings = settings.with_scan_progress(false).with_confirm(None);
+        Ok(Jobs { client: crate::build_client(&settings)?, settings, jobs: Mutex::new(BTreeMap::new()) })
+    }
+
+    /// Start uploading `files` to `path`.
+    pub(crate) fn submit(self: &Arc<Self>, path: String, files: Vec<String>) -> Status {
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.keys().next_back().map_or(1, |id| id + 1);
+        let (counts, counts_rx) = watch::channel(Counts::default());
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let observer = Observer { counts, files: events.clone() };
+        let settings = Arc::new(self.settings.clone().with_path(path.clone()));
+        let (client, finished) = (self.client.clone(), self.clone());
+        // the lock is held until the job is in, so it can't finish before that
+        let task = tokio::spawn(async move {
+            let collected = match crate::check_destinations(&client, &settings).await {
+                true => crate::collect_files(files, settings.recursive, settings.max_files, &settings).await,
+                false => None,
+            };
+            let ok = match collected {
+                Some((files, collected)) if !files.is_empty() => {
+                    crate::upload_inputs(&client, files, &collected, settings, Some(observer), None).await
+                }
+                _ => false,
+            };
+            if let Some(job) = finished.jobs.lock().unwrap().get_mut(&id) {
+                job.state = if ok { State::Done } else { State::Failed };
+                job.files = None;
+            }
+        });
+        cli_eprintln!("Job {}: uploading to {}", id, path);
+        let job = Job { path, state: State::Running, counts: counts_rx, files: Some(events), task: task.abort_handle() };
+        let status = job.status(id);
+        jobs.insert(id, job);
+        status
+    }
+
+    pub(crate) fn status(&self, id: u64) -> Option<Status> {
+        self.jobs.lock().unwrap().get(&id).map(|job| job.status(id))
+    }
+
+    pub(crate) fn list(&self) -> Vec<Status> {
+        self.jobs.lock().unwrap().iter().map(|(id, job)| job.status(*id)).collect()
+    }
+
+    /// Cancel a running job, dropping its uploads in flight.
+    pub(crate) fn cancel(&self, id: u64) -> Option<Status> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(&id)?;
+        if job.state == State::Running {
+            job.task.abort();
+            job.state = State::Cancelled;
+            job.files = None;
+        }
+        Some(job.status(id))
+    }
+
+    /// Events of the uploads of a job that complete from now on, None if
+    /// there's no such job or it isn't running.
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    pub(crate) fn subscribe(&self, id: u64) -> Option<broadcast::Receiver<FileEvent>> {
+        self.jobs.lock().unwrap().get(&id)?.files.as_ref().map(|files| files.subscribe())
+    }
+}
+
+/// Serve the REST API on `listen`, and gRPC on `grpc` if given, until
+/// interrupted, running jobs with `settings`. Returns false if it can't start.
+pub async fn serve(listen: SocketAddr, grpc: Option<SocketAddr>, settings: Settings) -> bool {
+    let jobs = match Jobs::new(settings) {
+        Ok(jobs) => Arc::new(jobs),
+        Err(e) => {
+            cli_eprintln!("Failed to set up http client: {}", e);
+            return false;
+        }
+    };
+    let secret: Arc<str> = match new_secret() {
+        Ok(secret) => secret.into(),
+        Err(e) => {
+            cli_eprintln!("Failed to make up a secret: {}", e);
+            return false;
+        }
+    };
+    let listener = match tokio::net::TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            cli_eprintln!("Failed to listen on {}: {}", listen, e);
+            return false;
+        }
+    };
+    if let Some(grpc) = grpc {
+        #[cfg(feature = "grpc")]
+        tokio::spawn(crate::grpc::serve(grpc, jobs.clone(), secret.clone()));
+        #[cfg(not(feature = "grpc"))]
+        {
+            cli_eprintln!("Can't serve gRPC on {}, built without the grpc feature", grpc);
+            return false;
+        }
+    }
+    cli_eprintln!("Serving on http://{}, send the header: Authorization: Bearer {}", listen, secret);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                cli_eprintln!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let jobs = jobs.clone();
+        let secret = secret.clone();
+        tokio::spawn(async move {
+            let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                let refused = refusal(request.headers(), &secret, listen);
+                let jobs = jobs.clone();
+                async move {
+                    match refused {
+                        Some(refused) => Ok(refused),
+                        None => handle(jobs, request).await,
+                    }
+                }
+            });
+            // clients going away is nothing to report
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+async fn handle(jobs: Arc<Jobs>, request: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let path: Vec<_> = request.uri().path().split('/').filter(|s| !s.is_empty()).collect();
+    let id = match path.as_slice() {
+        ["jobs"] => None,
+        ["jobs", id] => match id.parse::<u64>() {
+            Ok(id) => Some(id),
+            Err(_) => return Ok(error(StatusCode::NOT_FOUND, "no such job")),
+        },
+        _ => return Ok(error(StatusCode::NOT_FOUND, "not found")),
+    };
+    let response = match (request.method().clone(), id) {
+        (Method::POST, None) => {
+            let body = match Limited::new(request.into_body(), MAX_REQUEST).collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &e.to_string())),
+            };
+            match parse_job(&body) {
+                Ok((path, files)) => reply(StatusCode::CREATED, jobs.submit(path, files).to_json()),
+                Err(e) => error(StatusCode::BAD_REQUEST, &e),
+            }
+        }
+        (Method::GET, None) => reply(StatusCode::OK, jobs.list().iter().map(Status::to_json).collect()),
+        (Method::GET, Some(id)) => match jobs.status(id) {
+            Some(status) => reply(StatusCode::OK, status.to_json()),
+            None => error(StatusCode::NOT_FOUND, "no such job"),
+        },
+        (Method::DELETE, Some(id)) => match jobs.cancel(id) {
+            Some(status) => reply(StatusCode::OK, status.to_json()),
+            None => error(StatusCode::NOT_FOUND, "no such job"),
+        },
+        _ => error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+    };
+    Ok(response)
+}
+
+/// A random secret for callers to prove they may submit jobs, hex encoded.
+fn new_secret() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Whether `authorization` (a header's value) carries `secret`, comparing
+/// in constant time.
+pub(crate) fn has_secret(authorization: Option<&[u8]>, secret: &str) -> bool {
+    let Some(token) = authorization.and_then(|value| value.strip_prefix(b"Bearer ")) else {
+        return false;
+    };
+    token.len() == secret.len() && token.iter().zip(secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// The refusal of requests without the secret, or from web pages of other
+/// origins than the server on `listen`.
+fn refusal(headers: &HeaderMap, secret: &str, listen: SocketAddr) -> Option<Response<Full<Bytes>>> {
+    if let Some(origin) = headers.get(hyper::header::ORIGIN)
+        && origin.as_bytes() != format!("http://{}", listen).as_bytes() {
+        return Some(error(StatusCode::FORBIDDEN, "requests from other origins are refused"));
+    }
+    match has_secret(headers.get(hyper::header::AUTHORIZATION).map(|value| value.as_bytes()), secret) {
+        true => None,
+        false => Some(error(StatusCode::UNAUTHORIZED, "send the secret printed at start as bearer token")),
+    }
+}
+
+/// path and files of a job from its JSON
+fn parse_job(body: &[u8]) -> Result<(String, Vec<String>), String> {
+    let job: Value = serde_json::from_slice(body).map_err(|e| format!("invalid json: {}", e))?;
+    let path = job["path"].as_str().ok_or("path missing")?;
+    let files = job["files"].as_array().ok_or("files missing")?.iter()
+        .map(|f| f.as_str().map(str::to_string).ok_or("files must be strings"))
+        .collect::<Result<Vec<_>, _>>()?;
+    check_job(path, &files)?;
+    Ok((path.to_string(), files))
+}
+
+/// what's required of a job however it's submitted
+pub(crate) fn check_job(path: &str, files: &[String]) -> Result<(), String> {
+    if path.is_empty() {
+        return Err("path missing".to_string());
+    }
+    if files.is_empty() {
+        return Err("no files".to_string());
+    }
+    Ok(())
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response.headers_mut().insert(hyper::header::CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    reply(status, json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refusal() {
+        let listen: SocketAddr = "127.0.0.1:7878".parse().unwrap();
+        let secret = new_secret().unwrap();
+        assert_eq!(secret.len(), 32);
+        assert_ne!(secret, new_secret().unwrap());
+        let bearer = format!("Bearer {}", secret);
+        let status = |authorization: Option<&str>, origin: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(authorization) = authorization {
+                headers.insert(hyper::header::AUTHORIZATION, authorization.parse().unwrap());
+            }
+            if let Some(origin) = origin {
+                headers.insert(hyper::header::ORIGIN, origin.parse().unwrap());
+            }
+            refusal(&headers, &secret, listen).map(|refused| refused.status())
+        };
+        assert_eq!(status(Some(&bearer), None), None);
+        assert_eq!(status(Some(&bearer), Some("http://127.0.0.1:7878")), None);
+        assert_eq!(status(None, None), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(Some("Bearer 0123"), None), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(status(Some(&secret), None), Some(StatusCode::UNAUTHORIZED));
+        // a page elsewhere, even one that got hold of the secret
+        assert_eq!(status(Some(&bearer), Some("https://evil.example")), Some(StatusCode::FORBIDDEN));
+        assert_eq!(status(Some(&bearer), Some("null")), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn test_parse_job() {
+        let (path, files) = parse_job(br#"{"path": "Storage/u/p", "files": ["a", "b"]}"#).unwrap();
+        assert_eq!(path, "Storage/u/p");
+        assert_eq!(files, ["a", "b"]);
+        assert!(parse_job(b"nope").is_err());
+        assert!(parse_job(br#"{"files": ["a"]}"#).is_err());
+        assert!(parse_job(br#"{"path": "", "files": ["a"]}"#).is_err());
+        assert!(parse_job(br#"{"path": "p", "files": []}"#).is_err());
+        assert!(parse_job(br#"{"path": "p", "files": [1]}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jobs() {
+        let settings = Settings::with_endpoint("http://127.0.0.1:1".to_string(), String::new());
+        let jobs = Arc::new(Jobs::new(settings).unwrap());
+        // nothing to upload
+        let status = jobs.submit("p".to_string(), vec!["doesnotexist".to_string()]);
+        assert_eq!((status.id, status.state), (1, State::Running));
+        for _ in 0..100 {
+            if jobs.status(1).unwrap().state != State::Running {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(jobs.status(1).unwrap().state, State::Failed);
+        assert!(jobs.subscribe(1).is_none());
+        assert_eq!(jobs.cancel(1).unwrap().state, State::Failed);
+        assert!(jobs.status(2).is_none());
+        assert_eq!(jobs.list().len(), 1);
+
+        // the path is checked like for uploads from the command line
+        let mock = crate::test_util::MockFileservice::start().await;
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.txt");
+        std::fs::write(&path, "a").unwrap();
+        let jobs = Arc::new(Jobs::new(Settings::with_endpoint(mock.endpoint(), "token".to_string())).unwrap());
+        jobs.submit("Storage/u/../p".to_string(), vec![path.to_str().unwrap().to_string()]);
+        for _ in 0..100 {
+            if jobs.status(1).unwrap().state != State::Running {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(jobs.status(1).unwrap().state, State::Failed);
+        assert_eq!(mock.uploads(), 0);
+    }
+}
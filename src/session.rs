@@ -0,0 +1,142 @@
+//! `--login`: auth by a session cookie, for deployments only reachable behind
+//! an SSO gateway. Credentials are posted as a form to the login url and the
+//! cookies it sets are kept in the client's cookie store, sent along with
+//! every request. Once the cookies the login set expire the next upload logs
+//! in again (other cookies, e.g. of a load balancer, don't make a session),
+//! as does one refused with 401, trying once more after.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use reqwest::cookie::{CookieStore, Jar};
+use reqwest::{Client, Url};
+
+use crate::transport::TransportError;
+
+/// credentials to log in with
+#[derive(Clone)]
+pub struct Login {
+    /// where the `username` and `password` form is posted
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+/// A login and the cookies it got, shared by the clients of a run.
+pub(crate) struct Session {
+    login: Login,
+    jar: Arc<Jar>,
+    /// held while logging in, so uploads finding the session expired log in
+    /// once between them
+    logging_in: tokio::sync::Mutex<()>,
+    /// logins so far, to tell whether the session changed since a request
+    logins: AtomicUsize,
+    /// names of the cookies the latest login set, others (e.g. of a load
+    /// balancer) don't make a session
+    cookies: Mutex<Vec<String>>,
+}
+
+impl Session {
+    pub(crate) fn new(login: Login) -> Self {
+        let logging_in = tokio::sync::Mutex::new(());
+        let cookies = Mutex::new(Vec::new());
+        Session { login, jar: Arc::new(Jar::default()), logging_in, logins: AtomicUsize::new(0), cookies }
+    }
+
+    /// which login the session is from
+    pub(crate) fn generation(&self) -> usize {
+        self.logins.load(Ordering::Relaxed)
+    }
+
+    /// the cookie store to build clients with
+    pub(crate) fn jar(&self) -> Arc<Jar> {
+        self.jar.clone()
+    }
+
+    /// Log in with `client` unless there's a session cookie for `url` already.
+    pub(crate) async fn ensure(&self, client: &Client, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?;
+        if self.has_cookie(&url) {
+            return Ok(());
+        }
+        let _logging_in = self.logging_in.lock().await;
+        // logged in while waiting
+        if self.has_cookie(&url) {
+            return Ok(());
+        }
+        self.login(client, &url).await
+    }
+
+    /// whether the jar still has a cookie for `url` the latest login set,
+    /// the jar drops them once they expire
+    fn has_cookie(&self, url: &Url) -> bool {
+        let names = self.cookies.lock().unwrap();
+        self.sent(url).iter().any(|(name, _)| names.contains(name))
+    }
+
+    /// names and values of the cookies sent to `url`
+    fn sent(&self, url: &Url) -> Vec<(String, String)> {
+        let Some(header) = self.jar.cookies(url) else { return Vec::new() };
+        header.to_str().unwrap_or_default().split(';')
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Log in again after a request with the session of `generation` to
+    /// `url` was refused, unless another upload did since.
+    pub(crate) async fn renew(&self, client: &Client, url: &str, generation: usize) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?;
+        let _logging_in = self.logging_in.lock().await;
+        if self.generation() != generation {
+            return Ok(());
+        }
+        self.login(client, &url).await
+    }
+
+    /// Log in with `client`, which should set a session cookie for `url`.
+    async fn login(&self, client: &Client, url: &Url) -> Result<(), String> {
+        let before = self.sent(url);
+        let form = [("username", &self.login.username), ("password", &self.login.password)];
+        let response = client.post(&self.login.url).form(&form).send().await
+            .map_err(|e| format!("login at {} failed: {}", self.login.url, TransportError::classify(&e).describe()))?;
+        if !response.status().is_success() {
+            return Err(format!("login at {} refused: {}", self.login.url, response.status()));
+        }
+        // by the answer, or on the way there through redirects
+        let mut names: Vec<_> = response.cookies().map(|cookie| cookie.name().to_string()).collect();
+        names.extend(self.sent(url).into_iter().filter(|cookie| !before.contains(cookie)).map(|(name, _)| name));
+        *self.cookies.lock().unwrap() = names;
+        self.logins.fetch_add(1, Ordering::Relaxed);
+        match self.has_cookie(url) {
+            true => Ok(()),
+            false => Err(format!("login at {} set no session cookie for {}", self.login.url, url)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::MockFileservice;
+
+    #[tokio::test]
+    async fn test_ensure() {
+        let mock = MockFileservice::start().await;
+        mock.set_login("u", "secret", None);
+        let login = Login { url: mock.login_url(), username: "u".to_string(), password: "secret".to_string() };
+        let session = Session::new(login);
+        let client = Client::builder().cookie_provider(session.jar()).build().unwrap();
+        let url = mock.endpoint();
+        // a cookie the login didn't set
+        session.jar().add_cookie_str("lb=1; Path=/", &Url::parse(&url).unwrap());
+        session.ensure(&client, &url).await.unwrap();
+        assert_eq!(mock.logins(), 1);
+        session.ensure(&client, &url).await.unwrap();
+        assert_eq!(mock.logins(), 1);
+        // renewed with the same cookie
+        session.renew(&client, &url, session.generation()).await.unwrap();
+        session.ensure(&client, &url).await.unwrap();
+        assert_eq!(mock.logins(), 2);
+    }
+}
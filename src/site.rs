@@ -0,0 +1,104 @@
+//! Finding the fileservice of a SciServer deployment from its name or the url
+//! of its login portal, so the api endpoint needn't be known. Deployments
+//! list their fileservices in the RACM service registry,
+//! `<site>/racm/storem/fileservices`.
+
+use reqwest::Url;
+use serde_json::Value;
+
+use crate::{build_client, Settings};
+
+/// deployments known by name
+const SITES: &[(&str, &str)] = &[("idies", "https://apps.sciserver.org")];
+
+/// Base url of the deployment `site`, a known name or any url on it, such as
+/// that of its login portal.
+fn site_url(site: &str) -> Result<Url, String> {
+    if let Some((_, url)) = SITES.iter().find(|(name, _)| name.eq_ignore_ascii_case(site)) {
+        return Ok(Url::parse(url).unwrap());
+    }
+    let known: Vec<_> = SITES.iter().map(|(name, _)| *name).collect();
+    let url = Url::parse(site)
+        .map_err(|_| format!("unknown site {:?}, expected one of {} or a url", site, known.join(", ")))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return Err(format!("expected an http(s) url of the site, got {}", site));
+    }
+    Ok(url)
+}
+
+/// The api endpoint of a registry entry's `apiEndpoint`, which is the base
+/// of the service (e.g. `https://host/fileservice/`).
+fn file_endpoint(api: &str) -> String {
+    let api = api.trim_end_matches('/');
+    if api.ends_with("/api/file") {
+        api.to_string()
+    } else if api.ends_with("/api") {
+        format!("{}/file", api)
+    } else {
+        format!("{}/api/file", api)
+    }
+}
+
+/// Endpoints of the fileservices in a registry listing, in its order.
+fn parse_fileservices(listing: &Value) -> Vec<String> {
+    listing.as_array().into_iter().flatten()
+        .filter_map(|service| service["apiEndpoint"].as_str())
+        .map(file_endpoint)
+        .collect()
+}
+
+/// The fileservice endpoints of `site`, the first being the one to use,
+/// asking its service registry with the token and network settings.
+pub async fn discover_endpoints(settings: &Settings, site: &str) -> Result<Vec<String>, String> {
+    let mut url = site_url(site)?;
+    url.set_path("/racm/storem/fileservices");
+    url.set_query(None);
+    let client = build_client(settings).map_err(|e| e.to_string())?;
+    let listing: Value = client.get(url.clone()).send().await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("querying {} failed: {}", url, e))?
+        .json().await
+        .map_err(|e| format!("invalid listing from {}: {}", url, e))?;
+    match parse_fileservices(&listing) {
+        endpoints if endpoints.is_empty() => Err(format!("no fileservice listed by {}", url)),
+        endpoints => Ok(endpoints),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_site_url() {
+        assert_eq!(site_url("idies").unwrap().as_str(), "https://apps.sciserver.org/");
+        assert_eq!(site_url("https://sciserver.example.edu/login-portal/").unwrap().host_str(),
+            Some("sciserver.example.edu"));
+        assert!(site_url("nowhere").is_err());
+        assert!(site_url("ftp://host/").is_err());
+    }
+
+    #[test]
+    fn test_parse_fileservices() {
+        let listing = json!([
+            {"name": "FileServiceJHU", "apiEndpoint": "https://apps.sciserver.org/fileservice/"},
+            {"name": "Other", "apiEndpoint": "https://other.org/fs/api"},
+            {"name": "Broken"},
+        ]);
+        assert_eq!(parse_fileservices(&listing), vec![
+            "https://apps.sciserver.org/fileservice/api/file",
+            "https://other.org/fs/api/file",
+        ]);
+        assert_eq!(file_endpoint("https://h/fileservice/api/file/"), "https://h/fileservice/api/file");
+    }
+
+    #[tokio::test]
+    async fn test_discover_endpoints() {
+        let mock = crate::test_util::MockFileservice::start().await;
+        let settings = Settings::with_endpoint(String::new(), "token".to_string());
+        let portal = mock.endpoint().replace("/fileservice/api/file", "/login-portal/?callback=x");
+        assert_eq!(discover_endpoints(&settings, &portal).await, Ok(vec![mock.endpoint()]));
+    }
+}
@@ -0,0 +1,228 @@
+//! Deciding what to transfer to keep a local directory and a path on the
+//! fileservice in sync both ways. Files only on one side are copied to the
+//! other, files changed on one side since the last sync replace the other
+//! copy, and files changed on both sides are conflicts left for the user,
+//! unless their contents ended up the same.
+//!
+//! What each file looked like after the last sync is kept in a snapshot file
+//! in the local directory. Without one (the first sync) the newer copy wins.
+//!
+//! When mirroring, files only on the fileservice are deleted rather than
+//! downloaded, so the remote path ends up holding what's local.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::remote::RemoteFile;
+
+/// snapshot of the last sync, kept in the synced directory
+pub(crate) const STATE_FILE: &str = ".uploadsync";
+
+/// a local file
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LocalFile {
+    pub(crate) size: u64,
+    pub(crate) modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Action {
+    Upload,
+    Download,
+    /// only on the fileservice, when mirroring
+    Delete,
+    /// changed on both sides to the same size, in sync only if the contents
+    /// are the same
+    Compare,
+    /// changed on both sides, or no telling which copy is newer
+    Conflict(&'static str),
+}
+
+/// a file as seen on both sides after the last sync
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Entry {
+    local_size: u64,
+    local_modified: u64,
+    remote_size: u64,
+    remote_modified: String,
+}
+
+fn secs(time: Option<SystemTime>) -> u64 {
+    time.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs())
+}
+
+/// When the remote file was last modified, if the fileservice says so in a
+/// format we understand.
+pub(crate) fn remote_modified(remote: &RemoteFile) -> Option<SystemTime> {
+    humantime::parse_rfc3339_weak(remote.modified.as_deref()?.trim_end_matches('Z')).ok()
+}
+
+#[derive(Default)]
+pub(crate) struct Snapshot(HashMap<String, Entry>);
+
+impl Snapshot {
+    /// The snapshot of `dir`, empty if it was never synced. Fields are NUL
+    /// terminated, as names can hold anything else.
+    pub(crate) fn load(dir: &Path) -> Self {
+        let data = std::fs::read_to_string(dir.join(STATE_FILE)).unwrap_or_default();
+        let fields: Vec<_> = data.split_terminator('\0').collect();
+        Snapshot(fields.chunks_exact(5)
+            .filter_map(|f| Some((f[0].to_string(), Entry {
+                local_size: f[1].parse().ok()?,
+                local_modified: f[2].parse().ok()?,
+                remote_size: f[3].parse().ok()?,
+                remote_modified: f[4].to_string(),
+            })))
+            .collect())
+    }
+
+    /// Record the files that are the same on both sides, replacing this
+    /// snapshot. The `conflicts` are kept as they were, so they stay
+    /// conflicts until resolved.
+    pub(crate) fn save(&self, dir: &Path, local: &HashMap<String, LocalFile>, remote: &HashMap<String, RemoteFile>,
+        conflicts: &HashSet<String>) -> io::Result<()> {
+        let mut entries: Vec<_> = local.keys()
+            .filter(|name| !conflicts.contains(*name))
+            .filter(|name| remote.get(*name).is_some_and(|r| r.size == local[*name].size))
+            .map(|name| {
+                let (l, r) = (&local[name], &remote[name]);
+                let remote_modified = r.modified.clone().unwrap_or_default();
+                let (local_size, local_modified, remote_size) = (l.size, secs(l.modified), r.size);
+                (name, Entry { local_size, local_modified, remote_size, remote_modified })
+            })
+            .chain(conflicts.iter().filter_map(|name| Some((name, self.0.get(name)?.clone()))))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        let data: String = entries.into_iter().map(|(name, e)| {
+            format!("{}\0{}\0{}\0{}\0{}\0", name, e.local_size, e.local_modified, e.remote_size, e.remote_modified)
+        }).collect();
+        std::fs::write(dir.join(STATE_FILE), data)
+    }
+
+    fn local_changed(&self, name: &str, local: &LocalFile) -> bool {
+        self.0.get(name).is_none_or(|e| e.local_size != local.size || e.local_modified != secs(local.modified))
+    }
+
+    fn remote_changed(&self, name: &str, remote: &RemoteFile) -> bool {
+        self.0.get(name).is_none_or(|e| {
+            e.remote_size != remote.size || e.remote_modified != remote.modified.as_deref().unwrap_or_default()
+        })
+    }
+}
+
+/// What to do with each file that isn't in sync, by name.
+pub(crate) fn plan(local: &HashMap<String, LocalFile>, remote: &HashMap<String, RemoteFile>, snapshot: &Snapshot,
+    mirror: bool) -> Vec<(String, Action)> {
+    let names: HashSet<_> = local.keys().chain(remote.keys()).collect();
+    let mut actions: Vec<_> = names.into_iter().filter_map(|name| {
+        let action = match (local.get(name), remote.get(name)) {
+            (Some(_), None) => Action::Upload,
+            (None, Some(_)) if mirror => Action::Delete,
+            (None, Some(_)) => Action::Download,
+            (Some(l), Some(r)) => {
+                let (local_changed, remote_changed) = (snapshot.local_changed(name, l), snapshot.remote_changed(name, r));
+                if snapshot.0.contains_key(name) {
+                    match (local_changed, remote_changed) {
+                        (false, false) => return None,
+                        (true, false) => Action::Upload,
+                        (false, true) => Action::Download,
+                        (true, true) if l.size == r.size => Action::Compare,
+                        (true, true) => Action::Conflict("changed on both sides"),
+                    }
+                } else if l.size == r.size {
+                    // never synced, assume files of the same size are the same
+                    return None;
+                } else {
+                    // rounding to seconds, either side may lack sub-second times
+                    match (secs(l.modified), remote_modified(r).map(|t| secs(Some(t)))) {
+                        (_, None) => Action::Conflict("remote modification time unknown"),
+                        (l, Some(r)) if l > r => Action::Upload,
+                        (l, Some(r)) if l < r => Action::Download,
+                        _ => Action::Conflict("modified at the same time"),
+                    }
+                }
+            }
+            (None, None) => return None,
+        };
+        Some((name.clone(), action))
+    }).collect();
+    actions.sort_by(|a, b| a.0.cmp(&b.0));
+    actions
+}
+
+/// set a downloaded file's modification time to that of the remote copy
+pub(crate) fn set_modified(path: &Path, modified: SystemTime) -> io::Result<()> {
+    std::fs::File::options().write(true).open(path)?.set_modified(modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn local(size: u64, secs: u64) -> LocalFile {
+        LocalFile { size, modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)) }
+    }
+
+    fn remote(size: u64, modified: &str) -> RemoteFile {
+        RemoteFile { size, modified: Some(modified.to_string()), sha256: None }
+    }
+
+    #[test]
+    fn test_plan() {
+        // 2025-06-30T00:00:00Z
+        let day = 1751241600;
+        let local_files = HashMap::from([
+            ("up".to_string(), local(1, day)),
+            ("same".to_string(), local(2, day)),
+            ("newer".to_string(), local(3, day + 60)),
+            ("older".to_string(), local(3, day - 60)),
+        ]);
+        let remote_files = HashMap::from([
+            ("down".to_string(), remote(1, "2025-06-30T00:00:00Z")),
+            ("same".to_string(), remote(2, "2025-06-30T00:00:00Z")),
+            ("newer".to_string(), remote(4, "2025-06-30T00:00:00Z")),
+            ("older".to_string(), remote(4, "2025-06-30T00:00:00Z")),
+        ]);
+        let actions = plan(&local_files, &remote_files, &Snapshot::default(), false);
+        assert_eq!(actions, vec![
+            ("down".to_string(), Action::Download),
+            ("newer".to_string(), Action::Upload),
+            ("older".to_string(), Action::Download),
+            ("up".to_string(), Action::Upload),
+        ]);
+        let actions = plan(&local_files, &remote_files, &Snapshot::default(), true);
+        assert_eq!(actions[0], ("down".to_string(), Action::Delete));
+
+        // after a sync only changes since count
+        let tempdir = tempfile::tempdir().unwrap();
+        Snapshot::default().save(tempdir.path(), &local_files, &remote_files, &HashSet::new()).unwrap();
+        let snapshot = Snapshot::load(tempdir.path());
+        assert_eq!(snapshot.0.len(), 1);
+        let same = |local: &HashMap<_, _>, remote: &HashMap<_, _>| {
+            plan(local, remote, &snapshot, false).into_iter().find(|a| a.0 == "same").map(|a| a.1)
+        };
+        let mut local_files = HashMap::from([("same".to_string(), local(5, day - 3600))]);
+        assert_eq!(same(&local_files, &remote_files), Some(Action::Upload));
+        let mut remote_files = remote_files;
+        remote_files.insert("same".to_string(), remote(6, "2025-06-30T01:00:00Z"));
+        assert_eq!(same(&local_files, &remote_files), Some(Action::Conflict("changed on both sides")));
+        local_files.insert("same".to_string(), local(2, day));
+        assert_eq!(same(&local_files, &remote_files), Some(Action::Download));
+        remote_files.insert("same".to_string(), remote(2, "2025-06-30T00:00:00Z"));
+        assert_eq!(same(&local_files, &remote_files), None);
+        // the same size on both sides doesn't make them the same
+        local_files.insert("same".to_string(), local(7, day + 60));
+        remote_files.insert("same".to_string(), remote(7, "2025-06-30T01:00:00Z"));
+        assert_eq!(same(&local_files, &remote_files), Some(Action::Compare));
+
+        // conflicts stay what they were in the snapshot
+        snapshot.save(tempdir.path(), &local_files, &remote_files, &HashSet::from(["same".to_string()])).unwrap();
+        let resaved = Snapshot::load(tempdir.path());
+        assert_eq!(resaved.0, snapshot.0);
+        snapshot.save(tempdir.path(), &local_files, &remote_files, &HashSet::new()).unwrap();
+        assert!(!Snapshot::load(tempdir.path()).local_changed("same", &local_files["same"]));
+    }
+}
@@ -0,0 +1,397 @@
+//! A mock fileservice for tests of code uploading with this crate, built with
+//! the `test-util` feature. It stores uploaded files in memory and answers
+//! like the fileservice does, or with the replies it's told to give, after a
+//! configurable latency. Directories can be listed with the `jsonTree` api
+//! (with checksums once asked for), volumes with the `volumes` api once
+//! given, and CSV files loaded as CasJobs tables are kept as files named
+//! `<context>/Tables/<table>`. It takes logins
+//! for session cookies once set up, deletes files with the `data` api, lists
+//! itself in the service registry for `--site` discovery, and keeps the
+//! dataset manifests POSTed to its catalog:
+//!
+//! ```no_run
+//! # async fn example() {
+//! use upload::test_util::{MockFileservice, Reply};
+//!
+//! let mock = MockFileservice::start().await;
+//! // the first attempt is throttled, the retry goes through
+//! mock.push_replies([Reply::TooManyRequests]);
+//! let settings = upload::Settings::with_endpoint(mock.endpoint(), "token".to_string());
+//! # }
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+/// how the mock answers an upload
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reply {
+    /// stores the file, unless it exists and overwriting wasn't asked for
+    Store,
+    /// 401, as for a bad token
+    Unauthorized,
+    /// 500 "File already exists", whether it does or not
+    Exists,
+    /// 429 Too Many Requests
+    TooManyRequests,
+    /// 503 Service Unavailable
+    Unavailable,
+    /// stores the file but closes the connection without answering, as if
+    /// the response was lost
+    StoreAndHangUp,
+    /// stores half of the file and closes the connection, as if the transfer
+    /// broke off while being written
+    StorePartAndHangUp,
+    /// closes the connection without storing anything, as if the transfer
+    /// broke off before reaching the fileservice
+    HangUp,
+}
+
+#[derive(Default)]
+struct State {
+    files: BTreeMap<String, Bytes>,
+    replies: VecDeque<Reply>,
+    /// header and value requests need to carry
+    auth: Option<(String, String)>,
+    /// username, password and how long sessions last
+    login: Option<(String, String, Option<Duration>)>,
+    logins: usize,
+    /// the latest session ended before its cookie did
+    session_ended: bool,
+    /// the next answer expires the session cookie
+    expire_cookie: bool,
+    latency: Duration,
+    uploads: usize,
+    volumes: Option<Value>,
+    checksums: bool,
+    /// headers and body of each POST to the catalog
+    registrations: Vec<(hyper::HeaderMap, Bytes)>,
+}
+
+/// A fileservice on a local port, stopped when dropped.
+pub struct MockFileservice {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+impl MockFileservice {
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("can't bind a local port");
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = state.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = shared.clone();
+                tokio::spawn(async move {
+                    let service = hyper::service::service_fn(move |request| handle(state.clone(), request));
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        MockFileservice { addr, state, task }
+    }
+
+    /// the endpoint to upload to, as given to [`Settings::with_endpoint`](crate::Settings::with_endpoint)
+    pub fn endpoint(&self) -> String {
+        format!("http://{}/fileservice/api/file", self.addr)
+    }
+
+    /// the CasJobs REST API endpoint, for [`Settings::with_casjobs`](crate::Settings::with_casjobs)
+    pub fn casjobs_endpoint(&self) -> String {
+        format!("http://{}/casjobs/RestApi", self.addr)
+    }
+
+    /// a catalog taking dataset manifests, for [`ManifestTarget::Post`](crate::ManifestTarget::Post)
+    pub fn catalog_url(&self) -> String {
+        format!("http://{}/catalog/datasets", self.addr)
+    }
+
+    /// Answer requests without this token with 401. Any token goes by default.
+    pub fn set_token(&self, token: &str) {
+        self.set_auth("x-auth-token", token);
+    }
+
+    /// Answer requests without `header: value` with 401, e.g. for bearer auth.
+    pub fn set_auth(&self, header: &str, value: &str) {
+        self.state.lock().unwrap().auth = Some((header.to_string(), value.to_string()));
+    }
+
+    /// Take logins with these credentials at [`login_url`](Self::login_url),
+    /// answering requests without the cookie of the latest session with 401.
+    /// Sessions last `max_age`, as far as the cookie goes.
+    pub fn set_login(&self, username: &str, password: &str, max_age: Option<Duration>) {
+        self.state.lock().unwrap().login = Some((username.to_string(), password.to_string(), max_age));
+    }
+
+    /// where the form with username and password is posted to log in
+    pub fn login_url(&self) -> String {
+        format!("http://{}/login", self.addr)
+    }
+
+    /// End the latest session, as if it timed out on the server, answering
+    /// requests with 401 until the next login.
+    pub fn end_session(&self) {
+        self.state.lock().unwrap().session_ended = true;
+    }
+
+    /// Expire the session cookie on the client, as its max age running out
+    /// would: the next answer sets it with `Max-Age=0`, along with a cookie
+    /// that isn't the session's (like a load balancer's).
+    pub fn expire_cookie(&self) {
+        self.state.lock().unwrap().expire_cookie = true;
+    }
+
+    /// how many times credentials were accepted
+    pub fn logins(&self) -> usize {
+        self.state.lock().unwrap().logins
+    }
+
+    /// wait this long before answering each request
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Answer the `volumes` api with this, e.g. `{"rootVolumes": [{"name":
+    /// "Storage", "userVolumes": [{"name": "persistent", "owner": "u"}]}]}`.
+    /// Like older deployments it isn't there otherwise.
+    pub fn set_volumes(&self, volumes: Value) {
+        self.state.lock().unwrap().volumes = Some(volumes);
+    }
+
+    /// List the SHA-256 of files in `jsonTree` answers, as some deployments
+    /// do, for [`Settings::with_listed_checksums`](crate::Settings::with_listed_checksums).
+    pub fn set_checksums(&self, checksums: bool) {
+        self.state.lock().unwrap().checksums = checksums;
+    }
+
+    /// Answer the next uploads with these, in order, then as usual.
+    pub fn push_replies(&self, replies: impl IntoIterator<Item = Reply>) {
+        self.state.lock().unwrap().replies.extend(replies);
+    }
+
+    /// a file already there, at `path` (e.g. `Storage/u/persistent/a.txt`)
+    pub fn insert(&self, path: &str, data: impl Into<Bytes>) {
+        self.state.lock().unwrap().files.insert(path.trim_matches('/').to_string(), data.into());
+    }
+
+    /// the file uploaded to `path`, if any
+    pub fn file(&self, path: &str) -> Option<Bytes> {
+        self.state.lock().unwrap().files.get(path.trim_matches('/')).cloned()
+    }
+
+    /// upload attempts received, however they were answered
+    pub fn uploads(&self) -> usize {
+        self.state.lock().unwrap().uploads
+    }
+
+    /// the headers and body of each POST to the [catalog](Self::catalog_url),
+    /// however it was answered
+    pub fn registrations(&self) -> Vec<(hyper::HeaderMap, Bytes)> {
+        self.state.lock().unwrap().registrations.clone()
+    }
+}
+
+impl Drop for MockFileservice {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn reply(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+    response
+}
+
+/// A `jsonTree` folder of `files`, given by their path relative to it, with
+/// `checksums` listed.
+fn folder(files: &[(&str, &Bytes)], checksums: bool) -> Value {
+    let mut entries = Vec::new();
+    let mut folders: BTreeMap<&str, Vec<(&str, &Bytes)>> = BTreeMap::new();
+    for (path, data) in files {
+        match path.split_once('/') {
+            Some((dir, rest)) => folders.entry(dir).or_default().push((rest, *data)),
+            None if checksums => {
+                let sha256 = crate::checksum::to_hex(&<sha2::Sha256 as sha2::Digest>::digest(data));
+                entries.push(json!({ "name": path, "size": data.len(), "sha256": sha256 }));
+            }
+            None => entries.push(json!({ "name": path, "size": data.len() })),
+        }
+    }
+    let folders: Vec<_> = folders.into_iter()
+        .map(|(name, files)| {
+            let mut folder = folder(&files, checksums);
+            folder["name"] = name.into();
+            folder
+        })
+        .collect();
+    json!({ "files": entries, "folders": folders })
+}
+
+/// error closing the connection without a response
+fn hang_up() -> io::Error {
+    io::Error::from(io::ErrorKind::ConnectionAborted)
+}
+
+/// Answer a login, with a new session cookie if the credentials are right.
+async fn login(state: &Mutex<State>, request: Request<Incoming>) -> Response<Full<Bytes>> {
+    let form = request.into_body().collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+    let mut state = state.lock().unwrap();
+    let Some((username, password, max_age)) = state.login.clone() else {
+        return reply(StatusCode::NOT_FOUND, "not found");
+    };
+    if form != format!("username={}&password={}", username, password) {
+        return reply(StatusCode::UNAUTHORIZED, "");
+    }
+    state.logins += 1;
+    state.session_ended = false;
+    let mut cookie = format!("session=s{}; Path=/", state.logins);
+    if let Some(max_age) = max_age {
+        cookie.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+    }
+    let mut response = reply(StatusCode::OK, "");
+    response.headers_mut().insert(hyper::header::SET_COOKIE, cookie.parse().unwrap());
+    response
+}
+
+async fn handle(state: Arc<Mutex<State>>, request: Request<Incoming>) -> Result<Response<Full<Bytes>>, io::Error> {
+    let mut response = answer(state.clone(), request).await?;
+    if std::mem::take(&mut state.lock().unwrap().expire_cookie) {
+        let headers = response.headers_mut();
+        headers.append(hyper::header::SET_COOKIE, "session=; Path=/; Max-Age=0".parse().unwrap());
+        headers.append(hyper::header::SET_COOKIE, "lb=1; Path=/".parse().unwrap());
+    }
+    Ok(response)
+}
+
+async fn answer(state: Arc<Mutex<State>>, request: Request<Incoming>) -> Result<Response<Full<Bytes>>, io::Error> {
+    let (latency, auth, session) = {
+        let state = state.lock().unwrap();
+        let session = (!state.session_ended).then(|| format!("session=s{}", state.logins));
+        (state.latency, state.auth.clone(), state.login.as_ref().map(|_| session))
+    };
+    tokio::time::sleep(latency).await;
+    if request.uri().path() == "/login" {
+        return Ok(login(&state, request).await);
+    }
+    let authorized = auth.is_none_or(|(header, value)| {
+        request.headers().get(header).is_some_and(|v| v.as_bytes() == value.as_bytes())
+    }) && session.is_none_or(|session| session.is_some_and(|session| {
+        let cookies = request.headers().get("cookie").and_then(|c| c.to_str().ok()).unwrap_or_default();
+        cookies.split("; ").any(|cookie| cookie == session)
+    }));
+    if let Some(dir) = request.uri().path().strip_prefix("/fileservice/api/jsonTree/") {
+        if !authorized {
+            return Ok(reply(StatusCode::UNAUTHORIZED, ""));
+        }
+        let dir = format!("{}/", dir.trim_matches('/'));
+        let state = state.lock().unwrap();
+        let files: Vec<_> = state.files.iter()
+            .filter_map(|(path, data)| Some((path.strip_prefix(&dir)?, data)))
+            .collect();
+        if files.is_empty() {
+            return Ok(reply(StatusCode::NOT_FOUND, "not found"));
+        }
+        return Ok(reply(StatusCode::OK, json!({ "root": folder(&files, state.checksums) }).to_string()));
+    }
+    if request.uri().path() == "/racm/storem/fileservices" {
+        let host = request.headers().get("host").and_then(|h| h.to_str().ok()).unwrap_or_default();
+        let fileservice = json!({ "name": "FileServiceMock", "apiEndpoint": format!("http://{}/fileservice/", host) });
+        return Ok(reply(StatusCode::OK, json!([fileservice]).to_string()));
+    }
+    // the catalog takes no fileservice auth, and answers with the planned
+    // replies too
+    if request.uri().path() == "/catalog/datasets" && request.method() == Method::POST {
+        let headers = request.headers().clone();
+        let data = request.into_body().collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+        let mut state = state.lock().unwrap();
+        state.registrations.push((headers, data));
+        return match state.replies.pop_front() {
+            Some(Reply::Unavailable) => Ok(reply(StatusCode::SERVICE_UNAVAILABLE, "unavailable")),
+            Some(Reply::TooManyRequests) => Ok(reply(StatusCode::TOO_MANY_REQUESTS, "slow down")),
+            Some(Reply::HangUp) => Err(hang_up()),
+            _ => Ok(reply(StatusCode::CREATED, "")),
+        };
+    }
+    if request.uri().path() == "/fileservice/api/volumes" {
+        return Ok(match state.lock().unwrap().volumes.clone() {
+            _ if !authorized => reply(StatusCode::UNAUTHORIZED, ""),
+            Some(volumes) => reply(StatusCode::OK, volumes.to_string()),
+            None => reply(StatusCode::NOT_FOUND, "not found"),
+        });
+    }
+    if let Some(path) = request.uri().path().strip_prefix("/fileservice/api/data/")
+        && request.method() == Method::DELETE {
+        let path = path.trim_matches('/').to_string();
+        if !authorized {
+            return Ok(reply(StatusCode::UNAUTHORIZED, ""));
+        }
+        return Ok(match state.lock().unwrap().files.remove(&path) {
+            Some(_) => reply(StatusCode::OK, ""),
+            None => reply(StatusCode::NOT_FOUND, "not found"),
+        });
+    }
+    let path = request.uri().path();
+    let file = path.strip_prefix("/fileservice/api/file/").or_else(|| path.strip_prefix("/casjobs/RestApi/contexts/"));
+    let Some(path) = file.map(str::to_string) else {
+        return Ok(reply(StatusCode::NOT_FOUND, "not found"));
+    };
+    let overwrite = request.uri().query().is_some_and(|q| q.split('&').any(|p| p == "quiet=true"));
+    match *request.method() {
+        // tables are posted to CasJobs
+        Method::PUT | Method::POST => {
+            // read in full like the fileservice, whatever the answer
+            let data = match request.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return Ok(reply(StatusCode::BAD_REQUEST, "")),
+            };
+            let mut state = state.lock().unwrap();
+            state.uploads += 1;
+            let planned = state.replies.pop_front();
+            Ok(match planned.unwrap_or(Reply::Store) {
+                _ if !authorized => reply(StatusCode::UNAUTHORIZED, ""),
+                Reply::StoreAndHangUp => {
+                    state.files.insert(path, data);
+                    return Err(hang_up());
+                }
+                Reply::StorePartAndHangUp => {
+                    state.files.insert(path, data.slice(..data.len() / 2));
+                    return Err(hang_up());
+                }
+                Reply::HangUp => return Err(hang_up()),
+                Reply::Store if state.files.contains_key(&path) && !overwrite => {
+                    reply(StatusCode::INTERNAL_SERVER_ERROR, "File already exists")
+                }
+                Reply::Store => {
+                    state.files.insert(path, data);
+                    reply(StatusCode::OK, "")
+                }
+                Reply::Unauthorized => reply(StatusCode::UNAUTHORIZED, ""),
+                Reply::Exists => reply(StatusCode::INTERNAL_SERVER_ERROR, "File already exists"),
+                Reply::TooManyRequests => reply(StatusCode::TOO_MANY_REQUESTS, "slow down"),
+                Reply::Unavailable => reply(StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+            })
+        }
+        Method::GET if !authorized => Ok(reply(StatusCode::UNAUTHORIZED, "")),
+        Method::GET => Ok(match state.lock().unwrap().files.get(&path) {
+            Some(data) => reply(StatusCode::OK, data.clone()),
+            None => reply(StatusCode::NOT_FOUND, "not found"),
+        }),
+        _ => Ok(reply(StatusCode::METHOD_NOT_ALLOWED, "")),
+    }
+}
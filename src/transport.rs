@@ -0,0 +1,147 @@
+//! Classifying failed requests that got no response, as the cause decides
+//! whether trying again can help and how long to wait before doing so.
+
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+
+/// What to do after an upload failed once its data was being sent, when the
+/// fileservice may have written some or all of the file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AmbiguousRetry {
+    /// upload again as after any other failure
+    Blind,
+    /// List the file first: it's done if it's there with the same contents,
+    /// uploaded again if it isn't there, and anything else there is replaced
+    /// only when overwriting, failing as existing otherwise.
+    Verify,
+}
+
+impl std::str::FromStr for AmbiguousRetry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blind" => Ok(AmbiguousRetry::Blind),
+            "verify" => Ok(AmbiguousRetry::Verify),
+            _ => Err(format!("unknown retry mode {:?}, expected blind or verify", s)),
+        }
+    }
+}
+
+/// why a request got no response, more reasons may be told apart
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum TransportError {
+    /// the endpoint's name didn't resolve
+    Dns,
+    /// tls handshake or certificate failure, trying again won't help
+    Tls,
+    /// nothing listening, e.g. the service is restarting
+    Refused,
+    /// connecting or the transfer took too long
+    Timeout,
+    /// connection reset or closed mid-transfer
+    Reset,
+    /// reading the file for the body failed
+    Body,
+    /// anything else
+    Other,
+}
+
+impl TransportError {
+    pub(crate) fn classify(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            return TransportError::Timeout;
+        }
+        // what hyper and the tls backends report is only visible in the chain
+        let mut source: Option<&(dyn Error + 'static)> = error.source();
+        let mut text = String::new();
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<io::Error>() {
+                match e.kind() {
+                    io::ErrorKind::ConnectionRefused => return TransportError::Refused,
+                    io::ErrorKind::TimedOut => return TransportError::Timeout,
+                    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::BrokenPipe | io::ErrorKind::UnexpectedEof => return TransportError::Reset,
+                    _ => (),
+                }
+            }
+            text.push_str(&e.to_string().to_lowercase());
+            text.push('\n');
+            source = e.source();
+        }
+        if text.contains("body stream") {
+            TransportError::Body
+        } else if text.contains("dns error") {
+            TransportError::Dns
+        } else if ["certificate", "tls", "ssl", "handshake"].iter().any(|s| text.contains(s)) {
+            TransportError::Tls
+        } else if error.is_body() {
+            TransportError::Body
+        } else if error.is_connect() {
+            TransportError::Refused
+        } else {
+            TransportError::Other
+        }
+    }
+
+    /// How long to wait before attempt `retry` (from 1), None if trying again
+    /// is pointless. Services coming back and congestion need some time,
+    /// growing with each attempt, spread by `jitter` (0 to 1) so uploads
+    /// don't retry in lockstep.
+    pub(crate) fn retry_delay(&self, retry: usize, jitter: f64) -> Option<Duration> {
+        let base = match self {
+            TransportError::Tls => return None,
+            TransportError::Body => return Some(Duration::ZERO),
+            TransportError::Dns => Duration::from_secs(2),
+            TransportError::Refused | TransportError::Timeout => Duration::from_secs(1),
+            TransportError::Reset | TransportError::Other => Duration::from_millis(250),
+        };
+        let delay = base * 2u32.pow(retry.saturating_sub(1).min(5) as u32);
+        Some(delay.mul_f64(0.5 + jitter))
+    }
+
+    /// Whether the fileservice may have written (part of) the file, given
+    /// the transfer of its data had `started`. Failing to read the file
+    /// doesn't tell, the request is cut short either way.
+    pub(crate) fn is_ambiguous(&self, started: bool) -> bool {
+        started && *self != TransportError::Body
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            TransportError::Dns => "dns failure",
+            TransportError::Tls => "tls failure",
+            TransportError::Refused => "connection refused",
+            TransportError::Timeout => "timed out",
+            TransportError::Reset => "connection reset",
+            TransportError::Body => "reading the file failed",
+            TransportError::Other => "request failed",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_classify() {
+        // nothing listens on a port just released
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let error = reqwest::Client::new().get(format!("http://127.0.0.1:{}/", port)).send().await.unwrap_err();
+        assert_eq!(TransportError::classify(&error), TransportError::Refused);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let rng = &mut fastrand::Rng::new();
+        assert_eq!(TransportError::Tls.retry_delay(1, rng.f64()), None);
+        assert_eq!(TransportError::Body.retry_delay(3, rng.f64()), Some(Duration::ZERO));
+        let first = TransportError::Refused.retry_delay(1, rng.f64()).unwrap();
+        let third = TransportError::Refused.retry_delay(3, rng.f64()).unwrap();
+        assert!(first >= Duration::from_millis(500) && first <= Duration::from_millis(1500));
+        assert!(third >= Duration::from_secs(2) && third <= Duration::from_secs(6));
+    }
+}
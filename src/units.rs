@@ -0,0 +1,111 @@
+//! Parsing of human friendly quantities given on the command line.
+
+use std::time::{Duration, SystemTime};
+
+/// Parse a byte size like `512`, `4K`, `1.5M`, `500G` or `2TiB`. Suffixes are
+/// binary (K = 1024) and case insensitive, a trailing `B`/`iB` is allowed.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid size: {:?}", s))?;
+    let suffix = suffix.trim().to_ascii_lowercase();
+    let suffix = suffix.strip_suffix("ib").or_else(|| suffix.strip_suffix('b')).unwrap_or(&suffix);
+    let multiplier: u64 = match suffix {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1 << 40,
+        _ => return Err(format!("invalid size suffix: {:?}", s)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse a throughput in bytes per second like `200MB/s` or `1G`, with the
+/// suffixes of [`parse_size`] and an optional `/s`.
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    match parse_size(s.strip_suffix("/s").unwrap_or(s)) {
+        Ok(0) => Err(format!("rate must be more than 0: {:?}", s)),
+        Ok(rate) => Ok(rate),
+        Err(_) => Err(format!("invalid rate {:?}, expected e.g. 200MB/s", s)),
+    }
+}
+
+/// Parse a duration like `90`, `500ms`, `30s`, `15m`, `2h` or `1d`, plain
+/// numbers being seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid duration: {:?}", s))?;
+    let seconds = match suffix.trim() {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        _ => return Err(format!("invalid duration unit: {:?}", s)),
+    };
+    Ok(Duration::from_secs_f64(number * seconds))
+}
+
+/// Parse a point in time, either a duration ago (`2h`, `7d`) or a UTC
+/// timestamp (`2025-06-30`, `2025-06-30 14:00:00`, `2025-06-30T14:00:00Z`).
+pub fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let s = s.trim();
+    if let Ok(ago) = parse_duration(s) {
+        return SystemTime::now().checked_sub(ago).ok_or_else(|| format!("too long ago: {:?}", s));
+    }
+    let timestamp = if s.len() == 10 { format!("{}T00:00:00", s) } else { s.to_string() };
+    humantime::parse_rfc3339_weak(&timestamp)
+        .map_err(|_| format!("invalid time {:?}, expected a duration like 2h or a timestamp like 2025-06-30", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size("1.5m"), Ok(1536 * 1024));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("1 TB"), Ok(1 << 40));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("5X").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("200MB/s"), Ok(200 << 20));
+        assert_eq!(parse_rate("1.5G"), Ok(3 << 29));
+        assert!(parse_rate("0/s").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("3w").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let day = SystemTime::UNIX_EPOCH + Duration::from_secs(1751241600);
+        assert_eq!(parse_time("2025-06-30"), Ok(day));
+        assert_eq!(parse_time("2025-06-30 01:00:00"), Ok(day + Duration::from_secs(3600)));
+        assert_eq!(parse_time("2025-06-30T01:00:00Z"), Ok(day + Duration::from_secs(3600)));
+        let hour_ago = parse_time("1h").unwrap();
+        let ago = SystemTime::now().duration_since(hour_ago).unwrap();
+        assert!(ago >= Duration::from_secs(3600) && ago < Duration::from_secs(3660));
+        assert!(parse_time("yesterday").is_err());
+    }
+}
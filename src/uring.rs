@@ -0,0 +1,112 @@
+//! Reading files with io_uring (linux, `uring` feature). tokio_uring has its
+//! own single threaded runtime, so files are read on a dedicated thread and
+//! the chunks handed to the upload body over a channel. Far fewer context
+//! switches than tokio::fs, which does every read on the blocking pool.
+
+use std::io;
+use std::sync::OnceLock;
+
+use reqwest::Body;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+/// a file to read and where to send its chunks
+struct Read {
+    path: String,
+    chunk_size: usize,
+    chunks: mpsc::Sender<Chunk>,
+}
+
+enum Chunk {
+    Data(Vec<u8>),
+    Error(io::Error),
+    Done,
+}
+
+static READER: OnceLock<UnboundedSender<Read>> = OnceLock::new();
+
+/// Channel to the reader thread, started on first use. If io_uring isn't
+/// available (old kernel, seccomp) the thread dies and sending fails.
+fn reader() -> &'static UnboundedSender<Read> {
+    READER.get_or_init(|| {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Read>();
+        std::thread::spawn(move || {
+            tokio_uring::start(async move {
+                while let Some(read) = rx.recv().await {
+                    tokio_uring::spawn(read_file(read));
+                }
+            })
+        });
+        tx
+    })
+}
+
+async fn read_file(read: Read) {
+    let file = match tokio_uring::fs::File::open(&read.path).await {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = read.chunks.send(Chunk::Error(e)).await;
+            return;
+        }
+    };
+    let mut offset = 0;
+    loop {
+        let (result, buf) = file.read_at(Vec::with_capacity(read.chunk_size), offset).await;
+        let chunk = match result {
+            Ok(0) => Chunk::Done,
+            Ok(n) => {
+                offset += n as u64;
+                Chunk::Data(buf)
+            }
+            Err(e) => Chunk::Error(e),
+        };
+        let last = !matches!(chunk, Chunk::Data(_));
+        if read.chunks.send(chunk).await.is_err() || last {
+            break;
+        }
+    }
+    let _ = file.close().await;
+}
+
+/// Body streaming the file at `path`, None if io_uring can't be used. The
+/// body fails rather than ending early if the reader goes away, so a file is
+/// never uploaded truncated.
+pub(crate) fn body(path: &str, chunk_size: usize) -> Option<Body> {
+    // a couple of chunks read ahead of the upload
+    let (tx, rx) = mpsc::channel(2);
+    reader().send(Read { path: path.to_string(), chunk_size, chunks: tx }).ok()?;
+    let stream = futures_util::stream::unfold(Some(rx), |rx| async move {
+        let mut rx = rx?;
+        match rx.recv().await {
+            Some(Chunk::Data(data)) => Some((Ok(data), Some(rx))),
+            Some(Chunk::Done) => None,
+            Some(Chunk::Error(e)) => Some((Err(e), None)),
+            None => Some((Err(io::Error::other("io_uring reader stopped")), None)),
+        }
+    });
+    Some(Body::wrap_stream(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_body() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.bin");
+        // several chunks, the last one short
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let read = body(path.to_str().unwrap(), 4096).unwrap();
+        assert_eq!(read.collect().await.unwrap().to_bytes(), data);
+
+        std::fs::write(&path, "").unwrap();
+        assert!(body(path.to_str().unwrap(), 4096).unwrap().collect().await.unwrap().to_bytes().is_empty());
+
+        // fails rather than uploading nothing
+        let missing = tempdir.path().join("missing");
+        assert!(body(missing.to_str().unwrap(), 4096).unwrap().collect().await.is_err());
+    }
+}
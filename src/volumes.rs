@@ -0,0 +1,159 @@
+//! Checking the destination path before uploading. Paths on the fileservice
+//! are `<root volume>/<owner>/<user volume>/...` (e.g.
+//! `Storage/arik/persistent/test`) or `<data volume>/...`, and the `volumes`
+//! api lists those the token can write to. A typo in them would otherwise
+//! fail every upload with a 500 that doesn't tell why.
+
+use reqwest::{Client, StatusCode};
+use serde_json::Value;
+
+/// the volumes the token has access to
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Volumes {
+    /// root volumes and their user volumes, as (owner, name)
+    roots: Vec<(String, Vec<(String, String)>)>,
+    data: Vec<String>,
+}
+
+/// Parse a `volumes` response, none if it isn't a listing of volumes.
+pub(crate) fn parse(value: &Value) -> Option<Volumes> {
+    if !value["rootVolumes"].is_array() && !value["dataVolumes"].is_array() {
+        return None;
+    }
+    let name = |v: &Value, key: &str| v[key].as_str().map(str::to_string);
+    let roots = value["rootVolumes"].as_array().into_iter().flatten()
+        .filter_map(|root| {
+            let users = root["userVolumes"].as_array().into_iter().flatten()
+                .filter_map(|user| Some((name(user, "owner")?, name(user, "name")?)))
+                .collect();
+            Some((name(root, "name")?, users))
+        })
+        .collect();
+    let data = value["dataVolumes"].as_array().into_iter().flatten().filter_map(|v| name(v, "name")).collect();
+    Some(Volumes { roots, data })
+}
+
+/// number of single character edits from `a` to `b`
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate `name` was most likely meant to be, if any is close.
+fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    candidates.into_iter()
+        .map(|c| (if c.eq_ignore_ascii_case(name) { 0 } else { distance(name, c) }, c))
+        .filter(|(d, c)| *d <= (c.len() / 3).max(1))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+/// `what` wasn't found among `candidates`, suggesting the closest.
+fn not_found<'a>(what: String, name: &str, candidates: impl IntoIterator<Item = &'a str>) -> String {
+    let candidates: Vec<_> = candidates.into_iter().collect();
+    match closest(name, candidates.iter().copied()) {
+        Some(close) => format!("{} not found, did you mean '{}'?", what, close),
+        None if candidates.is_empty() => format!("{} not found", what),
+        None => format!("{} not found, there are: {}", what, candidates.join(", ")),
+    }
+}
+
+impl Volumes {
+    /// Whether `path` is on one of the volumes, and what's wrong with it if
+    /// it isn't.
+    pub(crate) fn check(&self, path: &str) -> Result<(), String> {
+        let parts: Vec<_> = path.trim_matches('/').split('/').collect();
+        if self.data.iter().any(|d| d == parts[0]) {
+            return Ok(());
+        }
+        let Some((_, users)) = self.roots.iter().find(|(root, _)| root == parts[0]) else {
+            let volumes = self.roots.iter().map(|(root, _)| root.as_str()).chain(self.data.iter().map(String::as_str));
+            return Err(not_found(format!("volume '{}'", parts[0]), parts[0], volumes));
+        };
+        let [root, owner, volume, ..] = parts[..] else {
+            return Err(format!("'{}' is not a path on a user volume, expected {}/<owner>/<volume>[/<path>], \
+                e.g. {}/{}/persistent", path, parts[0], parts[0], users.first().map_or("<owner>", |(o, _)| o)));
+        };
+        if !users.iter().any(|(o, _)| o == owner) {
+            let owners = users.iter().map(|(o, _)| o.as_str());
+            return Err(not_found(format!("owner '{}' of a volume in {}", owner, root), owner, owners));
+        }
+        if !users.iter().any(|(o, v)| o == owner && v == volume) {
+            let volumes = users.iter().filter(|(o, _)| o == owner).map(|(_, v)| v.as_str());
+            return Err(not_found(format!("volume '{}' of {} in {}", volume, owner, root), volume, volumes));
+        }
+        Ok(())
+    }
+}
+
+/// Check `path` against the volumes listed by the `volumes` api at `url`.
+/// Deployments without the api (or failing to answer, or answering with
+/// something else) aren't checked, the uploads tell then.
+pub(crate) async fn preflight(client: &Client, url: &str, path: &str) -> Result<(), String> {
+    if !crate::inputs::valid_name(path.trim_matches('/')) {
+        return Err(format!("'{}' is not a valid path, it has empty, . or .. parts", path));
+    }
+    let Ok(response) = client.get(url).send().await else { return Ok(()) };
+    if response.status() != StatusCode::OK {
+        return Ok(());
+    }
+    match response.json::<Value>().await {
+        Ok(volumes) => parse(&volumes).map_or(Ok(()), |volumes| volumes.check(path)),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let volumes = parse(&json!({
+            "rootVolumes": [
+                {"name": "Storage", "userVolumes": [
+                    {"name": "persistent", "owner": "arik"},
+                    {"name": "scratch", "owner": "arik"},
+                    {"name": "shared", "owner": "manuchis"},
+                ]},
+                {"name": "Temporary", "userVolumes": []},
+            ],
+            "dataVolumes": [{"name": "SDSS_DAS"}],
+        })).unwrap();
+        assert!(volumes.check("Storage/arik/persistent/test").is_ok());
+        assert!(volumes.check("/Storage/manuchis/shared/").is_ok());
+        assert!(volumes.check("SDSS_DAS/dr18").is_ok());
+        assert_eq!(volumes.check("Storge/arik/persistent"),
+            Err("volume 'Storge' not found, did you mean 'Storage'?".to_string()));
+        assert_eq!(volumes.check("Scratch/arik/persistent"),
+            Err("volume 'Scratch' not found, there are: Storage, Temporary, SDSS_DAS".to_string()));
+        assert_eq!(volumes.check("Storage/arikk/persistent"),
+            Err("owner 'arikk' of a volume in Storage not found, did you mean 'arik'?".to_string()));
+        assert_eq!(volumes.check("Storage/arik/Persistent/a"),
+            Err("volume 'Persistent' of arik in Storage not found, did you mean 'persistent'?".to_string()));
+        assert_eq!(parse(&json!({})), None);
+        assert_eq!(volumes.check("Storage/arik"), Err("'Storage/arik' is not a path on a user volume, \
+            expected Storage/<owner>/<volume>[/<path>], e.g. Storage/arik/persistent".to_string()));
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance("Storge", "Storage"), 1);
+        assert_eq!(distance("persistant", "persistent"), 1);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(closest("tmp", ["Storage", "Temporary"]), None);
+    }
+}